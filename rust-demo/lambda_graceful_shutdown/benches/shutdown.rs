@@ -0,0 +1,139 @@
+//! The coordinator's own overhead on the shutdown path, with hooks that do nothing.
+//!
+//! Every shutdown writes its `SHUTDOWN_SUMMARY` line to stdout, which is part of the cost, so
+//! filter it out of the output:
+//!
+//! ```text
+//! cargo bench --bench shutdown | grep -v SHUTDOWN_SUMMARY
+//! ```
+
+use std::{
+    hint::black_box,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use lambda_graceful_shutdown::{
+    hook_fn,
+    static_hooks::{StaticHook, StaticHooks},
+    Error, ShutdownContext, ShutdownCoordinator, ShutdownReason, ShutdownReport,
+};
+use tokio::{runtime::Runtime, time::Instant};
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+/// A coordinator with `hooks` hooks that return right away.
+fn coordinator(hooks: usize) -> ShutdownCoordinator {
+    let shutdown = ShutdownCoordinator::new();
+    for index in 0..hooks {
+        shutdown.register(hook_fn(format!("hook-{index}"), |_ctx| async { Ok(()) }));
+    }
+    shutdown
+}
+
+/// From calling `shutdown()` to the first hook starting, with the drain and the span set up in
+/// between.
+fn trigger_to_first_hook(c: &mut Criterion) {
+    let runtime = runtime();
+    c.bench_function("trigger_to_first_hook", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let hook_started = Arc::new(Mutex::new(None));
+                let shutdown = ShutdownCoordinator::new()
+                    .with_invocation_drain(Duration::from_millis(100))
+                    .with_hook(hook_fn("first", {
+                        let hook_started = hook_started.clone();
+                        move |_ctx| {
+                            *hook_started.lock().unwrap() = Some(Instant::now());
+                            async { Ok(()) }
+                        }
+                    }));
+                let triggered = Instant::now();
+                shutdown.shutdown(ShutdownReason::Sigterm).await;
+                let hook_started = hook_started.lock().unwrap().expect("the hook ran");
+                total += hook_started - triggered;
+            }
+            total
+        });
+    });
+}
+
+/// A whole shutdown with more and more hooks, to see what each one adds.
+fn dispatch_per_hook(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("dispatch_per_hook");
+    for hooks in [1, 10, 100] {
+        let shutdown = coordinator(hooks);
+        group.throughput(Throughput::Elements(hooks as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(hooks),
+            &shutdown,
+            |b, shutdown| {
+                b.to_async(&runtime)
+                    .iter(|| shutdown.shutdown(black_box(ShutdownReason::Sigterm)));
+            },
+        );
+    }
+    group.finish();
+}
+
+/// A static hook that returns right away.
+struct Noop;
+
+impl StaticHook for Noop {
+    fn name(&self) -> &str {
+        "noop"
+    }
+
+    async fn shutdown(&self, _ctx: &ShutdownContext) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Eight hooks dispatched from a tuple, without boxing, next to eight registered ones.
+fn dispatch_static(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("dispatch_static");
+    group.throughput(Throughput::Elements(8));
+    let shutdown = ShutdownCoordinator::new();
+    let hooks = StaticHooks::new((Noop, Noop, Noop, Noop, Noop, Noop, Noop, Noop));
+    group.bench_function("static", |b| {
+        b.to_async(&runtime)
+            .iter(|| shutdown.shutdown_static(ShutdownReason::Sigterm, &hooks));
+    });
+    let registered = coordinator(8);
+    group.bench_function("registered", |b| {
+        b.to_async(&runtime)
+            .iter(|| registered.shutdown(ShutdownReason::Sigterm));
+    });
+    group.finish();
+}
+
+/// Rendering the report of a shutdown with 100 hooks.
+fn report_generation(c: &mut Criterion) {
+    let report: ShutdownReport =
+        runtime().block_on(coordinator(100).shutdown(ShutdownReason::Sigterm));
+    let mut group = c.benchmark_group("report_generation");
+    group.bench_function("counters", |b| b.iter(|| black_box(&report).counters()));
+    group.bench_function("to_json", |b| b.iter(|| black_box(&report).to_json()));
+    group.bench_function("summary_line", |b| {
+        b.iter(|| black_box(&report).summary_line())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    trigger_to_first_hook,
+    dispatch_per_hook,
+    dispatch_static,
+    report_generation
+);
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+//! Stopping an actix-web server, and its actix `System`, before the hooks that flush.
+//!
+//! Behind the Lambda Web Adapter an actix-web app is a server like any other: at spindown its
+//! workers should finish the requests they are handling before whatever those requests
+//! produced is flushed. [`ActixServerHook`] stops the server gracefully through its
+//! [`ServerHandle`], waits for the workers to finish, and then stops the actix [`System`] if
+//! it was given one, so a `System::run()` in `main` returns.
+//!
+//! actix-web stops on `SIGTERM` on its own, which would race with the coordinator, so leave the
+//! signals to the coordinator with `disable_signals()`. Its workers get 30 seconds to finish
+//! by default, far more than the shutdown budget; set `shutdown_timeout()` below it, so they
+//! are stopped by actix rather than abandoned.
+//!
+//! ```no_run
+//! use actix_rt::System;
+//! use lambda_graceful_shutdown::{actix::ActixServerHook, hook_fn, ShutdownCoordinator};
+//!
+//! // `server` is what `HttpServer::new(app).disable_signals().run()` returns
+//! # fn example(server: actix_server::Server) {
+//! let shutdown = ShutdownCoordinator::new()
+//!     .with_hook(hook_fn("metrics", |_ctx| async { Ok(()) }))
+//!     // Registered last, so it runs first
+//!     .with_hook(ActixServerHook::new(server.handle()).with_system(System::current()));
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use actix_rt::System;
+use actix_server::ServerHandle;
+
+use crate::{clock, BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] that stops an actix server gracefully.
+///
+/// The workers get what is left of the budget, or the timeout if one is set. If they haven't
+/// finished by then, the server is stopped forcefully, dropping the requests still in
+/// progress.
+#[derive(Debug)]
+pub struct ActixServerHook {
+    server: ServerHandle,
+    system: Option<System>,
+    timeout: Option<Duration>,
+}
+
+impl ActixServerHook {
+    /// Stop the server behind `server`, the handle from `Server::handle()`.
+    pub fn new(server: ServerHandle) -> Self {
+        Self {
+            server,
+            system: None,
+            timeout: None,
+        }
+    }
+
+    /// Also stop `system` once the server has stopped, usually `System::current()` from the
+    /// `main` the server runs in.
+    pub fn with_system(mut self, system: System) -> Self {
+        self.system = Some(system);
+        self
+    }
+
+    /// Stop waiting for the workers after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl ShutdownHook for ActixServerHook {
+    fn name(&self) -> &str {
+        "actix"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let clock = ctx.clock();
+            let deadline = clock.now() + ctx.remaining_capped(self.timeout);
+            let stopped = clock::timeout_at(clock, deadline, self.server.stop(true)).await;
+            if stopped.is_none() {
+                // Only tells the workers to stop, so there is no need to wait for it
+                std::mem::drop(self.server.stop(false));
+            }
+            if let Some(system) = &self.system {
+                system.stop();
+            }
+            match stopped {
+                Some(()) => Ok(()),
+                None => {
+                    Err(DrainTimeout::new("the actix workers were still handling requests").into())
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,69 @@
+//! Flushing for [`tracing_appender::non_blocking()`] writers.
+//!
+//! A non-blocking writer hands log lines to a background thread, which only guarantees that
+//! everything has been written out once its [`WorkerGuard`] is dropped. Calling
+//! `std::process::exit()` skips destructors, so without a hook the last log lines of a
+//! shutting-down function, including the ones explaining what the shutdown did, are
+//! silently lost.
+
+use std::{io::Write, sync::Mutex};
+
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// Wrap `writer` with [`tracing_appender::non_blocking()`], returning the writer along with
+/// a hook that holds on to the worker guard.
+///
+/// Register the hook first so that it runs last, after any other hook that logs.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{appender, ShutdownCoordinator};
+///
+/// let (writer, log_flush_hook) = appender::non_blocking(std::io::stdout());
+/// tracing_subscriber::fmt().with_writer(writer).init();
+///
+/// let shutdown = ShutdownCoordinator::new().with_hook(log_flush_hook);
+/// ```
+pub fn non_blocking<W: Write + Send + 'static>(writer: W) -> (NonBlocking, NonBlockingFlushHook) {
+    let (writer, guard) = tracing_appender::non_blocking(writer);
+    (writer, NonBlockingFlushHook::new(guard))
+}
+
+/// A [`ShutdownHook`] that flushes a non-blocking writer by dropping its [`WorkerGuard`].
+#[derive(Debug)]
+pub struct NonBlockingFlushHook {
+    guard: Mutex<Option<WorkerGuard>>,
+}
+
+impl NonBlockingFlushHook {
+    /// Take ownership of the guard returned by [`tracing_appender::non_blocking()`].
+    pub fn new(guard: WorkerGuard) -> Self {
+        Self {
+            guard: Mutex::new(Some(guard)),
+        }
+    }
+}
+
+impl From<WorkerGuard> for NonBlockingFlushHook {
+    fn from(guard: WorkerGuard) -> Self {
+        Self::new(guard)
+    }
+}
+
+impl ShutdownHook for NonBlockingFlushHook {
+    fn name(&self) -> &str {
+        "tracing-appender"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let guard = self.guard.lock().unwrap().take();
+            if let Some(guard) = guard {
+                // Dropping the guard blocks until the worker thread has written everything out.
+                tokio::task::spawn_blocking(move || drop(guard)).await?;
+            }
+            Ok(())
+        })
+    }
+}
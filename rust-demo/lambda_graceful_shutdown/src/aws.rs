@@ -0,0 +1,226 @@
+//! Coordinated teardown of `aws-sdk-rust` clients.
+//!
+//! Every SDK client made from the same [`SdkConfig`] shares its HTTP client, and with it a
+//! pool of keep-alive connections. The pool is only closed once the last client and the
+//! config itself are dropped, and requests still running when the environment is torn down
+//! are cut off halfway.
+//!
+//! [`AwsClients`] owns the config and hands out clients made from it. Calls made through
+//! [`AwsClient::call()`] are tracked, so at shutdown its
+//! [`shutdown_hook()`](AwsClients::shutdown_hook) stops new calls, gives the ones in flight
+//! time to finish, cancels whichever are left, and then drops every client so the pool
+//! closes its connections.
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use aws_types::SdkConfig;
+use tokio::sync::watch;
+
+use crate::{clock, BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// How often to check whether the calls in flight have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Part of the budget kept back for cancelling calls and dropping the clients.
+const CANCEL_RESERVE: Duration = Duration::from_millis(20);
+
+type Release = Box<dyn Fn() + Send + Sync>;
+
+struct Inner {
+    config: Mutex<Option<SdkConfig>>,
+    closed: AtomicBool,
+    in_flight: AtomicUsize,
+    cancel: watch::Sender<bool>,
+    /// Drops the client held by each [`AwsClient`] that is still around.
+    releases: Mutex<Vec<Release>>,
+}
+
+/// Owns an [`SdkConfig`] and the SDK clients made from it.
+///
+/// Cloning is cheap, and all clones share the same config and clients.
+#[derive(Clone)]
+pub struct AwsClients {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for AwsClients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AwsClients")
+            .field("closed", &self.inner.closed.load(Ordering::SeqCst))
+            .field("in_flight", &self.inner.in_flight.load(Ordering::SeqCst))
+            .finish_non_exhaustive()
+    }
+}
+
+impl AwsClients {
+    /// Take ownership of `config`.
+    pub fn new(config: SdkConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config: Mutex::new(Some(config)),
+                closed: AtomicBool::new(false),
+                in_flight: AtomicUsize::new(0),
+                cancel: watch::Sender::new(false),
+                releases: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Make a client from the config, e.g. `clients.client(aws_sdk_s3::Client::new)`, or
+    /// `None` once the shutdown hook has run.
+    pub fn client<C, F>(&self, make: F) -> Option<AwsClient<C>>
+    where
+        C: Clone + Send + 'static,
+        F: FnOnce(&SdkConfig) -> C,
+    {
+        let client = make(self.inner.config.lock().unwrap().as_ref()?);
+        let client = Arc::new(Mutex::new(Some(client)));
+        let weak: Weak<Mutex<Option<C>>> = Arc::downgrade(&client);
+        self.inner.releases.lock().unwrap().push(Box::new(move || {
+            if let Some(client) = weak.upgrade() {
+                client.lock().unwrap().take();
+            }
+        }));
+        Some(AwsClient {
+            client,
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// The number of calls currently in flight, across all clients.
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// A hook that tears down every client when the environment shuts down.
+    pub fn shutdown_hook(&self) -> AwsClientsHook {
+        AwsClientsHook {
+            clients: self.clone(),
+            grace_period: None,
+        }
+    }
+}
+
+/// An SDK client handed out by [`AwsClients`].
+pub struct AwsClient<C> {
+    client: Arc<Mutex<Option<C>>>,
+    inner: Arc<Inner>,
+}
+
+impl<C> Clone for AwsClient<C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C> fmt::Debug for AwsClient<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AwsClient").finish_non_exhaustive()
+    }
+}
+
+struct InFlight<'a>(&'a Inner);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<C: Clone> AwsClient<C> {
+    /// Make a call with the client, e.g. `s3.call(|s3| s3.list_buckets().send())`.
+    ///
+    /// Fails without calling `f` once shutdown has started, and with an error if the call is
+    /// cancelled by the shutdown hook.
+    pub async fn call<F, Fut, T, E>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(C) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Into<Error>,
+    {
+        let mut cancelled = self.inner.cancel.subscribe();
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        let _in_flight = InFlight(&self.inner);
+        let client = match &*self.client.lock().unwrap() {
+            Some(client) if !self.inner.closed.load(Ordering::SeqCst) => client.clone(),
+            _ => return Err("the AWS clients have been shut down".into()),
+        };
+        tokio::select! {
+            result = f(client) => result.map_err(Into::into),
+            _ = cancelled.wait_for(|cancelled| *cancelled) => {
+                Err("the call was cancelled for shutdown".into())
+            }
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that tears down the clients of an [`AwsClients`].
+pub struct AwsClientsHook {
+    clients: AwsClients,
+    grace_period: Option<Duration>,
+}
+
+impl fmt::Debug for AwsClientsHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AwsClientsHook")
+            .field("clients", &self.clients)
+            .field("grace_period", &self.grace_period)
+            .finish()
+    }
+}
+
+impl AwsClientsHook {
+    /// Cancel calls still in flight after `grace_period`, rather than shortly before the
+    /// budget runs out.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = Some(grace_period);
+        self
+    }
+}
+
+impl ShutdownHook for AwsClientsHook {
+    fn name(&self) -> &str {
+        "aws-sdk"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let inner = &self.clients.inner;
+            inner.closed.store(true, Ordering::SeqCst);
+
+            let grace_period = ctx
+                .remaining_capped(self.grace_period)
+                .saturating_sub(CANCEL_RESERVE);
+            let clock = ctx.clock();
+            let finished = clock::timeout_at(clock, clock.now() + grace_period, async {
+                while inner.in_flight.load(Ordering::SeqCst) > 0 {
+                    clock.sleep_until(clock.now() + POLL_INTERVAL).await;
+                }
+            })
+            .await;
+
+            let cancelled = inner.in_flight.load(Ordering::SeqCst);
+            inner.cancel.send_replace(true);
+            for release in inner.releases.lock().unwrap().drain(..) {
+                release();
+            }
+            inner.config.lock().unwrap().take();
+
+            if finished.is_none() {
+                return Err(format!("cancelled {cancelled} calls still in flight").into());
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,406 @@
+//! Writing records to an HTTP endpoint in batches.
+//!
+//! Many analytics stores take batches of records in a single `POST`: ClickHouse's HTTP
+//! interface with `INSERT ... FORMAT JSONEachRow`, Quickwit's ingest API, and plenty of
+//! others. [`HttpBatchWriter`] covers them without an integration each. It collects records,
+//! sends them once there are enough for a batch, and its
+//! [`flush_hook()`](HttpBatchWriter::flush_hook) sends whatever is left at shutdown.
+//!
+//! A [`BatchSerializer`] turns a batch into a request body. [`JsonLines`] and [`JsonArray`]
+//! cover the common cases.
+//!
+//! Requests go through an [`HttpClient`], so set up authentication on the client, for example
+//! with default headers.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use serde::Serialize;
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    buffer::BatchBuffer,
+    clock::Clock,
+    transport::{self, HttpClient},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// Turns a batch of records into a request body.
+pub trait BatchSerializer<T>: Send + Sync {
+    /// The request's `Content-Type`.
+    fn content_type(&self) -> &str;
+
+    /// Serialize `batch` into a request body.
+    fn serialize(&self, batch: &[T]) -> Result<Vec<u8>, Error>;
+}
+
+/// Serializes a batch as newline-delimited JSON, one record per line.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonLines;
+
+impl<T: Serialize> BatchSerializer<T> for JsonLines {
+    fn content_type(&self) -> &str {
+        "application/x-ndjson"
+    }
+
+    fn serialize(&self, batch: &[T]) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        for record in batch {
+            serde_json::to_writer(&mut body, record)?;
+            body.push(b'\n');
+        }
+        Ok(body)
+    }
+}
+
+/// Serializes a batch as a JSON array.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonArray;
+
+impl<T: Serialize> BatchSerializer<T> for JsonArray {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn serialize(&self, batch: &[T]) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(batch)?)
+    }
+}
+
+struct Inner<T, S> {
+    client: Arc<dyn HttpClient>,
+    endpoint: String,
+    serializer: S,
+    buffer: Mutex<BatchBuffer<T>>,
+    dropped: AtomicUsize,
+}
+
+/// Sends records to an HTTP endpoint, in batches.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+pub struct HttpBatchWriter<T, S> {
+    inner: Arc<Inner<T, S>>,
+}
+
+impl<T, S> Clone for HttpBatchWriter<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, S> fmt::Debug for HttpBatchWriter<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpBatchWriter")
+            .field("endpoint", &self.inner.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, S> HttpBatchWriter<T, S>
+where
+    T: Send,
+    S: BatchSerializer<T>,
+{
+    /// Create a writer that posts batches of up to `max_batch` records to `endpoint`, such as
+    /// `http://clickhouse:8123/?query=INSERT%20INTO%20events%20FORMAT%20JSONEachRow`.
+    pub fn new(
+        client: impl HttpClient,
+        endpoint: impl Into<String>,
+        serializer: S,
+        max_batch: usize,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client: Arc::new(client),
+                endpoint: endpoint.into(),
+                serializer,
+                buffer: Mutex::new(BatchBuffer::new(max_batch.max(1), usize::MAX)),
+                dropped: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Queue `record`, sending a batch if this fills one up.
+    pub async fn write(&self, record: T) -> Result<(), Error> {
+        let mut buffer = self.inner.buffer.lock().await;
+        buffer.push(record, 0);
+        while buffer.has_full_batch() {
+            self.inner.send_batch(&mut buffer, None).await?;
+        }
+        Ok(())
+    }
+
+    /// The number of records waiting to be sent.
+    pub async fn pending(&self) -> usize {
+        self.inner.buffer.lock().await.len()
+    }
+
+    /// The number of records the endpoint rejected, which are not sent again.
+    pub fn dropped(&self) -> usize {
+        self.inner.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Send every queued record.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let mut buffer = self.inner.buffer.lock().await;
+        self.inner.flush(&mut buffer, None).await
+    }
+
+    /// A hook that sends any queued records when the environment shuts down.
+    pub fn flush_hook(&self) -> HttpBatchFlushHook<T, S> {
+        HttpBatchFlushHook {
+            writer: self.clone(),
+        }
+    }
+}
+
+impl<T, S: BatchSerializer<T>> Inner<T, S> {
+    /// Send every queued record, giving up on requests still running at `deadline`, on the
+    /// clock given with it.
+    async fn flush(
+        &self,
+        buffer: &mut BatchBuffer<T>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            self.send_batch(buffer, deadline).await?;
+        }
+        Ok(())
+    }
+
+    /// Send one batch. If it fails, the batch is put back in the buffer, unless the endpoint
+    /// rejected it as invalid, in which case it is dropped.
+    async fn send_batch(
+        &self,
+        buffer: &mut BatchBuffer<T>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> Result<(), Error> {
+        let batch = buffer.take_batch();
+        let records: Vec<T> = batch.into_iter().map(|(record, _)| record).collect();
+        let body = match self.serializer.serialize(&records) {
+            Ok(body) => body,
+            Err(error) => {
+                self.dropped.fetch_add(records.len(), Ordering::SeqCst);
+                return Err(error);
+            }
+        };
+
+        let content_type = self.serializer.content_type();
+        let sent = transport::post(&*self.client, &self.endpoint, content_type, body, deadline);
+        let error: Error = match sent.await {
+            Ok(response) if response.is_success() => return Ok(()),
+            Ok(response) if response.is_rejected() => {
+                self.dropped.fetch_add(records.len(), Ordering::SeqCst);
+                return Err(format!(
+                    "{} records were rejected: the endpoint responded with {}",
+                    records.len(),
+                    response.status
+                )
+                .into());
+            }
+            Ok(response) => format!("the endpoint responded with {}", response.status).into(),
+            Err(error) => error,
+        };
+        buffer.requeue(records.into_iter().map(|record| (record, 0)).collect());
+        Err(error)
+    }
+}
+
+/// A [`ShutdownHook`] that sends the records still buffered in an [`HttpBatchWriter`].
+///
+/// The records it could not deliver are counted in the hook's notes, along with any rejected
+/// earlier.
+pub struct HttpBatchFlushHook<T, S> {
+    writer: HttpBatchWriter<T, S>,
+}
+
+impl<T, S> fmt::Debug for HttpBatchFlushHook<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpBatchFlushHook")
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl<T, S> ShutdownHook for HttpBatchFlushHook<T, S>
+where
+    T: Send + 'static,
+    S: BatchSerializer<T> + 'static,
+{
+    fn name(&self) -> &str {
+        "http-batch"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let inner = &self.writer.inner;
+            let mut buffer = inner.buffer.lock().await;
+            let pending = buffer.len();
+            let rejected_before = inner.dropped.load(Ordering::SeqCst);
+            let flushed = inner
+                .flush(&mut buffer, Some((ctx.clock(), ctx.drain_deadline())))
+                .await;
+
+            let left = buffer.len();
+            let dropped = inner.dropped.fetch_add(left, Ordering::SeqCst) + left;
+            let sent = pending - (dropped - rejected_before);
+            ctx.note(format!(
+                "sent {sent} of {pending} pending records, {dropped} dropped in total"
+            ));
+            flushed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex as StdMutex, time::Duration};
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        transport::{HttpRequest, HttpResponse},
+        HookOutcome, ShutdownCoordinator, ShutdownReason,
+    };
+
+    const ENDPOINT: &str = "http://clickhouse:8123/?query=INSERT%20INTO%20events";
+
+    /// Answers each request with the next scripted status, and with `200` once they run out,
+    /// or never answers if `stall` is set. Records the requests.
+    #[derive(Default)]
+    struct FakeEndpoint {
+        statuses: StdMutex<VecDeque<u16>>,
+        stall: bool,
+        requests: StdMutex<Vec<HttpRequest>>,
+    }
+
+    impl FakeEndpoint {
+        fn responding(statuses: impl IntoIterator<Item = u16>) -> Arc<Self> {
+            Arc::new(Self {
+                statuses: StdMutex::new(statuses.into_iter().collect()),
+                ..Self::default()
+            })
+        }
+
+        fn bodies(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| String::from_utf8(request.body.clone()).unwrap())
+                .collect()
+        }
+    }
+
+    impl HttpClient for FakeEndpoint {
+        fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+            self.requests.lock().unwrap().push(request);
+            if self.stall {
+                return Box::pin(std::future::pending());
+            }
+            let status = self.statuses.lock().unwrap().pop_front().unwrap_or(200);
+            Box::pin(async move {
+                Ok(HttpResponse {
+                    status,
+                    body: Vec::new(),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn records_are_sent_once_a_batch_is_full() {
+        let endpoint = FakeEndpoint::responding([]);
+        let writer = HttpBatchWriter::new(endpoint.clone(), ENDPOINT, JsonLines, 2);
+        for id in 1..=3 {
+            writer.write(json!({ "id": id })).await.unwrap();
+        }
+        assert_eq!(writer.pending().await, 1);
+
+        let request = endpoint.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.url, ENDPOINT);
+        assert_eq!(request.content_type, "application/x-ndjson");
+        assert_eq!(endpoint.bodies(), ["{\"id\":1}\n{\"id\":2}\n"]);
+    }
+
+    #[tokio::test]
+    async fn json_array_sends_the_batch_as_one_array() {
+        let endpoint = FakeEndpoint::responding([]);
+        let writer = HttpBatchWriter::new(endpoint.clone(), ENDPOINT, JsonArray, 10);
+        writer.write(json!({ "id": 1 })).await.unwrap();
+        writer.write(json!({ "id": 2 })).await.unwrap();
+        writer.flush().await.unwrap();
+
+        let request = endpoint.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.content_type, "application/json");
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body, json!([{ "id": 1 }, { "id": 2 }]));
+    }
+
+    #[tokio::test]
+    async fn rejected_records_are_dropped_and_failed_ones_kept() {
+        let endpoint = FakeEndpoint::responding([400, 429]);
+        let writer = HttpBatchWriter::new(endpoint.clone(), ENDPOINT, JsonLines, 10);
+        writer.write(1).await.unwrap();
+        assert!(writer.flush().await.is_err());
+        assert_eq!((writer.pending().await, writer.dropped()), (0, 1));
+
+        writer.write(2).await.unwrap();
+        assert!(writer.flush().await.is_err());
+        assert_eq!((writer.pending().await, writer.dropped()), (1, 1));
+        writer.flush().await.unwrap();
+        assert_eq!(endpoint.bodies(), ["1\n", "2\n", "2\n"]);
+    }
+
+    #[tokio::test]
+    async fn the_hook_sends_what_is_left() {
+        let endpoint = FakeEndpoint::responding([]);
+        let writer = HttpBatchWriter::new(endpoint.clone(), ENDPOINT, JsonLines, 2);
+        for id in 1..=3 {
+            writer.write(id).await.unwrap();
+        }
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(writer.flush_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent 1 of 1 pending records, 0 dropped in total"]
+        );
+        assert_eq!(endpoint.bodies(), ["1\n2\n", "3\n"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_hook_gives_up_on_a_stalled_request_in_time() {
+        let endpoint = Arc::new(FakeEndpoint {
+            stall: true,
+            ..FakeEndpoint::default()
+        });
+        let writer = HttpBatchWriter::new(endpoint, ENDPOINT, JsonLines, 10);
+        writer.write(1).await.unwrap();
+
+        let report = ShutdownCoordinator::new()
+            .with_budget(Duration::from_secs(1))
+            .with_hook(writer.flush_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(matches!(
+            &report.hooks[0].outcome,
+            HookOutcome::Failed(error) if error.starts_with("the request to")
+        ));
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent 0 of 1 pending records, 1 dropped in total"]
+        );
+    }
+}
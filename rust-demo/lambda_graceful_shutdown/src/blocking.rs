@@ -0,0 +1,379 @@
+//! A synchronous coordinator, for functions that don't run an async runtime at all.
+//!
+//! A handler that only does blocking work, in a hand-rolled bootstrap loop or with one of
+//! `lambda_runtime`'s minimal setups, doesn't need futures for its shutdown either.
+//! [`BlockingCoordinator`] runs plain closures instead: each [`BlockingHook`] runs on a thread
+//! of its own, and is abandoned if it is still running when the budget runs out. With the
+//! `tokio-runtime` feature off, none of tokio's runtime is built.
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::blocking::{self, BlockingCoordinator};
+//!
+//! # fn next_invocation() -> Option<String> { None }
+//! fn main() -> std::io::Result<()> {
+//!     let shutdown = BlockingCoordinator::new().with_hook(blocking::hook_fn("flush", |ctx| {
+//!         println!("flushing with {:?} left", ctx.remaining());
+//!         Ok(())
+//!     }));
+//!     // Runs the hooks on SIGTERM or SIGINT, then exits the process
+//!     shutdown.spawn_signal_handler()?;
+//!
+//!     while let Some(_event) = next_invocation() {
+//!         // ...handle the event
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    coordinator::hook_outcome, stdout, Error, HookOutcome, HookReport, SandboxStats,
+    ShutdownReason, ShutdownReport, DEFAULT_BUDGET,
+};
+
+/// Cleanup logic that blocks, run by a [`BlockingCoordinator`].
+pub trait BlockingHook: Send + Sync {
+    /// Name used in logs and in the [`ShutdownReport`].
+    fn name(&self) -> &str;
+
+    /// Flush, close or otherwise tear down whatever this hook is responsible for.
+    fn shutdown(&self, ctx: &BlockingContext) -> Result<(), Error>;
+}
+
+/// Build a [`BlockingHook`] out of a name and a closure.
+pub fn hook_fn<F>(name: impl Into<String>, f: F) -> FnHook<F>
+where
+    F: Fn(&BlockingContext) -> Result<(), Error> + Send + Sync,
+{
+    FnHook {
+        name: name.into(),
+        f,
+    }
+}
+
+/// A [`BlockingHook`] created with [`hook_fn()`].
+pub struct FnHook<F> {
+    name: String,
+    f: F,
+}
+
+impl<F> fmt::Debug for FnHook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnHook").field("name", &self.name).finish()
+    }
+}
+
+impl<F> BlockingHook for FnHook<F>
+where
+    F: Fn(&BlockingContext) -> Result<(), Error> + Send + Sync,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn shutdown(&self, ctx: &BlockingContext) -> Result<(), Error> {
+        (self.f)(ctx)
+    }
+}
+
+/// Information handed to each [`BlockingHook`] while the shutdown is in progress.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockingContext {
+    reason: ShutdownReason,
+    started: Instant,
+    deadline: Instant,
+}
+
+impl BlockingContext {
+    /// Why the shutdown was triggered.
+    pub fn reason(&self) -> ShutdownReason {
+        self.reason
+    }
+
+    /// How long ago the shutdown started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The point in time at which the shutdown budget runs out.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// How much of the shutdown budget is left. Hooks that block on I/O can use it as their
+    /// timeout.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Runs the registered [`BlockingHook`]s when the environment shuts down, within a budget.
+///
+/// Cloning is cheap, and all clones share the same hooks.
+#[derive(Clone)]
+pub struct BlockingCoordinator {
+    budget: Duration,
+    hooks: Arc<Mutex<Vec<Arc<dyn BlockingHook>>>>,
+    started: Arc<AtomicBool>,
+    created: Instant,
+}
+
+impl fmt::Debug for BlockingCoordinator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockingCoordinator")
+            .field("budget", &self.budget)
+            .field("hooks", &self.hook_names())
+            .field("started", &self.is_shutting_down())
+            .finish()
+    }
+}
+
+impl Default for BlockingCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockingCoordinator {
+    /// Create a coordinator with no hooks and the [`DEFAULT_BUDGET`].
+    pub fn new() -> Self {
+        Self {
+            budget: DEFAULT_BUDGET,
+            hooks: Arc::default(),
+            started: Arc::default(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Set how long all hooks are allowed to take, in total.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Register a hook, builder style.
+    pub fn with_hook(self, hook: impl BlockingHook + 'static) -> Self {
+        self.register(hook);
+        self
+    }
+
+    /// Register a hook. Hooks run in reverse order of registration, like destructors.
+    pub fn register(&self, hook: impl BlockingHook + 'static) {
+        self.hooks.lock().unwrap().push(Arc::new(hook));
+    }
+
+    /// The total time hooks are allowed to take.
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// The names of the registered hooks, in the order they will run.
+    pub fn hook_names(&self) -> Vec<String> {
+        let hooks = self.hooks.lock().unwrap();
+        hooks
+            .iter()
+            .rev()
+            .map(|hook| hook.name().to_owned())
+            .collect()
+    }
+
+    /// Returns true once [`shutdown()`](Self::shutdown) has been called.
+    pub fn is_shutting_down(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// Run every registered hook, most recently registered first, each on a thread of its
+    /// own, and stop once the budget is used up.
+    ///
+    /// A hook still running when the budget runs out is recorded as timed out, and its thread
+    /// is left to finish, or to be stopped with the process. A hook that panics is recorded
+    /// as failed. The last thing this does is write the report's
+    /// [`summary_line()`](ShutdownReport::summary_line) to stdout.
+    pub fn shutdown(&self, reason: ShutdownReason) -> ShutdownReport {
+        let _stdout = stdout::hold();
+        self.started.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        let ctx = BlockingContext {
+            reason,
+            started,
+            deadline: started + self.budget,
+        };
+        // Don't hold the lock while the hooks run, they may want to register more hooks.
+        let hooks: Vec<_> = self.hooks.lock().unwrap().iter().rev().cloned().collect();
+        let hooks = hooks.into_iter().map(|hook| run_hook(hook, ctx)).collect();
+        let report = ShutdownReport {
+            reason,
+            request_id: None,
+            elapsed: started.elapsed(),
+            invocations: None,
+            sandbox: SandboxStats {
+                uptime: self.created.elapsed(),
+                ..SandboxStats::default()
+            },
+            hooks,
+        };
+        stdout::write_line(report.summary_line());
+        report
+    }
+
+    /// Block until `SIGTERM` or `SIGINT` arrives, then [shut down](Self::shutdown) and return
+    /// the report.
+    ///
+    /// Fails if the signal handlers can't be installed.
+    #[cfg(unix)]
+    pub fn shutdown_on_signal(&self) -> std::io::Result<ShutdownReport> {
+        let mut signals = Signals::new()?;
+        Ok(self.shutdown(signals.wait()))
+    }
+
+    /// Install the `SIGTERM` and `SIGINT` handlers, and wait for them on a thread of its own,
+    /// which [shuts down](Self::shutdown) and exits the process with the report's
+    /// [`exit_code()`](ShutdownReport::exit_code).
+    ///
+    /// The handlers are installed before this returns, so a signal that arrives right after
+    /// isn't lost. Fails if they can't be installed.
+    #[cfg(unix)]
+    pub fn spawn_signal_handler(&self) -> std::io::Result<()> {
+        let mut signals = Signals::new()?;
+        let coordinator = self.clone();
+        thread::Builder::new()
+            .name("shutdown-signals".to_owned())
+            .spawn(move || {
+                let report = coordinator.shutdown(signals.wait());
+                std::process::exit(report.exit_code())
+            })?;
+        Ok(())
+    }
+}
+
+/// Run `hook` on a thread of its own, for as long as `ctx` has budget left.
+fn run_hook(hook: Arc<dyn BlockingHook>, ctx: BlockingContext) -> HookReport {
+    let name = hook.name().to_owned();
+    let started = Instant::now();
+    let outcome = if ctx.remaining().is_zero() {
+        HookOutcome::Skipped
+    } else {
+        let (done, result) = mpsc::sync_channel(1);
+        let spawned = thread::Builder::new()
+            .name(format!("shutdown-{name}"))
+            .spawn(move || {
+                let _ = done.send(hook.shutdown(&ctx));
+            });
+        match spawned {
+            Ok(_) => match result.recv_timeout(ctx.remaining()) {
+                Ok(result) => hook_outcome(Some(result)),
+                Err(mpsc::RecvTimeoutError::Timeout) => hook_outcome(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    HookOutcome::Failed("the hook panicked".to_owned())
+                }
+            },
+            Err(error) => HookOutcome::Failed(format!("failed to start a thread: {error}")),
+        }
+    };
+    if outcome != HookOutcome::Completed {
+        tracing::warn!(hook = name, %outcome, "shutdown hook did not complete");
+    }
+    HookReport {
+        name,
+        elapsed: started.elapsed(),
+        outcome,
+        notes: Vec::new(),
+    }
+}
+
+/// Handlers for the signals that start a shutdown.
+#[cfg(unix)]
+struct Signals(signal_hook::iterator::Signals);
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> std::io::Result<Self> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        signal_hook::iterator::Signals::new([SIGINT, SIGTERM]).map(Self)
+    }
+
+    /// Block until the next `SIGINT` or `SIGTERM`.
+    fn wait(&mut self) -> ShutdownReason {
+        match self.0.forever().next() {
+            Some(signal_hook::consts::SIGINT) => ShutdownReason::Sigint,
+            // The iterator only ends once the handle is closed, which nothing here does
+            _ => ShutdownReason::Sigterm,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DrainTimeout;
+
+    #[test]
+    fn hooks_run_most_recently_registered_first() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let coordinator = BlockingCoordinator::new();
+        for name in ["first", "second"] {
+            let ran = ran.clone();
+            coordinator.register(hook_fn(name, move |ctx| {
+                assert_eq!(ctx.reason(), ShutdownReason::Sigint);
+                ran.lock().unwrap().push(name);
+                Ok(())
+            }));
+        }
+        assert_eq!(coordinator.hook_names(), ["second", "first"]);
+        assert!(!coordinator.is_shutting_down());
+
+        let report = coordinator.shutdown(ShutdownReason::Sigint);
+        assert!(coordinator.is_shutting_down());
+        assert!(report.is_clean());
+        assert_eq!(*ran.lock().unwrap(), ["second", "first"]);
+    }
+
+    #[test]
+    fn errors_and_panics_fail_the_hook_and_the_rest_still_run() {
+        let report = BlockingCoordinator::new()
+            .with_hook(hook_fn("last", |_| Ok(())))
+            .with_hook(hook_fn("drain", |_| {
+                Err(DrainTimeout::new("3 requests left").into())
+            }))
+            .with_hook(hook_fn("panics", |_| panic!("injected panic")))
+            .with_hook(hook_fn("fails", |_| Err("broken".into())))
+            .shutdown(ShutdownReason::Sigterm);
+        let outcomes: Vec<_> = report.hooks.iter().map(|hook| &hook.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [
+                &HookOutcome::Failed("broken".to_owned()),
+                &HookOutcome::Failed("the hook panicked".to_owned()),
+                &HookOutcome::DrainTimedOut("3 requests left".to_owned()),
+                &HookOutcome::Completed,
+            ]
+        );
+    }
+
+    #[test]
+    fn a_hook_still_running_at_the_deadline_is_abandoned() {
+        let budget = Duration::from_millis(50);
+        let report = BlockingCoordinator::new()
+            .with_budget(budget)
+            .with_hook(hook_fn("skipped", |_| Ok(())))
+            .with_hook(hook_fn("slow", move |ctx| {
+                assert!(ctx.remaining() <= budget);
+                thread::sleep(Duration::from_secs(1));
+                Ok(())
+            }))
+            .shutdown(ShutdownReason::Sigterm);
+        assert!(report.elapsed < Duration::from_millis(500));
+        assert_eq!(report.hooks[0].outcome, HookOutcome::TimedOut);
+        assert_eq!(report.hooks[1].outcome, HookOutcome::Skipped);
+    }
+}
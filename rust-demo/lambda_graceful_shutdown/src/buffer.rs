@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+/// Items waiting to be written in batches, bounded by a number of items and a total size per
+/// batch. Shared by the buffered writers for services with batch APIs.
+#[derive(Debug)]
+pub(crate) struct BatchBuffer<T> {
+    items: VecDeque<(T, usize)>,
+    bytes: usize,
+    max_items: usize,
+    max_bytes: usize,
+}
+
+impl<T> BatchBuffer<T> {
+    pub(crate) fn new(max_items: usize, max_bytes: usize) -> Self {
+        Self {
+            items: VecDeque::new(),
+            bytes: 0,
+            max_items,
+            max_bytes,
+        }
+    }
+
+    #[cfg(any(
+        feature = "dynamodb",
+        feature = "firehose",
+        feature = "kinesis",
+        feature = "loki",
+        feature = "opensearch",
+        feature = "sqs"
+    ))]
+    pub(crate) fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Queue an item. Callers check `size` against [`max_bytes()`](Self::max_bytes) first.
+    pub(crate) fn push(&mut self, item: T, size: usize) {
+        self.bytes += size;
+        self.items.push_back((item, size));
+    }
+
+    /// Returns true once there is enough queued to fill a whole batch.
+    pub(crate) fn has_full_batch(&self) -> bool {
+        self.items.len() >= self.max_items || self.bytes >= self.max_bytes
+    }
+
+    /// Take the oldest items, up to one batch worth.
+    pub(crate) fn take_batch(&mut self) -> Vec<(T, usize)> {
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        while let Some((_, size)) = self.items.front() {
+            if batch.len() == self.max_items
+                || (batch_bytes + size > self.max_bytes && !batch.is_empty())
+            {
+                break;
+            }
+            batch_bytes += size;
+            batch.extend(self.items.pop_front());
+        }
+        self.bytes -= batch_bytes;
+        batch
+    }
+
+    /// Put items back at the front of the queue, e.g. because writing them failed.
+    pub(crate) fn requeue(&mut self, batch: Vec<(T, usize)>) {
+        for (item, size) in batch.into_iter().rev() {
+            self.bytes += size;
+            self.items.push_front((item, size));
+        }
+    }
+}
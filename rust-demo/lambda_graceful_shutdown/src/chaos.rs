@@ -0,0 +1,131 @@
+//! Shutdowns injected at random points of invocations, to rehearse interruptions.
+//!
+//! On Lambda, a shutdown can land at any point of an invocation: an environment being spun
+//! down, a timeout, or an out-of-memory crash. Checkpoints, drains and compensations only
+//! get exercised when that happens, and rarely at the worst possible moment. With
+//! [`ShutdownCoordinator::with_chaos()`](crate::ShutdownCoordinator::with_chaos), some of the
+//! invocations tracked with
+//! [`track_invocation()`](crate::ShutdownCoordinator::track_invocation) trigger the shutdown
+//! after a random delay, as if a `SIGTERM` had arrived.
+//!
+//! This is meant for test and staging environments. [`Chaos::from_env()`] is disabled unless
+//! `LAMBDA_GRACEFUL_SHUTDOWN_CHAOS` is set, so it can be left in place and turned on per
+//! function.
+
+use std::{
+    collections::hash_map::RandomState,
+    env,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{ShutdownCoordinator, ShutdownReason};
+
+/// The longest delay before an injected shutdown, unless set with
+/// [`Chaos::with_max_delay()`] or `LAMBDA_GRACEFUL_SHUTDOWN_CHAOS_MAX_DELAY_MS`.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Feeds the random numbers, so that two calls in the same nanosecond differ.
+static DRAWS: AtomicU64 = AtomicU64::new(0);
+
+/// How often, and how far into an invocation, to inject a shutdown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    probability: f64,
+    max_delay: Duration,
+    exit: bool,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new(0.0)
+    }
+}
+
+impl Chaos {
+    /// Inject a shutdown into each tracked invocation with `probability`, between 0 and 1.
+    pub fn new(probability: f64) -> Self {
+        Self {
+            probability: probability.clamp(0.0, 1.0),
+            max_delay: DEFAULT_MAX_DELAY,
+            exit: true,
+        }
+    }
+
+    /// Settings from the environment: the probability from `LAMBDA_GRACEFUL_SHUTDOWN_CHAOS`,
+    /// and the longest delay from `LAMBDA_GRACEFUL_SHUTDOWN_CHAOS_MAX_DELAY_MS`.
+    ///
+    /// Disabled if the probability isn't set, or isn't a number.
+    pub fn from_env() -> Self {
+        let probability = env::var("LAMBDA_GRACEFUL_SHUTDOWN_CHAOS")
+            .ok()
+            .and_then(|probability| probability.parse().ok())
+            .unwrap_or(0.0);
+        let chaos = Self::new(probability);
+        match env::var("LAMBDA_GRACEFUL_SHUTDOWN_CHAOS_MAX_DELAY_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+        {
+            Some(ms) => chaos.with_max_delay(Duration::from_millis(ms)),
+            None => chaos,
+        }
+    }
+
+    /// Inject the shutdown up to `max_delay` after the invocation starts, instead of 1s.
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Keep the process running after an injected shutdown, instead of exiting like after a
+    /// real one. Handy for local testing.
+    pub fn without_exit(mut self) -> Self {
+        self.exit = false;
+        self
+    }
+
+    /// Whether shutdowns are injected at all.
+    pub fn is_enabled(&self) -> bool {
+        self.probability > 0.0
+    }
+
+    /// Maybe schedule a shutdown of `coordinator`, for an invocation that just started.
+    pub(crate) fn inject(&self, coordinator: &ShutdownCoordinator) {
+        if coordinator.is_shutting_down() || random() >= self.probability {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let delay = self.max_delay.mul_f64(random());
+        let exit = self.exit;
+        let coordinator = coordinator.clone();
+        let sleep = coordinator
+            .clock()
+            .sleep_until(coordinator.clock().now() + delay);
+        runtime.spawn(async move {
+            sleep.await;
+            if coordinator.is_shutting_down() {
+                return;
+            }
+            tracing::warn!(
+                delay_ms = delay.as_millis() as u64,
+                "chaos: injecting a shutdown into the running invocation"
+            );
+            if exit {
+                coordinator.shutdown_and_exit(ShutdownReason::Sigterm).await;
+            } else {
+                coordinator.shutdown(ShutdownReason::Sigterm).await;
+            }
+        });
+    }
+}
+
+/// A random number in `[0, 1)`.
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(DRAWS.fetch_add(1, Ordering::Relaxed));
+    // The top 53 bits fill the mantissa of an f64
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,770 @@
+//! Saving in-progress work at shutdown, to resume it on the next invocation.
+//!
+//! A handler that is halfway through a long piece of work when its environment spins down
+//! loses that progress, and the retry starts over. A [`CheckpointStore`] is somewhere durable
+//! to keep progress, such as a DynamoDB table or an S3 bucket.
+//!
+//! [`Checkpoints`] holds the latest progress of each piece of work in memory, where updating
+//! it costs nothing, and its [`checkpoint_hook()`](Checkpoints::checkpoint_hook) saves
+//! whatever is still in progress to the store at shutdown. The next invocation picks it up
+//! with [`Checkpoints::load()`].
+//!
+//! Types implementing [`Checkpointable`] are saved with a version number, and
+//! [`Checkpointable::resume()`] only loads checkpoints saved with the current one, so a
+//! deployment that changes the encoding doesn't resume from state it can't read. Handler
+//! state that implements serde's traits only needs a version to be checkpointable: implement
+//! [`SerdeCheckpoint`], and resume it with [`Checkpoints::resume_or_default()`] to start
+//! afresh whenever the saved state is from another version.
+//!
+//! [`FileCheckpointStore`] keeps checkpoints on local disk, for progress that only needs to
+//! outlive the runtime process rather than the execution environment.
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::checkpoint::{CheckpointStore, Checkpoints};
+//!
+//! # async fn example(store: impl CheckpointStore + 'static, job_id: &str) -> Result<(), lambda_graceful_shutdown::Error> {
+//! let checkpoints = Checkpoints::new(store);
+//! // register checkpoints.checkpoint_hook() with the ShutdownCoordinator
+//!
+//! // ...in the handler:
+//! let mut next = match checkpoints.load(job_id).await? {
+//!     Some(bytes) => u64::from_be_bytes(bytes.as_slice().try_into()?),
+//!     None => 0,
+//! };
+//! while next < 1_000 {
+//!     // process item `next`
+//!     next += 1;
+//!     checkpoints.update(job_id, next.to_be_bytes().to_vec());
+//! }
+//! checkpoints.complete(job_id).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::task::JoinSet;
+
+use crate::{hook::join_within, BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// Makes the names of the files written by [`FileCheckpointStore`] unique until they are
+/// renamed into place.
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// The longest key [`FileCheckpointStore`] takes: hex-encoded, it makes a 254-byte file name,
+/// just under the 255 bytes file systems allow.
+const MAX_FILE_KEY_BYTES: usize = 127;
+
+/// Durable storage for checkpoints, keyed by a string such as a job or message id.
+pub trait CheckpointStore: Send + Sync {
+    /// Save `bytes` under `key`, replacing any earlier checkpoint.
+    fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Load the checkpoint saved under `key`, if there is one.
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>>;
+
+    /// Delete the checkpoint saved under `key`, once the work it belongs to is done.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+impl<S: CheckpointStore + ?Sized> CheckpointStore for Arc<S> {
+    fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        (**self).save(key, bytes)
+    }
+
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        (**self).load(key)
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        (**self).delete(key)
+    }
+}
+
+/// State that can be saved as a checkpoint and resumed from.
+///
+/// Checkpoints are saved with [`Checkpoints::update_state()`], which prefixes the encoded state
+/// with [`VERSION`](Self::VERSION), and loaded back with [`resume()`](Self::resume).
+pub trait Checkpointable: Sized + Send {
+    /// The version of the encoding. Bump it whenever [`encode()`](Self::encode) changes in a
+    /// way [`decode()`](Self::decode) from an earlier version can't read.
+    const VERSION: u32;
+
+    /// Encode the state.
+    fn encode(&self) -> Result<Vec<u8>, Error>;
+
+    /// Decode state encoded with the current [`VERSION`](Self::VERSION).
+    fn decode(bytes: &[u8]) -> Result<Self, Error>;
+
+    /// Load the state saved under `key` by an earlier invocation, if there is any.
+    ///
+    /// Call this at the start of an invocation. Checkpoints saved with another version are
+    /// ignored, and logged.
+    fn resume<'a, S: CheckpointStore>(
+        store: &'a S,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Self>, Error>> {
+        Box::pin(async move {
+            let Some(bytes) = store.load(key).await? else {
+                return Ok(None);
+            };
+            let (version, payload) = open_envelope(&bytes)
+                .ok_or_else(|| format!("the checkpoint for {key} has no version header"))?;
+            if version != Self::VERSION {
+                tracing::warn!(
+                    key,
+                    version,
+                    current = Self::VERSION,
+                    "ignoring a checkpoint saved with another version"
+                );
+                return Ok(None);
+            }
+            Self::decode(payload).map(Some)
+        })
+    }
+}
+
+/// Handler state, such as progress counters and cursors, checkpointed as JSON.
+///
+/// Implementing this for a type that derives `Serialize` and `Deserialize` makes it
+/// [`Checkpointable`], with `VERSION` as the schema version:
+///
+/// ```
+/// use lambda_graceful_shutdown::checkpoint::SerdeCheckpoint;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Default, Serialize, Deserialize)]
+/// struct Progress {
+///     cursor: Option<String>,
+///     processed: u64,
+/// }
+///
+/// impl SerdeCheckpoint for Progress {
+///     const VERSION: u32 = 1;
+/// }
+/// ```
+pub trait SerdeCheckpoint: Serialize + DeserializeOwned + Send {
+    /// The version of the schema. Bump it whenever the type changes in a way that earlier
+    /// snapshots can't be deserialized into.
+    const VERSION: u32;
+}
+
+impl<T: SerdeCheckpoint> Checkpointable for T {
+    const VERSION: u32 = <T as SerdeCheckpoint>::VERSION;
+
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+pub(crate) fn seal_envelope(version: u32, payload: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + payload.len());
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes.extend(payload);
+    bytes
+}
+
+pub(crate) fn open_envelope(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    let (version, payload) = bytes.split_first_chunk::<4>()?;
+    Some((u32::from_be_bytes(*version), payload))
+}
+
+/// The latest progress of work in flight, saved to a [`CheckpointStore`] at shutdown.
+///
+/// Cloning is cheap, and all clones share the same store and progress.
+pub struct Checkpoints<S> {
+    store: Arc<S>,
+    in_progress: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl<S> Clone for Checkpoints<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            in_progress: self.in_progress.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for Checkpoints<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Checkpoints")
+            .field("in_progress", &self.in_progress.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: CheckpointStore> Checkpoints<S> {
+    /// Keep checkpoints in `store`.
+    pub fn new(store: S) -> Self {
+        Self {
+            store: Arc::new(store),
+            in_progress: Arc::default(),
+        }
+    }
+
+    /// Record the latest progress on `key`, to be saved if the environment shuts down before
+    /// it completes.
+    pub fn update(&self, key: impl Into<String>, bytes: Vec<u8>) {
+        self.in_progress.lock().unwrap().insert(key.into(), bytes);
+    }
+
+    /// Record `state` as the latest progress on `key`, encoded with its version, to be resumed
+    /// with [`Checkpointable::resume()`] or [`Checkpoints::resume()`].
+    pub fn update_state<T: Checkpointable>(
+        &self,
+        key: impl Into<String>,
+        state: &T,
+    ) -> Result<(), Error> {
+        let bytes = seal_envelope(T::VERSION, state.encode()?);
+        self.update(key, bytes);
+        Ok(())
+    }
+
+    /// Load the state on `key` saved with [`update_state()`](Self::update_state) by an
+    /// earlier environment, if there is any.
+    pub async fn resume<T: Checkpointable>(&self, key: &str) -> Result<Option<T>, Error> {
+        T::resume(&*self.store, key).await
+    }
+
+    /// Load the state on `key` like [`resume()`](Self::resume), starting from the default
+    /// state if there is none, or if it was saved with another version or can't be decoded.
+    ///
+    /// Only failures to reach the store are returned as errors.
+    pub async fn resume_or_default<T: Checkpointable + Default>(
+        &self,
+        key: &str,
+    ) -> Result<T, Error> {
+        let Some(bytes) = self.store.load(key).await? else {
+            return Ok(T::default());
+        };
+        let Some((version, payload)) = open_envelope(&bytes) else {
+            tracing::warn!(key, "starting afresh, the checkpoint has no version header");
+            return Ok(T::default());
+        };
+        if version != T::VERSION {
+            tracing::warn!(
+                key,
+                version,
+                current = T::VERSION,
+                "starting afresh, the checkpoint was saved with another version"
+            );
+            return Ok(T::default());
+        }
+        match T::decode(payload) {
+            Ok(state) => Ok(state),
+            Err(error) => {
+                tracing::warn!(key, %error, "starting afresh, the checkpoint can't be decoded");
+                Ok(T::default())
+            }
+        }
+    }
+
+    /// Forget the progress on `key` without touching the store.
+    pub fn clear(&self, key: &str) {
+        self.in_progress.lock().unwrap().remove(key);
+    }
+
+    /// Load the progress on `key` saved by an earlier environment, if there is any.
+    pub async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.store.load(key).await
+    }
+
+    /// Save the progress on `key` right away, rather than waiting for shutdown.
+    pub async fn save(&self, key: &str) -> Result<(), Error> {
+        let bytes = self.in_progress.lock().unwrap().get(key).cloned();
+        match bytes {
+            Some(bytes) => self.store.save(key, bytes).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Mark the work on `key` as done, deleting any checkpoint saved for it.
+    pub async fn complete(&self, key: &str) -> Result<(), Error> {
+        self.clear(key);
+        self.store.delete(key).await
+    }
+
+    /// The number of keys with progress that would be saved at shutdown.
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.lock().unwrap().len()
+    }
+
+    /// A hook that saves the progress still in memory when the environment shuts down.
+    pub fn checkpoint_hook(&self) -> CheckpointHook<S> {
+        CheckpointHook {
+            checkpoints: self.clone(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that saves the progress recorded in [`Checkpoints`].
+///
+/// Checkpoints are saved concurrently. The hook fails if any of them could not be saved
+/// within the budget.
+pub struct CheckpointHook<S> {
+    checkpoints: Checkpoints<S>,
+}
+
+impl<S> fmt::Debug for CheckpointHook<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckpointHook")
+            .field("checkpoints", &self.checkpoints)
+            .finish()
+    }
+}
+
+impl<S: CheckpointStore + 'static> ShutdownHook for CheckpointHook<S> {
+    fn name(&self) -> &str {
+        "checkpoint"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let in_progress = std::mem::take(&mut *self.checkpoints.in_progress.lock().unwrap());
+            let total = in_progress.len();
+
+            let mut saves = JoinSet::new();
+            for (key, bytes) in in_progress {
+                let store = self.checkpoints.store.clone();
+                saves.spawn(async move {
+                    let saved = store.save(&key, bytes).await;
+                    (key, saved)
+                });
+            }
+
+            let mut failed = Vec::new();
+            let mut saved = 0;
+            let joined = join_within(ctx, &mut saves, |result| match result {
+                (_, Ok(())) => saved += 1,
+                (key, Err(error)) => {
+                    tracing::debug!(key, error = %error, "failed to save checkpoint");
+                    failed.push(key);
+                }
+            })
+            .await;
+
+            ctx.note(format!("saved {saved} of {total} checkpoints"));
+            if let Some(timed_out) = joined.timed_out("checkpoints left to save") {
+                return Err(timed_out);
+            }
+            if joined.panicked > 0 {
+                failed.push(format!("{} more that panicked", joined.panicked));
+            }
+            if !failed.is_empty() {
+                return Err(format!("failed to save checkpoints for {}", failed.join(", ")).into());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A [`CheckpointStore`] keeping checkpoints as files in a local directory.
+///
+/// `/tmp` survives for as long as the execution environment does, across warm invocations and
+/// across restarts of the runtime process after a crash or a timeout, but not past a
+/// shutdown. This store is meant for caches and progress markers that only need to survive
+/// that far; pick a remote store for progress the replacement environment should resume.
+///
+/// Each checkpoint is written to a temporary file that is then renamed into place, so a
+/// crash halfway through a save never leaves a partial checkpoint behind. Keys are
+/// hex-encoded into the file names, which limits them to 127 bytes; longer keys are an error.
+#[derive(Debug, Clone)]
+pub struct FileCheckpointStore {
+    dir: PathBuf,
+}
+
+impl FileCheckpointStore {
+    /// Keep checkpoints in `dir`, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Keep checkpoints in a `checkpoints` directory in the temp directory, usually `/tmp`.
+    pub fn in_temp_dir() -> io::Result<Self> {
+        Self::new(std::env::temp_dir().join("checkpoints"))
+    }
+
+    /// Read back every checkpoint left in the directory, keyed by checkpoint key.
+    ///
+    /// Call this during init to pick up where an earlier runtime process in the same
+    /// environment left off, for example to seed [`Checkpoints::update()`] or a cache.
+    pub fn restore(&self) -> io::Result<HashMap<String, Vec<u8>>> {
+        let mut checkpoints = HashMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(decode_key)
+            else {
+                continue;
+            };
+            match fs::read(&path) {
+                Ok(bytes) => {
+                    checkpoints.insert(key, bytes);
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(checkpoints)
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.len() > MAX_FILE_KEY_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "checkpoint keys are limited to {MAX_FILE_KEY_BYTES} bytes, this one is {}",
+                    key.len()
+                ),
+            ));
+        }
+        Ok(self.dir.join(encode_key(key)))
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        let path = self.path(key);
+        let temp = self.dir.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        Box::pin(async move {
+            let path = path?;
+            tokio::task::spawn_blocking(move || write_atomically(&temp, &path, &bytes)).await??;
+            Ok(())
+        })
+    }
+
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        let path = self.path(key);
+        Box::pin(async move {
+            let path = path?;
+            let read = tokio::task::spawn_blocking(move || fs::read(path)).await?;
+            match read {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(error) => Err(error.into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let path = self.path(key);
+        Box::pin(async move {
+            let path = path?;
+            let removed = tokio::task::spawn_blocking(move || fs::remove_file(path)).await?;
+            match removed {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+fn write_atomically(temp: &Path, path: &Path, bytes: &[u8]) -> io::Result<()> {
+    fs::write(temp, bytes)?;
+    fs::rename(temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(temp);
+    })
+}
+
+/// Keys are hex-encoded into file names, so any key makes a valid one.
+fn encode_key(key: &str) -> String {
+    key.bytes().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_key(name: &str) -> Option<String> {
+    if !name.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ShutdownCoordinator, ShutdownReason};
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl CheckpointStore for MemoryStore {
+        fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+            self.0.lock().unwrap().insert(key.to_owned(), bytes);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+            let bytes = self.0.lock().unwrap().get(key).cloned();
+            Box::pin(async { Ok(bytes) })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+            self.0.lock().unwrap().remove(key);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// A count, checkpointed as 8 big-endian bytes.
+    #[derive(Debug, Default, PartialEq)]
+    struct Count(u64);
+
+    impl Checkpointable for Count {
+        const VERSION: u32 = 2;
+
+        fn encode(&self) -> Result<Vec<u8>, Error> {
+            Ok(self.0.to_be_bytes().to_vec())
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Self, Error> {
+            Ok(Self(u64::from_be_bytes(bytes.try_into()?)))
+        }
+    }
+
+    /// Checkpoints whose store holds `bytes` under `"job"`.
+    fn saved(bytes: Vec<u8>) -> Checkpoints<MemoryStore> {
+        let checkpoints = Checkpoints::new(MemoryStore::default());
+        checkpoints
+            .store
+            .0
+            .lock()
+            .unwrap()
+            .insert("job".to_owned(), bytes);
+        checkpoints
+    }
+
+    #[tokio::test]
+    async fn state_is_resumed_from_the_current_version_only() {
+        let checkpoints = Checkpoints::new(MemoryStore::default());
+        assert_eq!(checkpoints.resume::<Count>("job").await.unwrap(), None);
+        checkpoints.update_state("job", &Count(7)).unwrap();
+        checkpoints.save("job").await.unwrap();
+        assert_eq!(
+            checkpoints.store.0.lock().unwrap()["job"],
+            [0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 7]
+        );
+        assert_eq!(checkpoints.resume("job").await.unwrap(), Some(Count(7)));
+
+        // Saved by an older deployment, or by a newer one during a rollout
+        for version in [1, 3, u32::MAX] {
+            let checkpoints = saved(seal_envelope(version, 7u64.to_be_bytes().to_vec()));
+            assert_eq!(checkpoints.resume::<Count>("job").await.unwrap(), None);
+            assert_eq!(
+                checkpoints.resume_or_default::<Count>("job").await.unwrap(),
+                Count(0)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn an_envelope_too_short_for_a_version_is_not_resumed() {
+        for bytes in [vec![], vec![0, 0, 2]] {
+            let checkpoints = saved(bytes);
+            let error = checkpoints.resume::<Count>("job").await.unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "the checkpoint for job has no version header"
+            );
+            assert_eq!(
+                checkpoints.resume_or_default::<Count>("job").await.unwrap(),
+                Count(0)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn state_that_fails_to_decode_falls_back_to_the_default() {
+        // Just the version, and a payload of the wrong length
+        for payload in [vec![], vec![1, 2, 3]] {
+            let checkpoints = saved(seal_envelope(Count::VERSION, payload));
+            assert!(checkpoints.resume::<Count>("job").await.is_err());
+            assert_eq!(
+                checkpoints.resume_or_default::<Count>("job").await.unwrap(),
+                Count(0)
+            );
+        }
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, serde::Deserialize)]
+    struct Progress {
+        cursor: Option<String>,
+        processed: u64,
+    }
+
+    impl SerdeCheckpoint for Progress {
+        const VERSION: u32 = 1;
+    }
+
+    /// `Progress` after a change to its schema.
+    #[derive(Debug, Default, PartialEq, Serialize, serde::Deserialize)]
+    struct ProgressV2 {
+        cursors: Vec<String>,
+    }
+
+    impl SerdeCheckpoint for ProgressV2 {
+        const VERSION: u32 = 2;
+    }
+
+    #[tokio::test]
+    async fn serde_state_saved_at_shutdown_is_resumed_by_the_next_environment() {
+        let store = Arc::new(MemoryStore::default());
+        let checkpoints = Checkpoints::new(store.clone());
+        let progress = Progress {
+            cursor: Some("shard-1:42".to_owned()),
+            processed: 42,
+        };
+        checkpoints.update_state("job", &progress).unwrap();
+        let report = ShutdownCoordinator::new()
+            .with_hook(checkpoints.checkpoint_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+
+        let saved = store.0.lock().unwrap()["job"].clone();
+        let (version, json) = open_envelope(&saved).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(json).unwrap(),
+            serde_json::json!({ "cursor": "shard-1:42", "processed": 42 })
+        );
+
+        let next = Checkpoints::new(store);
+        assert_eq!(next.resume("job").await.unwrap(), Some(progress));
+        next.complete("job").await.unwrap();
+        assert_eq!(next.resume::<Progress>("job").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn serde_state_of_another_schema_version_starts_afresh() {
+        let store = Arc::new(MemoryStore::default());
+        let checkpoints = Checkpoints::new(store.clone());
+        checkpoints
+            .update_state(
+                "job",
+                &Progress {
+                    cursor: None,
+                    processed: 3,
+                },
+            )
+            .unwrap();
+        checkpoints.save("job").await.unwrap();
+
+        // The next deployment changed the schema, and its version
+        let next = Checkpoints::new(store);
+        assert_eq!(next.resume::<ProgressV2>("job").await.unwrap(), None);
+        assert_eq!(
+            next.resume_or_default::<ProgressV2>("job").await.unwrap(),
+            ProgressV2::default()
+        );
+    }
+
+    /// An empty directory for the test called `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "lambda-graceful-shutdown-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn keys_round_trip_through_file_names() {
+        for key in ["job-1", "", "a/b/../c", "naïve 🦀", "\0\n"] {
+            let name = encode_key(key);
+            assert!(name.bytes().all(|byte| byte.is_ascii_hexdigit()));
+            assert_eq!(decode_key(&name).as_deref(), Some(key));
+        }
+        // Not file names the store writes
+        for name in ["abc", "zz", ".tmp-1-2", "ff"] {
+            assert_eq!(decode_key(name), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_reads_back_what_an_earlier_process_saved() {
+        let dir = test_dir("restore");
+        let store = FileCheckpointStore::new(&dir).unwrap();
+        store.save("a", b"1".to_vec()).await.unwrap();
+        store.save("b/c", b"2".to_vec()).await.unwrap();
+        store.save("a", b"3".to_vec()).await.unwrap();
+        store.save("gone", b"4".to_vec()).await.unwrap();
+        store.delete("gone").await.unwrap();
+        // Left behind by a save that was cut off before the rename
+        fs::write(dir.join(".tmp-1-0"), b"partial").unwrap();
+
+        let restored = FileCheckpointStore::new(&dir).unwrap().restore().unwrap();
+        assert_eq!(
+            restored,
+            HashMap::from([
+                ("a".to_owned(), b"3".to_vec()),
+                ("b/c".to_owned(), b"2".to_vec())
+            ])
+        );
+        assert_eq!(store.load("b/c").await.unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.load("gone").await.unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saves_leave_no_temporary_files_behind() {
+        let dir = test_dir("atomic");
+        let store = FileCheckpointStore::new(&dir).unwrap();
+        store.save("a", b"1".to_vec()).await.unwrap();
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, [encode_key("a").as_str()]);
+
+        // A rename that fails removes the temporary file rather than leaving it
+        fs::create_dir(dir.join(encode_key("b"))).unwrap();
+        fs::write(dir.join(encode_key("b")).join("x"), b"").unwrap();
+        assert!(store.save("b", b"2".to_vec()).await.is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_too_long_for_a_file_name_are_rejected() {
+        let dir = test_dir("long-keys");
+        let store = FileCheckpointStore::new(&dir).unwrap();
+        let longest = "k".repeat(MAX_FILE_KEY_BYTES);
+        store.save(&longest, b"1".to_vec()).await.unwrap();
+        assert_eq!(store.load(&longest).await.unwrap(), Some(b"1".to_vec()));
+
+        let too_long = "k".repeat(MAX_FILE_KEY_BYTES + 1);
+        let error = store.save(&too_long, b"1".to_vec()).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "checkpoint keys are limited to 127 bytes, this one is 128"
+        );
+        assert!(store.load(&too_long).await.is_err());
+        assert!(store.delete(&too_long).await.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
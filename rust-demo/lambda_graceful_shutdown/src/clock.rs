@@ -0,0 +1,126 @@
+//! Where the coordinator gets the time from.
+//!
+//! The budget, the hook timeouts and the invocation drain window all run on a [`Clock`]. The
+//! default, [`TokioClock`], is tokio's own clock, so under
+//! `#[tokio::test(start_paused = true)]` the deadlines pass as soon as every task is waiting,
+//! and the timings are the same on every run. Without the `tokio-runtime` feature, the default
+//! is [`ThreadClock`] instead, which works on any executor. A test that needs to decide exactly when time
+//! moves on can hand the coordinator its own clock with
+//! [`ShutdownCoordinator::with_clock()`](crate::ShutdownCoordinator::with_clock), such as
+//! `testing::ManualClock` (feature `testing`).
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use tokio::time::Instant;
+
+use crate::BoxFuture;
+
+/// A source of the current time, and of timers.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Wait until `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// tokio's clock, which can be paused and advanced in tests with tokio's `test-util` feature.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio-runtime")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// A clock that doesn't need a runtime: every sleep waits on a thread of its own, and wakes
+/// the task once the deadline has passed.
+///
+/// A shutdown only sleeps a few times, for the budget and for each hook, so that is cheap
+/// enough. A custom runtime whose executor has timers of its own, such as `async-io`'s, can
+/// hand one of them to [`ShutdownCoordinator::with_clock()`](crate::ShutdownCoordinator::with_clock)
+/// instead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadClock;
+
+impl Clock for ThreadClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(ThreadSleep {
+            deadline: deadline.into_std(),
+            state: None,
+        })
+    }
+}
+
+/// A sleep on a [`ThreadClock`]. The thread is only started once the sleep is first polled.
+struct ThreadSleep {
+    deadline: std::time::Instant,
+    state: Option<Arc<Mutex<SleepState>>>,
+}
+
+#[derive(Default)]
+struct SleepState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+impl Future for ThreadSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if std::time::Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let state = self.state.get_or_insert_with(|| {
+            let state = Arc::new(Mutex::new(SleepState::default()));
+            let shared = state.clone();
+            thread::spawn(move || {
+                thread::sleep(deadline.saturating_duration_since(std::time::Instant::now()));
+                let mut state = shared.lock().unwrap();
+                state.done = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+            state
+        });
+        let mut state = state.lock().unwrap();
+        if state.done {
+            return Poll::Ready(());
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Run `future` until `deadline` on `clock`, or return `None` if it passes first.
+pub(crate) async fn timeout_at<F: std::future::Future>(
+    clock: &dyn Clock,
+    deadline: Instant,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        () = clock.sleep_until(deadline) => None,
+    }
+}
@@ -0,0 +1,1141 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use tracing::{field, Instrument};
+
+#[cfg(feature = "tokio-runtime")]
+use crate::chaos::Chaos;
+#[cfg(all(unix, feature = "signal"))]
+use crate::signal::ShutdownSignals;
+#[cfg(feature = "testing")]
+use crate::testing::TraceEventKind;
+use crate::{
+    clock::{self, Clock},
+    history::ReportHistory,
+    init::InitTimings,
+    lifecycle::Lifecycle,
+    schedule::{ScheduleState, Scheduler},
+    static_hooks::{HookSet, StaticHook, StaticHooks},
+    stdout,
+    sync::{InFlightCounter, Latch, Registry, Snapshot},
+    trigger::RunOutcome,
+    BoxFuture, DrainTimeout, Error, HookOutcome, HookReport, InvocationDrain, SandboxStats,
+    ShutdownHook, ShutdownReport,
+};
+
+/// Default shutdown budget.
+///
+/// Lambda allows 500ms for the shutdown phase when only internal extensions are registered
+/// (which is what `spawn_graceful_shutdown_handler()` does). We leave a bit of headroom so the
+/// process gets to exit on its own instead of being killed.
+pub const DEFAULT_BUDGET: Duration = Duration::from_millis(450);
+
+/// A gap between two tracked invocations at least this long is counted as a thaw. The
+/// runtime asks for the next event as soon as it has responded, so a sandbox that isn't
+/// frozen starts the next waiting invocation within a few milliseconds.
+const THAW_GAP: Duration = Duration::from_millis(500);
+
+/// How long before the deadline a drain should give up, so it gets to record what it got done
+/// and return a [`DrainTimeout`](crate::DrainTimeout) before the hook itself times out.
+const DRAIN_RESERVE: Duration = Duration::from_millis(10);
+
+/// Why a shutdown was triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The process received `SIGTERM`. This is what Lambda sends before spinning down.
+    Sigterm,
+    /// The process received `SIGINT`, usually from Ctrl-C while running locally.
+    Sigint,
+    /// An extension received a `SHUTDOWN` event from the Extensions API, because the
+    /// environment is being spun down.
+    Spindown,
+    /// An extension received a `SHUTDOWN` event because an invocation ran past the function's
+    /// timeout.
+    Timeout,
+    /// An extension received a `SHUTDOWN` event because the runtime failed, for example by
+    /// running out of memory.
+    Failure,
+}
+
+impl ShutdownReason {
+    /// The reason matching the `shutdownReason` of an Extensions API `SHUTDOWN` event, such as
+    /// `spindown`, `timeout` or `failure`.
+    pub fn from_extension_reason(reason: &str) -> Option<Self> {
+        match reason.to_ascii_lowercase().as_str() {
+            "spindown" => Some(ShutdownReason::Spindown),
+            "timeout" => Some(ShutdownReason::Timeout),
+            "failure" => Some(ShutdownReason::Failure),
+            _ => None,
+        }
+    }
+
+    /// Whether the environment is shutting down because an invocation timed out or failed,
+    /// rather than being spun down normally.
+    pub fn is_failure(&self) -> bool {
+        matches!(self, ShutdownReason::Timeout | ShutdownReason::Failure)
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownReason::Sigterm => f.write_str("SIGTERM"),
+            ShutdownReason::Sigint => f.write_str("SIGINT"),
+            ShutdownReason::Spindown => f.write_str("SPINDOWN"),
+            ShutdownReason::Timeout => f.write_str("TIMEOUT"),
+            ShutdownReason::Failure => f.write_str("FAILURE"),
+        }
+    }
+}
+
+/// Information handed to each [`ShutdownHook`] while the shutdown is in progress.
+#[derive(Debug, Clone)]
+pub struct ShutdownContext {
+    reason: ShutdownReason,
+    started: Instant,
+    deadline: Instant,
+    invocations: Option<InvocationDrain>,
+    sandbox: SandboxStats,
+    request_id: Option<String>,
+    notes: Arc<Mutex<Vec<String>>>,
+    reports: Arc<Mutex<Vec<HookReport>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ShutdownContext {
+    /// Why the shutdown was triggered.
+    pub fn reason(&self) -> ShutdownReason {
+        self.reason
+    }
+
+    /// How long ago the shutdown started.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started)
+    }
+
+    /// The point in time at which the shutdown budget runs out.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// When a hook draining something should stop waiting for it: a little before the
+    /// [`deadline()`](Self::deadline), so the hook can still say what was left, with a
+    /// [`DrainTimeout`](crate::DrainTimeout), instead of being cut off.
+    pub fn drain_deadline(&self) -> Instant {
+        self.deadline
+            .checked_sub(DRAIN_RESERVE)
+            .unwrap_or(self.deadline)
+    }
+
+    /// How much of the shutdown budget is left.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(self.clock.now())
+    }
+
+    /// How much of the shutdown budget is left, but no more than `limit` if one is given.
+    ///
+    /// Handy for hooks that accept their own timeout.
+    pub fn remaining_capped(&self, limit: Option<Duration>) -> Duration {
+        match limit {
+            Some(limit) => limit.min(self.remaining()),
+            None => self.remaining(),
+        }
+    }
+
+    /// How long until the [`drain_deadline()`](Self::drain_deadline), but no more than
+    /// `limit` if one is given.
+    ///
+    /// The timeout to give a call that drains something and takes a timeout of its own.
+    pub fn drain_remaining_capped(&self, limit: Option<Duration>) -> Duration {
+        let remaining = self
+            .drain_deadline()
+            .saturating_duration_since(self.clock.now());
+        match limit {
+            Some(limit) => limit.min(remaining),
+            None => remaining,
+        }
+    }
+
+    /// The request id of the invocation in flight or handled last, if recorded with
+    /// [`ShutdownCoordinator::set_request_id()`] or
+    /// [`ShutdownCoordinator::track_invocation()`].
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// How waiting for the invocations in flight went, if the coordinator was set up to with
+    /// [`with_invocation_drain()`](ShutdownCoordinator::with_invocation_drain).
+    pub fn invocation_drain(&self) -> Option<InvocationDrain> {
+        self.invocations
+    }
+
+    /// How many invocations the sandbox handled, and how often it was frozen, before the
+    /// shutdown.
+    pub fn sandbox_stats(&self) -> SandboxStats {
+        self.sandbox
+    }
+
+    /// Add a note to the running hook's entry in the [`ShutdownReport`].
+    ///
+    /// Use this for details that are worth keeping even when the hook succeeds, such as which
+    /// resources it cleaned up.
+    pub fn note(&self, note: impl Into<String>) {
+        self.notes.lock().unwrap().push(note.into());
+    }
+
+    /// The reports of the hooks that have already run during this shutdown.
+    ///
+    /// Hooks that report on the shutdown itself can use this. Register them first, so they
+    /// run last.
+    pub fn hook_reports(&self) -> Vec<HookReport> {
+        self.reports.lock().unwrap().clone()
+    }
+
+    /// The clock the shutdown is measured on. Hooks that wait on their own can sleep on it,
+    /// so they keep up with the clock a test gives the coordinator.
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    fn take_notes(&self) -> Vec<String> {
+        std::mem::take(&mut self.notes.lock().unwrap())
+    }
+
+    /// The same context, with notes of its own, for a hook running alongside others.
+    fn with_own_notes(&self) -> Self {
+        Self {
+            notes: Arc::default(),
+            ..self.clone()
+        }
+    }
+}
+
+/// What is known about the last invocation.
+#[derive(Debug, Default)]
+struct LastInvocation {
+    request_id: Option<String>,
+    trace_id: Option<String>,
+    /// The span of the invocation, while it is running.
+    span: Option<tracing::Span>,
+    /// Incremented for every tracked invocation, so that a guard only clears its own span.
+    /// This is also the number of tracked invocations.
+    generation: u64,
+    /// When the last tracked invocation finished, if none is running.
+    finished: Option<Instant>,
+    /// The number of tracked invocations that started after a freeze.
+    thaws: u64,
+}
+
+/// An invocation tracked by [`ShutdownCoordinator::track_invocation()`], until this is
+/// dropped.
+#[derive(Debug)]
+pub struct InvocationGuard {
+    invocation: Arc<Mutex<LastInvocation>>,
+    in_flight: Arc<InFlightCounter>,
+    generation: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl Drop for InvocationGuard {
+    fn drop(&mut self) {
+        let mut invocation = self.invocation.lock().unwrap();
+        if invocation.generation == self.generation {
+            invocation.span = None;
+        }
+        if self.in_flight.exit() {
+            invocation.finished = Some(self.clock.now());
+        }
+    }
+}
+
+/// A read-only view of whether the shutdown has started, from
+/// [`ShutdownCoordinator::handle()`].
+///
+/// Handler code that only needs to know when to stop early can take this instead of the
+/// whole coordinator. With the `testing` feature,
+/// [`test_controlled()`](Self::test_controlled) makes one that a test trips by hand.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    started: Arc<Latch>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ShutdownHandle {
+    /// Returns true once the shutdown has started.
+    pub fn is_shutting_down(&self) -> bool {
+        self.started.is_set()
+    }
+
+    /// Wait until the shutdown starts.
+    pub async fn shutting_down(&self) {
+        self.started.wait().await;
+    }
+
+    /// The clock of the coordinator the handle came from.
+    #[cfg_attr(not(feature = "tokio-runtime"), allow(dead_code))]
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    /// A handle with no coordinator behind it, and the controller that starts its "shutdown".
+    #[cfg(feature = "testing")]
+    pub fn test_controlled() -> (Self, crate::testing::ShutdownController) {
+        let started = Arc::new(Latch::default());
+        (
+            Self {
+                started: started.clone(),
+                clock: Arc::new(clock::TokioClock),
+            },
+            crate::testing::ShutdownController { started },
+        )
+    }
+}
+
+/// Keeps track of the registered [`ShutdownHook`]s and runs them when the shutdown signal
+/// arrives.
+///
+/// The coordinator is cheap to clone; all clones share the same set of hooks, so it can be
+/// handed to whichever part of the function sets up a resource that needs cleaning up.
+#[derive(Clone)]
+pub struct ShutdownCoordinator {
+    budget: Duration,
+    hooks: Arc<Registry<dyn ShutdownHook>>,
+    started: Arc<Latch>,
+    lifecycle: Option<Lifecycle>,
+    invocation: Arc<Mutex<LastInvocation>>,
+    in_flight: Arc<InFlightCounter>,
+    drain_window: Option<Duration>,
+    slow_hook_threshold: Option<f64>,
+    created: Instant,
+    sandbox_stats_every: Option<u64>,
+    history: Option<ReportHistory>,
+    #[cfg(feature = "tokio-runtime")]
+    chaos: Option<Chaos>,
+    clock: Arc<dyn Clock>,
+    scheduler: Option<Arc<dyn Scheduler>>,
+    init_timings: Option<InitTimings>,
+    last_report: Arc<Mutex<Option<ShutdownReport>>>,
+    /// Held while a shutdown runs, so that calls made in the meantime wait for it.
+    running: Arc<tokio::sync::Mutex<()>>,
+    /// The number of shutdowns whose report is in `last_report`.
+    finished: Arc<AtomicU64>,
+    #[cfg(feature = "testing")]
+    trace: Option<crate::testing::HookTrace>,
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ShutdownCoordinator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hooks = self.hooks.snapshot_rev();
+        f.debug_struct("ShutdownCoordinator")
+            .field("budget", &self.budget)
+            .field(
+                "hooks",
+                &hooks
+                    .iter()
+                    .rev()
+                    .map(|hook| hook.name())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl ShutdownCoordinator {
+    /// Create a coordinator with no hooks and the [`DEFAULT_BUDGET`].
+    pub fn new() -> Self {
+        #[cfg(feature = "tokio-runtime")]
+        let clock: Arc<dyn Clock> = Arc::new(clock::TokioClock);
+        #[cfg(not(feature = "tokio-runtime"))]
+        let clock: Arc<dyn Clock> = Arc::new(clock::ThreadClock);
+        Self {
+            budget: DEFAULT_BUDGET,
+            hooks: Arc::default(),
+            started: Arc::default(),
+            lifecycle: None,
+            invocation: Arc::default(),
+            in_flight: Arc::default(),
+            drain_window: None,
+            slow_hook_threshold: None,
+            created: clock.now(),
+            sandbox_stats_every: None,
+            history: None,
+            #[cfg(feature = "tokio-runtime")]
+            chaos: None,
+            clock,
+            scheduler: None,
+            init_timings: None,
+            last_report: Arc::default(),
+            running: Arc::default(),
+            finished: Arc::default(),
+            #[cfg(feature = "testing")]
+            trace: None,
+        }
+    }
+
+    /// Set how long all hooks are allowed to take, in total.
+    ///
+    /// If you have an external extension registered, Lambda gives you up to 2s instead of 500ms.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Write a record to stdout when the shutdown starts, after each hook, and when it
+    /// completes.
+    ///
+    /// With `AWS_LAMBDA_LOG_FORMAT=JSON`, each record is a JSON object with a `timestamp`,
+    /// `level`, `requestId` (see [`set_request_id()`](Self::set_request_id)), `message`, and
+    /// an `event_type` of `shutdown.started`, `shutdown.hook_finished` or
+    /// `shutdown.completed`, along with its details. Otherwise records are plain text lines.
+    /// They don't go through `tracing`, since the log writer is usually flushed by the last
+    /// hook.
+    pub fn with_lifecycle_logs(mut self) -> Self {
+        self.lifecycle = Some(Lifecycle::from_env());
+        self
+    }
+
+    /// Keep the lifecycle records in `capture`, as JSON objects, instead of writing them to
+    /// stdout. See [`with_lifecycle_logs()`](Self::with_lifecycle_logs).
+    #[cfg(feature = "testing")]
+    pub fn with_lifecycle_capture(mut self, capture: &crate::testing::LogCapture) -> Self {
+        self.lifecycle = Some(Lifecycle::Captured(capture.records.clone()));
+        self
+    }
+
+    /// Record in `trace` when each hook starts and finishes.
+    #[cfg(feature = "testing")]
+    pub fn with_hook_trace(mut self, trace: &crate::testing::HookTrace) -> Self {
+        self.trace = Some(trace.clone());
+        self
+    }
+
+    /// Record the id of the invocation being handled, to correlate lifecycle records with it.
+    pub fn set_request_id(&self, request_id: impl Into<String>) {
+        self.invocation.lock().unwrap().request_id = Some(request_id.into());
+    }
+
+    /// Record that the invocation `request_id`, traced as `trace_id` (such as the X-Ray trace
+    /// header from the invocation's context), is running in the current span until the
+    /// returned guard is dropped.
+    ///
+    /// The shutdown span records the request and trace ids of the last invocation as
+    /// `last_request_id` and `last_trace_id`. If the invocation is still running when the
+    /// shutdown starts, the shutdown span also follows from the invocation's span, which
+    /// `tracing-opentelemetry` exports as a span link.
+    pub fn track_invocation(
+        &self,
+        request_id: impl Into<String>,
+        trace_id: Option<String>,
+    ) -> InvocationGuard {
+        let mut invocation = self.invocation.lock().unwrap();
+        invocation.request_id = Some(request_id.into());
+        invocation.trace_id = trace_id;
+        invocation.generation += 1;
+        invocation.span = Some(tracing::Span::current());
+        if let Some(finished) = invocation.finished.take() {
+            if self.clock.now().saturating_duration_since(finished) >= THAW_GAP {
+                invocation.thaws += 1;
+            }
+        }
+        self.in_flight.enter();
+        #[cfg(feature = "tokio-runtime")]
+        if let Some(chaos) = &self.chaos {
+            chaos.inject(self);
+        }
+        if let Some(every) = self.sandbox_stats_every {
+            if invocation.generation.is_multiple_of(every) {
+                let stats = self.stats(&invocation);
+                tracing::info!(
+                    invocations = stats.invocations,
+                    thaws = stats.thaws,
+                    uptime_s = stats.uptime.as_secs(),
+                    "sandbox stats"
+                );
+            }
+        }
+        InvocationGuard {
+            invocation: self.invocation.clone(),
+            in_flight: self.in_flight.clone(),
+            generation: invocation.generation,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Log how many invocations the sandbox handled and how many times it was thawed, every
+    /// `every` tracked invocations.
+    ///
+    /// Across a fleet, these show how long telemetry sits in a sandbox's buffers between
+    /// invocations. The same numbers are in the [`ShutdownReport`] at the end of every
+    /// sandbox's life.
+    pub fn with_sandbox_stats_log(mut self, every: u64) -> Self {
+        self.sandbox_stats_every = Some(every.max(1));
+        self
+    }
+
+    /// How many invocations the sandbox handled, and how often it was frozen, so far.
+    pub fn sandbox_stats(&self) -> SandboxStats {
+        self.stats(&self.invocation.lock().unwrap())
+    }
+
+    fn stats(&self, invocation: &LastInvocation) -> SandboxStats {
+        SandboxStats {
+            invocations: invocation.generation,
+            thaws: invocation.thaws,
+            uptime: self.clock.now().saturating_duration_since(self.created),
+        }
+    }
+
+    /// Trigger the shutdown at a random point of some of the invocations tracked with
+    /// [`track_invocation()`](Self::track_invocation), as set by `chaos`. Does nothing if it
+    /// is disabled, as [`Chaos::from_env()`] is unless `LAMBDA_GRACEFUL_SHUTDOWN_CHAOS` is
+    /// set.
+    #[cfg(feature = "tokio-runtime")]
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = chaos.is_enabled().then_some(chaos);
+        self
+    }
+
+    /// Measure the budget, the hooks and the invocation drain on `clock` instead of the
+    /// default one. See the [`clock`](crate::clock) module.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.created = clock.now();
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The clock the budget, the hooks and the invocation drain are measured on.
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    /// Append the report of every shutdown to `history`, to look at the previous shutdowns
+    /// after the process was started again in the same environment.
+    ///
+    /// The report is written once the hooks have run, right before the summary line.
+    pub fn with_report_history(mut self, history: ReportHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Where the reports are kept, if set with
+    /// [`with_report_history()`](Self::with_report_history).
+    pub fn report_history(&self) -> Option<&ReportHistory> {
+        self.history.as_ref()
+    }
+
+    /// Log a warning for every hook that completes, but takes more than `fraction` of the
+    /// budget that was left when it started, e.g. `0.5` for half.
+    ///
+    /// Flush targets that get slower over time show up in these warnings well before their
+    /// hooks start timing out. The warning has the hook's name, how long it took, and how much
+    /// of the budget it had.
+    pub fn with_slow_hook_warning(mut self, fraction: f64) -> Self {
+        self.slow_hook_threshold = Some(fraction.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Before running the hooks, wait up to `window` for the invocations tracked with
+    /// [`track_invocation()`](Self::track_invocation) to finish.
+    ///
+    /// The wait comes out of the budget. How it went is recorded in
+    /// [`ShutdownReport::invocations`], and is available to hooks through
+    /// [`ShutdownContext::invocation_drain()`].
+    pub fn with_invocation_drain(mut self, window: Duration) -> Self {
+        self.drain_window = Some(window);
+        self
+    }
+
+    /// Let `scheduler` decide how many hooks run at the same time, instead of running them one
+    /// after the other. See the [`schedule`](crate::schedule) module.
+    pub fn with_scheduler(mut self, scheduler: impl Scheduler + 'static) -> Self {
+        self.scheduler = Some(Arc::new(scheduler));
+        self
+    }
+
+    /// Make room for `hooks` hooks up front, so registering them doesn't reallocate.
+    ///
+    /// Taking the hooks when the shutdown starts doesn't allocate for up to 16 hooks either,
+    /// which keeps the shutdown path light in a function short on memory.
+    pub fn with_hook_capacity(self, hooks: usize) -> Self {
+        self.hooks.reserve(hooks);
+        self
+    }
+
+    /// Add the time spent registering hooks to `timings`, to measure what the shutdown
+    /// handling adds to the cold start. Hooks registered before this aren't counted.
+    pub fn with_init_timings(mut self, timings: &InitTimings) -> Self {
+        self.init_timings = Some(timings.clone());
+        self
+    }
+
+    /// Register a hook, builder-style. See [`register()`](Self::register).
+    pub fn with_hook(self, hook: impl ShutdownHook + 'static) -> Self {
+        self.register(hook);
+        self
+    }
+
+    /// Register a hook to run on shutdown.
+    ///
+    /// Hooks run in reverse registration order, the same way values are dropped: something
+    /// registered early in `main()`, like a log writer, is flushed last, after everything that
+    /// might still log through it.
+    pub fn register(&self, hook: impl ShutdownHook + 'static) {
+        let started = self.clock.now();
+        self.hooks.push(Arc::new(hook));
+        if let Some(timings) = &self.init_timings {
+            timings.record_hook(self.clock.now().saturating_duration_since(started));
+        }
+    }
+
+    /// The configured shutdown budget.
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// The names of the registered hooks, in the order they will run.
+    pub fn hook_names(&self) -> Vec<String> {
+        self.hooks
+            .snapshot_rev()
+            .iter()
+            .map(|hook| hook.name().to_owned())
+            .collect()
+    }
+
+    /// The report of the last call to [`shutdown()`](Self::shutdown), if it has finished.
+    pub fn last_report(&self) -> Option<ShutdownReport> {
+        self.last_report.lock().unwrap().clone()
+    }
+
+    /// Log one record listing the registered hooks, in the order they will run, and the
+    /// budget they share.
+    ///
+    /// Call this at the end of Init, so the shutdown behaviour of a deployed function can be
+    /// checked from the start of its first log stream.
+    pub fn log_inventory(&self) {
+        let hooks = self.hook_names();
+        tracing::info!(
+            budget_ms = self.budget.as_millis() as u64,
+            hook_count = hooks.len(),
+            hooks = hooks.join(","),
+            "registered shutdown hooks"
+        );
+    }
+
+    /// A handle telling whether the shutdown has started, without access to the hooks.
+    pub fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            started: self.started.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Returns true once [`shutdown()`](Self::shutdown) has been called.
+    ///
+    /// Long-running work, like a loop over the records of a batch, can check this to stop
+    /// early when the environment is about to go away.
+    pub fn is_shutting_down(&self) -> bool {
+        self.started.is_set()
+    }
+
+    /// Wait until [`shutdown()`](Self::shutdown) is called.
+    pub async fn shutting_down(&self) {
+        self.started.wait().await;
+    }
+
+    /// Run every registered hook, stopping once the budget is used up.
+    ///
+    /// The hooks run one after the other, most recently registered first, unless a
+    /// [scheduler](Self::with_scheduler) starts some of them together. Hooks that fail or time
+    /// out don't prevent the remaining hooks from running; their outcome is logged and recorded
+    /// in the returned report. This doesn't exit the process, so the caller still decides what
+    /// to do afterwards. The last thing it does is write the report's
+    /// [`summary_line()`](ShutdownReport::summary_line) to stdout.
+    ///
+    /// Calling this while another shutdown is running doesn't run the hooks a second time, but
+    /// waits for that shutdown to finish and returns its report.
+    ///
+    /// The shutdown runs in a `shutdown` span, with the reason, budget, total time, the number
+    /// of hooks that didn't complete, the [`ShutdownCounters`](crate::ShutdownCounters), the
+    /// [last invocation](Self::track_invocation) and how the
+    /// [invocation drain](Self::with_invocation_drain) went.
+    /// Each hook runs in a `shutdown_hook` child span, with its name, how long it took, its
+    /// outcome and how much of the budget was left afterwards.
+    pub async fn shutdown(&self, reason: ShutdownReason) -> ShutdownReport {
+        self.run_shutdown(reason, &()).await
+    }
+
+    /// Run the `hooks`, in the order they appear in, then every registered hook, like
+    /// [`shutdown()`](Self::shutdown).
+    ///
+    /// The static hooks are dispatched with their concrete types, without boxing. See the
+    /// [`static_hooks`](crate::static_hooks) module.
+    pub async fn shutdown_static<T: HookSet>(
+        &self,
+        reason: ShutdownReason,
+        hooks: &StaticHooks<T>,
+    ) -> ShutdownReport {
+        self.run_shutdown(reason, hooks.hooks()).await
+    }
+
+    async fn run_shutdown<S: HookSet>(
+        &self,
+        reason: ShutdownReason,
+        statics: &S,
+    ) -> ShutdownReport {
+        // Everything written to stdout until the report is out goes in a few writes at the end
+        let _stdout = stdout::hold();
+        let finished = self.finished.load(Ordering::Acquire);
+        let _running = self.running.lock().await;
+        if self.finished.load(Ordering::Acquire) != finished {
+            // Another shutdown was running, e.g. a signal arrived during a chaos shutdown, and
+            // has now run the hooks
+            return self
+                .last_report()
+                .expect("the report is stored before the shutdown counts as finished");
+        }
+        self.started.trigger();
+        let started = self.clock.now();
+        let deadline = started + self.budget;
+        let span = tracing::info_span!(
+            "shutdown",
+            %reason,
+            budget_ms = self.budget.as_millis() as u64,
+            elapsed_ms = field::Empty,
+            hooks_failed = field::Empty,
+            hooks_timed_out = field::Empty,
+            drains_timed_out = field::Empty,
+            budget_exceeded = field::Empty,
+            last_request_id = field::Empty,
+            last_trace_id = field::Empty,
+            invocations_in_flight = field::Empty,
+            invocations_interrupted = field::Empty,
+            invocation_drain_ms = field::Empty,
+        );
+
+        let (request_id, trace_id, invocation_span, sandbox) = {
+            let invocation = self.invocation.lock().unwrap();
+            (
+                invocation.request_id.clone(),
+                invocation.trace_id.clone(),
+                invocation.span.clone(),
+                self.stats(&invocation),
+            )
+        };
+        span.record("last_request_id", request_id.as_deref());
+        span.record("last_trace_id", trace_id.as_deref());
+        if let Some(invocation_span) = invocation_span {
+            span.follows_from(&invocation_span);
+        }
+        if let Some(lifecycle) = &self.lifecycle {
+            lifecycle.started(request_id.as_deref(), reason, self.budget);
+        }
+
+        let invocations = match self.drain_window {
+            Some(window) => {
+                let drain = self.drain_invocations(window, deadline).await;
+                span.record("invocations_in_flight", drain.in_flight);
+                span.record("invocations_interrupted", drain.interrupted);
+                span.record("invocation_drain_ms", drain.elapsed.as_millis() as u64);
+                Some(drain)
+            }
+            None => None,
+        };
+        // Don't hold the lock while the hooks run, they may want to register more hooks.
+        let hooks = self.hooks.snapshot_rev();
+        let ctx = ShutdownContext {
+            reason,
+            started,
+            deadline,
+            invocations,
+            sandbox,
+            request_id: request_id.clone(),
+            notes: Arc::default(),
+            reports: Arc::new(Mutex::new(Vec::with_capacity(hooks.len()))),
+            clock: self.clock.clone(),
+        };
+
+        self.run_hooks(&ctx, statics, hooks)
+            .instrument(span.clone())
+            .await;
+
+        let hooks = std::mem::take(&mut *ctx.reports.lock().unwrap());
+        let report = ShutdownReport {
+            reason,
+            elapsed: ctx.elapsed(),
+            request_id,
+            invocations,
+            sandbox,
+            hooks,
+        };
+        span.record("elapsed_ms", report.elapsed.as_millis() as u64);
+        let counters = report.counters();
+        span.record(
+            "hooks_failed",
+            counters.hooks_failed + counters.hooks_skipped,
+        );
+        span.record("hooks_timed_out", counters.hooks_timed_out);
+        span.record("drains_timed_out", counters.drains_timed_out);
+        span.record("budget_exceeded", counters.budget_exceeded);
+        if let Some(lifecycle) = &self.lifecycle {
+            lifecycle.completed(&report);
+        }
+        *self.last_report.lock().unwrap() = Some(report.clone());
+        self.finished.fetch_add(1, Ordering::Release);
+        if let Some(history) = &self.history {
+            if let Err(error) = history.append(&report) {
+                stdout::write_line(format!(
+                    "[shutdown] failed to write the report to {}: {error}",
+                    history.path().display()
+                ));
+            }
+        }
+        stdout::write_line(report.summary_line());
+        report
+    }
+
+    /// Poll `runtime`, usually `lambda_runtime::run()`, until `SIGTERM` or `SIGINT` arrives,
+    /// then [shut down](Self::shutdown) while still polling it, and return the report.
+    ///
+    /// Keeping the runtime going lets the invocation in flight respond, which the
+    /// [invocation drain](Self::with_invocation_drain) waits for, and returning instead of
+    /// exiting leaves `main` to decide when the process ends. See the [`signal`](crate::signal)
+    /// module. Fails if the signal handlers can't be installed.
+    #[cfg(all(unix, feature = "signal"))]
+    pub async fn run_until_signal<F: Future>(
+        &self,
+        runtime: F,
+    ) -> std::io::Result<RunOutcome<F::Output>> {
+        let mut signals = ShutdownSignals::new()?;
+        Ok(self.run_until(signals.recv(), runtime).await)
+    }
+
+    /// Poll `runtime` until `trigger` returns the reason to shut down, then
+    /// [shut down](Self::shutdown) while still polling it, and return the report.
+    ///
+    /// This is [`run_until_signal()`](Self::run_until_signal) for shutdowns that don't come
+    /// as signals, such as a [`ShutdownTrigger`](crate::trigger::ShutdownTrigger) fired by the
+    /// host of a `wasm32-wasi` runtime.
+    pub async fn run_until<T, F>(&self, trigger: T, runtime: F) -> RunOutcome<F::Output>
+    where
+        T: Future<Output = ShutdownReason>,
+        F: Future,
+    {
+        tokio::pin!(runtime);
+        let reason = tokio::select! {
+            output = &mut runtime => return RunOutcome::Finished(output),
+            reason = trigger => reason,
+        };
+        tracing::info!(%reason, "graceful shutdown in progress");
+
+        let shutdown = self.shutdown(reason);
+        tokio::pin!(shutdown);
+        let mut runtime_done = false;
+        loop {
+            tokio::select! {
+                biased;
+                report = &mut shutdown => return RunOutcome::ShutDown(report),
+                // The runtime may still finish on its own, the shutdown goes on regardless
+                _ = &mut runtime, if !runtime_done => runtime_done = true,
+            }
+        }
+    }
+
+    /// Run every registered hook like [`shutdown()`](Self::shutdown), then exit the process
+    /// with the report's [`exit_code()`](ShutdownReport::exit_code).
+    pub async fn shutdown_and_exit(&self, reason: ShutdownReason) -> ! {
+        let report = self.shutdown(reason).await;
+        std::process::exit(report.exit_code())
+    }
+
+    /// Wait for the tracked invocations to finish, for up to `window` and not past `deadline`.
+    async fn drain_invocations(&self, window: Duration, deadline: Instant) -> InvocationDrain {
+        let started = self.clock.now();
+        let at_start = self.in_flight.get();
+        if at_start > 0 {
+            clock::timeout_at(
+                &*self.clock,
+                deadline.min(started + window),
+                self.in_flight.wait_idle(),
+            )
+            .await;
+        }
+        // Invocations that started during the drain weren't in flight when it started
+        let interrupted = self.in_flight.get().min(at_start);
+        InvocationDrain {
+            in_flight: at_start,
+            interrupted,
+            elapsed: self.clock.now().saturating_duration_since(started),
+        }
+    }
+
+    /// Run the `statics`, then the registered `hooks`, each in its own span, recording a
+    /// report for each one in `ctx`.
+    async fn run_hooks<S: HookSet>(
+        &self,
+        ctx: &ShutdownContext,
+        statics: &S,
+        mut hooks: Snapshot<dyn ShutdownHook>,
+    ) {
+        let mut runner = HookRunner {
+            coordinator: self,
+            ctx,
+            deadline: self.clock.sleep_until(ctx.deadline),
+            deadline_passed: false,
+        };
+        statics.run_each(&mut runner).await;
+
+        let Some(scheduler) = &self.scheduler else {
+            for hook in hooks {
+                runner.run_with(hook.name(), |ctx| hook.shutdown(ctx)).await;
+            }
+            return;
+        };
+        scheduler.order(&mut hooks);
+        let mut next = 0;
+        while next < hooks.len() {
+            let pending = &hooks[next..];
+            let batch = {
+                let finished = ctx.reports.lock().unwrap();
+                scheduler.next_batch(&ScheduleState {
+                    remaining: ctx.remaining(),
+                    pending,
+                    finished: &finished,
+                })
+            };
+            match batch.clamp(1, pending.len()) {
+                1 => {
+                    let hook = &pending[0];
+                    runner.run_with(hook.name(), |ctx| hook.shutdown(ctx)).await;
+                    next += 1;
+                }
+                batch => {
+                    runner.run_together(&pending[..batch]).await;
+                    next += batch;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    fn trace_hook(&self, ctx: &ShutdownContext, hook: &str, kind: TraceEventKind) {
+        if let Some(trace) = &self.trace {
+            trace.record(hook, ctx.elapsed(), kind);
+        }
+    }
+}
+
+/// Runs the hooks of a shutdown one at a time, each in its own span, recording a report for
+/// each one.
+///
+/// Every hook shares the shutdown's deadline, so a single timer is set for all of them rather
+/// than one per hook. The coordinator hands one to
+/// [`HookSet::run_each()`](crate::static_hooks::HookSet::run_each) during
+/// [`shutdown_static()`](ShutdownCoordinator::shutdown_static).
+pub struct HookRunner<'a> {
+    coordinator: &'a ShutdownCoordinator,
+    ctx: &'a ShutdownContext,
+    deadline: BoxFuture<'static, ()>,
+    deadline_passed: bool,
+}
+
+impl fmt::Debug for HookRunner<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HookRunner")
+            .field("deadline_passed", &self.deadline_passed)
+            .finish()
+    }
+}
+
+impl<'a> HookRunner<'a> {
+    /// Run `hook`, or record it as skipped if the budget has run out.
+    pub async fn run<H: StaticHook>(&mut self, hook: &H) {
+        self.run_with(hook.name(), |ctx| hook.shutdown(ctx)).await;
+    }
+
+    async fn run_with<F, Fut>(&mut self, name: &str, start: F)
+    where
+        F: FnOnce(&'a ShutdownContext) -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        let begun = self.begin(name);
+        let outcome = if begun.skipped {
+            HookOutcome::Skipped
+        } else {
+            let ctx = self.ctx;
+            let run = async {
+                tokio::select! {
+                    biased;
+                    result = start(ctx) => Some(result),
+                    () = &mut self.deadline => None,
+                }
+            };
+            let outcome = hook_outcome(run.instrument(begun.span.clone()).await);
+            // The timer is done, and mustn't be polled again
+            self.deadline_passed |= outcome == HookOutcome::TimedOut;
+            outcome
+        };
+        self.finish(name, begun, outcome, self.ctx.take_notes());
+    }
+
+    /// Run `hooks` at the same time, each against the deadline on a timer of its own and with
+    /// notes of its own, and record them in order once they have all finished.
+    async fn run_together(&mut self, hooks: &[Arc<dyn ShutdownHook>]) {
+        let ctx = self.ctx;
+        let timer = &*self.coordinator.clock;
+        let begun: Vec<HookStart> = hooks.iter().map(|hook| self.begin(hook.name())).collect();
+        let contexts: Vec<ShutdownContext> = hooks.iter().map(|_| ctx.with_own_notes()).collect();
+        let mut runs: Vec<_> = hooks
+            .iter()
+            .zip(&begun)
+            .zip(&contexts)
+            .map(|((hook, begun), ctx)| {
+                let (span, skipped) = (begun.span.clone(), begun.skipped);
+                Box::pin(async move {
+                    if skipped {
+                        return HookOutcome::Skipped;
+                    }
+                    let run = clock::timeout_at(timer, ctx.deadline, hook.shutdown(ctx));
+                    hook_outcome(run.instrument(span).await)
+                })
+            })
+            .collect();
+        let outcomes = join_all(&mut runs).await;
+        for (((hook, begun), outcome), own) in hooks.iter().zip(begun).zip(outcomes).zip(&contexts)
+        {
+            self.deadline_passed |= outcome == HookOutcome::TimedOut;
+            self.finish(hook.name(), begun, outcome, own.take_notes());
+        }
+    }
+
+    /// Open the span of the hook `name`, and note whether it has to be skipped.
+    fn begin(&self, name: &str) -> HookStart {
+        let ctx = self.ctx;
+        let span = tracing::info_span!(
+            "shutdown_hook",
+            hook = name,
+            elapsed_ms = field::Empty,
+            outcome = field::Empty,
+            remaining_ms = field::Empty,
+        );
+        let skipped = self.deadline_passed || ctx.remaining().is_zero();
+        #[cfg(feature = "testing")]
+        if !skipped {
+            self.coordinator
+                .trace_hook(ctx, name, TraceEventKind::Started);
+        }
+        HookStart {
+            span,
+            started: self.coordinator.clock.now(),
+            available: ctx.remaining(),
+            skipped,
+        }
+    }
+
+    /// Log how the hook `name` went, and add it to the report with the `notes` it added.
+    fn finish(&self, name: &str, begun: HookStart, outcome: HookOutcome, notes: Vec<String>) {
+        let ctx = self.ctx;
+        let HookStart {
+            span,
+            started,
+            available,
+            ..
+        } = begun;
+        #[cfg(feature = "testing")]
+        self.coordinator
+            .trace_hook(ctx, name, TraceEventKind::Finished(outcome.clone()));
+        let elapsed = self
+            .coordinator
+            .clock
+            .now()
+            .saturating_duration_since(started);
+        span.record("elapsed_ms", elapsed.as_millis() as u64);
+        span.record("outcome", field::display(&outcome));
+        span.record("remaining_ms", ctx.remaining().as_millis() as u64);
+        let slow = outcome == HookOutcome::Completed
+            && self
+                .coordinator
+                .slow_hook_threshold
+                .is_some_and(|fraction| elapsed > available.mul_f64(fraction));
+        if outcome != HookOutcome::Completed {
+            span.in_scope(
+                || tracing::warn!(hook = name, %outcome, "shutdown hook did not complete"),
+            );
+        } else if slow {
+            span.in_scope(|| {
+                tracing::warn!(
+                    hook = name,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    available_ms = available.as_millis() as u64,
+                    "shutdown hook is slow"
+                )
+            });
+        }
+        let report = HookReport {
+            name: name.to_owned(),
+            elapsed,
+            outcome,
+            notes,
+        };
+        if let Some(lifecycle) = &self.coordinator.lifecycle {
+            lifecycle.hook_finished(ctx.request_id(), &report, ctx.remaining(), slow);
+        }
+        ctx.reports.lock().unwrap().push(report);
+    }
+}
+
+/// A hook [`HookRunner::begin()`] opened the span of.
+struct HookStart {
+    span: tracing::Span,
+    started: Instant,
+    available: Duration,
+    skipped: bool,
+}
+
+/// The outcome of a hook that returned `result`, or ran out of time with `None`.
+pub(crate) fn hook_outcome(result: Option<Result<(), Error>>) -> HookOutcome {
+    match result {
+        Some(Ok(())) => HookOutcome::Completed,
+        Some(Err(error)) if error.is::<DrainTimeout>() => {
+            HookOutcome::DrainTimedOut(error.to_string())
+        }
+        Some(Err(error)) => HookOutcome::Failed(error.to_string()),
+        None => HookOutcome::TimedOut,
+    }
+}
+
+/// Poll all of `futures` until they are done, and return their outputs in order.
+async fn join_all<F: Future + Unpin>(futures: &mut [F]) -> Vec<F::Output> {
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if output.is_none() {
+                match std::pin::Pin::new(future).poll(cx) {
+                    std::task::Poll::Ready(done) => *output = Some(done),
+                    std::task::Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            std::task::Poll::Pending
+        } else {
+            std::task::Poll::Ready(())
+        }
+    })
+    .await;
+    outputs.into_iter().flatten().collect()
+}
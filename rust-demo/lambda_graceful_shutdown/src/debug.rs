@@ -0,0 +1,209 @@
+//! A local HTTP endpoint showing what the [`ShutdownCoordinator`] is doing.
+//!
+//! When developing against `cargo lambda watch` or the Runtime Interface Emulator, it helps to
+//! see which hooks are registered and what the last shutdown did without digging through the
+//! logs. [`DebugServer`] answers `GET /shutdown/state` with a JSON document holding:
+//!
+//! - `budget_ms` and `shutting_down`
+//! - `sandbox`: the [`SandboxStats`](crate::SandboxStats) so far
+//! - `hooks`: the registered hooks, in the order they will run
+//! - `counters`: whatever was added with [`with_counter()`](DebugServer::with_counter), such as
+//!   the requests in flight on a [`TrackedClient`](crate::http::TrackedClient)
+//! - `last_report`: the [`ShutdownReport`] of the last shutdown, or `null`
+//! - `history`: the reports kept by the coordinator's
+//!   [`ReportHistory`](crate::history::ReportHistory), oldest first, if it has one
+//!
+//! [`spawn_if_local()`](DebugServer::spawn_if_local) only starts the server when the function
+//! isn't running on Lambda, so it can be left in place when deploying.
+
+use std::{fmt, io, net::SocketAddr, sync::Arc};
+
+use serde_json::{json, Map, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{environment::Environment, ShutdownCoordinator, ShutdownReport};
+
+/// Where the server listens unless set with [`DebugServer::with_address()`]. Out of the way
+/// of the ports used by `cargo lambda watch` (9000) and the emulator (8080).
+pub const DEFAULT_ADDRESS: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 9900);
+
+/// The path of the state document.
+const STATE_PATH: &str = "/shutdown/state";
+
+/// Requests with headers larger than this are rejected.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+type Counter = Box<dyn Fn() -> usize + Send + Sync>;
+
+/// A tiny HTTP server exposing the state of a [`ShutdownCoordinator`], for local development.
+pub struct DebugServer {
+    coordinator: ShutdownCoordinator,
+    address: SocketAddr,
+    counters: Vec<(String, Counter)>,
+}
+
+impl fmt::Debug for DebugServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DebugServer")
+            .field("coordinator", &self.coordinator)
+            .field("address", &self.address)
+            .field(
+                "counters",
+                &self
+                    .counters
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl DebugServer {
+    /// Create a server for `coordinator`, listening on [`DEFAULT_ADDRESS`].
+    pub fn new(coordinator: ShutdownCoordinator) -> Self {
+        Self {
+            coordinator,
+            address: DEFAULT_ADDRESS,
+            counters: Vec::new(),
+        }
+    }
+
+    /// Listen on `address` instead.
+    pub fn with_address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Include the value returned by `counter` under `name` in the state document, e.g.
+    /// `.with_counter("http_in_flight", move || client.in_flight())`.
+    pub fn with_counter(
+        mut self,
+        name: impl Into<String>,
+        counter: impl Fn() -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.counters.push((name.into(), Box::new(counter)));
+        self
+    }
+
+    /// Serve requests until the task is dropped or the listener fails.
+    pub async fn serve(self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.address).await?;
+        tracing::info!(address = %self.address, "serving shutdown state on {STATE_PATH}");
+        let server = Arc::new(self);
+        loop {
+            let (stream, _peer) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(error) = server.respond(stream).await {
+                    tracing::debug!(%error, "failed to answer a debug request");
+                }
+            });
+        }
+    }
+
+    /// Start serving in the background, unless the function is running on Lambda, as found by
+    /// [`Environment::detect()`].
+    pub fn spawn_if_local(self) -> Option<JoinHandle<io::Result<()>>> {
+        Environment::detect()
+            .is_local_dev()
+            .then(|| tokio::spawn(self.serve()))
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..read]);
+            if request.len() > MAX_REQUEST_BYTES {
+                return write_response(&mut stream, "431 Request Header Fields Too Large", "")
+                    .await;
+            }
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+        let (method, path) = (request_line.next(), request_line.next());
+        match (method, path) {
+            (Some("GET"), Some(STATE_PATH)) => {
+                let body = self.state().to_string();
+                write_response(&mut stream, "200 OK", &body).await
+            }
+            (Some(_), Some(STATE_PATH)) => {
+                write_response(&mut stream, "405 Method Not Allowed", "").await
+            }
+            _ => write_response(&mut stream, "404 Not Found", "").await,
+        }
+    }
+
+    fn state(&self) -> Value {
+        let counters: Map<String, Value> = self
+            .counters
+            .iter()
+            .map(|(name, counter)| (name.clone(), json!(counter())))
+            .collect();
+        let sandbox = self.coordinator.sandbox_stats();
+        json!({
+            "budget_ms": self.coordinator.budget().as_millis() as u64,
+            "shutting_down": self.coordinator.is_shutting_down(),
+            "sandbox": {
+                "invocations": sandbox.invocations,
+                "thaws": sandbox.thaws,
+                "uptime_ms": sandbox.uptime.as_millis() as u64,
+            },
+            "hooks": self.coordinator.hook_names(),
+            "counters": counters,
+            "last_report": self.coordinator.last_report().as_ref().map(report),
+            "history": self
+                .coordinator
+                .report_history()
+                .map(|history| history.read().unwrap_or_default()),
+        })
+    }
+}
+
+fn report(report: &ShutdownReport) -> Value {
+    let hooks: Vec<Value> = report
+        .hooks
+        .iter()
+        .map(|hook| {
+            json!({
+                "name": hook.name,
+                "elapsed_ms": hook.elapsed.as_millis() as u64,
+                "outcome": hook.outcome.to_string(),
+                "notes": hook.notes,
+            })
+        })
+        .collect();
+    json!({
+        "reason": report.reason.to_string(),
+        "request_id": report.request_id,
+        "elapsed_ms": report.elapsed.as_millis() as u64,
+        "summary": report.to_string(),
+        "invocations": report.invocations.map(|drain| json!({
+            "in_flight": drain.in_flight,
+            "interrupted": drain.interrupted,
+            "elapsed_ms": drain.elapsed.as_millis() as u64,
+        })),
+        "hooks": hooks,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\
+         connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
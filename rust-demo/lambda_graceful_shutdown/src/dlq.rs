@@ -0,0 +1,406 @@
+//! Sending the payloads of invocations cut off by a timeout or failure to a dead-letter queue.
+//!
+//! When an invocation times out or the runtime crashes, Lambda shuts the environment down with
+//! the invocation still in flight. Asynchronous invocations may be retried, but the event that
+//! caused the failure is otherwise gone, along with any chance of replaying it once the bug is
+//! fixed.
+//!
+//! [`InFlightPayloads`] keeps the payload of every invocation in progress. When the shutdown
+//! reason is [`Timeout`](crate::ShutdownReason::Timeout) or
+//! [`Failure`](crate::ShutdownReason::Failure), its
+//! [`dead_letter_hook()`](InFlightPayloads::dead_letter_hook) sends each of them to an SQS
+//! queue, with diagnostics about the function and the shutdown. Only an extension registered
+//! for `SHUTDOWN` events learns the reason, so pass it to
+//! [`ShutdownCoordinator::shutdown()`](crate::ShutdownCoordinator::shutdown) with
+//! [`ShutdownReason::from_extension_reason()`](crate::ShutdownReason::from_extension_reason).
+//! For any other reason the hook does nothing.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_sqs::Client;
+use serde_json::{json, Value};
+use tokio::{task::JoinSet, time::Instant};
+
+use crate::{hook::join_within, BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// SQS rejects messages larger than this, unless the queue allows larger ones.
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 256 * 1024;
+
+/// Tells apart the guards of invocations tracked under the same request id.
+static NEXT_GUARD: AtomicU64 = AtomicU64::new(0);
+
+struct Invocation {
+    payload: String,
+    started: Instant,
+    guard: u64,
+}
+
+/// A registry of the payloads of invocations in progress.
+///
+/// Cloning is cheap, and all clones share the same registry.
+#[derive(Clone, Default)]
+pub struct InFlightPayloads {
+    invocations: Arc<Mutex<HashMap<String, Invocation>>>,
+}
+
+impl fmt::Debug for InFlightPayloads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InFlightPayloads")
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+impl InFlightPayloads {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep `payload`, the raw event of the invocation `request_id`, until the returned guard
+    /// is dropped at the end of the invocation.
+    ///
+    /// Tracking a request id again, as when an invocation is retried, replaces its payload, and
+    /// dropping the earlier guard leaves the new one tracked.
+    pub fn track(&self, request_id: impl Into<String>, payload: impl Into<String>) -> InFlight {
+        let request_id = request_id.into();
+        let guard = NEXT_GUARD.fetch_add(1, Ordering::Relaxed);
+        self.invocations.lock().unwrap().insert(
+            request_id.clone(),
+            Invocation {
+                payload: payload.into(),
+                started: Instant::now(),
+                guard,
+            },
+        );
+        InFlight {
+            payloads: self.clone(),
+            request_id,
+            guard,
+        }
+    }
+
+    /// The number of invocations currently tracked.
+    pub fn in_flight(&self) -> usize {
+        self.invocations.lock().unwrap().len()
+    }
+
+    /// A hook that sends the tracked payloads to the queue at `queue_url` when the environment
+    /// shuts down because of a timeout or failure.
+    pub fn dead_letter_hook(&self, client: Client, queue_url: impl Into<String>) -> DeadLetterHook {
+        DeadLetterHook {
+            client,
+            queue_url: queue_url.into(),
+            payloads: self.clone(),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+/// An invocation tracked by [`InFlightPayloads`], until this is dropped.
+pub struct InFlight {
+    payloads: InFlightPayloads,
+    request_id: String,
+    guard: u64,
+}
+
+impl fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InFlight")
+            .field("request_id", &self.request_id)
+            .finish()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut invocations = self.payloads.invocations.lock().unwrap();
+        if invocations
+            .get(&self.request_id)
+            .is_some_and(|invocation| invocation.guard == self.guard)
+        {
+            invocations.remove(&self.request_id);
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that sends the payloads tracked by [`InFlightPayloads`] to a
+/// dead-letter queue, when the shutdown is caused by a timeout or failure.
+///
+/// Each message is a JSON object with the `requestId`, the original `payload` (as JSON if it
+/// parses, as a string otherwise), and `diagnostics`: the shutdown reason, how long the
+/// invocation had been running, and the function's name, version and log stream. Payloads
+/// too large for the queue are logged and skipped.
+pub struct DeadLetterHook {
+    client: Client,
+    queue_url: String,
+    payloads: InFlightPayloads,
+    max_message_bytes: usize,
+}
+
+impl fmt::Debug for DeadLetterHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadLetterHook")
+            .field("queue_url", &self.queue_url)
+            .field("payloads", &self.payloads)
+            .field("max_message_bytes", &self.max_message_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeadLetterHook {
+    /// Skip messages larger than `max_message_bytes`, for queues that allow more than 256KiB.
+    pub fn with_max_message_bytes(mut self, max_message_bytes: usize) -> Self {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
+}
+
+impl ShutdownHook for DeadLetterHook {
+    fn name(&self) -> &str {
+        "dlq"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            if !ctx.reason().is_failure() {
+                return Ok(());
+            }
+            let messages: Vec<(String, String)> = {
+                let invocations = self.payloads.invocations.lock().unwrap();
+                invocations
+                    .iter()
+                    .map(|(request_id, invocation)| {
+                        (request_id.clone(), message(ctx, request_id, invocation))
+                    })
+                    .collect()
+            };
+            let total = messages.len();
+
+            let mut sends = JoinSet::new();
+            let mut too_large = 0;
+            for (request_id, body) in messages {
+                if body.len() > self.max_message_bytes {
+                    tracing::warn!(
+                        request_id,
+                        size = body.len(),
+                        "in-flight payload is too large for the dead-letter queue"
+                    );
+                    too_large += 1;
+                    continue;
+                }
+                let request = self
+                    .client
+                    .send_message()
+                    .queue_url(&self.queue_url)
+                    .message_body(body);
+                sends.spawn(async move {
+                    let sent = request.send().await;
+                    if let Err(error) = &sent {
+                        tracing::debug!(request_id, %error, "failed to send payload to the DLQ");
+                    }
+                    sent.is_ok()
+                });
+            }
+
+            let (mut sent, mut failed) = (0, 0);
+            let joined = join_within(ctx, &mut sends, |ok| {
+                if ok {
+                    sent += 1;
+                } else {
+                    failed += 1;
+                }
+            })
+            .await;
+            failed += joined.panicked;
+
+            ctx.note(format!(
+                "sent {sent} of {total} in-flight payloads, {too_large} too large"
+            ));
+            if let Some(timed_out) = joined.timed_out("payloads left to send") {
+                return Err(timed_out);
+            }
+            if failed > 0 {
+                return Err(format!("failed to send {failed} payloads").into());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The dead-letter message for the invocation `request_id`.
+fn message(ctx: &ShutdownContext, request_id: &str, invocation: &Invocation) -> String {
+    let payload = serde_json::from_str::<Value>(&invocation.payload)
+        .unwrap_or_else(|_| Value::String(invocation.payload.clone()));
+    let shutdown_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    json!({
+        "requestId": request_id,
+        "payload": payload,
+        "diagnostics": {
+            "shutdownReason": ctx.reason().to_string(),
+            "shutdownAt": shutdown_at,
+            "runningForMs": invocation.started.elapsed().as_millis() as u64,
+            "functionName": std::env::var("AWS_LAMBDA_FUNCTION_NAME").ok(),
+            "functionVersion": std::env::var("AWS_LAMBDA_FUNCTION_VERSION").ok(),
+            "logStream": std::env::var("AWS_LAMBDA_LOG_STREAM_NAME").ok(),
+        },
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_sqs::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+    use aws_smithy_runtime_api::{
+        client::{
+            http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector},
+            orchestrator::{HttpRequest, HttpResponse},
+        },
+        http::StatusCode,
+    };
+    use aws_smithy_types::body::SdkBody;
+
+    use super::*;
+    use crate::{ShutdownCoordinator, ShutdownReason};
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/dlq";
+
+    /// Answers `SendMessage`, and records the queue and body of each message.
+    #[derive(Debug, Default)]
+    struct FakeSqs {
+        messages: Mutex<Vec<(String, Value)>>,
+    }
+
+    #[derive(Debug)]
+    struct Connector(Arc<FakeSqs>);
+
+    impl HttpConnector for Connector {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+            let message = serde_json::from_str(body["MessageBody"].as_str().unwrap()).unwrap();
+            let queue_url = body["QueueUrl"].as_str().unwrap().to_owned();
+            self.0.messages.lock().unwrap().push((queue_url, message));
+            let body = json!({ "MessageId": "1", "MD5OfMessageBody": "" });
+            HttpConnectorFuture::ready(Ok(HttpResponse::new(
+                StatusCode::try_from(200).unwrap(),
+                SdkBody::from(body.to_string()),
+            )))
+        }
+    }
+
+    fn client(fake: &Arc<FakeSqs>) -> Client {
+        let connector = SharedHttpConnector::new(Connector(fake.clone()));
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .http_client(http_client_fn(move |_, _| connector.clone()))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        Client::from_conf(config)
+    }
+
+    #[tokio::test]
+    async fn payloads_cut_off_by_a_timeout_are_sent_with_diagnostics() {
+        let fake = Arc::new(FakeSqs::default());
+        let payloads = InFlightPayloads::new();
+        let _json = payloads.track("json", r#"{"order": 7}"#);
+        let _text = payloads.track("text", "not json");
+        let report = ShutdownCoordinator::new()
+            .with_hook(payloads.dead_letter_hook(client(&fake), QUEUE_URL))
+            .shutdown(ShutdownReason::Timeout)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent 2 of 2 in-flight payloads, 0 too large"]
+        );
+
+        let mut messages = fake.messages.lock().unwrap().clone();
+        messages.sort_by_key(|(_, message)| message["requestId"].to_string());
+        assert!(messages.iter().all(|(queue_url, _)| queue_url == QUEUE_URL));
+        let (json, text) = (&messages[0].1, &messages[1].1);
+        assert_eq!(json["requestId"], "json");
+        assert_eq!(json["payload"], json!({ "order": 7 }));
+        assert_eq!(text["payload"], "not json");
+        let diagnostics = &json["diagnostics"];
+        assert_eq!(
+            diagnostics["shutdownReason"],
+            ShutdownReason::Timeout.to_string()
+        );
+        assert!(diagnostics["shutdownAt"].is_u64());
+        assert!(diagnostics["runningForMs"].is_u64());
+        for field in ["functionName", "functionVersion", "logStream"] {
+            assert!(diagnostics.get(field).is_some(), "{field} is missing");
+        }
+    }
+
+    #[tokio::test]
+    async fn payloads_too_large_for_the_queue_are_skipped() {
+        let fake = Arc::new(FakeSqs::default());
+        let payloads = InFlightPayloads::new();
+        let _small = payloads.track("small", "{}");
+        let _large = payloads.track("large", "x".repeat(1024));
+        let hook = payloads
+            .dead_letter_hook(client(&fake), QUEUE_URL)
+            .with_max_message_bytes(1024);
+        let report = ShutdownCoordinator::new()
+            .with_hook(hook)
+            .shutdown(ShutdownReason::Failure)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent 1 of 2 in-flight payloads, 1 too large"]
+        );
+        let messages = fake.messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1["requestId"], "small");
+    }
+
+    #[tokio::test]
+    async fn nothing_is_sent_unless_the_shutdown_is_a_failure() {
+        let fake = Arc::new(FakeSqs::default());
+        let payloads = InFlightPayloads::new();
+        let _pending = payloads.track("request", "{}");
+        let report = ShutdownCoordinator::new()
+            .with_hook(payloads.dead_letter_hook(client(&fake), QUEUE_URL))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert!(report.hooks[0].notes.is_empty());
+        assert!(fake.messages.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn an_earlier_guard_for_the_same_request_leaves_the_new_one_tracked() {
+        let payloads = InFlightPayloads::new();
+        let first = payloads.track("request", "first");
+        let second = payloads.track("request", "retried");
+        drop(first);
+        assert_eq!(payloads.in_flight(), 1);
+        assert_eq!(
+            payloads.invocations.lock().unwrap()["request"].payload,
+            "retried"
+        );
+        drop(second);
+        assert_eq!(payloads.in_flight(), 0);
+    }
+}
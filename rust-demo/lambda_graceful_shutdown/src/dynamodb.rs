@@ -0,0 +1,1210 @@
+//! Batched writes to DynamoDB.
+//!
+//! [`BufferedDynamoDbWriter`] is a write-behind buffer for `BatchWriteItem`: puts and deletes
+//! are queued and written 25 at a time. DynamoDB rejects a whole batch that writes the same key
+//! twice, so only the last write to each key in a batch is sent. DynamoDB may leave part of a
+//! batch unprocessed when a table is throttled; those items are kept and written again later.
+//! The
+//! [`flush_hook()`](BufferedDynamoDbWriter::flush_hook) writes what is left at shutdown, and
+//! retries unprocessed items for as long as the budget allows.
+//!
+//! [`DynamoDbCheckpointStore`] is a [`CheckpointStore`] keeping each checkpoint in an item of
+//! its own. [`IdempotencyTable`] is one that also remembers which events have been processed,
+//! so a retried invocation can tell whether the work is already done.
+//!
+//! [`DynamoDbLocks`] holds leases on locks in the format of the DynamoDB lock client, and its
+//! [`release_hook()`](DynamoDbLocks::release_hook) releases them at shutdown, so other workers
+//! don't wait for the leases to expire.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_dynamodb::{
+    operation::{put_item::PutItemError, update_item::UpdateItemError},
+    primitives::Blob,
+    types::{
+        AttributeValue, DeleteRequest, PutRequest, ReturnValuesOnConditionCheckFailure,
+        WriteRequest,
+    },
+    Client,
+};
+use tokio::task::JoinSet;
+
+use crate::{
+    checkpoint::CheckpointStore,
+    hook::join_within,
+    records::{RecordApi, RecordWriter},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// `BatchWriteItem` accepts at most 25 items and 16 MB per request.
+const MAX_BATCH_ITEMS: usize = 25;
+const MAX_BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// DynamoDB accepts items of up to 400 KB, attribute names included.
+const MAX_ITEM_BYTES: usize = 400 * 1024;
+
+/// A write request, along with the table it is for.
+#[derive(Debug, Clone)]
+struct TableWrite {
+    table: String,
+    request: WriteRequest,
+}
+
+impl TableWrite {
+    /// The key the write is for, given the names of the table's key attributes, or `None` if
+    /// the item lacks one of them.
+    fn key(&self, attributes: &[String]) -> Option<Vec<AttributeValue>> {
+        let item = match (&self.request.put_request, &self.request.delete_request) {
+            (Some(put), _) => put.item(),
+            (None, Some(delete)) => delete.key(),
+            (None, None) => return None,
+        };
+        attributes
+            .iter()
+            .map(|attribute| item.get(attribute).cloned())
+            .collect()
+    }
+}
+
+struct DynamoDbApi {
+    client: Client,
+    /// The key attributes of each table written to, or `None` for tables whose key schema
+    /// couldn't be looked up.
+    key_schemas: Mutex<HashMap<String, Option<Vec<String>>>>,
+}
+
+impl DynamoDbApi {
+    /// The names of the key attributes of `table`, looked up with `DescribeTable` the first
+    /// time unless they were given with [`BufferedDynamoDbWriter::with_key_schema()`].
+    async fn key_schema(&self, table: &str) -> Option<Vec<String>> {
+        if let Some(schema) = self.key_schemas.lock().unwrap().get(table) {
+            return schema.clone();
+        }
+        let schema = match self.client.describe_table().table_name(table).send().await {
+            Ok(output) => output.table().map(|description| {
+                description
+                    .key_schema()
+                    .iter()
+                    .map(|element| element.attribute_name().to_owned())
+                    .collect()
+            }),
+            Err(error) => {
+                tracing::warn!(
+                    table,
+                    error = %aws_sdk_dynamodb::error::DisplayErrorContext(&error),
+                    "could not look up the key schema, so writes to the same key in a batch \
+                     are not merged"
+                );
+                None
+            }
+        };
+        self.key_schemas
+            .lock()
+            .unwrap()
+            .insert(table.to_owned(), schema.clone());
+        schema
+    }
+
+    /// Which of `records` a later one in the batch writes the same key over.
+    async fn superseded(&self, records: &[TableWrite]) -> Vec<bool> {
+        let mut keys = Vec::with_capacity(records.len());
+        for write in records {
+            let key = match self.key_schema(&write.table).await {
+                Some(attributes) => write.key(&attributes),
+                None => None,
+            };
+            keys.push(key);
+        }
+        (0..records.len())
+            .map(|i| {
+                keys[i].as_ref().is_some_and(|key| {
+                    (i + 1..records.len()).any(|j| {
+                        records[j].table == records[i].table && keys[j].as_ref() == Some(key)
+                    })
+                })
+            })
+            .collect()
+    }
+}
+
+impl RecordApi for DynamoDbApi {
+    type Record = TableWrite;
+
+    fn put(&self, records: Vec<Self::Record>) -> BoxFuture<'_, Result<Vec<Option<String>>, Error>> {
+        Box::pin(async move {
+            // A write with a later one to the same key counts as written: the later one is
+            // what the item ends up as, and is retried if it goes unprocessed
+            let superseded = self.superseded(&records).await;
+            let mut request_items: HashMap<String, Vec<WriteRequest>> = HashMap::new();
+            for (write, _) in records.iter().zip(&superseded).filter(|(_, &s)| !s) {
+                request_items
+                    .entry(write.table.clone())
+                    .or_default()
+                    .push(write.request.clone());
+            }
+            let output = self
+                .client
+                .batch_write_item()
+                .set_request_items(Some(request_items))
+                .send()
+                .await?;
+
+            let unprocessed = output.unprocessed_items();
+            Ok(records
+                .iter()
+                .zip(superseded)
+                .map(|(write, superseded)| {
+                    let is_unprocessed = !superseded
+                        && unprocessed
+                            .and_then(|items| items.get(&write.table))
+                            .is_some_and(|items| items.contains(&write.request));
+                    is_unprocessed.then(|| "UnprocessedItem".to_owned())
+                })
+                .collect())
+        })
+    }
+}
+
+/// Writes puts and deletes to DynamoDB tables in batches.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+#[derive(Clone)]
+pub struct BufferedDynamoDbWriter {
+    writer: Arc<RecordWriter<DynamoDbApi>>,
+}
+
+impl fmt::Debug for BufferedDynamoDbWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferedDynamoDbWriter")
+            .finish_non_exhaustive()
+    }
+}
+
+impl BufferedDynamoDbWriter {
+    /// Create a writer. A single writer can write to any number of tables.
+    pub fn new(client: Client) -> Self {
+        Self {
+            writer: Arc::new(RecordWriter::new(
+                DynamoDbApi {
+                    client,
+                    key_schemas: Mutex::new(HashMap::new()),
+                },
+                MAX_BATCH_ITEMS,
+                MAX_BATCH_BYTES,
+            )),
+        }
+    }
+
+    /// Give the names of `table`'s key attributes, its partition key and its sort key if it
+    /// has one, instead of looking them up with `DescribeTable` before the first write to it.
+    pub fn with_key_schema<I>(self, table: impl Into<String>, attributes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let attributes = attributes.into_iter().map(Into::into).collect();
+        self.writer
+            .api()
+            .key_schemas
+            .lock()
+            .unwrap()
+            .insert(table.into(), Some(attributes));
+        self
+    }
+
+    /// Queue a put of `item` into `table`, writing a batch if this fills one up.
+    ///
+    /// If some items of that batch are left unprocessed, they are kept for the next write, and
+    /// [`last_error()`](Self::last_error) says why. Items over 400 KB, which DynamoDB would
+    /// never accept, are rejected with an error, without being queued.
+    pub async fn put_item(
+        &self,
+        table: impl Into<String>,
+        item: HashMap<String, AttributeValue>,
+    ) -> Result<(), Error> {
+        let size = item_size(&item);
+        if size > MAX_ITEM_BYTES {
+            return Err(
+                format!("item is {size} bytes, DynamoDB accepts at most {MAX_ITEM_BYTES}").into(),
+            );
+        }
+        let request = WriteRequest::builder()
+            .put_request(PutRequest::builder().set_item(Some(item)).build()?)
+            .build();
+        self.push(table.into(), request, size).await
+    }
+
+    /// Queue a delete of the item with the given `key` from `table`.
+    pub async fn delete_item(
+        &self,
+        table: impl Into<String>,
+        key: HashMap<String, AttributeValue>,
+    ) -> Result<(), Error> {
+        let size = item_size(&key);
+        let request = WriteRequest::builder()
+            .delete_request(DeleteRequest::builder().set_key(Some(key)).build()?)
+            .build();
+        self.push(table.into(), request, size).await
+    }
+
+    async fn push(&self, table: String, request: WriteRequest, size: usize) -> Result<(), Error> {
+        let size = size + table.len();
+        self.writer.push(TableWrite { table, request }, size).await
+    }
+
+    /// The number of writes waiting to be sent.
+    pub async fn pending(&self) -> usize {
+        self.writer.pending().await
+    }
+
+    /// Why the last batch sent failed, in full or in part, unless one has been sent
+    /// in full since.
+    /// Its writes are still queued, and go out with a later batch or flush.
+    pub fn last_error(&self) -> Option<String> {
+        self.writer.last_error()
+    }
+
+    /// Send every queued write, including a final partial batch.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.writer.flush().await
+    }
+
+    /// A hook that sends any queued writes when the environment shuts down.
+    pub fn flush_hook(&self) -> DynamoDbFlushHook {
+        DynamoDbFlushHook {
+            writer: self.clone(),
+        }
+    }
+}
+
+/// Roughly how many bytes DynamoDB counts for an item: attribute names plus values.
+fn item_size(item: &HashMap<String, AttributeValue>) -> usize {
+    item.iter()
+        .map(|(name, value)| name.len() + value_size(value))
+        .sum()
+}
+
+fn value_size(value: &AttributeValue) -> usize {
+    match value {
+        AttributeValue::B(blob) => blob.as_ref().len(),
+        AttributeValue::Bool(_) | AttributeValue::Null(_) => 1,
+        AttributeValue::Bs(blobs) => blobs.iter().map(|blob| blob.as_ref().len()).sum(),
+        AttributeValue::L(values) => 3 + values.iter().map(|v| 1 + value_size(v)).sum::<usize>(),
+        AttributeValue::M(map) => 3 + item_size(map) + map.len(),
+        AttributeValue::N(number) => number.len(),
+        AttributeValue::Ns(numbers) | AttributeValue::Ss(numbers) => {
+            numbers.iter().map(String::len).sum()
+        }
+        AttributeValue::S(string) => string.len(),
+        _ => 0,
+    }
+}
+
+/// A [`ShutdownHook`] that sends the writes still buffered in a [`BufferedDynamoDbWriter`].
+#[derive(Debug)]
+pub struct DynamoDbFlushHook {
+    writer: BufferedDynamoDbWriter,
+}
+
+impl ShutdownHook for DynamoDbFlushHook {
+    fn name(&self) -> &str {
+        "dynamodb"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(
+            self.writer
+                .writer
+                .flush_until(ctx.clock(), ctx.drain_deadline()),
+        )
+    }
+}
+
+/// How long checkpoints are kept, unless set with [`DynamoDbCheckpointStore::with_ttl()`].
+const DEFAULT_CHECKPOINT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A [`CheckpointStore`] keeping checkpoints in a DynamoDB table.
+///
+/// Each checkpoint is an item with the key in the partition key attribute (`pk` by default),
+/// the bytes in `checkpoint`, the time it was saved in `saved_at` (milliseconds since the
+/// epoch), and when it expires in `expires_at` (seconds since the epoch). Enable TTL on the
+/// table with `expires_at` as the attribute, so abandoned checkpoints are deleted.
+///
+/// Saves are conditional on `saved_at`, so an environment that is slow to save can't replace
+/// a newer checkpoint saved by another one.
+#[derive(Debug, Clone)]
+pub struct DynamoDbCheckpointStore {
+    client: Client,
+    table: String,
+    key_attribute: String,
+    ttl: Duration,
+}
+
+impl DynamoDbCheckpointStore {
+    /// Keep checkpoints in `table`.
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        Self {
+            client,
+            table: table.into(),
+            key_attribute: "pk".to_owned(),
+            ttl: DEFAULT_CHECKPOINT_TTL,
+        }
+    }
+
+    /// Use `attribute` as the partition key instead of `pk`. The table must not have a sort
+    /// key.
+    pub fn with_key_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.key_attribute = attribute.into();
+        self
+    }
+
+    /// Let checkpoints expire `ttl` after they are saved, instead of after a day.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The `expires_at` of an item written now, in seconds since the epoch.
+    fn expires_at(&self) -> Result<u64, Error> {
+        Ok((SystemTime::now().duration_since(UNIX_EPOCH)? + self.ttl).as_secs())
+    }
+}
+
+impl CheckpointStore for DynamoDbCheckpointStore {
+    fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            let saved_at = AttributeValue::N(now.as_millis().to_string());
+            let expires_at = AttributeValue::N((now + self.ttl).as_secs().to_string());
+            let result = self
+                .client
+                .put_item()
+                .table_name(&self.table)
+                .item(&self.key_attribute, AttributeValue::S(key.to_owned()))
+                .item("checkpoint", AttributeValue::B(Blob::new(bytes)))
+                .item("saved_at", saved_at.clone())
+                .item("expires_at", expires_at)
+                .condition_expression("attribute_not_exists(#key) OR saved_at < :saved_at")
+                .expression_attribute_names("#key", &self.key_attribute)
+                .expression_attribute_values(":saved_at", saved_at)
+                .send()
+                .await;
+            match result {
+                Ok(_) => Ok(()),
+                Err(error)
+                    if error
+                        .as_service_error()
+                        .is_some_and(|error| error.is_conditional_check_failed_exception()) =>
+                {
+                    tracing::debug!(key, "a newer checkpoint has already been saved");
+                    Ok(())
+                }
+                Err(error) => Err(error.into()),
+            }
+        })
+    }
+
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        Box::pin(async move {
+            let output = self
+                .client
+                .get_item()
+                .table_name(&self.table)
+                .key(&self.key_attribute, AttributeValue::S(key.to_owned()))
+                .consistent_read(true)
+                .send()
+                .await?;
+            let Some(item) = output.item else {
+                return Ok(None);
+            };
+            // TTL deletes expired items eventually, not right away.
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let expired = item
+                .get("expires_at")
+                .and_then(|expires_at| expires_at.as_n().ok())
+                .and_then(|expires_at| expires_at.parse::<u64>().ok())
+                .is_some_and(|expires_at| expires_at <= now);
+            if expired {
+                return Ok(None);
+            }
+            match item.get("checkpoint") {
+                Some(AttributeValue::B(bytes)) => Ok(Some(bytes.clone().into_inner())),
+                None => Ok(None),
+                Some(_) => Err(format!("the checkpoint for {key} is not binary").into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.client
+                .delete_item()
+                .table_name(&self.table)
+                .key(&self.key_attribute, AttributeValue::S(key.to_owned()))
+                .send()
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// The `status` of an event in an [`IdempotencyTable`] while it is being processed.
+const IN_PROGRESS: &str = "IN_PROGRESS";
+
+/// The `status` of an event in an [`IdempotencyTable`] once it has been processed.
+const COMPLETED: &str = "COMPLETED";
+
+/// How long an invocation holds an event it began, unless set with
+/// [`IdempotencyTable::with_lease()`]: the longest a Lambda invocation can run.
+const DEFAULT_EVENT_LEASE: Duration = Duration::from_secs(15 * 60);
+
+/// What an [`IdempotencyTable`] knows about an event, returned by
+/// [`IdempotencyTable::begin()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventStatus {
+    /// The event hasn't been seen before. Process it.
+    New,
+    /// An earlier invocation started processing the event but didn't finish before its lease
+    /// ran out, usually because its environment shut down. This invocation holds the event
+    /// now. Resume from its checkpoint, if it saved one.
+    Interrupted,
+    /// Another invocation is processing the event right now, e.g. the first delivery of an SQS
+    /// message that was delivered again while it ran. Don't process it; fail the invocation if
+    /// the event should be retried once the other one is done.
+    InProgress,
+    /// The event has already been processed. Skip it.
+    Completed,
+}
+
+/// A DynamoDB table recording which events have been processed, keyed by event id, that also
+/// works as a [`CheckpointStore`] for the events in progress.
+///
+/// Call [`begin()`](Self::begin) at the start of an invocation, and skip the work if the event
+/// has already been completed. Keep progress with [`Checkpoints`](crate::checkpoint::Checkpoints)
+/// on this table, with the event id as the key, so the checkpoint hook saves it in the event's
+/// item at shutdown. [`Checkpoints::complete()`](crate::checkpoint::Checkpoints::complete)
+/// then marks the event as completed, rather than deleting its item, so a retry of an
+/// invocation that finished its work, but was cut off before returning, skips it instead of
+/// repeating its side effects.
+///
+/// Items use the same attributes as [`DynamoDbCheckpointStore`], plus `status`, and
+/// `lease_expires_at` (milliseconds since the epoch) for how long the invocation that began the
+/// event holds it. Enable TTL on the table with `expires_at` as the attribute.
+#[derive(Debug, Clone)]
+pub struct IdempotencyTable {
+    store: DynamoDbCheckpointStore,
+    lease: Duration,
+}
+
+impl IdempotencyTable {
+    /// Record events in `table`.
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        Self {
+            store: DynamoDbCheckpointStore::new(client, table),
+            lease: DEFAULT_EVENT_LEASE,
+        }
+    }
+
+    /// Use `attribute` as the partition key instead of `pk`. The table must not have a sort
+    /// key.
+    pub fn with_key_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.store = self.store.with_key_attribute(attribute);
+        self
+    }
+
+    /// Remember events for `ttl` after they were last updated, instead of a day. Retries
+    /// arriving later than that process the event again.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.store = self.store.with_ttl(ttl);
+        self
+    }
+
+    /// Let an invocation hold an event for `lease` after it [began](Self::begin) it, instead of
+    /// 15 minutes. Until then, other invocations of the event are told it is
+    /// [in progress](EventStatus::InProgress), and after it, the next one takes it over as
+    /// [interrupted](EventStatus::Interrupted). Set it to the function's timeout, or a little
+    /// more, to allow for clock skew between environments.
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Start processing `event_id`, unless another invocation already has.
+    ///
+    /// The event is [new](EventStatus::New) if it has no item yet, and
+    /// [interrupted](EventStatus::Interrupted) if it was begun by an invocation whose lease has
+    /// run out. Both writes are conditional, so only one of the invocations racing for an event
+    /// gets to process it.
+    pub async fn begin(&self, event_id: &str) -> Result<EventStatus, Error> {
+        let store = &self.store;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let lease_expires_at = AttributeValue::N((now + self.lease).as_millis().to_string());
+        let expires_at = AttributeValue::N(store.expires_at()?.to_string());
+        let result = store
+            .client
+            .put_item()
+            .table_name(&store.table)
+            .item(&store.key_attribute, AttributeValue::S(event_id.to_owned()))
+            .item("status", AttributeValue::S(IN_PROGRESS.to_owned()))
+            .item("lease_expires_at", lease_expires_at.clone())
+            .item("expires_at", expires_at.clone())
+            .condition_expression("attribute_not_exists(#key)")
+            .expression_attribute_names("#key", &store.key_attribute)
+            .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+            .send()
+            .await;
+        let item = match result {
+            Ok(_) => return Ok(EventStatus::New),
+            Err(error) => match error.as_service_error() {
+                Some(PutItemError::ConditionalCheckFailedException(failed)) => failed.item.clone(),
+                _ => return Err(error.into()),
+            },
+        };
+        if let Some(status) = held_status(item.as_ref(), now) {
+            return Ok(status);
+        }
+
+        // The lease has run out, so take the event over, unless another invocation just did
+        let result = store
+            .client
+            .update_item()
+            .table_name(&store.table)
+            .key(&store.key_attribute, AttributeValue::S(event_id.to_owned()))
+            .update_expression(
+                "SET #status = :in_progress, lease_expires_at = :lease_expires_at, \
+                 expires_at = :expires_at",
+            )
+            .condition_expression(
+                "(attribute_not_exists(#status) OR #status = :in_progress) \
+                 AND (attribute_not_exists(lease_expires_at) OR lease_expires_at <= :now)",
+            )
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":in_progress", AttributeValue::S(IN_PROGRESS.to_owned()))
+            .expression_attribute_values(":lease_expires_at", lease_expires_at)
+            .expression_attribute_values(":expires_at", expires_at)
+            .expression_attribute_values(":now", AttributeValue::N(now.as_millis().to_string()))
+            .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(EventStatus::Interrupted),
+            Err(error) => match error.as_service_error() {
+                Some(UpdateItemError::ConditionalCheckFailedException(failed)) => {
+                    Ok(held_status(failed.item.as_ref(), now).unwrap_or(EventStatus::InProgress))
+                }
+                _ => Err(error.into()),
+            },
+        }
+    }
+}
+
+/// The status of an event whose item is `item`, as of `now` since the epoch, or `None` if no
+/// invocation holds it any more and the next one can take it over.
+fn held_status(
+    item: Option<&HashMap<String, AttributeValue>>,
+    now: Duration,
+) -> Option<EventStatus> {
+    let item = item?;
+    if item
+        .get("status")
+        .and_then(|status| status.as_s().ok())
+        .map(String::as_str)
+        == Some(COMPLETED)
+    {
+        return Some(EventStatus::Completed);
+    }
+    item.get("lease_expires_at")
+        .and_then(|lease| lease.as_n().ok())
+        .and_then(|lease| lease.parse::<u128>().ok())
+        .is_some_and(|lease| lease > now.as_millis())
+        .then_some(EventStatus::InProgress)
+}
+
+impl CheckpointStore for IdempotencyTable {
+    /// Save the checkpoint in the event's item, unless the event has been completed since.
+    fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let store = &self.store;
+            let result = store
+                .client
+                .update_item()
+                .table_name(&store.table)
+                .key(&store.key_attribute, AttributeValue::S(key.to_owned()))
+                .update_expression("SET checkpoint = :checkpoint, expires_at = :expires_at")
+                .condition_expression("attribute_not_exists(#status) OR #status <> :completed")
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_values(":checkpoint", AttributeValue::B(Blob::new(bytes)))
+                .expression_attribute_values(
+                    ":expires_at",
+                    AttributeValue::N(store.expires_at()?.to_string()),
+                )
+                .expression_attribute_values(":completed", AttributeValue::S(COMPLETED.to_owned()))
+                .send()
+                .await;
+            match result {
+                Ok(_) => Ok(()),
+                Err(error)
+                    if error
+                        .as_service_error()
+                        .is_some_and(|error| error.is_conditional_check_failed_exception()) =>
+                {
+                    tracing::debug!(key, "not saving a checkpoint for a completed event");
+                    Ok(())
+                }
+                Err(error) => Err(error.into()),
+            }
+        })
+    }
+
+    /// Load the checkpoint of an event that hasn't been completed.
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        self.store.load(key)
+    }
+
+    /// Mark the event as completed, dropping its checkpoint.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let store = &self.store;
+            store
+                .client
+                .update_item()
+                .table_name(&store.table)
+                .key(&store.key_attribute, AttributeValue::S(key.to_owned()))
+                .update_expression(
+                    "SET #status = :completed, expires_at = :expires_at REMOVE checkpoint",
+                )
+                .expression_attribute_names("#status", "status")
+                .expression_attribute_values(":completed", AttributeValue::S(COMPLETED.to_owned()))
+                .expression_attribute_values(
+                    ":expires_at",
+                    AttributeValue::N(store.expires_at()?.to_string()),
+                )
+                .send()
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// How long a lease lasts, unless set with [`DynamoDbLocks::with_lease_duration()`].
+const DEFAULT_LEASE_DURATION: Duration = Duration::from_secs(20);
+
+/// Makes record version numbers unique within the process.
+static NEXT_RECORD_VERSION: AtomicU64 = AtomicU64::new(0);
+
+/// Leases on locks kept in a DynamoDB table, in the format of the DynamoDB lock client.
+///
+/// Each lock is an item with its name in the partition key attribute (`key` by default), and
+/// `ownerName`, `leaseDuration` and `recordVersionNumber` attributes. Other workers consider
+/// a lock abandoned once its record version number has stayed the same for the lease
+/// duration, so a lock held by an environment that disappears stays taken that long. The
+/// [`release_hook()`](Self::release_hook) releases the leases still held at shutdown, so
+/// other workers can take over right away.
+///
+/// Clones share the same leases.
+#[derive(Clone)]
+pub struct DynamoDbLocks {
+    client: Client,
+    table: String,
+    key_attribute: String,
+    owner: String,
+    lease_duration: Duration,
+    held: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl fmt::Debug for DynamoDbLocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DynamoDbLocks")
+            .field("table", &self.table)
+            .field("owner", &self.owner)
+            .field("held", &self.held())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DynamoDbLocks {
+    /// Keep locks in `table`, owned by this execution environment's log stream.
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        let owner = std::env::var("AWS_LAMBDA_LOG_STREAM_NAME")
+            .unwrap_or_else(|_| format!("pid-{}", std::process::id()));
+        Self {
+            client,
+            table: table.into(),
+            key_attribute: "key".to_owned(),
+            owner,
+            lease_duration: DEFAULT_LEASE_DURATION,
+            held: Arc::default(),
+        }
+    }
+
+    /// Use `attribute` as the partition key instead of `key`. The table must not have a sort
+    /// key.
+    pub fn with_key_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.key_attribute = attribute.into();
+        self
+    }
+
+    /// Take locks as `owner` instead of the name of the log stream.
+    pub fn with_owner(mut self, owner: impl Into<String>) -> Self {
+        self.owner = owner.into();
+        self
+    }
+
+    /// Take leases for `lease_duration` instead of 20s.
+    pub fn with_lease_duration(mut self, lease_duration: Duration) -> Self {
+        self.lease_duration = lease_duration;
+        self
+    }
+
+    /// Take the lock `key` if nobody holds it, or renew it if this owner already does.
+    /// Returns whether the lock is held.
+    pub async fn acquire(&self, key: &str) -> Result<bool, Error> {
+        let version = record_version(&self.owner);
+        let result = self
+            .client
+            .put_item()
+            .table_name(&self.table)
+            .item(&self.key_attribute, AttributeValue::S(key.to_owned()))
+            .item("ownerName", AttributeValue::S(self.owner.clone()))
+            .item(
+                "leaseDuration",
+                AttributeValue::S(self.lease_duration.as_millis().to_string()),
+            )
+            .item("recordVersionNumber", AttributeValue::S(version.clone()))
+            .condition_expression("attribute_not_exists(#key) OR ownerName = :owner")
+            .expression_attribute_names("#key", &self.key_attribute)
+            .expression_attribute_values(":owner", AttributeValue::S(self.owner.clone()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => {
+                self.hold(key, version);
+                Ok(true)
+            }
+            Err(error)
+                if error
+                    .as_service_error()
+                    .is_some_and(|error| error.is_conditional_check_failed_exception()) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Track a lease on `key` taken by another lock client, with its record version number,
+    /// so the release hook releases it too.
+    pub fn hold(&self, key: impl Into<String>, record_version_number: impl Into<String>) {
+        self.held
+            .lock()
+            .unwrap()
+            .insert(key.into(), record_version_number.into());
+    }
+
+    /// Release the lease on `key`.
+    pub async fn release(&self, key: &str) -> Result<(), Error> {
+        let version = self.held.lock().unwrap().remove(key);
+        match version {
+            Some(version) => self.release_lease(key, &version, None).await.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// The number of leases held.
+    pub fn held(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+
+    /// A hook that releases every lease held when the environment shuts down.
+    pub fn release_hook(&self) -> LockReleaseHook {
+        LockReleaseHook {
+            locks: self.clone(),
+            shorten_to: None,
+        }
+    }
+}
+
+impl DynamoDbLocks {
+    /// Release the lease on `key` if it is still at `version`, by deleting the lock, or by
+    /// shortening the lease to `shorten_to`. Returns whether the lease was still held.
+    async fn release_lease(
+        &self,
+        key: &str,
+        version: &str,
+        shorten_to: Option<Duration>,
+    ) -> Result<bool, Error> {
+        let key_value = AttributeValue::S(key.to_owned());
+        let version = AttributeValue::S(version.to_owned());
+        let owner = AttributeValue::S(self.owner.clone());
+        let condition = "recordVersionNumber = :version AND ownerName = :owner";
+        let error = match shorten_to {
+            None => self
+                .client
+                .delete_item()
+                .table_name(&self.table)
+                .key(&self.key_attribute, key_value)
+                .condition_expression(condition)
+                .expression_attribute_values(":version", version)
+                .expression_attribute_values(":owner", owner)
+                .send()
+                .await
+                .err()
+                .map(|error| error.into_service_error())
+                .map(|error| {
+                    (
+                        error.is_conditional_check_failed_exception(),
+                        Error::from(error),
+                    )
+                }),
+            Some(lease) => self
+                .client
+                .update_item()
+                .table_name(&self.table)
+                .key(&self.key_attribute, key_value)
+                .update_expression("SET leaseDuration = :lease, recordVersionNumber = :new_version")
+                .condition_expression(condition)
+                .expression_attribute_values(":version", version)
+                .expression_attribute_values(":owner", owner)
+                .expression_attribute_values(
+                    ":lease",
+                    AttributeValue::S(lease.as_millis().to_string()),
+                )
+                .expression_attribute_values(
+                    ":new_version",
+                    AttributeValue::S(record_version(&self.owner)),
+                )
+                .send()
+                .await
+                .err()
+                .map(|error| error.into_service_error())
+                .map(|error| {
+                    (
+                        error.is_conditional_check_failed_exception(),
+                        Error::from(error),
+                    )
+                }),
+        };
+        match error {
+            None => Ok(true),
+            Some((true, _)) => {
+                tracing::debug!(key, "the lease was already taken over");
+                Ok(false)
+            }
+            Some((false, error)) => Err(error),
+        }
+    }
+}
+
+/// A record version number that no other lease update uses.
+fn record_version(owner: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let count = NEXT_RECORD_VERSION.fetch_add(1, Ordering::Relaxed);
+    format!("{owner}-{nanos:x}-{count:x}")
+}
+
+/// A [`ShutdownHook`] that releases the leases held by [`DynamoDbLocks`].
+///
+/// Leases that were taken over in the meantime are left alone. The hook fails if any release
+/// fails, or if the budget runs out before every lease has been released.
+#[derive(Debug)]
+pub struct LockReleaseHook {
+    locks: DynamoDbLocks,
+    shorten_to: Option<Duration>,
+}
+
+impl LockReleaseHook {
+    /// Shorten each lease to `lease` instead of deleting the lock, for lock clients that only
+    /// take over locks they have seen expire.
+    pub fn with_shortened_lease(mut self, lease: Duration) -> Self {
+        self.shorten_to = Some(lease);
+        self
+    }
+}
+
+impl ShutdownHook for LockReleaseHook {
+    fn name(&self) -> &str {
+        "dynamodb-locks"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let held = std::mem::take(&mut *self.locks.held.lock().unwrap());
+            let total = held.len();
+
+            let mut releases = JoinSet::new();
+            for (key, version) in held {
+                let locks = self.locks.clone();
+                let shorten_to = self.shorten_to;
+                releases.spawn(async move {
+                    let released = locks.release_lease(&key, &version, shorten_to).await;
+                    if let Err(error) = &released {
+                        tracing::debug!(key, %error, "failed to release lease");
+                    }
+                    released
+                });
+            }
+
+            let (mut released, mut taken_over, mut failed) = (0, 0, 0);
+            let joined = join_within(ctx, &mut releases, |result| match result {
+                Ok(true) => released += 1,
+                Ok(false) => taken_over += 1,
+                Err(_) => failed += 1,
+            })
+            .await;
+            failed += joined.panicked;
+
+            ctx.note(format!(
+                "released {released} of {total} leases, {taken_over} were already taken over"
+            ));
+            if let Some(timed_out) = joined.timed_out("leases left to release") {
+                return Err(timed_out);
+            }
+            if failed > 0 {
+                return Err(format!("failed to release {failed} leases").into());
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+    use aws_smithy_runtime_api::{
+        client::{
+            http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector},
+            orchestrator::{HttpRequest, HttpResponse},
+        },
+        http::StatusCode,
+    };
+    use aws_smithy_types::body::SdkBody;
+    use serde_json::{json, Map, Value};
+
+    use super::*;
+
+    fn put(table: &str, pk: &str, value: &str) -> TableWrite {
+        let item = HashMap::from([
+            ("pk".to_owned(), AttributeValue::S(pk.to_owned())),
+            ("value".to_owned(), AttributeValue::S(value.to_owned())),
+        ]);
+        TableWrite {
+            table: table.to_owned(),
+            request: WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(item)).build().unwrap())
+                .build(),
+        }
+    }
+
+    fn delete(table: &str, pk: &str) -> TableWrite {
+        let key = HashMap::from([("pk".to_owned(), AttributeValue::S(pk.to_owned()))]);
+        TableWrite {
+            table: table.to_owned(),
+            request: WriteRequest::builder()
+                .delete_request(DeleteRequest::builder().set_key(Some(key)).build().unwrap())
+                .build(),
+        }
+    }
+
+    /// A client for tests that never send a request, so nothing needs a sleep implementation.
+    fn offline_client() -> Client {
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        Client::from_conf(config)
+    }
+
+    #[tokio::test]
+    async fn items_dynamodb_would_reject_are_not_queued() {
+        let writer = BufferedDynamoDbWriter::new(offline_client());
+        let item = |len| {
+            HashMap::from([
+                ("pk".to_owned(), AttributeValue::S("a".to_owned())),
+                ("v".to_owned(), AttributeValue::S("x".repeat(len))),
+            ])
+        };
+        let error = writer
+            .put_item("orders", item(MAX_ITEM_BYTES - 4 + 1))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "item is 409601 bytes, DynamoDB accepts at most 409600"
+        );
+        writer
+            .put_item("orders", item(MAX_ITEM_BYTES - 4))
+            .await
+            .unwrap();
+        assert_eq!(writer.pending().await, 1);
+    }
+
+    #[tokio::test]
+    async fn only_the_last_write_to_a_key_is_sent() {
+        let writer = BufferedDynamoDbWriter::new(offline_client())
+            .with_key_schema("orders", ["pk"])
+            .with_key_schema("carts", ["pk"]);
+        let records = [
+            put("orders", "a", "1"),
+            put("orders", "b", "1"),
+            put("carts", "a", "1"),
+            put("orders", "a", "2"),
+            delete("orders", "b"),
+        ];
+        let superseded = writer.writer.api().superseded(&records).await;
+        assert_eq!(superseded, [true, true, false, false, false]);
+    }
+
+    /// Answers the `PutItem` and `UpdateItem` requests of an [`IdempotencyTable`], keeping the
+    /// items by `pk`. Only the conditions the table uses are evaluated.
+    #[derive(Debug, Default)]
+    struct FakeTable {
+        items: Mutex<HashMap<String, Map<String, Value>>>,
+    }
+
+    #[derive(Debug)]
+    struct Connector(Arc<FakeTable>);
+
+    impl HttpConnector for Connector {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let target = request.headers().get("x-amz-target").unwrap().to_owned();
+            let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+            let mut items = self.0.items.lock().unwrap();
+            let failed = |item: Option<&Map<String, Value>>| {
+                let body = json!({
+                    "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                    "message": "The conditional request failed",
+                    "Item": item,
+                });
+                HttpConnectorFuture::ready(Ok(HttpResponse::new(
+                    StatusCode::try_from(400).unwrap(),
+                    SdkBody::from(body.to_string()),
+                )))
+            };
+            let n = |value: &Value| value["N"].as_str().unwrap().parse::<u128>().unwrap();
+            match target.rsplit('.').next().unwrap() {
+                "PutItem" => {
+                    let item = body["Item"].as_object().unwrap().clone();
+                    let key = item["pk"]["S"].as_str().unwrap().to_owned();
+                    if let Some(existing) = items.get(&key) {
+                        return failed(Some(existing));
+                    }
+                    items.insert(key, item);
+                }
+                "UpdateItem" => {
+                    let key = body["Key"]["pk"]["S"].as_str().unwrap();
+                    let values = &body["ExpressionAttributeValues"];
+                    let item = items.get_mut(key);
+                    if body["UpdateExpression"]
+                        .as_str()
+                        .unwrap()
+                        .contains(":in_progress")
+                    {
+                        // Taking over an event whose lease has run out
+                        let Some(item) = item else {
+                            return failed(None);
+                        };
+                        let in_progress = item
+                            .get("status")
+                            .is_none_or(|status| status == &values[":in_progress"]);
+                        let expired = item
+                            .get("lease_expires_at")
+                            .is_none_or(|lease| n(lease) <= n(&values[":now"]));
+                        if !(in_progress && expired) {
+                            return failed(Some(item));
+                        }
+                        item.insert("status".to_owned(), values[":in_progress"].clone());
+                        item.insert(
+                            "lease_expires_at".to_owned(),
+                            values[":lease_expires_at"].clone(),
+                        );
+                    } else {
+                        // Completing an event
+                        let item = item.unwrap();
+                        item.insert("status".to_owned(), values[":completed"].clone());
+                        item.remove("checkpoint");
+                    }
+                }
+                operation => panic!("unexpected {operation}"),
+            }
+            HttpConnectorFuture::ready(Ok(HttpResponse::new(
+                StatusCode::try_from(200).unwrap(),
+                SdkBody::from("{}"),
+            )))
+        }
+    }
+
+    fn table(fake: &Arc<FakeTable>) -> IdempotencyTable {
+        let connector = SharedHttpConnector::new(Connector(fake.clone()));
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .http_client(http_client_fn(move |_, _| connector.clone()))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        IdempotencyTable::new(Client::from_conf(config), "events")
+    }
+
+    #[tokio::test]
+    async fn an_event_held_by_another_invocation_is_in_progress() {
+        let fake = Arc::new(FakeTable::default());
+        let table = table(&fake);
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::New);
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::InProgress);
+        assert_eq!(table.begin("b").await.unwrap(), EventStatus::New);
+
+        table.delete("a").await.unwrap();
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::Completed);
+    }
+
+    #[tokio::test]
+    async fn an_event_whose_lease_ran_out_is_taken_over_once() {
+        let fake = Arc::new(FakeTable::default());
+        let table = table(&fake).with_lease(Duration::ZERO);
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::New);
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::Interrupted);
+
+        // The invocation that took it over holds it for a lease of its own
+        let table = table.with_lease(Duration::from_secs(60));
+        fake.items
+            .lock()
+            .unwrap()
+            .get_mut("a")
+            .unwrap()
+            .insert("lease_expires_at".to_owned(), json!({ "N": "0" }));
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::Interrupted);
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::InProgress);
+
+        // An event begun before items had a lease can be taken over
+        fake.items
+            .lock()
+            .unwrap()
+            .get_mut("a")
+            .unwrap()
+            .remove("lease_expires_at");
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::Interrupted);
+
+        table.delete("a").await.unwrap();
+        assert_eq!(table.begin("a").await.unwrap(), EventStatus::Completed);
+    }
+}
@@ -0,0 +1,185 @@
+//! Making writes to EFS mounts durable before the environment goes away.
+//!
+//! Writes to a file on EFS sit in the process's buffers and the NFS client's page cache until
+//! they are flushed and the file is closed. A teardown in between leaves a file that other
+//! clients see partially written. [`DurableFiles`] keeps track of files opened for writing,
+//! and its [`sync_hook()`](DurableFiles::sync_hook) flushes, `fsync`s and closes whichever are
+//! still open at shutdown. Nothing here is specific to EFS, so it works on any file system.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+type Shared = Mutex<Option<BufWriter<File>>>;
+
+struct Entry {
+    path: PathBuf,
+    writer: Weak<Shared>,
+}
+
+/// A registry of files being written to, to sync and close at shutdown.
+///
+/// Cloning is cheap, and all clones share the same registry.
+#[derive(Clone, Default)]
+pub struct DurableFiles {
+    files: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl fmt::Debug for DurableFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let files = self.files.lock().unwrap();
+        f.debug_struct("DurableFiles")
+            .field(
+                "open",
+                &files
+                    .iter()
+                    .filter(|entry| entry.writer.strong_count() > 0)
+                    .map(|entry| &entry.path)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl DurableFiles {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create or truncate the file at `path` for writing, like [`File::create()`].
+    pub fn create(&self, path: impl AsRef<Path>) -> io::Result<DurableFile> {
+        self.open(
+            path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )
+    }
+
+    /// Open the file at `path` with the given options, which should allow writing.
+    pub fn open(&self, path: impl AsRef<Path>, options: &OpenOptions) -> io::Result<DurableFile> {
+        let path = path.as_ref();
+        let file = options.open(path)?;
+        Ok(self.register(path, file))
+    }
+
+    /// Track a file that is already open. `path` is only used in error messages.
+    pub fn register(&self, path: impl Into<PathBuf>, file: File) -> DurableFile {
+        let writer = Arc::new(Mutex::new(Some(BufWriter::new(file))));
+        let mut files = self.files.lock().unwrap();
+        files.retain(|entry| entry.writer.strong_count() > 0);
+        files.push(Entry {
+            path: path.into(),
+            writer: Arc::downgrade(&writer),
+        });
+        DurableFile { writer }
+    }
+
+    /// A hook that flushes, syncs and closes every file still open when the environment shuts
+    /// down.
+    pub fn sync_hook(&self) -> DurableFilesHook {
+        DurableFilesHook {
+            files: self.clone(),
+        }
+    }
+
+    /// Flush, sync and close every open file, returning the number closed and the failures.
+    fn close_all(&self) -> (usize, Vec<String>) {
+        let entries = std::mem::take(&mut *self.files.lock().unwrap());
+        let mut closed = 0;
+        let mut failures = Vec::new();
+        for entry in entries {
+            let Some(writer) = entry.writer.upgrade() else {
+                continue;
+            };
+            match close(&writer) {
+                Ok(true) => closed += 1,
+                Ok(false) => {}
+                Err(error) => failures.push(format!("{}: {error}", entry.path.display())),
+            }
+        }
+        (closed, failures)
+    }
+}
+
+/// A buffered file, registered with [`DurableFiles`].
+///
+/// Once the shutdown hook has closed it, writes fail. Dropping this without calling
+/// [`close()`](Self::close) flushes it, but doesn't wait for the data to reach the disk.
+pub struct DurableFile {
+    writer: Arc<Shared>,
+}
+
+impl fmt::Debug for DurableFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DurableFile").finish_non_exhaustive()
+    }
+}
+
+impl DurableFile {
+    /// Flush, sync and close the file.
+    pub fn close(self) -> io::Result<()> {
+        close(&self.writer).map(drop)
+    }
+}
+
+impl Write for DurableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.writer.lock().unwrap() {
+            Some(writer) => writer.write(buf),
+            None => Err(closed()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.writer.lock().unwrap() {
+            Some(writer) => writer.flush(),
+            None => Err(closed()),
+        }
+    }
+}
+
+/// Flush, sync and close a file, returning false if it was already closed.
+fn close(writer: &Shared) -> io::Result<bool> {
+    let Some(writer) = writer.lock().unwrap().take() else {
+        return Ok(false);
+    };
+    let file = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+fn closed() -> io::Error {
+    io::Error::other("the file has already been closed")
+}
+
+/// A [`ShutdownHook`] that closes the files registered with [`DurableFiles`].
+#[derive(Debug)]
+pub struct DurableFilesHook {
+    files: DurableFiles,
+}
+
+impl ShutdownHook for DurableFilesHook {
+    fn name(&self) -> &str {
+        "durable-files"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let files = self.files.clone();
+            let (closed, failures) = tokio::task::spawn_blocking(move || files.close_all()).await?;
+            ctx.note(format!("synced and closed {closed} files"));
+            if !failures.is_empty() {
+                return Err(format!("failed to sync {}", failures.join(", ")).into());
+            }
+            Ok(())
+        })
+    }
+}
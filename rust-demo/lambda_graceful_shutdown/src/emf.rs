@@ -0,0 +1,461 @@
+//! Buffered CloudWatch metrics in the [Embedded Metric Format].
+//!
+//! EMF metrics are plain JSON log lines that CloudWatch Logs turns into metrics, so they
+//! don't need the CloudWatch SDK or an extra network call. [`EmfMetrics`] aggregates values
+//! in memory and writes them out in as few lines as possible when flushed. Flushing once per
+//! invocation is cheap, but whatever was recorded since the last flush is lost if the
+//! environment shuts down first, which is what [`EmfMetrics::flush_hook()`] is for.
+//!
+//! [Embedded Metric Format]: https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    stdout::BatchedStdout, BoxFuture, Error, ShutdownContext, ShutdownCounters, ShutdownHook,
+};
+
+/// CloudWatch allows at most 100 values per metric, and 100 metrics per directive.
+const MAX_VALUES_PER_LINE: usize = 100;
+const MAX_METRICS_PER_LINE: usize = 100;
+
+/// Unit of a metric, as understood by CloudWatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Bytes,
+    Kilobytes,
+    Megabytes,
+    Count,
+    Percent,
+    None,
+}
+
+impl Unit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Unit::Seconds => "Seconds",
+            Unit::Milliseconds => "Milliseconds",
+            Unit::Microseconds => "Microseconds",
+            Unit::Bytes => "Bytes",
+            Unit::Kilobytes => "Kilobytes",
+            Unit::Megabytes => "Megabytes",
+            Unit::Count => "Count",
+            Unit::Percent => "Percent",
+            Unit::None => "None",
+        }
+    }
+}
+
+type Writer = Box<dyn Write + Send>;
+
+struct State {
+    pending: BTreeMap<String, (Unit, Vec<f64>)>,
+    writer: Writer,
+}
+
+/// An in-memory EMF metrics aggregator.
+///
+/// Cloning is cheap, and all clones record into the same buffer.
+#[derive(Clone)]
+pub struct EmfMetrics {
+    namespace: String,
+    dimensions: Vec<(String, String)>,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for EmfMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmfMetrics")
+            .field("namespace", &self.namespace)
+            .field("dimensions", &self.dimensions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EmfMetrics {
+    /// Create an aggregator for the given CloudWatch namespace, writing to
+    /// [`BatchedStdout`].
+    pub fn new(namespace: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            dimensions: Vec::new(),
+            state: Arc::new(Mutex::new(State {
+                pending: BTreeMap::new(),
+                writer: Box::new(BatchedStdout),
+            })),
+        }
+    }
+
+    /// Add a dimension to every metric emitted by this aggregator.
+    pub fn with_dimension(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.dimensions.push((name.into(), value.into()));
+        self
+    }
+
+    /// Write metric lines somewhere other than stdout.
+    ///
+    /// Lambda only picks up EMF lines from the function's own log stream, so this is mostly
+    /// useful for tests.
+    pub fn with_writer(self, writer: impl Write + Send + 'static) -> Self {
+        self.state.lock().unwrap().writer = Box::new(writer);
+        self
+    }
+
+    /// Record a value for `name`. Values are kept in memory until the next [`flush()`](Self::flush).
+    ///
+    /// If the same metric is recorded with different units, the first one wins.
+    pub fn record(&self, name: impl Into<String>, value: f64, unit: Unit) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .pending
+            .entry(name.into())
+            .or_insert_with(|| (unit, Vec::new()))
+            .1
+            .push(value);
+    }
+
+    /// Shorthand for recording a [`Unit::Count`] value.
+    pub fn count(&self, name: impl Into<String>, value: u64) {
+        self.record(name, value as f64, Unit::Count);
+    }
+
+    /// Returns true if there are recorded values that haven't been flushed yet.
+    pub fn has_pending(&self) -> bool {
+        !self.state.lock().unwrap().pending.is_empty()
+    }
+
+    /// Write all pending values out as EMF lines, and clear the buffer.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let pending = std::mem::take(&mut state.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut out = Vec::new();
+        for line in self.lines(pending) {
+            serde_json::to_writer(&mut out, &line)?;
+            out.push(b'\n');
+        }
+        // A single write keeps the lines together when several things log at once.
+        state.writer.write_all(&out)?;
+        state.writer.flush()
+    }
+
+    /// A hook that flushes anything still pending when the environment shuts down.
+    pub fn flush_hook(&self) -> EmfFlushHook {
+        EmfFlushHook {
+            metrics: self.clone(),
+        }
+    }
+
+    /// A hook that records how the shutdown went, then flushes everything pending like
+    /// [`flush_hook()`](Self::flush_hook).
+    ///
+    /// It records `TotalShutdownMs`, the time taken by each hook that ran before it as
+    /// `HookMs.<name>`, and the [`ShutdownCounters`]: the number of hooks that didn't complete
+    /// as `HooksFailed`, of those that timed out as `HooksTimedOut`, of drains that gave up as
+    /// `DrainsTimedOut`, and `BudgetExceeded`, which is 1 if a hook timed out or was skipped
+    /// and 0 otherwise. With an
+    /// [invocation drain](crate::ShutdownCoordinator::with_invocation_drain), it also records
+    /// `InvocationsInFlight`, `InvocationsDrained`, `InvocationsInterrupted`, and
+    /// `InvocationDrainMs` when an invocation was in flight. The
+    /// [`SandboxStats`](crate::SandboxStats) are recorded as `SandboxInvocations` and
+    /// `SandboxThaws`.
+    /// Register it first, so it runs last and sees every other hook, and instead of the flush
+    /// hook.
+    pub fn shutdown_metrics_hook(&self) -> ShutdownMetricsHook {
+        ShutdownMetricsHook {
+            metrics: self.clone(),
+        }
+    }
+
+    fn lines(&self, pending: BTreeMap<String, (Unit, Vec<f64>)>) -> Vec<Value> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        // Split metrics with more than MAX_VALUES_PER_LINE values into chunks, and give each
+        // chunk its own line, along with chunks of other metrics.
+        let mut chunked: Vec<Vec<(&str, Unit, &[f64])>> = Vec::new();
+        for (name, (unit, values)) in &pending {
+            for (i, chunk) in values.chunks(MAX_VALUES_PER_LINE).enumerate() {
+                if chunked.len() <= i {
+                    chunked.push(Vec::new());
+                }
+                chunked[i].push((name.as_str(), *unit, chunk));
+            }
+        }
+
+        chunked
+            .iter()
+            .flat_map(|metrics| metrics.chunks(MAX_METRICS_PER_LINE))
+            .map(|metrics| {
+                let mut line = Map::new();
+                let definitions: Vec<_> = metrics
+                    .iter()
+                    .map(|(name, unit, _)| json!({ "Name": name, "Unit": unit.as_str() }))
+                    .collect();
+                let dimension_names: Vec<_> = self
+                    .dimensions
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                line.insert(
+                    "_aws".to_owned(),
+                    json!({
+                        "Timestamp": timestamp,
+                        "CloudWatchMetrics": [{
+                            "Namespace": self.namespace,
+                            "Dimensions": [dimension_names],
+                            "Metrics": definitions,
+                        }],
+                    }),
+                );
+                for (name, value) in &self.dimensions {
+                    line.insert(name.clone(), Value::from(value.as_str()));
+                }
+                for (name, _, values) in metrics {
+                    let value = match values {
+                        [single] => json!(single),
+                        many => json!(many),
+                    };
+                    line.insert((*name).to_owned(), value);
+                }
+                Value::Object(line)
+            })
+            .collect()
+    }
+}
+
+/// A [`ShutdownHook`] that writes out pending [`EmfMetrics`], see [`EmfMetrics::flush_hook()`].
+#[derive(Debug)]
+pub struct EmfFlushHook {
+    metrics: EmfMetrics,
+}
+
+impl ShutdownHook for EmfFlushHook {
+    fn name(&self) -> &str {
+        "emf"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move { Ok(self.metrics.flush()?) })
+    }
+}
+
+/// A [`ShutdownHook`] that records shutdown metrics and writes out pending [`EmfMetrics`], see
+/// [`EmfMetrics::shutdown_metrics_hook()`].
+#[derive(Debug)]
+pub struct ShutdownMetricsHook {
+    metrics: EmfMetrics,
+}
+
+impl ShutdownHook for ShutdownMetricsHook {
+    fn name(&self) -> &str {
+        "emf-shutdown-metrics"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let reports = ctx.hook_reports();
+            for report in &reports {
+                let elapsed = report.elapsed.as_secs_f64() * 1000.0;
+                self.metrics.record(
+                    format!("HookMs.{}", report.name),
+                    elapsed,
+                    Unit::Milliseconds,
+                );
+            }
+            let counters = ShutdownCounters::from_hooks(&reports);
+            self.metrics.record(
+                "TotalShutdownMs",
+                ctx.elapsed().as_secs_f64() * 1000.0,
+                Unit::Milliseconds,
+            );
+            self.metrics
+                .count("HooksFailed", counters.hooks_failed as u64);
+            self.metrics
+                .count("HooksTimedOut", counters.hooks_timed_out as u64);
+            self.metrics
+                .count("DrainsTimedOut", counters.drains_timed_out as u64);
+            self.metrics
+                .count("BudgetExceeded", u64::from(counters.budget_exceeded));
+            let sandbox = ctx.sandbox_stats();
+            self.metrics
+                .count("SandboxInvocations", sandbox.invocations);
+            self.metrics.count("SandboxThaws", sandbox.thaws);
+            if let Some(drain) = ctx.invocation_drain() {
+                let drained = drain.in_flight.saturating_sub(drain.interrupted);
+                self.metrics
+                    .count("InvocationsInFlight", drain.in_flight as u64);
+                self.metrics.count("InvocationsDrained", drained as u64);
+                self.metrics
+                    .count("InvocationsInterrupted", drain.interrupted as u64);
+                if drain.in_flight > 0 {
+                    self.metrics.record(
+                        "InvocationDrainMs",
+                        drain.elapsed.as_secs_f64() * 1000.0,
+                        Unit::Milliseconds,
+                    );
+                }
+            }
+            Ok(self.metrics.flush()?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hook_fn, ShutdownCoordinator, ShutdownReason};
+
+    /// Collects what the metrics write.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn lines(&self) -> Vec<Value> {
+            let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+            String::from_utf8(bytes)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn metric_names(line: &Value) -> Vec<&str> {
+        line["_aws"]["CloudWatchMetrics"][0]["Metrics"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|metric| metric["Name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lines_follow_the_embedded_metric_format() {
+        let output = Output::default();
+        let metrics = EmfMetrics::new("Orders")
+            .with_dimension("Service", "checkout")
+            .with_writer(output.clone());
+        metrics.record("Latency", 12.5, Unit::Milliseconds);
+        metrics.record("Latency", 7.0, Unit::Milliseconds);
+        metrics.count("Placed", 3);
+        assert!(metrics.has_pending());
+        metrics.flush().unwrap();
+        assert!(!metrics.has_pending());
+
+        let lines = output.lines();
+        assert_eq!(lines.len(), 1);
+        let mut line = lines[0].clone();
+        assert!(line["_aws"]["Timestamp"].as_u64().unwrap() > 0);
+        line["_aws"]["Timestamp"] = json!(0);
+        assert_eq!(
+            line,
+            json!({
+                "_aws": {
+                    "Timestamp": 0,
+                    "CloudWatchMetrics": [{
+                        "Namespace": "Orders",
+                        "Dimensions": [["Service"]],
+                        "Metrics": [
+                            { "Name": "Latency", "Unit": "Milliseconds" },
+                            { "Name": "Placed", "Unit": "Count" },
+                        ],
+                    }],
+                },
+                "Service": "checkout",
+                "Latency": [12.5, 7.0],
+                "Placed": 3.0,
+            })
+        );
+
+        // Nothing is written when nothing is pending
+        metrics.flush().unwrap();
+        assert!(output.lines().is_empty());
+    }
+
+    #[test]
+    fn more_than_100_values_are_split_across_lines() {
+        let output = Output::default();
+        let metrics = EmfMetrics::new("Orders").with_writer(output.clone());
+        for value in 0..250 {
+            metrics.record("Latency", f64::from(value), Unit::Milliseconds);
+        }
+        metrics.count("Placed", 1);
+        metrics.flush().unwrap();
+
+        let lines = output.lines();
+        let values: Vec<_> = lines
+            .iter()
+            .map(|line| line["Latency"].as_array().unwrap().len())
+            .collect();
+        assert_eq!(values, [100, 100, 50]);
+        assert_eq!(lines[2]["Latency"][49], json!(249.0));
+        // Other metrics share the first line, and only it
+        assert_eq!(metric_names(&lines[0]), ["Latency", "Placed"]);
+        assert_eq!(metric_names(&lines[1]), ["Latency"]);
+    }
+
+    #[test]
+    fn more_than_100_metrics_are_split_across_lines() {
+        let output = Output::default();
+        let metrics = EmfMetrics::new("Orders").with_writer(output.clone());
+        for metric in 0..150 {
+            metrics.count(format!("Metric{metric:03}"), 1);
+        }
+        metrics.flush().unwrap();
+
+        let lines = output.lines();
+        let names: Vec<_> = lines.iter().map(|line| metric_names(line).len()).collect();
+        assert_eq!(names, [100, 50]);
+        assert_eq!(lines[1]["Metric149"], json!(1.0));
+    }
+
+    #[tokio::test]
+    async fn shutdown_metrics_cover_the_hooks_that_ran() {
+        let output = Output::default();
+        let metrics = EmfMetrics::new("Orders").with_writer(output.clone());
+        metrics.count("Placed", 1);
+        ShutdownCoordinator::new()
+            .with_hook(metrics.shutdown_metrics_hook())
+            .with_hook(hook_fn("fails", |_| async { Err("unavailable".into()) }))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+
+        let lines = output.lines();
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert!(line["HookMs.fails"].is_number());
+        assert!(line["TotalShutdownMs"].is_number());
+        assert_eq!(line["HooksFailed"], json!(1.0));
+        assert_eq!(line["HooksTimedOut"], json!(0.0));
+        assert_eq!(line["BudgetExceeded"], json!(0.0));
+        assert_eq!(line["Placed"], json!(1.0));
+        assert!(line.get("InvocationsInFlight").is_none());
+    }
+}
@@ -0,0 +1,453 @@
+//! Which kind of execution environment the function runs in.
+//!
+//! The same binary runs on Lambda, under the Runtime Interface Emulator in a container, and
+//! under `cargo lambda watch`, and from a `.zip` archive or a container image once deployed.
+//! [`Environment::detect()`] works out which, from what each of them sets up:
+//!
+//! - on Lambda, `AWS_LAMBDA_INITIALIZATION_TYPE` is always set, and `AWS_EXECUTION_ENV` is too
+//!   on the managed runtimes, but not on the OS-only ones such as `provided.al2023`
+//! - the emulator ships as `aws-lambda-rie` in the AWS base images, or is mounted at
+//!   `/aws-lambda/aws-lambda-rie`, and names the function `test_function` by default
+//! - the AWS base images start the runtime with `/lambda-entrypoint.sh`, and images built on
+//!   another distribution have an `/etc/os-release` other than Amazon Linux
+//!
+//! None of it is an API, so the detection is a best guess, and [`Host::Local`] and
+//! [`PackageType::Unknown`] are what it falls back to. The local-only tools,
+//! `LocalSpindown::spawn_if_local()` and `DebugServer::spawn_if_local()`, start unless it
+//! finds Lambda, and [`sidecar_hook()`](Environment::sidecar_hook) only signals sidecars in
+//! container images.
+
+use std::{fmt, fs, io, path::Path, time::Duration};
+
+use crate::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// Where the emulator lives in the AWS base images, and where its docs mount it otherwise.
+const EMULATOR_PATHS: [&str; 2] = [
+    "/usr/local/bin/aws-lambda-rie",
+    "/aws-lambda/aws-lambda-rie",
+];
+
+/// The function name the emulator uses unless `AWS_LAMBDA_FUNCTION_NAME` is set.
+const EMULATOR_FUNCTION_NAME: &str = "test_function";
+
+/// The entrypoint of the AWS base images.
+const BASE_IMAGE_ENTRYPOINT: &str = "/lambda-entrypoint.sh";
+
+/// How often [`SidecarHook`] checks whether the sidecars have exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What runs the function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Host {
+    /// Lambda itself.
+    Lambda,
+    /// The Runtime Interface Emulator, usually in a container on a development machine.
+    Emulator,
+    /// Anything else, such as `cargo lambda watch` or a test.
+    Local,
+}
+
+/// How the function was deployed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PackageType {
+    /// A `.zip` archive, running on one of Lambda's runtimes.
+    Zip,
+    /// A container image.
+    Image,
+    /// Not deployed, or not known.
+    Unknown,
+}
+
+/// The runtime the function runs on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RuntimeFamily {
+    /// The OS-only `provided.al2023` runtime, or an image built on Amazon Linux 2023.
+    ProvidedAl2023,
+    /// The OS-only `provided.al2` runtime, or an image built on Amazon Linux 2.
+    ProvidedAl2,
+    /// A managed runtime, named by `AWS_EXECUTION_ENV` without its `AWS_Lambda_` prefix, such
+    /// as `python3.12` for an extension running next to a Python function.
+    Managed(String),
+    /// Another operating system, named by the `PRETTY_NAME` of its `/etc/os-release`.
+    Other(String),
+    /// No `/etc/os-release` to tell.
+    Unknown,
+}
+
+/// The execution environment the function runs in, as found by [`detect()`](Self::detect).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Environment {
+    /// What runs the function.
+    pub host: Host,
+    /// How the function was deployed.
+    pub package: PackageType,
+    /// The runtime it runs on.
+    pub runtime: RuntimeFamily,
+}
+
+impl Environment {
+    /// Work out the environment from the process's environment variables and files.
+    pub fn detect() -> Self {
+        let os_release = fs::read_to_string("/etc/os-release").ok();
+        Self::from_probes(
+            |name| std::env::var(name).ok(),
+            os_release.as_deref(),
+            |path| Path::new(path).exists(),
+        )
+    }
+
+    /// Work out the environment from the variables `var` looks up, the contents of
+    /// `/etc/os-release`, and the files `exists` finds.
+    fn from_probes(
+        var: impl Fn(&str) -> Option<String>,
+        os_release: Option<&str>,
+        exists: impl Fn(&str) -> bool,
+    ) -> Self {
+        let execution_env = var("AWS_EXECUTION_ENV");
+        let (os_id, os_version, os_name) = match os_release {
+            Some(release) => (
+                os_release_field(release, "ID"),
+                os_release_field(release, "VERSION_ID"),
+                os_release_field(release, "PRETTY_NAME"),
+            ),
+            None => (None, None, None),
+        };
+        let amazon_linux = os_id.as_deref() == Some("amzn");
+
+        let managed = execution_env
+            .as_deref()
+            .and_then(|execution_env| execution_env.strip_prefix("AWS_Lambda_"));
+        let host = if managed.is_some() || var("AWS_LAMBDA_INITIALIZATION_TYPE").is_some() {
+            Host::Lambda
+        } else if var("AWS_LAMBDA_RUNTIME_API").is_some()
+            && (EMULATOR_PATHS.iter().any(|path| exists(path))
+                || var("AWS_LAMBDA_FUNCTION_NAME").as_deref() == Some(EMULATOR_FUNCTION_NAME))
+        {
+            Host::Emulator
+        } else {
+            Host::Local
+        };
+
+        let package = match host {
+            Host::Emulator => PackageType::Image,
+            Host::Lambda
+                if exists(BASE_IMAGE_ENTRYPOINT)
+                    || (managed.is_none() && os_release.is_some() && !amazon_linux) =>
+            {
+                PackageType::Image
+            }
+            Host::Lambda => PackageType::Zip,
+            Host::Local => PackageType::Unknown,
+        };
+
+        let runtime = match (managed, os_version.as_deref()) {
+            (Some(runtime), _) => RuntimeFamily::Managed(runtime.to_owned()),
+            (None, Some("2023")) if amazon_linux => RuntimeFamily::ProvidedAl2023,
+            (None, Some("2")) if amazon_linux => RuntimeFamily::ProvidedAl2,
+            (None, _) => match os_name.or(os_id) {
+                Some(name) => RuntimeFamily::Other(name),
+                None => RuntimeFamily::Unknown,
+            },
+        };
+
+        Self {
+            host,
+            package,
+            runtime,
+        }
+    }
+
+    /// Whether the function runs on Lambda itself.
+    pub fn is_lambda(&self) -> bool {
+        self.host == Host::Lambda
+    }
+
+    /// Whether the local development tools should run: under the emulator, or anywhere else
+    /// that isn't Lambda.
+    pub fn is_local_dev(&self) -> bool {
+        !self.is_lambda()
+    }
+
+    /// A hook that stops the sidecar processes named `names`, if the function runs from a
+    /// container image, which is the only place it can start any. See [`SidecarHook`].
+    pub fn sidecar_hook<I, S>(&self, names: I) -> Option<SidecarHook>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        (self.package == PackageType::Image).then(|| SidecarHook::new(names))
+    }
+}
+
+/// `Lambda (zip, provided.al2023)`, or `Emulator (image, Debian GNU/Linux 12 (bookworm))`.
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let package = match self.package {
+            PackageType::Zip => "zip",
+            PackageType::Image => "image",
+            PackageType::Unknown => "unknown package",
+        };
+        write!(f, "{:?} ({package}, ", self.host)?;
+        match &self.runtime {
+            RuntimeFamily::ProvidedAl2023 => f.write_str("provided.al2023)"),
+            RuntimeFamily::ProvidedAl2 => f.write_str("provided.al2)"),
+            RuntimeFamily::Managed(name) | RuntimeFamily::Other(name) => write!(f, "{name})"),
+            RuntimeFamily::Unknown => f.write_str("unknown runtime)"),
+        }
+    }
+}
+
+/// The value of `field` in the contents of an `os-release` file, unquoted.
+fn os_release_field(release: &str, field: &str) -> Option<String> {
+    release.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        (name == field).then(|| value.trim().trim_matches('"').to_owned())
+    })
+}
+
+/// Sends `SIGTERM` to sidecar processes, such as a log forwarder the image's entrypoint starts
+/// next to the function, and waits for them to exit.
+///
+/// Lambda only signals the runtime and the extensions, so a sidecar started next to them is
+/// frozen with whatever it still had buffered. Register this hook first, so it runs last,
+/// after the hooks that hand the sidecars their final data. The processes are found by the
+/// name in `/proc/<pid>/comm`, which is the executable's name cut to 15 bytes.
+#[derive(Debug, Clone)]
+pub struct SidecarHook {
+    names: Vec<String>,
+}
+
+impl SidecarHook {
+    /// Stop the processes named `names`, wherever the function runs.
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            names: names.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// The ids of the running processes with one of the names, other than this one.
+    fn pids(&self) -> Vec<u32> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+            .filter(|&pid| pid != std::process::id())
+            .filter(|pid| {
+                fs::read_to_string(format!("/proc/{pid}/comm")).is_ok_and(|comm| {
+                    self.names
+                        .iter()
+                        .any(|name| comm.trim_end() == name.as_str())
+                })
+            })
+            .collect()
+    }
+}
+
+/// Whether the process `pid` is still around, and not just a zombie waiting to be reaped.
+fn is_running(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+        // The state follows the command name, which is in parentheses and may contain spaces
+        let state = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().next());
+        state != Some("Z")
+    })
+}
+
+/// The signals the hooks send to the processes they stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Signal {
+    /// `SIGTERM`, asking the process to exit.
+    Term,
+    /// `SIGKILL`, which the process can't handle.
+    #[cfg_attr(
+        not(any(feature = "spindown", feature = "supervisor")),
+        allow(dead_code)
+    )]
+    Kill,
+}
+
+/// Send `signal` to the process `pid`.
+#[cfg(unix)]
+pub(crate) fn signal(pid: u32, signal: Signal) -> io::Result<()> {
+    let pid = libc::pid_t::try_from(pid).map_err(io::Error::other)?;
+    let signal = match signal {
+        Signal::Term => libc::SIGTERM,
+        Signal::Kill => libc::SIGKILL,
+    };
+    // SAFETY: `kill` only takes plain integers, and a `pid` that isn't ours is an error.
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Signals only exist on Unix.
+#[cfg(not(unix))]
+pub(crate) fn signal(_pid: u32, _signal: Signal) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+impl ShutdownHook for SidecarHook {
+    fn name(&self) -> &str {
+        "sidecars"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let pids = self.pids();
+            for &pid in &pids {
+                if let Err(error) = signal(pid, Signal::Term) {
+                    ctx.note(format!("failed to signal sidecar {pid}: {error}"));
+                }
+            }
+            let clock = ctx.clock();
+            loop {
+                let running = pids.iter().filter(|&&pid| is_running(pid)).count();
+                if running == 0 {
+                    return Ok(());
+                }
+                if ctx.remaining() <= POLL_INTERVAL {
+                    return Err(DrainTimeout::new(format!(
+                        "{running} of {} sidecars still running",
+                        pids.len()
+                    ))
+                    .into());
+                }
+                clock.sleep_until(clock.now() + POLL_INTERVAL).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, process::Command, time::Duration};
+
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    const AL2023: &str = "NAME=\"Amazon Linux\"\nVERSION_ID=\"2023\"\nID=\"amzn\"\n";
+    const DEBIAN: &str =
+        "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nVERSION_ID=\"12\"\nID=debian\n";
+
+    fn detect(vars: &[(&str, &str)], os_release: Option<&str>, files: &[&str]) -> Environment {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Environment::from_probes(
+            |name| vars.get(name).map(|value| (*value).to_owned()),
+            os_release,
+            |path| files.contains(&path),
+        )
+    }
+
+    #[test]
+    fn a_managed_runtime_is_a_zip_on_lambda() {
+        let environment = detect(
+            &[("AWS_EXECUTION_ENV", "AWS_Lambda_python3.12")],
+            Some(AL2023),
+            &[],
+        );
+        assert_eq!(environment.host, Host::Lambda);
+        assert_eq!(environment.package, PackageType::Zip);
+        assert_eq!(
+            environment.runtime,
+            RuntimeFamily::Managed("python3.12".to_owned())
+        );
+        assert_eq!(environment.to_string(), "Lambda (zip, python3.12)");
+        assert!(environment.sidecar_hook(["forwarder"]).is_none());
+    }
+
+    #[test]
+    fn os_only_runtimes_are_told_apart_by_os_release() {
+        let on_demand = [("AWS_LAMBDA_INITIALIZATION_TYPE", "on-demand")];
+        let environment = detect(&on_demand, Some(AL2023), &[]);
+        assert_eq!(environment.to_string(), "Lambda (zip, provided.al2023)");
+        let al2 = AL2023.replace("2023", "2");
+        assert_eq!(
+            detect(&on_demand, Some(&al2), &[]).runtime,
+            RuntimeFamily::ProvidedAl2
+        );
+
+        // Only an image brings its own distribution, or the base images' entrypoint
+        let environment = detect(&on_demand, Some(DEBIAN), &[]);
+        assert_eq!(
+            environment.to_string(),
+            "Lambda (image, Debian GNU/Linux 12 (bookworm))"
+        );
+        assert!(environment.sidecar_hook(["forwarder"]).is_some());
+        let environment = detect(&on_demand, Some(AL2023), &[BASE_IMAGE_ENTRYPOINT]);
+        assert_eq!(environment.package, PackageType::Image);
+    }
+
+    #[test]
+    fn the_emulator_is_found_by_its_binary_or_function_name() {
+        let api = ("AWS_LAMBDA_RUNTIME_API", "127.0.0.1:9001");
+        let environment = detect(&[api], Some(DEBIAN), &[EMULATOR_PATHS[1]]);
+        assert_eq!(environment.host, Host::Emulator);
+        assert_eq!(environment.package, PackageType::Image);
+        assert!(environment.is_local_dev());
+        let named = ("AWS_LAMBDA_FUNCTION_NAME", EMULATOR_FUNCTION_NAME);
+        assert_eq!(detect(&[api, named], None, &[]).host, Host::Emulator);
+
+        // `cargo lambda watch` sets the runtime API too
+        let environment = detect(&[api], None, &[]);
+        assert_eq!(environment.host, Host::Local);
+        assert!(!environment.is_lambda());
+        assert_eq!(
+            environment.to_string(),
+            "Local (unknown package, unknown runtime)"
+        );
+    }
+
+    #[test]
+    fn os_release_values_are_unquoted() {
+        assert_eq!(
+            os_release_field(DEBIAN, "PRETTY_NAME").as_deref(),
+            Some("Debian GNU/Linux 12 (bookworm)")
+        );
+        assert_eq!(os_release_field(DEBIAN, "ID").as_deref(), Some("debian"));
+        assert_eq!(os_release_field(DEBIAN, "NAME"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn sidecars_are_stopped_by_name() {
+        // A copy of `sleep` with a name nothing else runs under
+        let name = "gs-sidecar-test";
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join(name);
+        fs::copy("/bin/sleep", &binary).unwrap();
+        let mut sidecar = Command::new(&binary).arg("30").spawn().unwrap();
+        while !SidecarHook::new([name]).pids().contains(&sidecar.id()) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(SidecarHook::new([name]))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert!(!sidecar.wait().unwrap().success());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_sidecars_is_nothing_to_stop() {
+        let report = ShutdownCoordinator::new()
+            .with_hook(SidecarHook::new(["no-such-sidecar"]))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(report.hooks[0].outcome, HookOutcome::Completed);
+    }
+}
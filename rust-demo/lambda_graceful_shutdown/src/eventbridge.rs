@@ -0,0 +1,132 @@
+//! Publishing an EventBridge event when an execution environment shuts down.
+//!
+//! Spindowns are invisible from the outside, and so are cleanups that fail during them.
+//! [`ShutdownEventHook`] puts a structured event on an event bus for every shutdown, with the
+//! outcome of each hook, so platform teams can follow spindown patterns and failed cleanups
+//! across a fleet of functions with a single rule.
+
+use std::{fmt, time::Duration};
+
+use aws_sdk_eventbridge::{types::PutEventsRequestEntry, Client};
+use serde_json::json;
+
+use crate::{BoxFuture, Error, HookOutcome, ShutdownContext, ShutdownHook};
+
+/// The event's `source`, unless set with [`ShutdownEventHook::with_source()`].
+const DEFAULT_SOURCE: &str = "lambda-graceful-shutdown";
+
+/// The event's `detail-type`.
+const DETAIL_TYPE: &str = "Lambda Environment Shutdown";
+
+/// A [`ShutdownHook`] that puts an "environment shutdown" event on an event bus.
+///
+/// The event includes the reports of the hooks that ran before this one, so register this
+/// hook first, and it runs last. Its `detail` looks like this:
+///
+/// ```json
+/// {
+///   "function_name": "my-function",
+///   "function_version": "$LATEST",
+///   "log_stream_name": "2024/01/01/[$LATEST]0123456789abcdef",
+///   "reason": "SIGTERM",
+///   "request_id": "8f5a1c2e-4b3d-4e7a-9c1f-2d6b8e0a7f31",
+///   "clean": false,
+///   "elapsed_ms": 112,
+///   "hooks": [
+///     { "name": "sqs", "outcome": "completed", "elapsed_ms": 35, "notes": [] },
+///     { "name": "redis", "outcome": "failed: connection refused", "elapsed_ms": 77, "notes": [] }
+///   ]
+/// }
+/// ```
+pub struct ShutdownEventHook {
+    client: Client,
+    event_bus_name: Option<String>,
+    source: String,
+}
+
+impl fmt::Debug for ShutdownEventHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownEventHook")
+            .field("event_bus_name", &self.event_bus_name)
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShutdownEventHook {
+    /// Create a hook that puts events on the default event bus.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            event_bus_name: None,
+            source: DEFAULT_SOURCE.to_owned(),
+        }
+    }
+
+    /// Put events on the bus with this name or ARN instead.
+    pub fn with_event_bus(mut self, event_bus_name: impl Into<String>) -> Self {
+        self.event_bus_name = Some(event_bus_name.into());
+        self
+    }
+
+    /// Set the event's `source`, which defaults to `lambda-graceful-shutdown`.
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = source.into();
+        self
+    }
+}
+
+impl ShutdownHook for ShutdownEventHook {
+    fn name(&self) -> &str {
+        "eventbridge"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let reports = ctx.hook_reports();
+            let hooks: Vec<_> = reports
+                .iter()
+                .map(|hook| {
+                    json!({
+                        "name": hook.name,
+                        "outcome": hook.outcome.to_string(),
+                        "elapsed_ms": hook.elapsed.as_millis(),
+                        "notes": hook.notes,
+                    })
+                })
+                .collect();
+            let env = |name| std::env::var(name).ok();
+            let clean = reports
+                .iter()
+                .all(|hook| hook.outcome == HookOutcome::Completed);
+            let elapsed: Duration = reports.iter().map(|hook| hook.elapsed).sum();
+            let detail = json!({
+                "function_name": env("AWS_LAMBDA_FUNCTION_NAME"),
+                "function_version": env("AWS_LAMBDA_FUNCTION_VERSION"),
+                "log_stream_name": env("AWS_LAMBDA_LOG_STREAM_NAME"),
+                "reason": ctx.reason().to_string(),
+                "request_id": ctx.request_id(),
+                "clean": clean,
+                "elapsed_ms": elapsed.as_millis(),
+                "hooks": hooks,
+            });
+
+            let entry = PutEventsRequestEntry::builder()
+                .source(&self.source)
+                .detail_type(DETAIL_TYPE)
+                .detail(detail.to_string())
+                .set_event_bus_name(self.event_bus_name.clone())
+                .build();
+            let output = self.client.put_events().entries(entry).send().await?;
+            if output.failed_entry_count() > 0 {
+                let error = output
+                    .entries()
+                    .iter()
+                    .find_map(|entry| entry.error_message())
+                    .unwrap_or("unknown error");
+                return Err(format!("the event was rejected: {error}").into());
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,172 @@
+//! Batched writes to Amazon Data Firehose.
+//!
+//! [`BufferedFirehoseWriter`] collects records and writes them with `PutRecordBatch` once a
+//! batch is full. Its [`flush_hook()`](BufferedFirehoseWriter::flush_hook) writes the final
+//! partial batch at shutdown, retrying throttled records for as long as the budget allows.
+
+use std::{fmt, sync::Arc};
+
+use aws_sdk_firehose::{primitives::Blob, types::Record, Client};
+
+use crate::{
+    records::{RecordApi, RecordWriter},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// `PutRecordBatch` accepts at most 500 records and 4 MiB per request.
+const MAX_BATCH_RECORDS: usize = 500;
+const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Firehose accepts records of up to 1000 KiB.
+const MAX_RECORD_BYTES: usize = 1000 * 1024;
+
+struct FirehoseApi {
+    client: Client,
+    delivery_stream_name: String,
+}
+
+impl RecordApi for FirehoseApi {
+    type Record = Record;
+
+    fn put(&self, records: Vec<Self::Record>) -> BoxFuture<'_, Result<Vec<Option<String>>, Error>> {
+        Box::pin(async move {
+            let output = self
+                .client
+                .put_record_batch()
+                .delivery_stream_name(&self.delivery_stream_name)
+                .set_records(Some(records))
+                .send()
+                .await?;
+            Ok(output
+                .request_responses()
+                .iter()
+                .map(|result| result.error_code().map(str::to_owned))
+                .collect())
+        })
+    }
+}
+
+/// Writes records to a Firehose stream in batches.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+#[derive(Clone)]
+pub struct BufferedFirehoseWriter {
+    writer: Arc<RecordWriter<FirehoseApi>>,
+}
+
+impl fmt::Debug for BufferedFirehoseWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferedFirehoseWriter")
+            .field(
+                "delivery_stream_name",
+                &self.writer.api().delivery_stream_name,
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl BufferedFirehoseWriter {
+    /// Create a writer for the Firehose stream called `delivery_stream_name`.
+    pub fn new(client: Client, delivery_stream_name: impl Into<String>) -> Self {
+        let api = FirehoseApi {
+            client,
+            delivery_stream_name: delivery_stream_name.into(),
+        };
+        Self {
+            writer: Arc::new(RecordWriter::new(api, MAX_BATCH_RECORDS, MAX_BATCH_BYTES)),
+        }
+    }
+
+    /// Queue a record, writing a batch if this fills one up.
+    ///
+    /// If some records of that batch are throttled, they are kept for the next write, and
+    /// [`last_error()`](Self::last_error) says why. Records over 1000 KiB, which Firehose
+    /// would never accept, are rejected with an error, without being queued.
+    pub async fn put(&self, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+        let data = data.into();
+        let size = data.len();
+        if size > MAX_RECORD_BYTES {
+            return Err(format!(
+                "record is {size} bytes, Firehose accepts at most {MAX_RECORD_BYTES}"
+            )
+            .into());
+        }
+        let record = Record::builder().data(Blob::new(data)).build()?;
+        self.writer.push(record, size).await
+    }
+
+    /// The number of records waiting to be written.
+    pub async fn pending(&self) -> usize {
+        self.writer.pending().await
+    }
+
+    /// Why the last batch written failed, in full or in part, unless one has been written
+    /// in full since.
+    /// Its records are still queued, and go out with a later batch or flush.
+    pub fn last_error(&self) -> Option<String> {
+        self.writer.last_error()
+    }
+
+    /// Write every queued record, including a final partial batch.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.writer.flush().await
+    }
+
+    /// A hook that writes any queued records when the environment shuts down.
+    pub fn flush_hook(&self) -> FirehoseFlushHook {
+        FirehoseFlushHook {
+            writer: self.clone(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that writes the records still buffered in a [`BufferedFirehoseWriter`].
+#[derive(Debug)]
+pub struct FirehoseFlushHook {
+    writer: BufferedFirehoseWriter,
+}
+
+impl ShutdownHook for FirehoseFlushHook {
+    fn name(&self) -> &str {
+        "firehose"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(
+            self.writer
+                .writer
+                .flush_until(ctx.clock(), ctx.drain_deadline()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_firehose::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn records_firehose_would_reject_are_not_queued() {
+        let config = aws_sdk_firehose::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        let writer = BufferedFirehoseWriter::new(Client::from_conf(config), "events");
+        let error = writer.put(vec![0; MAX_RECORD_BYTES + 1]).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "record is 1024001 bytes, Firehose accepts at most 1024000"
+        );
+        writer.put(vec![0; MAX_RECORD_BYTES]).await.unwrap();
+        assert_eq!(writer.pending().await, 1);
+    }
+}
@@ -0,0 +1,266 @@
+//! Running code written for [`tokio-graceful`](tokio_graceful) or
+//! [`tokio-graceful-shutdown`](tokio_graceful_shutdown) as the coordinator's hooks.
+//!
+//! A service moved into Lambda often already shuts down with one of those crates: tasks hold
+//! a `ShutdownGuard` until they are done, or run as subsystems under a `Toplevel`. Both
+//! normally start shutting down on `SIGTERM` themselves, which would race with the
+//! coordinator. The hooks here hand them the shutdown instead, when the hook runs, and wait
+//! for them within the budget:
+//!
+//! - [`GuardsHook`] owns a `tokio_graceful::Shutdown`, and hands out its guards
+//! - [`SubsystemsHook`] owns a `tokio_graceful_shutdown::Toplevel`, and starts the subsystems
+//!   under it
+//!
+//! Register them last, so they run first, and the hooks registered before them flush what
+//! the tasks or subsystems produced.
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+#[cfg(feature = "tokio-graceful")]
+use tokio_graceful::{Shutdown, ShutdownGuard, WeakShutdownGuard};
+#[cfg(feature = "tokio-graceful-shutdown")]
+use tokio_graceful_shutdown::{
+    errors::{GracefulShutdownError, SubsystemError},
+    ErrTypeTraits, SubsystemHandle, Toplevel,
+};
+
+use crate::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// Fires once, when the hook it belongs to runs.
+#[derive(Clone)]
+struct Trigger(Arc<watch::Sender<bool>>);
+
+impl Trigger {
+    fn new() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+
+    fn fire(&self) {
+        self.0.send_replace(true);
+    }
+
+    fn fired(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut fired = self.0.subscribe();
+        async move {
+            let _ = fired.wait_for(|&fired| fired).await;
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that triggers a [`tokio_graceful::Shutdown`] and waits for its guards.
+///
+/// The `Shutdown` is triggered when the hook runs, rather than by a signal of its own. Tasks
+/// take a guard with [`guard()`](Self::guard), or are spawned with one by
+/// [`spawn_task()`](Self::spawn_task), and the hook waits for every guard to be dropped.
+/// Cloning is cheap, and all clones share the same `Shutdown`.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{graceful::GuardsHook, ShutdownCoordinator};
+///
+/// # async fn example() {
+/// let guards = GuardsHook::new();
+/// guards.spawn_task(|guard: tokio_graceful::ShutdownGuard| async move {
+///     guard.cancelled().await;
+///     // ...finish the work in progress
+/// });
+/// let shutdown = ShutdownCoordinator::new().with_hook(guards);
+/// # }
+/// ```
+#[cfg(feature = "tokio-graceful")]
+#[derive(Clone)]
+pub struct GuardsHook {
+    trigger: Trigger,
+    shutdown: Arc<Mutex<Option<Shutdown>>>,
+    guard: WeakShutdownGuard,
+}
+
+#[cfg(feature = "tokio-graceful")]
+impl fmt::Debug for GuardsHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GuardsHook")
+            .field("triggered", &*self.trigger.0.borrow())
+            .finish()
+    }
+}
+
+#[cfg(feature = "tokio-graceful")]
+impl Default for GuardsHook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio-graceful")]
+impl GuardsHook {
+    /// A `Shutdown` with no guards yet. Must be called within a tokio runtime.
+    pub fn new() -> Self {
+        let trigger = Trigger::new();
+        let shutdown = Shutdown::new(trigger.fired());
+        Self {
+            guard: shutdown.guard_weak(),
+            trigger,
+            shutdown: Arc::new(Mutex::new(Some(shutdown))),
+        }
+    }
+
+    /// A guard that holds the shutdown back until it is dropped.
+    pub fn guard(&self) -> ShutdownGuard {
+        self.guard.clone().upgrade()
+    }
+
+    /// A guard that is told about the shutdown, but doesn't hold it back.
+    pub fn guard_weak(&self) -> WeakShutdownGuard {
+        self.guard.clone()
+    }
+
+    /// Spawn `task` with a guard of its own, which it drops when it finishes.
+    pub fn spawn_task<F, Fut>(&self, task: F) -> tokio::task::JoinHandle<Fut::Output>
+    where
+        F: FnOnce(ShutdownGuard) -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        self.guard().into_spawn_task_fn(task)
+    }
+}
+
+#[cfg(feature = "tokio-graceful")]
+impl ShutdownHook for GuardsHook {
+    fn name(&self) -> &str {
+        "tokio-graceful"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.trigger.fire();
+            let Some(shutdown) = self.shutdown.lock().unwrap().take() else {
+                return Ok(());
+            };
+            match shutdown
+                .shutdown_with_limit(
+                    ctx.drain_deadline()
+                        .saturating_duration_since(ctx.clock().now()),
+                )
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(_) => Err(DrainTimeout::new("tasks were still holding shutdown guards").into()),
+            }
+        })
+    }
+}
+
+/// A [`ShutdownHook`] that shuts down a [`tokio_graceful_shutdown::Toplevel`] and waits for
+/// its subsystems.
+///
+/// The toplevel is shut down when the hook runs, rather than on a signal, or when one of
+/// the subsystems requests it. Subsystems that are still running when the budget runs out
+/// are cancelled.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{graceful::SubsystemsHook, ShutdownCoordinator};
+/// use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+///
+/// async fn poller(subsys: SubsystemHandle) -> Result<(), std::io::Error> {
+///     subsys.on_shutdown_requested().await;
+///     Ok(())
+/// }
+///
+/// # async fn example() {
+/// let subsystems = SubsystemsHook::new(|s: &SubsystemHandle| {
+///     s.start(SubsystemBuilder::new("poller", poller));
+/// });
+/// let shutdown = ShutdownCoordinator::new().with_hook(subsystems);
+/// # }
+/// ```
+#[cfg(feature = "tokio-graceful-shutdown")]
+pub struct SubsystemsHook<E: ErrTypeTraits = Error> {
+    trigger: Trigger,
+    toplevel: Mutex<Option<Toplevel<E>>>,
+}
+
+#[cfg(feature = "tokio-graceful-shutdown")]
+impl<E: ErrTypeTraits> fmt::Debug for SubsystemsHook<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubsystemsHook")
+            .field("triggered", &*self.trigger.0.borrow())
+            .finish()
+    }
+}
+
+#[cfg(feature = "tokio-graceful-shutdown")]
+impl<E: ErrTypeTraits> SubsystemsHook<E> {
+    /// Start a toplevel, and the subsystems `start` starts under it. Must be called within a
+    /// tokio runtime.
+    ///
+    /// `start` does what the closure passed to `Toplevel::new()` would, usually starting the
+    /// subsystems with `start()`, but it doesn't have to wait for anything.
+    pub fn new<F>(start: F) -> Self
+    where
+        F: FnOnce(&SubsystemHandle<E>) + Send + 'static,
+    {
+        let trigger = Trigger::new();
+        let fired = trigger.fired();
+        let toplevel = Toplevel::new(move |s: SubsystemHandle<E>| async move {
+            start(&s);
+            tokio::select! {
+                () = fired => s.request_shutdown(),
+                () = s.on_shutdown_requested() => {}
+                // So the toplevel still finishes on its own when all its subsystems have
+                () = s.wait_for_children() => {}
+            }
+        });
+        Self {
+            trigger,
+            toplevel: Mutex::new(Some(toplevel)),
+        }
+    }
+}
+
+#[cfg(feature = "tokio-graceful-shutdown")]
+impl<E: ErrTypeTraits> ShutdownHook for SubsystemsHook<E> {
+    fn name(&self) -> &str {
+        "tokio-graceful-shutdown"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.trigger.fire();
+            let Some(toplevel) = self.toplevel.lock().unwrap().take() else {
+                return Ok(());
+            };
+            match toplevel
+                .handle_shutdown_requests(
+                    ctx.drain_deadline()
+                        .saturating_duration_since(ctx.clock().now()),
+                )
+                .await
+            {
+                Ok(()) => Ok(()),
+                Err(GracefulShutdownError::ShutdownTimeout(_)) => {
+                    Err(DrainTimeout::new("subsystems were still running").into())
+                }
+                Err(error @ GracefulShutdownError::SubsystemsFailed(_)) => Err(failed(error)),
+            }
+        })
+    }
+}
+
+/// The error for subsystems that failed, naming them.
+#[cfg(feature = "tokio-graceful-shutdown")]
+fn failed<E: ErrTypeTraits>(error: GracefulShutdownError<E>) -> Error {
+    let failures: Vec<_> = error
+        .get_subsystem_errors()
+        .iter()
+        .map(|error| match error {
+            SubsystemError::Failed(name, error) => format!("{name}: {error}"),
+            SubsystemError::Panicked(name) => format!("{name} panicked"),
+        })
+        .collect();
+    format!("subsystems failed: {}", failures.join(", ")).into()
+}
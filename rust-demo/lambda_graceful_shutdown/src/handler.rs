@@ -0,0 +1,117 @@
+//! Plugging the coordinator into `lambda_runtime::spawn_graceful_shutdown_handler()`.
+//!
+//! The runtime's helper registers a no-op extension, waits for `SIGTERM` or `SIGINT`, runs the
+//! closure it was given, and exits. A function that already uses it can keep that wiring, and
+//! hand it the coordinator instead of the closure, with
+//! [`into_runtime_hook()`](IntoRuntimeShutdownHook::into_runtime_hook):
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{handler::IntoRuntimeShutdownHook, ShutdownCoordinator};
+//! use lambda_runtime::{run, service_fn, spawn_graceful_shutdown_handler, Error, LambdaEvent};
+//! use serde_json::Value;
+//!
+//! # async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> { Ok(event.payload) }
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let shutdown = ShutdownCoordinator::new();
+//!     spawn_graceful_shutdown_handler(shutdown.into_runtime_hook()).await;
+//!     run(service_fn(handler)).await
+//! }
+//! ```
+//!
+//! The closure the function passed before can keep running, as one of the coordinator's hooks,
+//! with [`handler_hook()`]:
+//!
+//! ```no_run
+//! # use lambda_graceful_shutdown::handler::{handler_hook, IntoRuntimeShutdownHook};
+//! # use lambda_graceful_shutdown::ShutdownCoordinator;
+//! # use lambda_runtime::spawn_graceful_shutdown_handler;
+//! # async fn example(log_guard: String) {
+//! let drop_log_guard = handler_hook("log-guard", move || async move {
+//!     std::mem::drop(log_guard);
+//! });
+//! let shutdown = ShutdownCoordinator::new().with_hook(drop_log_guard);
+//! spawn_graceful_shutdown_handler(shutdown.into_runtime_hook()).await;
+//! # }
+//! ```
+
+use std::{fmt, future::Future, sync::Mutex};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownCoordinator, ShutdownHook, ShutdownReason};
+
+/// A closure for the `shutdown_hook` of `lambda_runtime::spawn_graceful_shutdown_handler()`.
+pub type RuntimeShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+/// Something `lambda_runtime::spawn_graceful_shutdown_handler()` can run when the signal
+/// comes.
+pub trait IntoRuntimeShutdownHook {
+    /// The closure to pass to `spawn_graceful_shutdown_handler()`.
+    fn into_runtime_hook(self) -> RuntimeShutdownHook;
+}
+
+/// Runs [`shutdown_and_exit()`](ShutdownCoordinator::shutdown_and_exit), so the process exits
+/// with the report's [`exit_code()`](crate::ShutdownReport::exit_code) rather than the 0 the
+/// helper exits with. The helper doesn't say which signal fired, so the reason is always
+/// [`ShutdownReason::Sigterm`], which is the one Lambda sends.
+impl IntoRuntimeShutdownHook for ShutdownCoordinator {
+    fn into_runtime_hook(self) -> RuntimeShutdownHook {
+        Box::new(move || {
+            Box::pin(async move { self.shutdown_and_exit(ShutdownReason::Sigterm).await })
+        })
+    }
+}
+
+impl IntoRuntimeShutdownHook for &ShutdownCoordinator {
+    fn into_runtime_hook(self) -> RuntimeShutdownHook {
+        self.clone().into_runtime_hook()
+    }
+}
+
+/// Turn a closure written for `spawn_graceful_shutdown_handler()` into a [`ShutdownHook`].
+///
+/// The closure runs the first time the hook does, and is bounded by the budget like any other
+/// hook.
+pub fn handler_hook<F, Fut>(name: impl Into<String>, f: F) -> HandlerHook<F>
+where
+    F: FnOnce() -> Fut + Send,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    HandlerHook {
+        name: name.into(),
+        f: Mutex::new(Some(f)),
+    }
+}
+
+/// A [`ShutdownHook`] created with [`handler_hook()`].
+pub struct HandlerHook<F> {
+    name: String,
+    f: Mutex<Option<F>>,
+}
+
+impl<F> fmt::Debug for HandlerHook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerHook")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<F, Fut> ShutdownHook for HandlerHook<F>
+where
+    F: FnOnce() -> Fut + Send,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        let f = self.f.lock().unwrap().take();
+        Box::pin(async move {
+            if let Some(f) = f {
+                f().await;
+            }
+            Ok(())
+        })
+    }
+}
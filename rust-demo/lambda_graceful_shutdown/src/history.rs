@@ -0,0 +1,109 @@
+//! The last few shutdown reports, kept in a file in `/tmp`.
+//!
+//! The report of a shutdown normally only ends up in the logs. When the process is started
+//! again in the same environment, as the Runtime Interface Emulator and `cargo lambda watch`
+//! do, or when a `SIGTERM` turns out not to be the end of the environment, it helps to look at
+//! what the previous shutdowns did. [`ReportHistory`] keeps the last few
+//! [`ShutdownReport`]s in a file, one JSON object per line, oldest first.
+//!
+//! Set it up with
+//! [`ShutdownCoordinator::with_report_history()`](crate::ShutdownCoordinator::with_report_history),
+//! which appends the report at the end of every shutdown, and read it back with
+//! [`read()`](ReportHistory::read), or from the `debug` server with the `debug-server` feature.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::ShutdownReport;
+
+/// Where the reports are kept, unless set with [`ReportHistory::new()`].
+pub const DEFAULT_PATH: &str = "/tmp/lambda-graceful-shutdown/reports.jsonl";
+
+/// How many reports are kept, unless set with [`ReportHistory::with_capacity()`].
+const DEFAULT_CAPACITY: usize = 10;
+
+/// A file holding the last few shutdown reports.
+#[derive(Debug, Clone)]
+pub struct ReportHistory {
+    path: PathBuf,
+    capacity: usize,
+}
+
+impl Default for ReportHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_PATH)
+    }
+}
+
+impl ReportHistory {
+    /// Keep the reports in the file at `path`. Missing parent directories are created.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+
+    /// Keep the last `capacity` reports, instead of 10.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// The file the reports are kept in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add `report` to the file, dropping the oldest reports beyond the capacity.
+    ///
+    /// Each entry is the report's [`to_json()`](ShutdownReport::to_json), with the time it was
+    /// recorded as `recorded_at_ms`, in milliseconds since the Unix epoch. The file is
+    /// replaced in one go, so a shutdown cut short never leaves it half written.
+    pub fn append(&self, report: &ShutdownReport) -> io::Result<()> {
+        let mut entries = self.read_lines()?;
+        let mut entry = report.to_json();
+        entry["recorded_at_ms"] = json!(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64);
+        entries.push(entry.to_string());
+        let skip = entries.len().saturating_sub(self.capacity);
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = entries[skip..].join("\n");
+        contents.push('\n');
+        let partial = self.path.with_extension("partial");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &self.path)
+    }
+
+    /// The reports in the file, oldest first. A missing file holds no reports, and lines
+    /// that aren't valid JSON are skipped.
+    pub fn read(&self) -> io::Result<Vec<Value>> {
+        Ok(self
+            .read_lines()?
+            .iter()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    fn read_lines(&self) -> io::Result<Vec<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_owned)
+                .collect()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(error) => Err(error),
+        }
+    }
+}
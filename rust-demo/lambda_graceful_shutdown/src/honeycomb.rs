@@ -0,0 +1,115 @@
+//! Flushing `libhoney` events to Honeycomb.
+//!
+//! `libhoney` batches events in a background task, and sends a batch once it is full or no
+//! event has been added for the batch timeout (100ms by default). Its own
+//! [`flush()`](libhoney::Client::flush) and [`close()`](libhoney::Client::close) stop that
+//! task without sending the batch it holds, so whatever was added in the last moments before
+//! the environment goes away is lost.
+//!
+//! [`HoneycombFlushHook`] waits for the last batches to be sent instead, by reading the
+//! transmission's responses until none has arrived for a while, and only then closes the
+//! client.
+
+use std::{fmt, time::Duration};
+
+use libhoney::{Client, Sender};
+
+use crate::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// How long to wait for another response, unless set with
+/// [`HoneycombFlushHook::with_quiet_period()`]. Longer than the default batch timeout, plus a
+/// round trip to Honeycomb.
+const DEFAULT_QUIET_PERIOD: Duration = Duration::from_millis(150);
+
+/// Part of the budget kept back for closing the client.
+const CLOSE_RESERVE: Duration = Duration::from_millis(10);
+
+/// A [`ShutdownHook`] that waits for `libhoney` to send its pending events, then closes the
+/// client.
+///
+/// The hook reads the client's [`responses()`](libhoney::Client::responses), so nothing else
+/// should read them at shutdown, or it may stop waiting too early. It fails if any event
+/// could not be sent, or if responses were still arriving when the budget ran out.
+pub struct HoneycombFlushHook<T: Sender> {
+    client: Client<T>,
+    quiet_period: Duration,
+}
+
+impl<T: Sender> fmt::Debug for HoneycombFlushHook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HoneycombFlushHook")
+            .field("quiet_period", &self.quiet_period)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Sender> HoneycombFlushHook<T> {
+    /// Create a hook that flushes `client`, usually a clone of the one the function uses.
+    pub fn new(client: Client<T>) -> Self {
+        Self {
+            client,
+            quiet_period: DEFAULT_QUIET_PERIOD,
+        }
+    }
+
+    /// Stop waiting once no response has arrived for `quiet_period`.
+    ///
+    /// Set this above the transmission's `batch_timeout` if it has been raised.
+    pub fn with_quiet_period(mut self, quiet_period: Duration) -> Self {
+        self.quiet_period = quiet_period;
+        self
+    }
+}
+
+impl<T> ShutdownHook for HoneycombFlushHook<T>
+where
+    T: Sender + Clone + Send + Sync + 'static,
+{
+    fn name(&self) -> &str {
+        "honeycomb"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let client = self.client.clone();
+            let quiet_period = self.quiet_period;
+            // The responses are waited for on a blocking thread, so the budget left on the
+            // coordinator's clock is turned into a deadline on the system's.
+            let deadline =
+                std::time::Instant::now() + ctx.remaining().saturating_sub(CLOSE_RESERVE);
+            let (sent, failed, timed_out) = tokio::task::spawn_blocking(move || {
+                let responses = client.responses();
+                let (mut sent, mut failed) = (0, 0);
+                let timed_out = loop {
+                    let now = std::time::Instant::now();
+                    if now >= deadline {
+                        break true;
+                    }
+                    match responses.recv_deadline((now + quiet_period).min(deadline)) {
+                        Ok(response) if response.error.is_none() => sent += 1,
+                        Ok(response) => {
+                            failed += 1;
+                            tracing::debug!(error = ?response.error, "failed to send Honeycomb event");
+                        }
+                        Err(_) => break std::time::Instant::now() >= deadline,
+                    }
+                };
+                client.close()?;
+                Ok::<_, Error>((sent, failed, timed_out))
+            })
+            .await??;
+
+            ctx.note(format!("sent {sent} events"));
+            if timed_out {
+                return Err(DrainTimeout::new(
+                    "ran out of time while events were still being sent",
+                )
+                .into());
+            }
+            if failed > 0 {
+                return Err(format!("failed to send {failed} events").into());
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,149 @@
+use std::{fmt, future::Future, pin::Pin};
+
+use crate::{Error, ShutdownContext};
+
+/// A boxed future, as returned by [`ShutdownHook::shutdown()`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Cleanup logic that runs when the execution environment shuts down.
+///
+/// Hooks are run by the [`ShutdownCoordinator`](crate::ShutdownCoordinator) once the shutdown
+/// signal arrives. A hook that is still running when the budget runs out is abandoned, so
+/// hooks should check [`ShutdownContext::remaining()`] before starting anything slow.
+pub trait ShutdownHook: Send + Sync {
+    /// Name used in logs and in the [`ShutdownReport`](crate::ShutdownReport).
+    fn name(&self) -> &str;
+
+    /// Flush, close or otherwise tear down whatever this hook is responsible for.
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Build a [`ShutdownHook`] out of a name and an async closure.
+///
+/// ```
+/// use lambda_graceful_shutdown::hook_fn;
+///
+/// let hook = hook_fn("say-goodbye", |ctx| async move {
+///     println!("shutting down with {:?} left", ctx.remaining());
+///     Ok(())
+/// });
+/// ```
+pub fn hook_fn<F, Fut>(name: impl Into<String>, f: F) -> FnHook<F>
+where
+    F: Fn(ShutdownContext) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    FnHook {
+        name: name.into(),
+        f,
+    }
+}
+
+/// A [`ShutdownHook`] created with [`hook_fn()`].
+pub struct FnHook<F> {
+    name: String,
+    f: F,
+}
+
+impl<F> fmt::Debug for FnHook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnHook").field("name", &self.name).finish()
+    }
+}
+
+impl<F, Fut> ShutdownHook for FnHook<F>
+where
+    F: Fn(ShutdownContext) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(), Error>> + Send + 'static,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin((self.f)(ctx.clone()))
+    }
+}
+
+/// The error a hook returns when it stops waiting for something to drain, such as requests
+/// still in flight or messages left to send, because its time ran out.
+///
+/// The coordinator records these as
+/// [`HookOutcome::DrainTimedOut`](crate::HookOutcome::DrainTimedOut) rather than as plain
+/// failures, so that drains that gave up can be counted separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrainTimeout(String);
+
+impl DrainTimeout {
+    /// Describe what was left when the drain gave up.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+impl fmt::Display for DrainTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DrainTimeout {}
+
+/// How a [`join_within()`] ended.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Joined {
+    /// The tasks that panicked, which are logged but not passed on.
+    pub(crate) panicked: usize,
+    /// The tasks still running at the drain deadline, which are aborted.
+    pub(crate) left: usize,
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl Joined {
+    /// A [`DrainTimeout`] saying how many tasks were left, described by `left`, e.g.
+    /// `"checkpoints left to save"`, or `None` if they all finished.
+    pub(crate) fn timed_out(&self, left: &str) -> Option<Error> {
+        (self.left > 0)
+            .then(|| DrainTimeout::new(format!("ran out of time with {} {left}", self.left)).into())
+    }
+}
+
+/// Join `tasks`, passing the output of each one to `each`, until they have all finished or
+/// the [drain deadline](ShutdownContext::drain_deadline) passes. Tasks still running then are
+/// aborted.
+#[cfg(feature = "tokio-runtime")]
+pub(crate) async fn join_within<T: 'static>(
+    ctx: &ShutdownContext,
+    tasks: &mut tokio::task::JoinSet<T>,
+    each: impl FnMut(T),
+) -> Joined {
+    join_until(ctx, ctx.drain_deadline(), tasks, each).await
+}
+
+/// [`join_within()`], but until `deadline`, for hooks that keep back more of the budget for
+/// something they do afterwards.
+#[cfg(feature = "tokio-runtime")]
+pub(crate) async fn join_until<T: 'static>(
+    ctx: &ShutdownContext,
+    deadline: tokio::time::Instant,
+    tasks: &mut tokio::task::JoinSet<T>,
+    mut each: impl FnMut(T),
+) -> Joined {
+    let mut panicked = 0;
+    let finished = crate::clock::timeout_at(ctx.clock(), deadline, async {
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(output) => each(output),
+                Err(error) => {
+                    tracing::warn!(%error, "a shutdown task failed");
+                    panicked += 1;
+                }
+            }
+        }
+    })
+    .await;
+    let left = if finished.is_some() { 0 } else { tasks.len() };
+    tasks.abort_all();
+    Joined { panicked, left }
+}
@@ -0,0 +1,180 @@
+//! Tearing down HTTP client connection pools.
+//!
+//! Clients such as `reqwest::Client` and hyper-util's legacy `Client` keep connections to the
+//! APIs they call alive between invocations. Those connections are only shut down cleanly,
+//! TLS `close_notify` included, when the last handle to the client's pool is dropped; a
+//! spindown otherwise just cuts them off.
+//!
+//! [`TrackedClient`] holds on to such a client and lends it out per request. Its
+//! [`shutdown_hook()`](TrackedClient::shutdown_hook) stops lending it out, waits for the
+//! requests in flight to finish, and then drops the client. It works with any client that is
+//! a cheap, `Clone`-able handle to a shared pool.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{clock, BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// How often to check whether the requests in flight have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+struct Inner<C> {
+    client: Mutex<Option<C>>,
+    in_flight: AtomicUsize,
+}
+
+/// An HTTP client that can be taken down at shutdown.
+///
+/// Cloning is cheap, and all clones share the same client. Don't keep clones of the wrapped
+/// client elsewhere, or the pool outlives the shutdown hook.
+pub struct TrackedClient<C> {
+    inner: Arc<Inner<C>>,
+}
+
+impl<C> Clone for TrackedClient<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C> fmt::Debug for TrackedClient<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrackedClient")
+            .field("closed", &self.inner.client.lock().unwrap().is_none())
+            .field("in_flight", &self.inner.in_flight.load(Ordering::SeqCst))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: Clone> TrackedClient<C> {
+    /// Wrap `client`.
+    pub fn new(client: C) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client: Mutex::new(Some(client)),
+                in_flight: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Borrow the client for one request, or `None` once the shutdown hook has run.
+    ///
+    /// The request counts as in flight until the returned [`InFlight`] is dropped, so hold
+    /// on to it until the response has been read.
+    pub fn acquire(&self) -> Option<InFlight<C>> {
+        let client = self.inner.client.lock().unwrap();
+        let client = client.as_ref()?.clone();
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(InFlight {
+            client,
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// The number of requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Returns true once the shutdown hook has stopped lending out the client.
+    pub fn is_closed(&self) -> bool {
+        self.inner.client.lock().unwrap().is_none()
+    }
+
+    /// A hook that drops the client once the requests in flight are done.
+    pub fn shutdown_hook(&self) -> HttpClientHook<C> {
+        HttpClientHook {
+            client: self.clone(),
+            timeout: None,
+        }
+    }
+}
+
+/// The client, borrowed from a [`TrackedClient`] for one request.
+pub struct InFlight<C> {
+    client: C,
+    inner: Arc<Inner<C>>,
+}
+
+impl<C> Deref for InFlight<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.client
+    }
+}
+
+impl<C> Drop for InFlight<C> {
+    fn drop(&mut self) {
+        self.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<C> fmt::Debug for InFlight<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InFlight").finish_non_exhaustive()
+    }
+}
+
+/// A [`ShutdownHook`] that tears down the client in a [`TrackedClient`].
+pub struct HttpClientHook<C> {
+    client: TrackedClient<C>,
+    timeout: Option<Duration>,
+}
+
+impl<C> fmt::Debug for HttpClientHook<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpClientHook")
+            .field("client", &self.client)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl<C> HttpClientHook<C> {
+    /// Stop waiting for requests in flight after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<C: Send + 'static> ShutdownHook for HttpClientHook<C> {
+    fn name(&self) -> &str {
+        "http-client"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let inner = &self.client.inner;
+            let client = inner.client.lock().unwrap().take();
+            let timeout = ctx.drain_remaining_capped(self.timeout);
+            let clock = ctx.clock();
+            let finished = clock::timeout_at(clock, clock.now() + timeout, async {
+                while inner.in_flight.load(Ordering::SeqCst) > 0 {
+                    clock.sleep_until(clock.now() + POLL_INTERVAL).await;
+                }
+            })
+            .await;
+            // Dropping the last handle closes the idle connections; ones still in use by a
+            // request that didn't finish are closed when it does.
+            drop(client);
+            finished.ok_or_else(|| {
+                DrainTimeout::new(format!(
+                    "{} requests still in flight after {timeout:?}",
+                    inner.in_flight.load(Ordering::SeqCst)
+                ))
+            })?;
+            Ok(())
+        })
+    }
+}
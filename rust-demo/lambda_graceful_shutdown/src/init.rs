@@ -0,0 +1,427 @@
+//! Extension registration overlapped with the rest of the Init phase.
+//!
+//! An extension has to be registered before `lambda_runtime::run()` starts asking for
+//! invocations, so the registration call is usually awaited first thing in `main`, and every
+//! other step of the cold start, such as building SDK clients or loading configuration, waits
+//! for its round trip. [`start_early()`] starts it in the background instead, and the
+//! [`EarlyRegistration`] it returns is awaited right before the runtime starts, which keeps
+//! the order Lambda requires:
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{init, ShutdownCoordinator, ShutdownReason};
+//! use lambda_runtime::{run, service_fn, spawn_graceful_shutdown_handler, Error, LambdaEvent};
+//! use serde_json::Value;
+//!
+//! # async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> { Ok(event.payload) }
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let shutdown = ShutdownCoordinator::new();
+//!     let coordinator = shutdown.clone();
+//!     let registration = init::start_early(spawn_graceful_shutdown_handler(|| async move {
+//!         coordinator.shutdown(ShutdownReason::Sigterm).await;
+//!     }));
+//!
+//!     // ...build clients and register their hooks while the extension registers
+//!
+//!     registration.before(run(service_fn(handler))).await
+//! }
+//! ```
+//!
+//! [`InitTimings`] measures what each step adds to the cold start: the registration, and how
+//! long the Init phase still had to wait for it, the hooks being registered, and the
+//! function's own setup. Started first thing in `main`, it logs them all in one record when
+//! the runtime starts:
+//!
+//! ```no_run
+//! # use lambda_graceful_shutdown::{init::{self, InitTimings}, ShutdownCoordinator};
+//! # use lambda_runtime::{run, service_fn, spawn_graceful_shutdown_handler, Error, LambdaEvent};
+//! # use serde_json::Value;
+//! # async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> { Ok(event.payload) }
+//! # async fn build_clients() {}
+//! # async fn example() -> Result<(), Error> {
+//! let timings = InitTimings::start();
+//! let shutdown = ShutdownCoordinator::new().with_init_timings(&timings);
+//! let registration =
+//!     init::start_early(spawn_graceful_shutdown_handler(|| async {})).timed(&timings);
+//! let clients = timings.measure_user_init(build_clients()).await;
+//!
+//! registration.before(run(service_fn(handler))).await
+//! # }
+//! ```
+//!
+//! The clients whose teardown the hooks take care of can also be connected during Init,
+//! rather than by the first invocation: [`Warmups`] handed to the registration with
+//! [`with_warmups()`](EarlyRegistration::with_warmups) run once it has finished, right
+//! before the runtime starts, for up to a budget of their own:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use lambda_graceful_shutdown::init::{self, Warmups};
+//! # use lambda_runtime::{run, service_fn, spawn_graceful_shutdown_handler, Error, LambdaEvent};
+//! # use serde_json::Value;
+//! # async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> { Ok(event.payload) }
+//! # async fn example() -> Result<(), Error> {
+//! let warmups = Warmups::new()
+//!     .with_budget(Duration::from_millis(500))
+//!     .with("redis", async {
+//!         // e.g. take a connection from the pool and put it back
+//!         Ok(())
+//!     });
+//! init::start_early(spawn_graceful_shutdown_handler(|| async {}))
+//!     .with_warmups(warmups)
+//!     .before(run(service_fn(handler)))
+//!     .await
+//! # }
+//! ```
+
+#[cfg(feature = "tokio-runtime")]
+use std::fmt;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "tokio-runtime")]
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+
+#[cfg(feature = "tokio-runtime")]
+use crate::{BoxFuture, Error};
+
+/// How long the warmups get, unless set with [`Warmups::with_budget()`].
+#[cfg(feature = "tokio-runtime")]
+const DEFAULT_WARMUP_BUDGET: Duration = Duration::from_secs(1);
+
+/// Start `registration`, such as `lambda_runtime::spawn_graceful_shutdown_handler()` or
+/// `lambda_extension::Extension::register()`, in a task of its own.
+///
+/// Hooks can still be registered with the coordinator afterwards, since its clones share them.
+#[cfg(feature = "tokio-runtime")]
+pub fn start_early<F>(registration: F) -> EarlyRegistration<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    EarlyRegistration {
+        task: tokio::spawn(async move {
+            let started = Instant::now();
+            let output = registration.await;
+            (output, started.elapsed())
+        }),
+        timings: None,
+        warmups: None,
+    }
+}
+
+/// A registration started with [`start_early()`], to wait for before the runtime starts.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug)]
+#[must_use = "the registration has to finish before the runtime starts"]
+pub struct EarlyRegistration<T> {
+    task: JoinHandle<(T, Duration)>,
+    timings: Option<InitTimings>,
+    warmups: Option<Warmups>,
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl<T> EarlyRegistration<T> {
+    /// Record in `timings` how long the registration took, and how long waiting for it
+    /// held up the Init phase.
+    pub fn timed(mut self, timings: &InitTimings) -> Self {
+        self.timings = Some(timings.clone());
+        self
+    }
+
+    /// Run `warmups` in [`before()`](Self::before), once the registration has finished.
+    pub fn with_warmups(mut self, warmups: Warmups) -> Self {
+        self.warmups = Some(warmups);
+        self
+    }
+
+    /// Wait for the registration to finish, and return its output.
+    ///
+    /// A panic in the registration, such as `spawn_graceful_shutdown_handler()` failing to
+    /// register, is resumed here, as if it had been awaited in place.
+    pub async fn wait(self) -> T {
+        let waiting = Instant::now();
+        match self.task.await {
+            Ok((output, elapsed)) => {
+                if let Some(timings) = &self.timings {
+                    let mut phases = timings.phases.lock().unwrap();
+                    phases.registration = Some(elapsed);
+                    phases.registration_wait = Some(waiting.elapsed());
+                }
+                output
+            }
+            Err(error) => match error.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(error) => panic!("the extension registration was cancelled: {error}"),
+            },
+        }
+    }
+
+    /// Wait for the registration to finish, then run `runtime`, usually
+    /// `lambda_runtime::run()`. The registration's output is dropped.
+    ///
+    /// The [warmups](Self::with_warmups) run in between. If the registration is
+    /// [timed](Self::timed), the Init phase counts as finished, and its timings are logged,
+    /// right before the runtime starts.
+    pub async fn before<R: Future>(mut self, runtime: R) -> R::Output {
+        let timings = self.timings.clone();
+        let warmups = self.warmups.take();
+        self.wait().await;
+        if let Some(warmups) = warmups {
+            let report = warmups.run().await;
+            if let Some(timings) = &timings {
+                timings.phases.lock().unwrap().warmup = Some(report.elapsed);
+            }
+        }
+        if let Some(timings) = timings {
+            timings.finish();
+        }
+        runtime.await
+    }
+}
+
+/// How long the steps of the Init phase took, to check what the shutdown handling adds to
+/// the cold start.
+///
+/// Cloning is cheap, and all clones share the same timings.
+#[derive(Debug, Clone)]
+pub struct InitTimings {
+    started: Instant,
+    phases: Arc<Mutex<Phases>>,
+}
+
+/// The timings recorded so far.
+#[derive(Debug, Default)]
+struct Phases {
+    registration: Option<Duration>,
+    registration_wait: Option<Duration>,
+    hook_registration: Duration,
+    hooks_registered: usize,
+    user_init: Duration,
+    warmup: Option<Duration>,
+    total: Option<Duration>,
+}
+
+impl InitTimings {
+    /// Start measuring the Init phase, first thing in `main`.
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            phases: Arc::default(),
+        }
+    }
+
+    /// Run `init`, part of the function's own setup such as building SDK clients, and add
+    /// how long it took to the user init time.
+    pub async fn measure_user_init<F: Future>(&self, init: F) -> F::Output {
+        let started = Instant::now();
+        let output = init.await;
+        self.phases.lock().unwrap().user_init += started.elapsed();
+        output
+    }
+
+    /// Add `elapsed` to the time spent registering hooks, as the coordinator does when set
+    /// up with [`with_init_timings()`](crate::ShutdownCoordinator::with_init_timings).
+    pub(crate) fn record_hook(&self, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap();
+        phases.hook_registration += elapsed;
+        phases.hooks_registered += 1;
+    }
+
+    /// The timings so far. The total is the time since [`start()`](Self::start) until the
+    /// Init phase [finished](Self::finish), or until now.
+    pub fn report(&self) -> InitReport {
+        let phases = self.phases.lock().unwrap();
+        InitReport {
+            total: phases.total.unwrap_or_else(|| self.started.elapsed()),
+            registration: phases.registration,
+            registration_wait: phases.registration_wait,
+            hook_registration: phases.hook_registration,
+            hooks_registered: phases.hooks_registered,
+            user_init: phases.user_init,
+            warmup: phases.warmup,
+        }
+    }
+
+    /// Mark the end of the Init phase, and log the timings in one record. Done by
+    /// [`EarlyRegistration::before()`] for a timed registration.
+    pub fn finish(&self) -> InitReport {
+        self.phases
+            .lock()
+            .unwrap()
+            .total
+            .get_or_insert_with(|| self.started.elapsed());
+        let report = self.report();
+        tracing::info!(
+            total_ms = report.total.as_millis() as u64,
+            registration_ms = report
+                .registration
+                .map(|elapsed| elapsed.as_millis() as u64),
+            registration_wait_ms = report
+                .registration_wait
+                .map(|elapsed| elapsed.as_millis() as u64),
+            hook_registration_us = report.hook_registration.as_micros() as u64,
+            hooks_registered = report.hooks_registered,
+            user_init_ms = report.user_init.as_millis() as u64,
+            warmup_ms = report.warmup.map(|elapsed| elapsed.as_millis() as u64),
+            "init phase finished"
+        );
+        report
+    }
+}
+
+/// The timings of the Init phase, from [`InitTimings::report()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InitReport {
+    /// The whole Init phase, as far as it was measured.
+    pub total: Duration,
+    /// How long the extension registration took, if it was [timed](EarlyRegistration::timed).
+    pub registration: Option<Duration>,
+    /// How much of the registration the Init phase had to wait for, after everything else
+    /// was done. The rest of it overlapped with the function's setup.
+    pub registration_wait: Option<Duration>,
+    /// How long registering the hooks took, in total.
+    pub hook_registration: Duration,
+    /// How many hooks were registered.
+    pub hooks_registered: usize,
+    /// How long the setup measured with [`InitTimings::measure_user_init()`] took.
+    pub user_init: Duration,
+    /// How long the [`Warmups`] ran for, if the registration had any.
+    pub warmup: Option<Duration>,
+}
+
+/// Connections and clients set up ahead of the first invocation, all at once, for up to a
+/// budget.
+///
+/// A warmup that fails is logged and otherwise ignored, since the first invocation can still
+/// connect on its own. One that is still running when the budget runs out is left to finish
+/// in the background.
+#[cfg(feature = "tokio-runtime")]
+pub struct Warmups {
+    budget: Duration,
+    warmups: Vec<(String, BoxFuture<'static, Result<(), Error>>)>,
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl fmt::Debug for Warmups {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Warmups")
+            .field("budget", &self.budget)
+            .field(
+                "warmups",
+                &self
+                    .warmups
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl Default for Warmups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio-runtime")]
+impl Warmups {
+    /// No warmups yet, with a budget of 1s.
+    pub fn new() -> Self {
+        Self {
+            budget: DEFAULT_WARMUP_BUDGET,
+            warmups: Vec::new(),
+        }
+    }
+
+    /// Give the warmups `budget`, together, instead of 1s.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Add `warmup` under `name`, builder-style. See [`add()`](Self::add).
+    pub fn with<F>(mut self, name: impl Into<String>, warmup: F) -> Self
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.add(name, warmup);
+        self
+    }
+
+    /// Add `warmup`, such as opening a connection of a pool, under `name` for the logs.
+    pub fn add<F>(&mut self, name: impl Into<String>, warmup: F)
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.warmups.push((name.into(), Box::pin(warmup)));
+    }
+
+    /// Run the warmups at the same time, until they have all finished or the budget has run
+    /// out, and log how they went.
+    pub async fn run(self) -> WarmupReport {
+        let started = Instant::now();
+        let deadline = started + self.budget;
+        let mut running = JoinSet::new();
+        let mut pending = Vec::with_capacity(self.warmups.len());
+        for (name, warmup) in self.warmups {
+            pending.push(name.clone());
+            running.spawn(async move { (name, warmup.await) });
+        }
+
+        let mut report = WarmupReport {
+            elapsed: Duration::ZERO,
+            completed: Vec::new(),
+            failed: Vec::new(),
+            still_running: Vec::new(),
+        };
+        while let Ok(Some(finished)) = tokio::time::timeout_at(deadline, running.join_next()).await
+        {
+            // A warmup that panicked is left out, and counts as still running
+            let Ok((name, result)) = finished else {
+                continue;
+            };
+            pending.retain(|pending| *pending != name);
+            match result {
+                Ok(()) => report.completed.push(name),
+                Err(error) => {
+                    tracing::warn!(warmup = name, %error, "warmup failed");
+                    report.failed.push(name);
+                }
+            }
+        }
+        running.detach_all();
+        report.still_running = pending;
+        report.elapsed = started.elapsed();
+        tracing::info!(
+            elapsed_ms = report.elapsed.as_millis() as u64,
+            completed = report.completed.join(","),
+            failed = report.failed.join(","),
+            still_running = report.still_running.join(","),
+            "warmups finished"
+        );
+        report
+    }
+}
+
+/// How the [`Warmups`] went.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WarmupReport {
+    /// How long the warmups ran for, at most the budget.
+    pub elapsed: Duration,
+    /// The warmups that finished in time.
+    pub completed: Vec<String>,
+    /// The warmups that returned an error.
+    pub failed: Vec<String>,
+    /// The warmups still running when the budget ran out, or that panicked.
+    pub still_running: Vec<String>,
+}
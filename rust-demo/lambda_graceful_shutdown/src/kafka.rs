@@ -0,0 +1,74 @@
+//! Flushing an [`rdkafka`] producer, e.g. one writing to MSK.
+//!
+//! `FutureProducer::send()` resolves once librdkafka has accepted a message into its internal
+//! queue, which is sent to the brokers in the background. Whatever is still in that queue
+//! when the environment goes away is lost.
+
+use std::fmt;
+
+use rdkafka::{
+    client::{ClientContext, DefaultClientContext},
+    producer::{FutureProducer, Producer},
+    util::{AsyncRuntime, DefaultRuntime},
+};
+
+use crate::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] that calls [`Producer::flush()`] with the time left until the
+/// [drain deadline](ShutdownContext::drain_deadline).
+pub struct KafkaFlushHook<C = DefaultClientContext, R = DefaultRuntime>
+where
+    C: ClientContext + 'static,
+{
+    producer: FutureProducer<C, R>,
+}
+
+impl<C, R> fmt::Debug for KafkaFlushHook<C, R>
+where
+    C: ClientContext + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KafkaFlushHook").finish_non_exhaustive()
+    }
+}
+
+impl<C, R> KafkaFlushHook<C, R>
+where
+    C: ClientContext + 'static,
+    R: AsyncRuntime + Send + Sync + 'static,
+{
+    /// Create a hook for the given producer. Producers are cheap to clone, so pass in a clone
+    /// of yours.
+    pub fn new(producer: FutureProducer<C, R>) -> Self {
+        Self { producer }
+    }
+}
+
+impl<C, R> ShutdownHook for KafkaFlushHook<C, R>
+where
+    C: ClientContext + 'static,
+    R: AsyncRuntime + Send + Sync + 'static,
+{
+    fn name(&self) -> &str {
+        "rdkafka"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let producer = self.producer.clone();
+            let timeout = ctx.drain_remaining_capped(None);
+            // `flush()` polls the producer until its queue is empty, blocking the thread, and
+            // only fails if it times out.
+            tokio::task::spawn_blocking(move || {
+                producer.flush(timeout).map_err(|error| {
+                    DrainTimeout::new(format!(
+                        "{error}, {} messages were not delivered",
+                        producer.in_flight_count()
+                    ))
+                })
+            })
+            .await??;
+            Ok(())
+        })
+    }
+}
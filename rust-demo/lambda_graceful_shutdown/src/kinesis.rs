@@ -0,0 +1,183 @@
+//! Batched writes to Kinesis Data Streams.
+//!
+//! [`BufferedKinesisWriter`] collects records and writes them with `PutRecords` once a batch
+//! is full. Its [`flush_hook()`](BufferedKinesisWriter::flush_hook) writes the final partial
+//! batch at shutdown, retrying throttled records for as long as the budget allows.
+
+use std::{fmt, sync::Arc};
+
+use aws_sdk_kinesis::{primitives::Blob, types::PutRecordsRequestEntry, Client};
+
+use crate::{
+    records::{RecordApi, RecordWriter},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// `PutRecords` accepts at most 500 records and 5 MiB per request.
+const MAX_BATCH_RECORDS: usize = 500;
+const MAX_BATCH_BYTES: usize = 5 * 1024 * 1024;
+
+/// Kinesis accepts records of up to 1 MiB, partition key included.
+const MAX_RECORD_BYTES: usize = 1024 * 1024;
+
+struct KinesisApi {
+    client: Client,
+    stream_name: String,
+}
+
+impl RecordApi for KinesisApi {
+    type Record = PutRecordsRequestEntry;
+
+    fn put(&self, records: Vec<Self::Record>) -> BoxFuture<'_, Result<Vec<Option<String>>, Error>> {
+        Box::pin(async move {
+            let output = self
+                .client
+                .put_records()
+                .stream_name(&self.stream_name)
+                .set_records(Some(records))
+                .send()
+                .await?;
+            Ok(output
+                .records()
+                .iter()
+                .map(|result| result.error_code().map(str::to_owned))
+                .collect())
+        })
+    }
+}
+
+/// Writes records to a Kinesis data stream in batches.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+#[derive(Clone)]
+pub struct BufferedKinesisWriter {
+    writer: Arc<RecordWriter<KinesisApi>>,
+}
+
+impl fmt::Debug for BufferedKinesisWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferedKinesisWriter")
+            .field("stream_name", &self.writer.api().stream_name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BufferedKinesisWriter {
+    /// Create a writer for the stream called `stream_name`.
+    pub fn new(client: Client, stream_name: impl Into<String>) -> Self {
+        let api = KinesisApi {
+            client,
+            stream_name: stream_name.into(),
+        };
+        Self {
+            writer: Arc::new(RecordWriter::new(api, MAX_BATCH_RECORDS, MAX_BATCH_BYTES)),
+        }
+    }
+
+    /// Queue a record, writing a batch if this fills one up.
+    ///
+    /// If some records of that batch are throttled, they are kept for the next write, and
+    /// [`last_error()`](Self::last_error) says why. Records over 1 MiB, which Kinesis would
+    /// never accept, are rejected with an error, without being queued.
+    pub async fn put(
+        &self,
+        partition_key: impl Into<String>,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let partition_key = partition_key.into();
+        let data = data.into();
+        let size = partition_key.len() + data.len();
+        if size > MAX_RECORD_BYTES {
+            return Err(format!(
+                "record is {size} bytes, Kinesis accepts at most {MAX_RECORD_BYTES}"
+            )
+            .into());
+        }
+        let record = PutRecordsRequestEntry::builder()
+            .partition_key(partition_key)
+            .data(Blob::new(data))
+            .build()?;
+        self.writer.push(record, size).await
+    }
+
+    /// The number of records waiting to be written.
+    pub async fn pending(&self) -> usize {
+        self.writer.pending().await
+    }
+
+    /// Why the last batch written failed, in full or in part, unless one has been written
+    /// in full since.
+    /// Its records are still queued, and go out with a later batch or flush.
+    pub fn last_error(&self) -> Option<String> {
+        self.writer.last_error()
+    }
+
+    /// Write every queued record, including a final partial batch.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.writer.flush().await
+    }
+
+    /// A hook that writes any queued records when the environment shuts down.
+    pub fn flush_hook(&self) -> KinesisFlushHook {
+        KinesisFlushHook {
+            writer: self.clone(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that writes the records still buffered in a [`BufferedKinesisWriter`].
+#[derive(Debug)]
+pub struct KinesisFlushHook {
+    writer: BufferedKinesisWriter,
+}
+
+impl ShutdownHook for KinesisFlushHook {
+    fn name(&self) -> &str {
+        "kinesis"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(
+            self.writer
+                .writer
+                .flush_until(ctx.clock(), ctx.drain_deadline()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_kinesis::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn records_kinesis_would_reject_are_not_queued() {
+        let config = aws_sdk_kinesis::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        let writer = BufferedKinesisWriter::new(Client::from_conf(config), "events");
+        let error = writer
+            .put("key", vec![0; MAX_RECORD_BYTES - 2])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "record is 1048577 bytes, Kinesis accepts at most 1048576"
+        );
+        writer
+            .put("key", vec![0; MAX_RECORD_BYTES - 3])
+            .await
+            .unwrap();
+        assert_eq!(writer.pending().await, 1);
+    }
+}
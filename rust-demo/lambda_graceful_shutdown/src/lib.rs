@@ -0,0 +1,334 @@
+//! Shutdown hooks for Rust functions running on AWS Lambda.
+//!
+//! Once at least one extension is registered, Lambda sends the runtime a `SIGTERM` before
+//! the execution environment is torn down, and then waits for a short shutdown window:
+//! 500ms when only internal extensions are registered, 2s with external extensions.
+//! Anything still buffered in memory after that window is lost.
+//!
+//! This crate gives that window some structure: register [`ShutdownHook`]s with a
+//! [`ShutdownCoordinator`], and call [`ShutdownCoordinator::shutdown()`] from your signal
+//! handler. Hooks run one after the other, bounded by the shutdown budget, and the outcome of
+//! each one is collected in a [`ShutdownReport`].
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{hook_fn, ShutdownCoordinator, ShutdownReason};
+//!
+//! # async fn example() {
+//! let shutdown = ShutdownCoordinator::new().with_hook(hook_fn("flush-metrics", |_ctx| async {
+//!     // flush whatever is still buffered
+//!     Ok(())
+//! }));
+//!
+//! // ...from the SIGTERM handler:
+//! let report = shutdown.shutdown(ShutdownReason::Sigterm).await;
+//! println!("[runtime] {report}");
+//! # }
+//! ```
+//!
+//! With the `macros` feature, the [`shutdown_hook`] attribute turns an async function into a
+//! hook, with logging and error conversion taken care of.
+//!
+//! Ready-made hooks live in their own modules. The ones that integrate with another crate
+//! are behind a cargo feature:
+//!
+//! - `actix`: stops actix-web servers and their `System` (feature `actix`)
+//! - `appender`: flushes `tracing-appender` non-blocking writers (feature `tracing-appender`)
+//! - `aws`: tears down `aws-sdk-rust` clients and their connection pools (feature `aws-sdk`)
+//! - `batch`: records posted to an HTTP endpoint in batches, flushed on shutdown
+//!   (feature `http-batch`)
+//! - `blocking`: a synchronous coordinator, running blocking hooks on threads, for functions
+//!   without an async runtime (feature `blocking`)
+//! - `chaos`: shutdowns injected at random points of invocations, to rehearse interruptions
+//! - `checkpoint`: saves the progress of work in flight to a store, to resume it later
+//! - `clock`: where the coordinator gets the time from, to test deadlines on a paused or
+//!   manual clock
+//! - `debug`: a local HTTP endpoint showing the registered hooks and the last shutdown report
+//!   (feature `debug-server`)
+//! - `dynamodb`: batched DynamoDB writes, flushed on shutdown, a checkpoint store, an
+//!   idempotency table, and lock leases released at shutdown (feature `dynamodb`)
+//! - `dlq`: sends the payloads of invocations cut off by a timeout or failure to an SQS
+//!   dead-letter queue (feature `sqs`)
+//! - `efs`: syncs and closes files being written, e.g. on EFS mounts
+//! - `emf`: CloudWatch Embedded Metric Format metrics, buffered in memory, and metrics on how
+//!   the shutdown went
+//! - `environment`: whether the function runs on Lambda, under the Runtime Interface Emulator
+//!   or locally, from a `.zip` archive or a container image, and sidecars stopped in images
+//! - `eventbridge`: publishes an EventBridge event for every shutdown (feature `eventbridge`)
+//! - `firehose`: batched Firehose writes, flushed on shutdown (feature `firehose`)
+//! - `handler`: the coordinator run by `lambda_runtime::spawn_graceful_shutdown_handler()`,
+//!   for functions already using it
+//! - `graceful`: runs `tokio-graceful` guards and `tokio-graceful-shutdown` subsystems as
+//!   hooks (features `tokio-graceful`, `tokio-graceful-shutdown`)
+//! - `history`: the last few shutdown reports, kept in a file in `/tmp`
+//! - `honeycomb`: waits for `libhoney` to send its pending events (feature `libhoney`)
+//! - `http`: tears down HTTP client connection pools, such as `reqwest` and `hyper` clients
+//! - `init`: extension registration started early, overlapping the rest of the cold start,
+//!   connections warmed up before the first invocation, and how long each step of the Init
+//!   phase took
+//! - `kafka`: flushes `rdkafka` producers (feature `rdkafka`)
+//! - `kinesis`: batched Kinesis writes, flushed on shutdown (feature `kinesis`)
+//! - `logging`: a `tracing` subscriber following Lambda's log level and format settings
+//!   (feature `logging`)
+//! - `loki`: log lines shipped to Loki or a Vector sidecar over HTTP, flushed on shutdown
+//!   (feature `loki`)
+//! - `memcached`: closes `async-memcached` connections (feature `memcached`)
+//! - `mqtt`: disconnects `rumqttc` MQTT clients cleanly, e.g. from AWS IoT Core
+//!   (feature `rumqttc`)
+//! - `opensearch`: bulk indexing into OpenSearch or Elasticsearch, flushed on shutdown
+//!   (feature `opensearch`)
+//! - `outbox`: side-effect messages staged during an invocation and published to SQS or SNS
+//!   together, or at shutdown (features `sqs`, `sns`)
+//! - `pool`: drains `deadpool` and `bb8` connection pools (features `deadpool`, `bb8`)
+//! - `prometheus`: a final push to a Prometheus Pushgateway (feature `prometheus`)
+//! - `queue`: an in-process work queue that persists unacknowledged items at shutdown
+//! - `redis`: closes `redis` and `fred` connections (features `redis`, `fred`)
+//! - `retry`: retries outbound calls with backoff, stopping as soon as the shutdown starts
+//! - `saga`: runs or saves the compensations of sagas a shutdown interrupts
+//! - `signal`: waits for `SIGTERM` and `SIGINT` alongside the runtime, which keeps running
+//!   while the hooks do (feature `signal`, on by default, Unix only)
+//! - `spindown`: sends the process a `SIGTERM` on request, to rehearse a spindown under
+//!   `cargo lambda watch` (feature `spindown`, Unix only)
+//! - `static_hooks`: a fixed set of hooks dispatched without boxing
+//! - `stdout`: writes to stdout held back while the shutdown runs, and written together at the
+//!   end
+//! - `s3`: completes or aborts S3 multipart uploads left in progress, and a checkpoint store
+//!   (feature `s3`)
+//! - `schedule`: hooks started at the same time when the budget is too tight to run them one
+//!   after the other
+//! - `scratch`: deletes temporary files in `/tmp`, per invocation or at shutdown
+//! - `sentry`: flushes the Sentry client (feature `sentry`)
+//! - `sfn`: settles Step Functions task tokens (feature `sfn`)
+//! - `sqlx`: closes `sqlx` connection pools (feature `sqlx`)
+//! - `sqs`: batched SQS sends, flushed on shutdown (feature `sqs`)
+//! - `sqs_batch`: returns the SQS messages a shutdown cut off as batch item failures
+//!   (feature `lambda-events`)
+//! - `statsd`: flushes `cadence` StatsD/DogStatsD clients (feature `statsd`)
+//! - `streams`: checkpoints the position in a Kinesis or DynamoDB stream when a batch is
+//!   interrupted (feature `lambda-events`)
+//! - `subprocess`: runs the handler as a child process, passing it events and relaying the
+//!   shutdown to it (feature `subprocess`, Unix only)
+//! - `supervisor`: starts sidecar processes during Init, restarts them, and stops them in order
+//!   at shutdown (feature `supervisor`, Unix only)
+//! - `testing`: emulated Lambda APIs, for testing shutdown handling with `cargo test`
+//!   (feature `testing`)
+//! - `tonic`: drains `tonic` gRPC channels, and servers run with `serve_with_shutdown()`
+//!   (feature `tonic`)
+//! - `transport`: the HTTP stack the integrations that send requests go through, with
+//!   implementations for `hyper` and `reqwest` (features `transport`, `hyper`, `reqwest`)
+//! - `trigger`: shutdowns started by the host rather than a signal, e.g. for `wasm32-wasi`
+//!   custom runtimes
+//! - `webhook`: POSTs the shutdown report to a webhook (feature `webhook`)
+//! - `websocket`: tells API Gateway WebSocket clients the server is going away
+//!   (feature `apigateway`)
+//! - `window`: micro-batches events across invocations, closing the batch early at shutdown
+//! - `xray`: X-Ray segments sent to the daemon over UDP, buffered in memory (feature `xray`)
+//!
+//! Only the `signal` and `tokio-runtime` features are on by default, so a function that just
+//! needs the coordinator doesn't build, or ship, anything else.
+//!
+//! The coordinator itself doesn't need tokio's runtime: its timers run on a
+//! [`Clock`](clock::Clock), and it only uses tokio's synchronization primitives, which work on
+//! any executor. Without the `tokio-runtime` feature it measures time with a
+//! [`ThreadClock`](clock::ThreadClock), or the clock given to
+//! [`ShutdownCoordinator::with_clock()`], so a custom runtime built on smol or async-std can
+//! use it too. The signal handling, the `chaos`, `checkpoint`, `efs`, `queue`, `retry`, `saga`
+//! and `scratch` modules, `init`'s early registration and warmups, and all the integrations need
+//! tokio's runtime, and turn the feature on.
+//!
+//! The integrations that send HTTP requests take any
+//! [`HttpClient`](transport::HttpClient), so they don't bring an HTTP stack of their own: turn
+//! on `hyper` or `reqwest` next to them for an implementation, or implement it for the client
+//! the function already has. Through `reqwest`, they use rustls, with the default `rustls-tls`
+//! feature, or the platform's TLS with `native-tls` instead of it. `native-tls-vendored` builds
+//! OpenSSL from source, for static binaries.
+//!
+//! The coordinator doesn't talk to the Runtime or Extensions APIs itself, so
+//! [`HttpClient`](transport::HttpClient) doesn't cover them: `lambda_runtime` and
+//! `lambda-extension` bring their own clients, on hyper 1.x, the same stack as the `hyper`
+//! feature.
+//!
+//! Everything builds for `x86_64-unknown-linux-musl` and `aarch64-unknown-linux-musl`, given
+//! a C compiler for the target such as the one `cargo lambda build` sets up, except the
+//! `memcached`, `prometheus` and `libhoney` features, whose crates link OpenSSL, and `rdkafka`,
+//! which builds `librdkafka`.
+
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "aws-sdk")]
+pub mod aws;
+#[cfg(feature = "http-batch")]
+pub mod batch;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(any(
+    feature = "dynamodb",
+    feature = "firehose",
+    feature = "http-batch",
+    feature = "kinesis",
+    feature = "loki",
+    feature = "opensearch",
+    feature = "sqs"
+))]
+mod buffer;
+#[cfg(feature = "tokio-runtime")]
+pub mod chaos;
+#[cfg(feature = "tokio-runtime")]
+pub mod checkpoint;
+pub mod clock;
+mod coordinator;
+#[cfg(feature = "debug-server")]
+pub mod debug;
+#[cfg(feature = "sqs")]
+pub mod dlq;
+#[cfg(feature = "dynamodb")]
+pub mod dynamodb;
+#[cfg(feature = "tokio-runtime")]
+pub mod efs;
+pub mod emf;
+pub mod environment;
+#[cfg(feature = "eventbridge")]
+pub mod eventbridge;
+#[cfg(feature = "firehose")]
+pub mod firehose;
+#[cfg(any(feature = "tokio-graceful", feature = "tokio-graceful-shutdown"))]
+pub mod graceful;
+pub mod handler;
+pub mod history;
+#[cfg(feature = "libhoney")]
+pub mod honeycomb;
+mod hook;
+pub mod http;
+pub mod init;
+#[cfg(feature = "rdkafka")]
+pub mod kafka;
+#[cfg(feature = "kinesis")]
+pub mod kinesis;
+mod lifecycle;
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(feature = "loki")]
+pub mod loki;
+#[cfg(feature = "memcached")]
+pub mod memcached;
+#[cfg(feature = "rumqttc")]
+pub mod mqtt;
+#[cfg(feature = "opensearch")]
+pub mod opensearch;
+#[cfg(any(feature = "sns", feature = "sqs"))]
+pub mod outbox;
+#[cfg(any(feature = "bb8", feature = "deadpool"))]
+pub mod pool;
+#[cfg(feature = "tokio-runtime")]
+pub mod queue;
+#[cfg(any(
+    feature = "dynamodb",
+    feature = "firehose",
+    feature = "kinesis",
+    feature = "sqs"
+))]
+mod records;
+mod report;
+#[cfg(feature = "tokio-runtime")]
+pub mod retry;
+#[cfg(feature = "tokio-runtime")]
+pub mod saga;
+pub mod schedule;
+#[cfg(feature = "tokio-runtime")]
+pub mod scratch;
+#[cfg(all(unix, feature = "signal"))]
+pub mod signal;
+#[cfg(all(unix, feature = "spindown"))]
+pub mod spindown;
+pub mod static_hooks;
+pub mod stdout;
+#[cfg(all(unix, feature = "subprocess"))]
+pub mod subprocess;
+#[cfg(all(unix, feature = "supervisor"))]
+pub mod supervisor;
+#[cfg(not(lambda_graceful_shutdown_loom))]
+mod sync;
+#[cfg(lambda_graceful_shutdown_loom)]
+#[doc(hidden)]
+pub mod sync;
+pub mod window;
+#[cfg(feature = "xray")]
+pub mod xray;
+
+#[cfg(feature = "tracing-appender")]
+pub mod appender;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(any(feature = "redis", feature = "fred"))]
+pub mod redis;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sentry")]
+pub mod sentry;
+#[cfg(feature = "sfn")]
+pub mod sfn;
+#[cfg(feature = "sqlx")]
+pub mod sqlx;
+#[cfg(feature = "sqs")]
+pub mod sqs;
+#[cfg(feature = "lambda-events")]
+pub mod sqs_batch;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+#[cfg(feature = "lambda-events")]
+pub mod streams;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tonic")]
+pub mod tonic;
+#[cfg(feature = "transport")]
+pub mod transport;
+pub mod trigger;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+#[cfg(feature = "apigateway")]
+pub mod websocket;
+
+pub use coordinator::{
+    InvocationGuard, ShutdownContext, ShutdownCoordinator, ShutdownHandle, ShutdownReason,
+    DEFAULT_BUDGET,
+};
+pub use hook::{hook_fn, BoxFuture, DrainTimeout, FnHook, ShutdownHook};
+pub use report::{
+    HookOutcome, HookReport, InvocationDrain, SandboxStats, ShutdownCounters, ShutdownReport,
+};
+
+#[cfg(all(feature = "macros", feature = "testing"))]
+pub use lambda_graceful_shutdown_macros::lambda_shutdown_test;
+#[cfg(feature = "macros")]
+pub use lambda_graceful_shutdown_macros::shutdown_hook;
+
+/// Error type returned by shutdown hooks.
+///
+/// This is the same boxed error used by `lambda_runtime`, so hooks can use `?` freely.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Items used by the code generated by [`shutdown_hook`].
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use tracing;
+
+    use crate::Error;
+
+    /// What a `#[shutdown_hook]` function can return.
+    pub trait IntoHookResult {
+        fn into_hook_result(self) -> Result<(), Error>;
+    }
+
+    impl IntoHookResult for () {
+        fn into_hook_result(self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl<E: Into<Error>> IntoHookResult for Result<(), E> {
+        fn into_hook_result(self) -> Result<(), Error> {
+            self.map_err(Into::into)
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Lifecycle records written while the shutdown runs.
+//!
+//! These go to stdout rather than through `tracing`: the hook flushing the log writer usually
+//! runs last, and anything logged through it afterwards is lost. They are held back until the
+//! shutdown is over, see the [`stdout`](crate::stdout) module.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::{stdout::write_line, HookOutcome, HookReport, ShutdownReason, ShutdownReport};
+
+/// How lifecycle records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LifecycleFormat {
+    /// One JSON object per line, for functions with `AWS_LAMBDA_LOG_FORMAT=JSON`.
+    Json,
+    /// One `[shutdown]` line per record.
+    Text,
+}
+
+/// Where lifecycle records go.
+#[derive(Debug, Clone)]
+pub(crate) enum Lifecycle {
+    /// Written to stdout.
+    Stdout(LifecycleFormat),
+    /// Kept in memory, as JSON objects, instead.
+    #[cfg(feature = "testing")]
+    Captured(std::sync::Arc<std::sync::Mutex<Vec<Value>>>),
+}
+
+impl Lifecycle {
+    /// Write to stdout, in the format matching the function's logging configuration.
+    pub(crate) fn from_env() -> Self {
+        match std::env::var("AWS_LAMBDA_LOG_FORMAT") {
+            Ok(format) if format.eq_ignore_ascii_case("json") => {
+                Lifecycle::Stdout(LifecycleFormat::Json)
+            }
+            _ => Lifecycle::Stdout(LifecycleFormat::Text),
+        }
+    }
+
+    pub(crate) fn started(
+        &self,
+        request_id: Option<&str>,
+        reason: ShutdownReason,
+        budget: Duration,
+    ) {
+        self.write(
+            "INFO",
+            "shutdown.started",
+            request_id,
+            format!("{reason} received, shutting down"),
+            [
+                ("reason", json!(reason.to_string())),
+                ("budget_ms", json!(budget.as_millis() as u64)),
+            ],
+        );
+    }
+
+    pub(crate) fn hook_finished(
+        &self,
+        request_id: Option<&str>,
+        report: &HookReport,
+        remaining: Duration,
+        slow: bool,
+    ) {
+        let level = match report.outcome {
+            HookOutcome::Completed if !slow => "INFO",
+            _ => "WARN",
+        };
+        self.write(
+            level,
+            "shutdown.hook_finished",
+            request_id,
+            format!("shutdown hook {} {}", report.name, report.outcome),
+            [
+                ("hook", json!(report.name)),
+                ("outcome", json!(report.outcome.to_string())),
+                ("elapsed_ms", json!(report.elapsed.as_millis() as u64)),
+                ("remaining_ms", json!(remaining.as_millis() as u64)),
+                ("slow", json!(slow)),
+            ],
+        );
+    }
+
+    pub(crate) fn completed(&self, report: &ShutdownReport) {
+        let counters = report.counters();
+        self.write(
+            if report.is_clean() { "INFO" } else { "WARN" },
+            "shutdown.completed",
+            report.request_id.as_deref(),
+            report.to_string(),
+            [
+                ("elapsed_ms", json!(report.elapsed.as_millis() as u64)),
+                (
+                    "hooks_failed",
+                    json!(counters.hooks_failed + counters.hooks_skipped),
+                ),
+                ("hooks_timed_out", json!(counters.hooks_timed_out)),
+                ("drains_timed_out", json!(counters.drains_timed_out)),
+                ("budget_exceeded", json!(counters.budget_exceeded)),
+                (
+                    "invocations_in_flight",
+                    json!(report.invocations.map(|drain| drain.in_flight)),
+                ),
+                (
+                    "invocations_interrupted",
+                    json!(report.invocations.map(|drain| drain.interrupted)),
+                ),
+                (
+                    "invocation_drain_ms",
+                    json!(report
+                        .invocations
+                        .map(|drain| drain.elapsed.as_millis() as u64)),
+                ),
+            ],
+        );
+    }
+
+    fn write<const N: usize>(
+        &self,
+        level: &str,
+        event_type: &str,
+        request_id: Option<&str>,
+        message: String,
+        fields: [(&str, Value); N],
+    ) {
+        let record = || {
+            let mut record = Map::new();
+            record.insert("timestamp".to_owned(), json!(timestamp()));
+            record.insert("level".to_owned(), json!(level));
+            record.insert("event_type".to_owned(), json!(event_type));
+            if let Some(request_id) = request_id {
+                record.insert("requestId".to_owned(), json!(request_id));
+            }
+            record.insert("message".to_owned(), json!(message));
+            for (name, value) in fields {
+                record.insert(name.to_owned(), value);
+            }
+            Value::Object(record)
+        };
+        match self {
+            Lifecycle::Stdout(LifecycleFormat::Json) => write_line(record().to_string()),
+            Lifecycle::Stdout(LifecycleFormat::Text) => write_line(format!("[shutdown] {message}")),
+            #[cfg(feature = "testing")]
+            Lifecycle::Captured(records) => records.lock().unwrap().push(record()),
+        }
+    }
+}
+
+/// The current time in RFC 3339 format, in UTC with millisecond precision.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since the epoch, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        now.subsec_millis()
+    )
+}
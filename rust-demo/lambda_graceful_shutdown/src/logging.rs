@@ -0,0 +1,158 @@
+//! A `tracing` subscriber set up from Lambda's advanced logging controls.
+//!
+//! Lambda tells the function which log level and format it was configured with through the
+//! `AWS_LAMBDA_LOG_LEVEL` and `AWS_LAMBDA_LOG_FORMAT` environment variables. [`Logging`] reads
+//! them, falls back to `RUST_LOG` for the level like `lambda_runtime` does, and lets the
+//! function override either.
+//!
+//! The shutdown hooks log what they do at `debug`, which is usually filtered out. Set
+//! `LAMBDA_GRACEFUL_SHUTDOWN_LOG_LEVEL=debug` on a function, or call
+//! [`with_shutdown_level()`](Logging::with_shutdown_level), to see those logs without turning
+//! on debug logging for everything else.
+
+use std::{env, str::FromStr};
+
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt::MakeWriter,
+};
+
+use crate::Error;
+
+/// The target of everything this crate logs.
+const SHUTDOWN_TARGET: &str = "lambda_graceful_shutdown";
+
+/// How log lines are formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The default `tracing-subscriber` format.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Settings for the `tracing` subscriber installed by [`init()`](Logging::init).
+#[derive(Debug, Clone)]
+pub struct Logging {
+    level: LevelFilter,
+    format: LogFormat,
+    shutdown_level: Option<LevelFilter>,
+    directives: Vec<String>,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+impl Logging {
+    /// Settings from the function's environment.
+    ///
+    /// - The level is `AWS_LAMBDA_LOG_LEVEL` if set. Otherwise `RUST_LOG` is used as a filter,
+    ///   on top of the `INFO` level.
+    /// - The format is JSON when `AWS_LAMBDA_LOG_FORMAT` is `JSON`, text otherwise.
+    /// - This crate's logs use `LAMBDA_GRACEFUL_SHUTDOWN_LOG_LEVEL` if set.
+    pub fn from_env() -> Self {
+        let mut directives = Vec::new();
+        let level = match env::var("AWS_LAMBDA_LOG_LEVEL") {
+            Ok(level) => parse_level(&level).unwrap_or(LevelFilter::INFO),
+            Err(_) => {
+                if let Ok(rust_log) = env::var("RUST_LOG") {
+                    directives.push(rust_log);
+                }
+                LevelFilter::INFO
+            }
+        };
+        let format = match env::var("AWS_LAMBDA_LOG_FORMAT") {
+            Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        };
+        let shutdown_level = env::var("LAMBDA_GRACEFUL_SHUTDOWN_LOG_LEVEL")
+            .ok()
+            .and_then(|level| parse_level(&level));
+        Self {
+            level,
+            format,
+            shutdown_level,
+            directives,
+        }
+    }
+
+    /// Log events at `level` and above, regardless of the environment.
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Format log lines as `format`, regardless of the environment.
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Log this crate's events, including what each shutdown hook does, at `level` and above.
+    pub fn with_shutdown_level(mut self, level: LevelFilter) -> Self {
+        self.shutdown_level = Some(level);
+        self
+    }
+
+    /// Add a filter directive, such as `aws_smithy_runtime=warn`, in the `RUST_LOG` syntax.
+    ///
+    /// Invalid directives are ignored.
+    pub fn with_directive(mut self, directive: impl Into<String>) -> Self {
+        self.directives.push(directive.into());
+        self
+    }
+
+    /// Install the subscriber as the global default, writing to `writer`.
+    ///
+    /// Lines have no timestamp or target, since CloudWatch records when each line was written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a global subscriber is already installed. See [`try_init()`](Self::try_init).
+    pub fn init<W>(self, writer: W)
+    where
+        W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+    {
+        self.try_init(writer)
+            .expect("failed to install the tracing subscriber");
+    }
+
+    /// Like [`init()`](Self::init), but returns an error if a global subscriber is already
+    /// installed.
+    pub fn try_init<W>(self, writer: W) -> Result<(), Error>
+    where
+        W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+    {
+        let builder = tracing_subscriber::fmt()
+            .with_target(false)
+            .without_time()
+            .with_env_filter(self.filter())
+            .with_writer(writer);
+        match self.format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().try_init(),
+        }
+    }
+
+    fn filter(&self) -> EnvFilter {
+        let mut directives = self.directives.clone();
+        // Later directives for the same target win, so this overrides anything in `RUST_LOG`
+        if let Some(level) = self.shutdown_level {
+            directives.push(format!("{SHUTDOWN_TARGET}={level}"));
+        }
+        EnvFilter::builder()
+            .with_default_directive(self.level.into())
+            .parse_lossy(directives.join(","))
+    }
+}
+
+/// Parse a log level the way Lambda names them, which includes `FATAL`.
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    if level.eq_ignore_ascii_case("fatal") {
+        return Some(LevelFilter::ERROR);
+    }
+    LevelFilter::from_str(level).ok()
+}
@@ -0,0 +1,407 @@
+//! Shipping log lines to Loki, or to a Vector sidecar, over HTTP.
+//!
+//! Log lines pushed over HTTP are batched, and the last batch is lost at spindown unless it is
+//! sent and acknowledged first. [`LogShipper`] sends a batch once it is big enough, and its
+//! [`flush_hook()`](LogShipper::flush_hook) sends whatever is left at shutdown, waiting for a
+//! `2xx` within the budget. Lines that could not be delivered are counted, and the count is
+//! added to the [`ShutdownReport`](crate::ShutdownReport).
+//!
+//! Requests go through an [`HttpClient`], so set up authentication or a tenant header like
+//! `X-Scope-OrgID` on the client, for example with default headers.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    buffer::BatchBuffer,
+    clock::Clock,
+    transport::{self, HttpClient},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// Default limits on the size of one request.
+const DEFAULT_MAX_BATCH_LINES: usize = 1000;
+const DEFAULT_MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// A rough allowance for the timestamp and JSON punctuation around each line.
+const LINE_OVERHEAD: usize = 32;
+
+/// Where log lines are sent, and how the request body is laid out.
+#[derive(Clone, Debug)]
+pub enum LogFormat {
+    /// Loki's push API, with every line in one stream with these labels. The URL is that of
+    /// the Loki server, like `http://loki:3100`.
+    Loki {
+        /// The stream's labels, such as `("service", "checkout")`.
+        labels: Vec<(String, String)>,
+    },
+    /// Newline-delimited JSON objects with `timestamp` (milliseconds since the epoch) and
+    /// `message` fields, for Vector's `http_server` source with the `json` codec. The URL is
+    /// that of the source, like `http://127.0.0.1:8080`.
+    Ndjson,
+}
+
+struct LogLine {
+    timestamp: Duration,
+    line: String,
+}
+
+struct Inner {
+    client: Arc<dyn HttpClient>,
+    url: String,
+    format: LogFormat,
+    buffer: Mutex<BatchBuffer<LogLine>>,
+    dropped: AtomicUsize,
+}
+
+/// Sends log lines to Loki or Vector, in batches.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+#[derive(Clone)]
+pub struct LogShipper {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for LogShipper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogShipper")
+            .field("url", &self.inner.url)
+            .field("format", &self.inner.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogShipper {
+    /// Create a shipper sending lines in `format` to the server at `url`.
+    pub fn new(client: impl HttpClient, url: impl AsRef<str>, format: LogFormat) -> Self {
+        Self::with_limits(
+            client,
+            url,
+            format,
+            DEFAULT_MAX_BATCH_LINES,
+            DEFAULT_MAX_BATCH_BYTES,
+        )
+    }
+
+    /// Create a shipper that sends a request once it has `max_lines` lines, or about
+    /// `max_bytes` of request body.
+    pub fn with_limits(
+        client: impl HttpClient,
+        url: impl AsRef<str>,
+        format: LogFormat,
+        max_lines: usize,
+        max_bytes: usize,
+    ) -> Self {
+        let url = url.as_ref().trim_end_matches('/');
+        let url = match format {
+            LogFormat::Loki { .. } => format!("{url}/loki/api/v1/push"),
+            LogFormat::Ndjson => url.to_owned(),
+        };
+        Self {
+            inner: Arc::new(Inner {
+                client: Arc::new(client),
+                url,
+                format,
+                buffer: Mutex::new(BatchBuffer::new(max_lines, max_bytes)),
+                dropped: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Queue `line`, timestamped now, sending a request if this fills one up.
+    pub async fn push(&self, line: impl Into<String>) -> Result<(), Error> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let line = line.into();
+        let size = line.len() + LINE_OVERHEAD;
+
+        let mut buffer = self.inner.buffer.lock().await;
+        if size > buffer.max_bytes() {
+            return Err(format!(
+                "line is {size} bytes, requests are limited to {}",
+                buffer.max_bytes()
+            )
+            .into());
+        }
+        buffer.push(LogLine { timestamp, line }, size);
+        while buffer.has_full_batch() {
+            self.inner.send_batch(&mut buffer, None).await?;
+        }
+        Ok(())
+    }
+
+    /// The number of lines waiting to be sent.
+    pub async fn pending(&self) -> usize {
+        self.inner.buffer.lock().await.len()
+    }
+
+    /// The number of lines the server rejected, which are not sent again.
+    pub fn dropped(&self) -> usize {
+        self.inner.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Send every queued line.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let mut buffer = self.inner.buffer.lock().await;
+        self.inner.flush(&mut buffer, None).await
+    }
+
+    /// A hook that sends any queued lines when the environment shuts down.
+    pub fn flush_hook(&self) -> LogFlushHook {
+        LogFlushHook {
+            shipper: self.clone(),
+        }
+    }
+}
+
+impl Inner {
+    /// Send every queued line, giving up on requests still running at `deadline`, on the
+    /// clock given with it.
+    async fn flush(
+        &self,
+        buffer: &mut BatchBuffer<LogLine>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            self.send_batch(buffer, deadline).await?;
+        }
+        Ok(())
+    }
+
+    /// Send one batch. If it fails, the batch is put back in the buffer, unless the server
+    /// rejected it as invalid, in which case it is dropped.
+    async fn send_batch(
+        &self,
+        buffer: &mut BatchBuffer<LogLine>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> Result<(), Error> {
+        let batch = buffer.take_batch();
+        let (content_type, body) = match &self.format {
+            LogFormat::Loki { labels } => {
+                let labels: Map<String, Value> = labels
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone().into()))
+                    .collect();
+                let values: Vec<_> = batch
+                    .iter()
+                    .map(|(log, _)| json!([log.timestamp.as_nanos().to_string(), log.line]))
+                    .collect();
+                let body = json!({ "streams": [{ "stream": labels, "values": values }] });
+                ("application/json", body.to_string())
+            }
+            LogFormat::Ndjson => {
+                let body: String = batch
+                    .iter()
+                    .map(|(log, _)| {
+                        let line = json!({
+                            "timestamp": log.timestamp.as_millis(),
+                            "message": log.line,
+                        });
+                        format!("{line}\n")
+                    })
+                    .collect();
+                ("application/x-ndjson", body)
+            }
+        };
+
+        let sent = transport::post(&*self.client, &self.url, content_type, body, deadline);
+        let error: Error = match sent.await {
+            Ok(response) if response.is_success() => return Ok(()),
+            Ok(response) if response.is_rejected() => {
+                self.dropped.fetch_add(batch.len(), Ordering::SeqCst);
+                return Err(format!(
+                    "{} lines were rejected: the server responded with {}",
+                    batch.len(),
+                    response.status
+                )
+                .into());
+            }
+            Ok(response) => format!("the server responded with {}", response.status).into(),
+            Err(error) => error,
+        };
+        buffer.requeue(batch);
+        Err(error)
+    }
+}
+
+/// A [`ShutdownHook`] that sends the lines still buffered in a [`LogShipper`].
+///
+/// The lines it could not deliver are counted in the hook's notes, along with any rejected
+/// earlier.
+#[derive(Debug)]
+pub struct LogFlushHook {
+    shipper: LogShipper,
+}
+
+impl ShutdownHook for LogFlushHook {
+    fn name(&self) -> &str {
+        "loki"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let inner = &self.shipper.inner;
+            let mut buffer = inner.buffer.lock().await;
+            let pending = buffer.len();
+            let rejected_before = inner.dropped.load(Ordering::SeqCst);
+            let flushed = inner
+                .flush(&mut buffer, Some((ctx.clock(), ctx.drain_deadline())))
+                .await;
+
+            let left = buffer.len();
+            let dropped = inner.dropped.fetch_add(left, Ordering::SeqCst) + left;
+            let sent = pending - (dropped - rejected_before);
+            ctx.note(format!(
+                "sent {sent} of {pending} pending lines, {dropped} dropped in total"
+            ));
+            flushed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex as StdMutex};
+
+    use super::*;
+    use crate::{
+        transport::{HttpRequest, HttpResponse},
+        HookOutcome, ShutdownCoordinator, ShutdownReason,
+    };
+
+    /// Answers each request with the next scripted status, and with `204` once they run out.
+    /// Records the requests.
+    #[derive(Default)]
+    struct FakeServer {
+        statuses: StdMutex<VecDeque<u16>>,
+        requests: StdMutex<Vec<HttpRequest>>,
+    }
+
+    impl FakeServer {
+        fn responding(statuses: impl IntoIterator<Item = u16>) -> Arc<Self> {
+            Arc::new(Self {
+                statuses: StdMutex::new(statuses.into_iter().collect()),
+                ..Self::default()
+            })
+        }
+
+        fn bodies(&self) -> Vec<Value> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| serde_json::from_slice(&request.body).unwrap())
+                .collect()
+        }
+    }
+
+    impl HttpClient for FakeServer {
+        fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+            self.requests.lock().unwrap().push(request);
+            let status = self.statuses.lock().unwrap().pop_front().unwrap_or(204);
+            Box::pin(async move {
+                Ok(HttpResponse {
+                    status,
+                    body: Vec::new(),
+                })
+            })
+        }
+    }
+
+    fn loki() -> LogFormat {
+        LogFormat::Loki {
+            labels: vec![("service".to_owned(), "checkout".to_owned())],
+        }
+    }
+
+    #[tokio::test]
+    async fn loki_batches_are_pushed_as_one_stream() {
+        let server = FakeServer::responding([]);
+        let shipper = LogShipper::with_limits(server.clone(), "http://loki:3100/", loki(), 2, 1024);
+        shipper.push("first").await.unwrap();
+        assert_eq!(shipper.pending().await, 1);
+        shipper.push("second").await.unwrap();
+        assert_eq!(shipper.pending().await, 0);
+
+        let request = server.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.url, "http://loki:3100/loki/api/v1/push");
+        assert_eq!(request.content_type, "application/json");
+        let body = &server.bodies()[0];
+        assert_eq!(
+            body["streams"][0]["stream"],
+            json!({ "service": "checkout" })
+        );
+        let values = body["streams"][0]["values"].as_array().unwrap();
+        let lines: Vec<_> = values.iter().map(|value| &value[1]).collect();
+        assert_eq!(lines, ["first", "second"]);
+        assert!(values[0][0].as_str().unwrap().parse::<u128>().is_ok());
+
+        assert!(shipper.push("x".repeat(1024)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ndjson_batches_have_one_object_per_line() {
+        let server = FakeServer::responding([]);
+        let shipper = LogShipper::new(server.clone(), "http://127.0.0.1:8080", LogFormat::Ndjson);
+        shipper.push("first").await.unwrap();
+        shipper.push("second").await.unwrap();
+        shipper.flush().await.unwrap();
+
+        let request = server.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.url, "http://127.0.0.1:8080");
+        assert_eq!(request.content_type, "application/x-ndjson");
+        let lines: Vec<Value> = String::from_utf8(request.body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["message"], "second");
+        assert!(lines[1]["timestamp"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn rejected_lines_are_dropped_and_failed_ones_kept() {
+        let server = FakeServer::responding([400, 503]);
+        let shipper = LogShipper::new(server.clone(), "http://loki:3100", loki());
+        shipper.push("rejected").await.unwrap();
+        assert!(shipper.flush().await.is_err());
+        assert_eq!((shipper.pending().await, shipper.dropped()), (0, 1));
+
+        shipper.push("kept").await.unwrap();
+        assert!(shipper.flush().await.is_err());
+        assert_eq!((shipper.pending().await, shipper.dropped()), (1, 1));
+        shipper.flush().await.unwrap();
+        assert_eq!(shipper.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn the_hook_counts_the_lines_it_could_not_deliver() {
+        let server = FakeServer::responding([400, 503, 503]);
+        let shipper = LogShipper::with_limits(server.clone(), "http://loki:3100", loki(), 1, 1024);
+        assert!(shipper.push("rejected").await.is_err());
+        assert!(shipper.push("undelivered").await.is_err());
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(shipper.flush_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::Failed("the server responded with 503".to_owned())
+        );
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent 0 of 1 pending lines, 2 dropped in total"]
+        );
+        assert_eq!(shipper.dropped(), 2);
+    }
+}
@@ -0,0 +1,78 @@
+//! Closing [`async_memcached`] connections, e.g. to ElastiCache.
+//!
+//! When the environment is torn down mid-command, memcached sees a connection reset rather
+//! than a close, and with enough environments spinning down at once that shows up as
+//! connection churn on the cluster. [`SharedMemcached`] serialises access to a connection, so
+//! its [`close_hook()`](SharedMemcached::close_hook) can wait for the command in progress to
+//! be answered and then close the connection cleanly.
+
+use std::{fmt, sync::Arc};
+
+use async_memcached::Client;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// A memcached connection, shared between tasks, that can be closed at shutdown.
+///
+/// Cloning is cheap, and all clones share the same connection.
+#[derive(Clone)]
+pub struct SharedMemcached {
+    client: Arc<Mutex<Option<Client>>>,
+}
+
+impl fmt::Debug for SharedMemcached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedMemcached").finish_non_exhaustive()
+    }
+}
+
+impl SharedMemcached {
+    /// Share `client`.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client: Arc::new(Mutex::new(Some(client))),
+        }
+    }
+
+    /// Connect to the server at `dsn`, like `tcp://10.0.0.1:11211`.
+    pub async fn connect(dsn: impl AsRef<str>) -> Result<Self, Error> {
+        Ok(Self::new(Client::new(dsn).await?))
+    }
+
+    /// Get exclusive use of the connection, or `None` once it has been closed.
+    ///
+    /// Hold on to the guard for as long as the commands you run on it, so the close hook
+    /// doesn't close the connection between a command and its response.
+    pub async fn lock(&self) -> Option<MappedMutexGuard<'_, Client>> {
+        MutexGuard::try_map(self.client.lock().await, Option::as_mut).ok()
+    }
+
+    /// A hook that closes the connection once the command in progress has been answered.
+    pub fn close_hook(&self) -> MemcachedCloseHook {
+        MemcachedCloseHook {
+            client: self.clone(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that closes the connection in a [`SharedMemcached`].
+#[derive(Debug)]
+pub struct MemcachedCloseHook {
+    client: SharedMemcached,
+}
+
+impl ShutdownHook for MemcachedCloseHook {
+    fn name(&self) -> &str {
+        "memcached"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            // Dropping the client closes the socket. Nothing is left unread on it once the
+            // lock is ours, so the server sees a regular close rather than a reset.
+            drop(self.client.client.lock().await.take());
+            Ok(())
+        })
+    }
+}
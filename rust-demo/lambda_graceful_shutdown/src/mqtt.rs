@@ -0,0 +1,129 @@
+//! Disconnecting [`rumqttc`] MQTT clients cleanly, e.g. from AWS IoT Core.
+//!
+//! When a client's connection drops without a `DISCONNECT` packet, the broker publishes the
+//! client's last will, telling everyone subscribed to it that the device went away
+//! unexpectedly. QoS 1 messages that were sent but not yet acknowledged are lost as well.
+//!
+//! [`MqttDisconnectHook::spawn()`] runs the client's event loop, and at shutdown waits for the
+//! outstanding QoS 1 acknowledgements before sending a clean `DISCONNECT`.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rumqttc::{AsyncClient, Event, EventLoop, Outgoing};
+use tokio::task::JoinHandle;
+
+use crate::{clock, BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// How often to check whether the outstanding acknowledgements have arrived.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long to wait before polling the event loop again after a connection error; polling
+/// reconnects.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Part of the budget kept back for sending the `DISCONNECT`, if acknowledgements are slow.
+const DISCONNECT_RESERVE: Duration = Duration::from_millis(50);
+
+struct State {
+    draining: AtomicBool,
+    inflight: AtomicU16,
+}
+
+/// A [`ShutdownHook`] that disconnects a `rumqttc` client cleanly.
+pub struct MqttDisconnectHook {
+    client: AsyncClient,
+    state: Arc<State>,
+    event_loop: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl fmt::Debug for MqttDisconnectHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttDisconnectHook")
+            .field("inflight", &self.state.inflight.load(Ordering::SeqCst))
+            .finish_non_exhaustive()
+    }
+}
+
+impl MqttDisconnectHook {
+    /// Spawn a task that polls `event_loop`, passing each event to `on_event`, and return a
+    /// hook that disconnects `client` at shutdown.
+    ///
+    /// Connection errors are logged, and the event loop is polled again after a second, which
+    /// reconnects to the broker.
+    pub fn spawn<F>(client: AsyncClient, mut event_loop: EventLoop, mut on_event: F) -> Self
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        let state = Arc::new(State {
+            draining: AtomicBool::new(false),
+            inflight: AtomicU16::new(0),
+        });
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(event) => on_event(event),
+                    Err(error) if task_state.draining.load(Ordering::SeqCst) => {
+                        tracing::warn!(%error, "MQTT connection failed during shutdown");
+                        break;
+                    }
+                    Err(error) => {
+                        tracing::warn!(%error, "MQTT connection failed, reconnecting");
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+                task_state
+                    .inflight
+                    .store(event_loop.state.inflight(), Ordering::SeqCst);
+            }
+        });
+        Self {
+            client,
+            state,
+            event_loop: Mutex::new(Some(task)),
+        }
+    }
+}
+
+impl ShutdownHook for MqttDisconnectHook {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.state.draining.store(true, Ordering::SeqCst);
+
+            // Messages published before now are sent ahead of the DISCONNECT, since requests
+            // are handled in order, but wait for the ones already sent to be acknowledged.
+            let clock = ctx.clock();
+            let acked = clock::timeout_at(clock, ctx.deadline() - DISCONNECT_RESERVE, async {
+                while self.state.inflight.load(Ordering::SeqCst) > 0 {
+                    clock.sleep_until(clock.now() + POLL_INTERVAL).await;
+                }
+            })
+            .await;
+
+            self.client.disconnect().await?;
+            let event_loop = self.event_loop.lock().unwrap().take();
+            if let Some(event_loop) = event_loop {
+                event_loop.await?;
+            }
+            acked.ok_or_else(|| {
+                DrainTimeout::new(format!(
+                    "disconnected with {} QoS 1 messages unacknowledged",
+                    self.state.inflight.load(Ordering::SeqCst)
+                ))
+            })?;
+            Ok(())
+        })
+    }
+}
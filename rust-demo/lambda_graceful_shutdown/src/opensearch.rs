@@ -0,0 +1,337 @@
+//! Bulk indexing into OpenSearch or Elasticsearch.
+//!
+//! Sending documents one at a time is slow, so they are usually collected into a `_bulk`
+//! request body first, and that body is lost at spindown. [`BulkIndexer`] sends the body once
+//! it is big enough, and its [`flush_hook()`](BulkIndexer::flush_hook) sends whatever is left
+//! at shutdown and waits for the response within the budget.
+//!
+//! Requests go through an [`HttpClient`], so set up authentication on the client, for example
+//! with default headers. Signing requests for Amazon OpenSearch Service with SigV4 is
+//! not handled here.
+
+use std::{fmt, sync::Arc};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    buffer::BatchBuffer,
+    clock::Clock,
+    transport::{self, HttpClient},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// Default limits on the size of one `_bulk` request.
+const DEFAULT_MAX_BATCH_DOCUMENTS: usize = 1000;
+const DEFAULT_MAX_BATCH_BYTES: usize = 5 * 1024 * 1024;
+
+/// The status OpenSearch and Elasticsearch return for items rejected because the cluster is
+/// overloaded. Those are worth sending again; other failures are not.
+const TOO_MANY_REQUESTS: u64 = 429;
+
+struct Inner {
+    client: Arc<dyn HttpClient>,
+    bulk_url: String,
+    /// Action and document lines, ready to go into a request body.
+    buffer: Mutex<BatchBuffer<String>>,
+}
+
+/// Indexes documents through the `_bulk` API, in batches.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+#[derive(Clone)]
+pub struct BulkIndexer {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for BulkIndexer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BulkIndexer")
+            .field("bulk_url", &self.inner.bulk_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BulkIndexer {
+    /// Create an indexer for the cluster at `url`, such as `https://search.example.com:9200`.
+    pub fn new(client: impl HttpClient, url: impl AsRef<str>) -> Self {
+        Self::with_limits(
+            client,
+            url,
+            DEFAULT_MAX_BATCH_DOCUMENTS,
+            DEFAULT_MAX_BATCH_BYTES,
+        )
+    }
+
+    /// Create an indexer that sends a request once it has `max_documents` documents, or
+    /// `max_bytes` of request body.
+    pub fn with_limits(
+        client: impl HttpClient,
+        url: impl AsRef<str>,
+        max_documents: usize,
+        max_bytes: usize,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client: Arc::new(client),
+                bulk_url: format!("{}/_bulk", url.as_ref().trim_end_matches('/')),
+                buffer: Mutex::new(BatchBuffer::new(max_documents, max_bytes)),
+            }),
+        }
+    }
+
+    /// Queue `document` for indexing into `index`, sending a request if this fills one up.
+    pub async fn index(&self, index: &str, document: &impl Serialize) -> Result<(), Error> {
+        let action = json!({ "index": { "_index": index } });
+        let lines = format!("{action}\n{}\n", serde_json::to_string(document)?);
+        let size = lines.len();
+
+        let mut buffer = self.inner.buffer.lock().await;
+        if size > buffer.max_bytes() {
+            return Err(format!(
+                "document is {size} bytes, requests are limited to {}",
+                buffer.max_bytes()
+            )
+            .into());
+        }
+        buffer.push(lines, size);
+        while buffer.has_full_batch() {
+            self.inner.send_batch(&mut buffer, None).await?;
+        }
+        Ok(())
+    }
+
+    /// The number of documents waiting to be sent.
+    pub async fn pending(&self) -> usize {
+        self.inner.buffer.lock().await.len()
+    }
+
+    /// Send every queued document.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.inner.flush(None).await
+    }
+
+    /// A hook that sends any queued documents when the environment shuts down.
+    pub fn flush_hook(&self) -> BulkFlushHook {
+        BulkFlushHook {
+            indexer: self.clone(),
+        }
+    }
+}
+
+impl Inner {
+    /// Send every queued document, giving up on requests still running at `deadline`, on the
+    /// clock given with it.
+    async fn flush(&self, deadline: Option<(&dyn Clock, Instant)>) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        while !buffer.is_empty() {
+            self.send_batch(&mut buffer, deadline).await?;
+        }
+        Ok(())
+    }
+
+    /// Send one batch. Documents rejected because the cluster is overloaded are put back in
+    /// the buffer; any other rejected document is dropped, and reported as an error.
+    async fn send_batch(
+        &self,
+        buffer: &mut BatchBuffer<String>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> Result<(), Error> {
+        let batch = buffer.take_batch();
+        let body: String = batch.iter().map(|(lines, _)| lines.as_str()).collect();
+
+        let sent = transport::post(
+            &*self.client,
+            &self.bulk_url,
+            "application/x-ndjson",
+            body,
+            deadline,
+        );
+        let response = match sent.await {
+            Ok(response) if response.is_success() => response,
+            Ok(response) => {
+                buffer.requeue(batch);
+                return Err(format!("the cluster responded with {}", response.status).into());
+            }
+            Err(error) => {
+                buffer.requeue(batch);
+                return Err(error);
+            }
+        };
+        let response: Value = serde_json::from_slice(&response.body)?;
+        if response["errors"] != Value::Bool(true) {
+            return Ok(());
+        }
+        let items = response["items"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        let mut throttled = Vec::new();
+        let mut rejected = 0;
+        // Each item is keyed by its action, e.g. `{"index": {"status": 201, ...}}`.
+        for (document, item) in batch.into_iter().zip(items) {
+            let status = item
+                .as_object()
+                .and_then(|item| item.values().next())
+                .and_then(|result| result["status"].as_u64())
+                .unwrap_or_default();
+            match status {
+                200..=299 => {}
+                TOO_MANY_REQUESTS => throttled.push(document),
+                _ => rejected += 1,
+            }
+        }
+        let throttled_count = throttled.len();
+        buffer.requeue(throttled);
+        Err(format!(
+            "{rejected} documents were rejected, and {throttled_count} throttled ones are kept \
+             for retry"
+        )
+        .into())
+    }
+}
+
+/// A [`ShutdownHook`] that sends the documents still buffered in a [`BulkIndexer`].
+#[derive(Debug)]
+pub struct BulkFlushHook {
+    indexer: BulkIndexer,
+}
+
+impl ShutdownHook for BulkFlushHook {
+    fn name(&self) -> &str {
+        "opensearch"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(
+            self.indexer
+                .inner
+                .flush(Some((ctx.clock(), ctx.drain_deadline()))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex as StdMutex};
+
+    use super::*;
+    use crate::{
+        transport::{HttpRequest, HttpResponse},
+        ShutdownCoordinator, ShutdownReason,
+    };
+
+    /// Answers each request with the next scripted response, and with an empty `_bulk`
+    /// response once they run out. Records the requests.
+    #[derive(Default)]
+    struct FakeCluster {
+        responses: StdMutex<VecDeque<HttpResponse>>,
+        requests: StdMutex<Vec<HttpRequest>>,
+    }
+
+    impl HttpClient for FakeCluster {
+        fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+            self.requests.lock().unwrap().push(request);
+            let response = self.responses.lock().unwrap().pop_front();
+            Box::pin(async move {
+                Ok(response.unwrap_or(HttpResponse {
+                    status: 200,
+                    body: br#"{"errors": false, "items": []}"#.to_vec(),
+                }))
+            })
+        }
+    }
+
+    fn bulk_response(statuses: &[u64]) -> HttpResponse {
+        let items: Vec<_> = statuses
+            .iter()
+            .map(|status| json!({ "index": { "status": status } }))
+            .collect();
+        HttpResponse {
+            status: 200,
+            body: json!({ "errors": true, "items": items })
+                .to_string()
+                .into_bytes(),
+        }
+    }
+
+    fn bodies(cluster: &FakeCluster) -> Vec<String> {
+        cluster
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| String::from_utf8(request.body.clone()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn documents_are_sent_as_bulk_requests_once_a_batch_is_full() {
+        let cluster = Arc::new(FakeCluster::default());
+        let indexer = BulkIndexer::with_limits(cluster.clone(), "http://search:9200/", 2, 1024);
+        indexer.index("orders", &json!({ "id": 1 })).await.unwrap();
+        assert_eq!(indexer.pending().await, 1);
+        indexer.index("orders", &json!({ "id": 2 })).await.unwrap();
+        assert_eq!(indexer.pending().await, 0);
+
+        let request = cluster.requests.lock().unwrap()[0].clone();
+        assert_eq!(request.url, "http://search:9200/_bulk");
+        assert_eq!(request.content_type, "application/x-ndjson");
+        assert_eq!(
+            bodies(&cluster),
+            [concat!(
+                "{\"index\":{\"_index\":\"orders\"}}\n{\"id\":1}\n",
+                "{\"index\":{\"_index\":\"orders\"}}\n{\"id\":2}\n",
+            )]
+        );
+        assert!(indexer.index("orders", &"x".repeat(1024)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn throttled_documents_are_kept_and_rejected_ones_dropped() {
+        let cluster = Arc::new(FakeCluster::default());
+        cluster
+            .responses
+            .lock()
+            .unwrap()
+            .push_back(bulk_response(&[201, 429, 400]));
+        let indexer = BulkIndexer::new(cluster.clone(), "http://search:9200");
+        for id in 1..=3 {
+            indexer.index("orders", &json!({ "id": id })).await.unwrap();
+        }
+        let error = indexer.flush().await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1 documents were rejected, and 1 throttled ones are kept for retry"
+        );
+        assert_eq!(indexer.pending().await, 1);
+
+        indexer.flush().await.unwrap();
+        assert!(bodies(&cluster)[1].contains("{\"id\":2}"));
+        assert_eq!(indexer.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn a_failed_request_keeps_the_batch_for_the_flush_hook() {
+        let cluster = Arc::new(FakeCluster::default());
+        cluster.responses.lock().unwrap().push_back(HttpResponse {
+            status: 503,
+            body: Vec::new(),
+        });
+        let indexer = BulkIndexer::new(cluster.clone(), "http://search:9200");
+        indexer.index("orders", &json!({ "id": 1 })).await.unwrap();
+        let error = indexer.flush().await.unwrap_err();
+        assert_eq!(error.to_string(), "the cluster responded with 503");
+        assert_eq!(indexer.pending().await, 1);
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(indexer.flush_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(indexer.pending().await, 0);
+        assert_eq!(bodies(&cluster).len(), 2);
+    }
+}
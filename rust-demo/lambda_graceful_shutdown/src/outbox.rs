@@ -0,0 +1,484 @@
+//! Staging side-effect messages during an invocation and publishing them together.
+//!
+//! A handler that publishes notifications as it goes leaves some of them behind when it fails
+//! halfway, and loses them when the environment shuts down between the work and the publish.
+//! [`Outbox`] is an in-memory transactional outbox: messages are [staged](Outbox::stage) while
+//! the invocation runs, [committed](Outbox::commit) in batches of up to 10 once the response
+//! is ready, or [discarded](Outbox::discard) if the invocation fails. Its
+//! [`flush_hook()`](Outbox::flush_hook) publishes whatever is still staged at shutdown, so
+//! effects of work that completed are not lost.
+//!
+//! Messages go to SQS with [`SqsPublisher`] (feature `sqs`) or to SNS with [`SnsPublisher`]
+//! (feature `sns`).
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "sns")]
+use aws_sdk_sns::types::PublishBatchRequestEntry;
+#[cfg(feature = "sqs")]
+use aws_sdk_sqs::types::SendMessageBatchRequestEntry;
+use tokio::time::Instant;
+
+use crate::{
+    clock::{self, Clock},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// SQS and SNS both accept at most 10 messages per batch.
+const MAX_BATCH_MESSAGES: usize = 10;
+
+/// A message staged in an [`Outbox`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboxMessage {
+    body: String,
+    group_id: Option<String>,
+    deduplication_id: Option<String>,
+}
+
+impl OutboxMessage {
+    /// Create a message with `body`.
+    pub fn new(body: impl Into<String>) -> Self {
+        Self {
+            body: body.into(),
+            group_id: None,
+            deduplication_id: None,
+        }
+    }
+
+    /// Set the message group id, required by FIFO queues and topics.
+    pub fn with_group_id(mut self, group_id: impl Into<String>) -> Self {
+        self.group_id = Some(group_id.into());
+        self
+    }
+
+    /// Set the deduplication id, for FIFO queues and topics without content-based
+    /// deduplication.
+    pub fn with_deduplication_id(mut self, deduplication_id: impl Into<String>) -> Self {
+        self.deduplication_id = Some(deduplication_id.into());
+        self
+    }
+
+    /// The message body.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+}
+
+impl From<String> for OutboxMessage {
+    fn from(body: String) -> Self {
+        Self::new(body)
+    }
+}
+
+impl From<&str> for OutboxMessage {
+    fn from(body: &str) -> Self {
+        Self::new(body)
+    }
+}
+
+/// Publishes a batch of outbox messages somewhere.
+pub trait Publisher: Send + Sync {
+    /// Publish `batch`, of at most 10 messages, and return the positions of the messages that
+    /// could not be published.
+    fn publish<'a>(
+        &'a self,
+        batch: &'a [OutboxMessage],
+    ) -> BoxFuture<'a, Result<Vec<usize>, Error>>;
+}
+
+/// Publishes outbox messages to an SQS queue.
+#[cfg(feature = "sqs")]
+#[derive(Clone, Debug)]
+pub struct SqsPublisher {
+    client: aws_sdk_sqs::Client,
+    queue_url: String,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsPublisher {
+    /// Create a publisher for the queue at `queue_url`.
+    pub fn new(client: aws_sdk_sqs::Client, queue_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            queue_url: queue_url.into(),
+        }
+    }
+}
+
+#[cfg(feature = "sqs")]
+impl Publisher for SqsPublisher {
+    fn publish<'a>(
+        &'a self,
+        batch: &'a [OutboxMessage],
+    ) -> BoxFuture<'a, Result<Vec<usize>, Error>> {
+        Box::pin(async move {
+            let entries = batch
+                .iter()
+                .enumerate()
+                .map(|(i, message)| {
+                    SendMessageBatchRequestEntry::builder()
+                        .id(i.to_string())
+                        .message_body(&message.body)
+                        .set_message_group_id(message.group_id.clone())
+                        .set_message_deduplication_id(message.deduplication_id.clone())
+                        .build()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let output = self
+                .client
+                .send_message_batch()
+                .queue_url(&self.queue_url)
+                .set_entries(Some(entries))
+                .send()
+                .await?;
+            if let Some(failure) = output.failed().first() {
+                tracing::debug!(
+                    code = failure.code(),
+                    message = failure.message(),
+                    "failed to send outbox message to SQS"
+                );
+            }
+            Ok(failed_positions(
+                output.failed().iter().map(|failure| failure.id()),
+            ))
+        })
+    }
+}
+
+/// Publishes outbox messages to an SNS topic.
+#[cfg(feature = "sns")]
+#[derive(Clone, Debug)]
+pub struct SnsPublisher {
+    client: aws_sdk_sns::Client,
+    topic_arn: String,
+}
+
+#[cfg(feature = "sns")]
+impl SnsPublisher {
+    /// Create a publisher for the topic `topic_arn`.
+    pub fn new(client: aws_sdk_sns::Client, topic_arn: impl Into<String>) -> Self {
+        Self {
+            client,
+            topic_arn: topic_arn.into(),
+        }
+    }
+}
+
+#[cfg(feature = "sns")]
+impl Publisher for SnsPublisher {
+    fn publish<'a>(
+        &'a self,
+        batch: &'a [OutboxMessage],
+    ) -> BoxFuture<'a, Result<Vec<usize>, Error>> {
+        Box::pin(async move {
+            let entries = batch
+                .iter()
+                .enumerate()
+                .map(|(i, message)| {
+                    PublishBatchRequestEntry::builder()
+                        .id(i.to_string())
+                        .message(&message.body)
+                        .set_message_group_id(message.group_id.clone())
+                        .set_message_deduplication_id(message.deduplication_id.clone())
+                        .build()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let output = self
+                .client
+                .publish_batch()
+                .topic_arn(&self.topic_arn)
+                .set_publish_batch_request_entries(Some(entries))
+                .send()
+                .await?;
+            if let Some(failure) = output.failed().first() {
+                tracing::debug!(
+                    code = failure.code(),
+                    message = failure.message(),
+                    "failed to publish outbox message to SNS"
+                );
+            }
+            Ok(failed_positions(
+                output.failed().iter().map(|failure| failure.id()),
+            ))
+        })
+    }
+}
+
+/// The positions of the failed entries, whose ids are their position in the batch.
+fn failed_positions<'a>(ids: impl Iterator<Item = &'a str>) -> Vec<usize> {
+    ids.filter_map(|id| id.parse().ok()).collect()
+}
+
+struct Inner<P> {
+    publisher: P,
+    staged: Mutex<Vec<OutboxMessage>>,
+}
+
+/// Messages staged during an invocation, published together once it succeeds.
+///
+/// Cloning is cheap, and all clones share the same staged messages.
+pub struct Outbox<P> {
+    inner: Arc<Inner<P>>,
+}
+
+impl<P> Clone for Outbox<P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<P> fmt::Debug for Outbox<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("staged", &self.inner.staged.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P: Publisher> Outbox<P> {
+    /// Create an outbox that publishes with `publisher`.
+    pub fn new(publisher: P) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                publisher,
+                staged: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Stage `message`, to be published on the next commit.
+    pub fn stage(&self, message: impl Into<OutboxMessage>) {
+        self.inner.staged.lock().unwrap().push(message.into());
+    }
+
+    /// The number of messages staged and not published yet.
+    pub fn staged(&self) -> usize {
+        self.inner.staged.lock().unwrap().len()
+    }
+
+    /// Drop every staged message, when the invocation failed and its effects shouldn't be
+    /// published. Returns the number of messages dropped.
+    pub fn discard(&self) -> usize {
+        std::mem::take(&mut *self.inner.staged.lock().unwrap()).len()
+    }
+
+    /// Publish every staged message, in batches of up to 10.
+    ///
+    /// Messages that could not be published stay staged, so a later commit or the shutdown
+    /// hook tries them again. So do messages of batches still being published if this is
+    /// cancelled, e.g. by the invocation timing out.
+    pub async fn commit(&self) -> Result<(), Error> {
+        self.inner.commit(None).await.map(|_| ())
+    }
+
+    /// A hook that publishes any staged messages when the environment shuts down.
+    pub fn flush_hook(&self) -> OutboxFlushHook<P> {
+        OutboxFlushHook {
+            outbox: self.clone(),
+        }
+    }
+}
+
+impl<P: Publisher> Inner<P> {
+    /// Publish the staged messages, giving up on batches still running at `deadline`, on the
+    /// clock given with it. Returns the number of messages published.
+    ///
+    /// Messages are taken off the outbox one batch at a time, and the ones not published go
+    /// back to the front of it, also if this is dropped halfway.
+    async fn commit(&self, deadline: Option<(&dyn Clock, Instant)>) -> Result<usize, Error> {
+        let mut left = self.staged.lock().unwrap().len();
+        let mut taken = Taken {
+            staged: &self.staged,
+            unpublished: Vec::new(),
+            batch: Vec::new(),
+        };
+        let mut published = 0;
+        let mut first_error = None;
+
+        while left > 0 {
+            {
+                let mut staged = self.staged.lock().unwrap();
+                let n = left.min(MAX_BATCH_MESSAGES).min(staged.len());
+                if n == 0 {
+                    break;
+                }
+                taken.batch.extend(staged.drain(..n));
+                left -= n;
+            }
+            let publish = self.publisher.publish(&taken.batch);
+            let result = match deadline {
+                Some((clock, deadline)) => clock::timeout_at(clock, deadline, publish)
+                    .await
+                    .unwrap_or_else(|| Err("ran out of time publishing the outbox".into())),
+                None => publish.await,
+            };
+            let batch = std::mem::take(&mut taken.batch);
+            match result {
+                Ok(mut failed) => {
+                    // Positions a publisher reports twice, or that aren't in the batch, don't
+                    // count as failures
+                    failed.sort_unstable();
+                    failed.dedup();
+                    failed.retain(|&i| i < batch.len());
+                    published += batch.len() - failed.len();
+                    if !failed.is_empty() {
+                        first_error.get_or_insert_with(|| {
+                            format!("{} outbox messages were not published", failed.len())
+                        });
+                    }
+                    taken
+                        .unpublished
+                        .extend(failed.into_iter().map(|i| batch[i].clone()));
+                }
+                Err(error) => {
+                    first_error.get_or_insert_with(|| error.to_string());
+                    taken.unpublished.extend(batch);
+                    if deadline.is_some_and(|(clock, deadline)| clock.now() >= deadline) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        drop(taken);
+        match first_error {
+            Some(error) => Err(error.into()),
+            None => Ok(published),
+        }
+    }
+}
+
+/// The messages a commit has taken off the outbox and not published, which go back to the
+/// front of it when this is dropped.
+struct Taken<'a> {
+    staged: &'a Mutex<Vec<OutboxMessage>>,
+    /// Messages of earlier batches that could not be published.
+    unpublished: Vec<OutboxMessage>,
+    /// The batch being published.
+    batch: Vec<OutboxMessage>,
+}
+
+impl Drop for Taken<'_> {
+    fn drop(&mut self) {
+        if self.unpublished.is_empty() && self.batch.is_empty() {
+            return;
+        }
+        let mut back = std::mem::take(&mut self.unpublished);
+        back.append(&mut self.batch);
+        let mut staged = self.staged.lock().unwrap();
+        back.append(&mut staged);
+        *staged = back;
+    }
+}
+
+/// A [`ShutdownHook`] that publishes the messages still staged in an [`Outbox`].
+pub struct OutboxFlushHook<P> {
+    outbox: Outbox<P>,
+}
+
+impl<P> fmt::Debug for OutboxFlushHook<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboxFlushHook")
+            .field("outbox", &self.outbox)
+            .finish()
+    }
+}
+
+impl<P: Publisher + 'static> ShutdownHook for OutboxFlushHook<P> {
+    fn name(&self) -> &str {
+        "outbox"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let staged = self.outbox.staged();
+            if staged == 0 {
+                return Ok(());
+            }
+            let result = self
+                .outbox
+                .inner
+                .commit(Some((ctx.clock(), ctx.drain_deadline())))
+                .await;
+            let published = staged - self.outbox.staged().min(staged);
+            ctx.note(format!("published {published} of {staged} staged messages"));
+            result.map(|_| ())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Reports the same positions as failed for every batch.
+    struct FailingPublisher(Vec<usize>);
+
+    impl Publisher for FailingPublisher {
+        fn publish<'a>(
+            &'a self,
+            _batch: &'a [OutboxMessage],
+        ) -> BoxFuture<'a, Result<Vec<usize>, Error>> {
+            Box::pin(async { Ok(self.0.clone()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_and_unknown_failed_positions_are_ignored() {
+        let outbox = Outbox::new(FailingPublisher(vec![1, 1, 7, 0, 12]));
+        for body in ["a", "b", "c"] {
+            outbox.stage(body);
+        }
+        let error = outbox.inner.commit(None).await.unwrap_err();
+        assert_eq!(error.to_string(), "2 outbox messages were not published");
+        let staged = outbox.inner.staged.lock().unwrap();
+        let bodies: Vec<_> = staged.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["a", "b"]);
+    }
+
+    /// Publishes the first batch, and never finishes publishing the ones after it.
+    #[derive(Default)]
+    struct StallingPublisher(AtomicUsize);
+
+    impl Publisher for StallingPublisher {
+        fn publish<'a>(
+            &'a self,
+            _batch: &'a [OutboxMessage],
+        ) -> BoxFuture<'a, Result<Vec<usize>, Error>> {
+            Box::pin(async {
+                if self.0.fetch_add(1, Ordering::SeqCst) > 0 {
+                    std::future::pending::<()>().await;
+                }
+                Ok(Vec::new())
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_cancelled_commit_keeps_the_messages_it_did_not_publish() {
+        let outbox = Outbox::new(StallingPublisher::default());
+        for i in 0..25 {
+            outbox.stage(i.to_string());
+        }
+        tokio::time::timeout(Duration::from_secs(1), outbox.commit())
+            .await
+            .unwrap_err();
+        outbox.stage("late");
+
+        let staged = outbox.inner.staged.lock().unwrap();
+        let bodies: Vec<_> = staged.iter().map(|message| message.body.as_str()).collect();
+        let mut expected: Vec<_> = (10..25).map(|i| i.to_string()).collect();
+        expected.push("late".to_owned());
+        assert_eq!(bodies, expected);
+    }
+}
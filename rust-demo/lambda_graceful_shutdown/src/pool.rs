@@ -0,0 +1,269 @@
+//! Draining `deadpool` and `bb8` connection pools.
+//!
+//! Both hooks stop the pool from lending out connections, then wait for the connections that
+//! are checked out to be returned, so a query that is still running isn't cut off halfway.
+//! They work with any manager, so they cover Redis, Postgres and whatever else is pooled with
+//! these crates.
+
+use std::{fmt, time::Duration};
+
+use crate::{clock, BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// How often to check whether checked-out connections have come back.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A [`ShutdownHook`] that closes a managed [`deadpool`] pool.
+///
+/// The pool is closed right away, which drops idle connections and makes any further
+/// `get()` fail. Connections that are checked out are dropped as they are returned.
+#[cfg(feature = "deadpool")]
+pub struct DeadpoolHook<M: deadpool::managed::Manager> {
+    pool: deadpool::managed::Pool<M>,
+    timeout: Option<Duration>,
+}
+
+#[cfg(feature = "deadpool")]
+impl<M: deadpool::managed::Manager> fmt::Debug for DeadpoolHook<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadpoolHook")
+            .field("status", &self.pool.status())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[cfg(feature = "deadpool")]
+impl<M: deadpool::managed::Manager> DeadpoolHook<M> {
+    /// Create a hook for the given pool. Pools are cheap to clone, so pass in a clone of yours.
+    pub fn new(pool: deadpool::managed::Pool<M>) -> Self {
+        Self {
+            pool,
+            timeout: None,
+        }
+    }
+
+    /// Stop waiting for connections after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[cfg(feature = "deadpool")]
+impl<M: deadpool::managed::Manager> ShutdownHook for DeadpoolHook<M> {
+    fn name(&self) -> &str {
+        "deadpool"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.pool.close();
+            let timeout = ctx.drain_remaining_capped(self.timeout);
+            let clock = ctx.clock();
+            let drained = clock::timeout_at(clock, clock.now() + timeout, async {
+                while self.pool.status().size > 0 {
+                    clock.sleep_until(clock.now() + POLL_INTERVAL).await;
+                }
+            });
+            drained.await.ok_or_else(|| {
+                DrainTimeout::new(format!(
+                    "{} connections still checked out after {timeout:?}",
+                    self.pool.status().size
+                ))
+            })?;
+            Ok(())
+        })
+    }
+}
+
+/// Closes a connection the [`Bb8Hook`] checked out.
+#[cfg(feature = "bb8")]
+type Close<C> = Box<dyn for<'c> Fn(&'c mut C) -> BoxFuture<'c, ()> + Send + Sync>;
+
+/// A [`ShutdownHook`] that drains a [`bb8`] pool.
+///
+/// bb8 has no way to close a pool that other handles still point to, so instead this hook
+/// checks out every connection as it becomes idle, and keeps hold of it. That stops the
+/// pool from lending it out again, and returns once nothing else has a connection checked
+/// out. bb8 can't hand a connection over either, so each one is closed with the function
+/// given to [`with_close()`](Self::with_close) as it is checked out, or left for the process
+/// exit to cut off without one.
+#[cfg(feature = "bb8")]
+pub struct Bb8Hook<M: bb8::ManageConnection> {
+    pool: bb8::Pool<M>,
+    timeout: Option<Duration>,
+    close: Option<Close<M::Connection>>,
+    parked: tokio::sync::Mutex<Vec<bb8::PooledConnection<'static, M>>>,
+}
+
+#[cfg(feature = "bb8")]
+impl<M: bb8::ManageConnection> fmt::Debug for Bb8Hook<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bb8Hook")
+            .field("state", &self.pool.state())
+            .field("timeout", &self.timeout)
+            .field("close", &self.close.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "bb8")]
+impl<M: bb8::ManageConnection> Bb8Hook<M> {
+    /// Create a hook for the given pool. Pools are cheap to clone, so pass in a clone of yours.
+    pub fn new(pool: bb8::Pool<M>) -> Self {
+        Self {
+            pool,
+            timeout: None,
+            close: None,
+            parked: tokio::sync::Mutex::default(),
+        }
+    }
+
+    /// Stop waiting for connections after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Close each connection with `close` once it is checked in, such as by sending the
+    /// server a goodbye. The connection stays checked out by the hook afterwards, so it isn't
+    /// lent out again.
+    pub fn with_close<F>(mut self, close: F) -> Self
+    where
+        F: for<'c> Fn(&'c mut M::Connection) -> BoxFuture<'c, ()> + Send + Sync + 'static,
+    {
+        self.close = Some(Box::new(close));
+        self
+    }
+}
+
+#[cfg(feature = "bb8")]
+impl<M: bb8::ManageConnection> ShutdownHook for Bb8Hook<M> {
+    fn name(&self) -> &str {
+        "bb8"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let timeout = ctx.drain_remaining_capped(self.timeout);
+            let mut parked = self.parked.lock().await;
+            let clock = ctx.clock();
+            let drained = clock::timeout_at(clock, clock.now() + timeout, async {
+                loop {
+                    let state = self.pool.state();
+                    if state.connections as usize <= parked.len() {
+                        return;
+                    }
+                    // Only check out idle connections, otherwise `get()` would open a new one.
+                    if state.idle_connections > 0 {
+                        if let Ok(mut conn) = self.pool.get_owned().await {
+                            if let Some(close) = &self.close {
+                                close(&mut conn).await;
+                            }
+                            parked.push(conn);
+                            continue;
+                        }
+                    }
+                    clock.sleep_until(clock.now() + POLL_INTERVAL).await;
+                }
+            });
+            let drained = drained.await;
+            if self.close.is_some() {
+                ctx.note(format!("closed {} connections", parked.len()));
+            }
+            drained.ok_or_else(|| {
+                let checked_out =
+                    (self.pool.state().connections as usize).saturating_sub(parked.len());
+                DrainTimeout::new(format!(
+                    "{checked_out} connections still checked out after {timeout:?}"
+                ))
+            })?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(all(test, feature = "bb8"))]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    /// Connections that only know whether they were closed.
+    struct Manager;
+
+    impl bb8::ManageConnection for Manager {
+        type Connection = Arc<AtomicBool>;
+        type Error = std::io::Error;
+
+        async fn connect(&self) -> Result<Arc<AtomicBool>, std::io::Error> {
+            Ok(Arc::default())
+        }
+
+        async fn is_valid(&self, _conn: &mut Arc<AtomicBool>) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _conn: &mut Arc<AtomicBool>) -> bool {
+            false
+        }
+    }
+
+    fn hook(pool: &bb8::Pool<Manager>) -> Bb8Hook<Manager> {
+        Bb8Hook::new(pool.clone())
+            .with_close(|conn| Box::pin(async move { conn.store(true, Ordering::SeqCst) }))
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_as_they_are_checked_in() {
+        let pool = bb8::Pool::builder()
+            .max_size(2)
+            .build(Manager)
+            .await
+            .unwrap();
+        let idle = pool.get_owned().await.unwrap();
+        let conn = pool.get_owned().await.unwrap();
+        drop(idle);
+        let in_use = Arc::clone(&conn);
+        let query = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(conn);
+        });
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(hook(&pool))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        query.await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.hooks[0].notes, ["closed 2 connections"]);
+        assert!(in_use.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn connections_still_checked_out_are_counted() {
+        let pool = bb8::Pool::builder()
+            .max_size(2)
+            .build(Manager)
+            .await
+            .unwrap();
+        let _held = pool.get_owned().await.unwrap();
+        drop(pool.get_owned().await.unwrap());
+
+        let report = ShutdownCoordinator::new()
+            .with_budget(Duration::from_millis(100))
+            .with_hook(hook(&pool))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(matches!(
+            &report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut(error)
+                if error.starts_with("1 connections still checked out after")
+        ));
+        assert_eq!(report.hooks[0].notes, ["closed 1 connections"]);
+    }
+}
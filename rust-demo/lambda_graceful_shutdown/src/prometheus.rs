@@ -0,0 +1,133 @@
+//! A final push to a Prometheus Pushgateway.
+//!
+//! Nothing scrapes a Lambda function, so teams using Prometheus push its metrics to a
+//! Pushgateway instead. Whatever was recorded since the last push is lost at spindown, unless
+//! [`PushgatewayHook`] pushes the registry one final time.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use prometheus::{BasicAuthentication, Registry};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] that pushes a [`Registry`] to a Pushgateway.
+///
+/// Every push replaces the metrics previously pushed with the same job and grouping labels.
+/// Give each execution environment its own group, for example with
+/// [`with_lambda_labels()`](Self::with_lambda_labels), or they overwrite each other.
+pub struct PushgatewayHook {
+    url: String,
+    job: String,
+    registry: Registry,
+    grouping: Arc<Mutex<HashMap<String, String>>>,
+    basic_auth: Option<(String, String)>,
+}
+
+impl fmt::Debug for PushgatewayHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushgatewayHook")
+            .field("url", &self.url)
+            .field("job", &self.job)
+            .field("grouping", &self.grouping.lock().unwrap())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PushgatewayHook {
+    /// Create a hook that pushes `registry` to the Pushgateway at `url`, under `job`.
+    ///
+    /// `url` is the address of the Pushgateway, without the `/metrics/job/...` path.
+    pub fn new(url: impl Into<String>, job: impl Into<String>, registry: Registry) -> Self {
+        Self {
+            url: url.into(),
+            job: job.into(),
+            registry,
+            grouping: Arc::default(),
+            basic_auth: None,
+        }
+    }
+
+    /// Add a grouping label.
+    pub fn with_label(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels().set(name, value);
+        self
+    }
+
+    /// Add the `function_name` grouping label, and an `instance` label set to the
+    /// execution environment's log stream name, which is unique to it.
+    pub fn with_lambda_labels(self) -> Self {
+        let mut hook = self;
+        for (name, var) in [
+            ("function_name", "AWS_LAMBDA_FUNCTION_NAME"),
+            ("instance", "AWS_LAMBDA_LOG_STREAM_NAME"),
+        ] {
+            if let Ok(value) = std::env::var(var) {
+                hook = hook.with_label(name, value);
+            }
+        }
+        hook
+    }
+
+    /// Authenticate to the Pushgateway with HTTP basic auth.
+    pub fn with_basic_auth(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.basic_auth = Some((username.into(), password.into()));
+        self
+    }
+
+    /// A handle for setting grouping labels after the hook has been registered, for values
+    /// only known while handling invocations, like the request id of the latest one.
+    pub fn labels(&self) -> PushgatewayLabels {
+        PushgatewayLabels {
+            grouping: self.grouping.clone(),
+        }
+    }
+}
+
+/// Sets the grouping labels of a [`PushgatewayHook`] that has already been registered.
+#[derive(Debug, Clone)]
+pub struct PushgatewayLabels {
+    grouping: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl PushgatewayLabels {
+    /// Set a grouping label, replacing any previous value.
+    pub fn set(&self, name: impl Into<String>, value: impl Into<String>) {
+        self.grouping
+            .lock()
+            .unwrap()
+            .insert(name.into(), value.into());
+    }
+}
+
+impl ShutdownHook for PushgatewayHook {
+    fn name(&self) -> &str {
+        "pushgateway"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let url = self.url.clone();
+            let job = self.job.clone();
+            let grouping = self.grouping.lock().unwrap().clone();
+            let basic_auth = self
+                .basic_auth
+                .clone()
+                .map(|(username, password)| BasicAuthentication { username, password });
+            let metrics = self.registry.gather();
+            // The push goes through a blocking HTTP client.
+            tokio::task::spawn_blocking(move || {
+                prometheus::push_metrics(&job, grouping, &url, metrics, basic_auth)
+            })
+            .await??;
+            Ok(())
+        })
+    }
+}
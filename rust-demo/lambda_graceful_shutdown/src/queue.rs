@@ -0,0 +1,466 @@
+//! An in-process work queue that keeps its unfinished items across spindowns.
+//!
+//! Work handed to background tasks inside the function is lost when the environment shuts
+//! down, whether it was still queued or halfway done. [`WorkQueue`] leases items out to
+//! workers and forgets them only once they are acknowledged, so at shutdown it knows exactly
+//! which items haven't been done. Its [`checkpoint_hook()`](WorkQueue::checkpoint_hook)
+//! saves those to a [`CheckpointStore`] for the next environment to
+//! [`resume()`](WorkQueue::resume), and [`requeue_hook()`](WorkQueue::requeue_hook) hands them
+//! to a closure instead, for example to send them back to an SQS queue. Either way every item
+//! is processed at least once.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    checkpoint::{open_envelope, seal_envelope, CheckpointStore, Checkpointable},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+struct State<T> {
+    queued: VecDeque<T>,
+    leased: HashMap<u64, T>,
+    next_lease: u64,
+    closed: bool,
+}
+
+struct Inner<T> {
+    state: Mutex<State<T>>,
+    available: Notify,
+}
+
+/// A queue of work items, leased to workers and acknowledged once done.
+///
+/// Cloning is cheap, and all clones share the same queue.
+pub struct WorkQueue<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for WorkQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for WorkQueue<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queued: VecDeque::new(),
+                    leased: HashMap::new(),
+                    next_lease: 0,
+                    closed: false,
+                }),
+                available: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl<T> fmt::Debug for WorkQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.state.lock().unwrap();
+        f.debug_struct("WorkQueue")
+            .field("queued", &state.queued.len())
+            .field("leased", &state.leased.len())
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+impl<T: Clone + Send + 'static> WorkQueue<T> {
+    /// Create an empty queue.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `item` to the back of the queue, or hand it back if the queue has been closed by
+    /// its shutdown hook.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            return Err(item);
+        }
+        state.queued.push_back(item);
+        drop(state);
+        self.inner.available.notify_one();
+        Ok(())
+    }
+
+    /// Lease the item at the front of the queue, if there is one.
+    pub fn try_lease(&self) -> Option<Lease<T>> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        let item = state.queued.pop_front()?;
+        let id = state.next_lease;
+        state.next_lease += 1;
+        state.leased.insert(id, item.clone());
+        Some(Lease {
+            queue: self.inner.clone(),
+            id,
+            item: Some(item),
+        })
+    }
+
+    /// Wait for an item and lease it, or return `None` once the queue has been closed.
+    pub async fn lease(&self) -> Option<Lease<T>> {
+        loop {
+            let available = self.inner.available.notified();
+            if let Some(lease) = self.try_lease() {
+                return Some(lease);
+            }
+            if self.inner.state.lock().unwrap().closed {
+                return None;
+            }
+            available.await;
+        }
+    }
+
+    /// The number of items waiting to be leased.
+    pub fn queued(&self) -> usize {
+        self.inner.state.lock().unwrap().queued.len()
+    }
+
+    /// The number of items leased and not acknowledged yet.
+    pub fn leased(&self) -> usize {
+        self.inner.state.lock().unwrap().leased.len()
+    }
+
+    /// A hook that hands the items not acknowledged yet to `requeue` when the environment shuts
+    /// down, for example to send them back to the SQS queue they came from.
+    pub fn requeue_hook<F, Fut>(&self, requeue: F) -> WorkQueueHook<T>
+    where
+        F: Fn(Vec<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        WorkQueueHook {
+            queue: self.clone(),
+            persist: Box::new(move |items| Box::pin(requeue(items))),
+        }
+    }
+
+    /// Close the queue and take every item that hasn't been acknowledged, queued ones first.
+    fn close(&self) -> (usize, Vec<T>) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.closed = true;
+        let leased = state.leased.len();
+        let mut items: Vec<T> = state.queued.drain(..).collect();
+        let mut in_flight: Vec<_> = state
+            .leased
+            .iter()
+            .map(|(id, item)| (*id, item.clone()))
+            .collect();
+        in_flight.sort_by_key(|(id, _)| *id);
+        items.extend(in_flight.into_iter().map(|(_, item)| item));
+        drop(state);
+        self.inner.available.notify_waiters();
+        (leased, items)
+    }
+}
+
+impl<T: Checkpointable + Clone + 'static> WorkQueue<T> {
+    /// A hook that saves the items not acknowledged yet under `key` in `store` when the
+    /// environment shuts down.
+    pub fn checkpoint_hook<S>(&self, store: S, key: impl Into<String>) -> WorkQueueHook<T>
+    where
+        S: CheckpointStore + 'static,
+    {
+        let store = Arc::new(store);
+        let key: Arc<str> = key.into().into();
+        self.requeue_hook(move |items| {
+            let store = store.clone();
+            let key = key.clone();
+            async move { store.save(&key, encode(&items)?).await }
+        })
+    }
+
+    /// Queue the items saved under `key` by the checkpoint hook of an earlier environment,
+    /// then delete them from the store. Returns the number of items queued.
+    pub async fn resume(&self, store: &impl CheckpointStore, key: &str) -> Result<usize, Error> {
+        let Some(bytes) = store.load(key).await? else {
+            return Ok(0);
+        };
+        let items: Vec<T> = decode(&bytes)?;
+        let count = items.len();
+        for item in items {
+            if self.push(item).is_err() {
+                return Err("the work queue has been closed".into());
+            }
+        }
+        store.delete(key).await?;
+        Ok(count)
+    }
+}
+
+/// Encode `items` as a versioned checkpoint, each item prefixed with its length.
+fn encode<T: Checkpointable>(items: &[T]) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    for item in items {
+        let bytes = item.encode()?;
+        payload.extend_from_slice(&u32::try_from(bytes.len())?.to_be_bytes());
+        payload.extend(bytes);
+    }
+    Ok(seal_envelope(T::VERSION, payload))
+}
+
+fn decode<T: Checkpointable>(bytes: &[u8]) -> Result<Vec<T>, Error> {
+    let (version, mut payload) =
+        open_envelope(bytes).ok_or("the saved work items have no version header")?;
+    if version != T::VERSION {
+        return Err(format!(
+            "the work items were saved with version {version}, not {}",
+            T::VERSION
+        )
+        .into());
+    }
+    let mut items = Vec::new();
+    while let Some((len, rest)) = payload.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        let item = rest
+            .get(..len)
+            .ok_or("the saved work items are truncated")?;
+        items.push(T::decode(item)?);
+        payload = &rest[len..];
+    }
+    if !payload.is_empty() {
+        return Err("the saved work items are truncated".into());
+    }
+    Ok(items)
+}
+
+/// An item leased from a [`WorkQueue`].
+///
+/// Call [`ack()`](Self::ack) once the work is done. A lease dropped without being acknowledged
+/// puts the item back at the front of the queue.
+pub struct Lease<T> {
+    queue: Arc<Inner<T>>,
+    id: u64,
+    item: Option<T>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Lease<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lease").field("item", &self.item).finish()
+    }
+}
+
+impl<T> Lease<T> {
+    /// Acknowledge the item, so it is never handed out or saved again.
+    pub fn ack(mut self) -> T {
+        self.queue.state.lock().unwrap().leased.remove(&self.id);
+        self.item.take().expect("the item is only taken once")
+    }
+}
+
+impl<T> Deref for Lease<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("the item is only taken once")
+    }
+}
+
+impl<T> Drop for Lease<T> {
+    fn drop(&mut self) {
+        let Some(item) = self.item.take() else {
+            return;
+        };
+        let mut state = self.queue.state.lock().unwrap();
+        state.leased.remove(&self.id);
+        if !state.closed {
+            state.queued.push_front(item);
+            drop(state);
+            self.queue.available.notify_one();
+        }
+    }
+}
+
+type Persist<T> = Box<dyn Fn(Vec<T>) -> BoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+/// A [`ShutdownHook`] that closes a [`WorkQueue`] and persists the items it still holds.
+///
+/// Items that are leased when the hook runs are persisted too, and may end up processed twice
+/// if their worker finishes them before the environment goes away.
+pub struct WorkQueueHook<T> {
+    queue: WorkQueue<T>,
+    persist: Persist<T>,
+}
+
+impl<T> fmt::Debug for WorkQueueHook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkQueueHook")
+            .field("queue", &self.queue)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Clone + Send + 'static> ShutdownHook for WorkQueueHook<T> {
+    fn name(&self) -> &str {
+        "work-queue"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let (leased, items) = self.queue.close();
+            let count = items.len();
+            if count > 0 {
+                (self.persist)(items).await?;
+            }
+            ctx.note(format!(
+                "persisted {count} unacknowledged items, {leased} of them leased"
+            ));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{checkpoint::SerdeCheckpoint, ShutdownCoordinator, ShutdownReason};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Job(u32);
+
+    impl SerdeCheckpoint for Job {
+        const VERSION: u32 = 1;
+    }
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl CheckpointStore for MemoryStore {
+        fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+            self.0.lock().unwrap().insert(key.to_owned(), bytes);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+            let bytes = self.0.lock().unwrap().get(key).cloned();
+            Box::pin(async { Ok(bytes) })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+            self.0.lock().unwrap().remove(key);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn queue_of(jobs: impl IntoIterator<Item = u32>) -> WorkQueue<Job> {
+        let queue = WorkQueue::new();
+        for job in jobs {
+            queue.push(Job(job)).unwrap();
+        }
+        queue
+    }
+
+    #[test]
+    fn a_dropped_lease_puts_its_item_back_at_the_front() {
+        let queue = queue_of([1, 2]);
+        let lease = queue.try_lease().unwrap();
+        assert_eq!(*lease, Job(1));
+        assert_eq!((queue.queued(), queue.leased()), (1, 1));
+
+        drop(lease);
+        assert_eq!((queue.queued(), queue.leased()), (2, 0));
+        assert_eq!(queue.try_lease().unwrap().ack(), Job(1));
+        assert_eq!(queue.try_lease().unwrap().ack(), Job(2));
+        assert_eq!((queue.queued(), queue.leased()), (0, 0));
+    }
+
+    #[test]
+    fn close_takes_queued_items_then_leased_ones_in_lease_order() {
+        let queue = queue_of([1, 2, 3, 4]);
+        let first = queue.try_lease().unwrap();
+        let second = queue.try_lease().unwrap();
+        let third = queue.try_lease().unwrap();
+        third.ack();
+
+        let (leased, items) = queue.close();
+        assert_eq!(leased, 2);
+        assert_eq!(items, [Job(4), Job(1), Job(2)]);
+
+        // Once closed, nothing goes back in or comes out
+        drop((first, second));
+        assert_eq!(queue.queued(), 0);
+        assert_eq!(queue.push(Job(5)), Err(Job(5)));
+        assert!(queue.try_lease().is_none());
+    }
+
+    #[tokio::test]
+    async fn close_wakes_up_waiting_workers() {
+        let queue = WorkQueue::<Job>::new();
+        let worker = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.lease().await.is_none() }
+        });
+        tokio::task::yield_now().await;
+        queue.close();
+        assert!(worker.await.unwrap());
+    }
+
+    #[test]
+    fn encoded_items_decode_to_the_same_items() {
+        let items = [Job(1), Job(22), Job(333)];
+        let bytes = encode(&items).unwrap();
+        assert_eq!(decode::<Job>(&bytes).unwrap(), items);
+        assert_eq!(decode::<Job>(&encode::<Job>(&[]).unwrap()).unwrap(), []);
+    }
+
+    #[test]
+    fn decode_rejects_truncated_items_and_other_versions() {
+        // A 4 byte header, then each item as a 4 byte length and `1` or `2`
+        let bytes = encode(&[Job(1), Job(2)]).unwrap();
+        assert_eq!(bytes.len(), 14);
+        for len in (5..14).filter(|&len| len != 9) {
+            assert!(decode::<Job>(&bytes[..len]).is_err(), "decoded {len} bytes");
+        }
+        assert_eq!(decode::<Job>(&bytes[..9]).unwrap(), [Job(1)]);
+        assert!(decode::<Job>(&[0, 0]).is_err());
+
+        let mut other_version = bytes.clone();
+        other_version[..4].copy_from_slice(&2u32.to_be_bytes());
+        assert!(decode::<Job>(&other_version).is_err());
+    }
+
+    #[tokio::test]
+    async fn checkpointed_items_resume_in_the_next_queue() {
+        let store = Arc::new(MemoryStore::default());
+        let queue = queue_of([1, 2, 3]);
+        let _leased = queue.try_lease().unwrap();
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(queue.checkpoint_hook(store.clone(), "jobs"))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["persisted 3 unacknowledged items, 1 of them leased"]
+        );
+
+        let next = WorkQueue::<Job>::new();
+        assert_eq!(next.resume(&*store, "jobs").await.unwrap(), 3);
+        assert_eq!(next.try_lease().unwrap().ack(), Job(2));
+        assert_eq!(next.try_lease().unwrap().ack(), Job(3));
+        assert_eq!(next.try_lease().unwrap().ack(), Job(1));
+        assert!(store.0.lock().unwrap().is_empty());
+
+        // Nothing saved, nothing to resume
+        assert_eq!(next.resume(&*store, "jobs").await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,381 @@
+use std::{sync::Mutex as StdMutex, time::Duration};
+
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    buffer::BatchBuffer,
+    clock::{self, Clock},
+    BoxFuture, DrainTimeout, Error,
+};
+
+/// Backoff between retries of throttled records during a final flush.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(25);
+const MAX_BACKOFF: Duration = Duration::from_millis(200);
+
+/// A batch API that accepts a list of records and reports failures per record, like Kinesis
+/// `PutRecords`, Firehose `PutRecordBatch` and DynamoDB `BatchWriteItem`.
+pub(crate) trait RecordApi: Send + Sync + 'static {
+    type Record: Clone + Send + Sync + 'static;
+
+    /// Write a batch, returning the error code of each record that failed, by position.
+    fn put(&self, records: Vec<Self::Record>) -> BoxFuture<'_, Result<Vec<Option<String>>, Error>>;
+}
+
+/// Buffers records and writes them in batches through a [`RecordApi`].
+pub(crate) struct RecordWriter<A: RecordApi> {
+    api: A,
+    buffer: Mutex<BatchBuffer<A::Record>>,
+    /// Why the last batch could not be written in full, until one is.
+    last_error: StdMutex<Option<String>>,
+}
+
+impl<A: RecordApi> RecordWriter<A> {
+    pub(crate) fn new(api: A, max_records: usize, max_bytes: usize) -> Self {
+        Self {
+            api,
+            buffer: Mutex::new(BatchBuffer::new(max_records, max_bytes)),
+            last_error: StdMutex::new(None),
+        }
+    }
+
+    pub(crate) fn api(&self) -> &A {
+        &self.api
+    }
+
+    /// Why the last batch written could not be written in full, unless one has been since.
+    pub(crate) fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Buffer `record`, and write the full batches. Only a record that can't be buffered is an
+    /// error: records of a batch that could not be written stay buffered, and are only
+    /// reported by [`last_error()`](Self::last_error), so the caller doesn't queue them again.
+    pub(crate) async fn push(&self, record: A::Record, size: usize) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        if size > buffer.max_bytes() {
+            return Err(format!(
+                "record is {size} bytes, batches are limited to {}",
+                buffer.max_bytes()
+            )
+            .into());
+        }
+        buffer.push(record, size);
+        while buffer.has_full_batch() {
+            let Ok(failed) = self.write_batch(&mut buffer).await else {
+                break;
+            };
+            if !failed.is_empty() {
+                buffer.requeue(failed);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn pending(&self) -> usize {
+        self.buffer.lock().await.len()
+    }
+
+    /// Write everything that is buffered, giving each record one attempt.
+    pub(crate) async fn flush(&self) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        let (failed, error) = self.write_all(&mut buffer, None).await;
+        if let Some(error) = error {
+            buffer.requeue(failed);
+            return Err(error);
+        }
+        if !failed.is_empty() {
+            return Err(kept_for_retry(&mut buffer, failed));
+        }
+        Ok(())
+    }
+
+    /// Write everything that is buffered, retrying failed records with backoff until
+    /// `deadline` on `clock`. A request still running at the deadline is given up on, and its
+    /// batch kept in the buffer.
+    pub(crate) async fn flush_until(
+        &self,
+        clock: &dyn Clock,
+        deadline: Instant,
+    ) -> Result<(), Error> {
+        let mut buffer = self.buffer.lock().await;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let (failed, error) = self.write_all(&mut buffer, Some((clock, deadline))).await;
+            if failed.is_empty() {
+                return Ok(());
+            }
+            let retry_at = clock.now() + backoff;
+            if retry_at >= deadline {
+                return Err(match error {
+                    Some(error) => {
+                        buffer.requeue(failed);
+                        error
+                    }
+                    None => kept_for_retry(&mut buffer, failed),
+                });
+            }
+            buffer.requeue(failed);
+            clock.sleep_until(retry_at).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Write every buffered record once, returning the ones that failed, and the error of the
+    /// first request that failed as a whole. The batches after a failed request are still
+    /// written, each until `deadline`, on the clock given with it.
+    async fn write_all(
+        &self,
+        buffer: &mut BatchBuffer<A::Record>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> (Vec<(A::Record, usize)>, Option<Error>) {
+        let mut failed = Vec::new();
+        let mut first_error = None;
+        while !buffer.is_empty() {
+            let batch = buffer.take_batch();
+            match self.write(batch, deadline).await {
+                Ok(batch_failed) => failed.extend(batch_failed),
+                Err((error, batch)) => {
+                    first_error.get_or_insert(error);
+                    failed.extend(batch);
+                }
+            }
+        }
+        (failed, first_error)
+    }
+
+    /// Write one batch taken off the buffer, returning the records that failed. If the whole
+    /// request fails, the batch is put back in the buffer.
+    async fn write_batch(
+        &self,
+        buffer: &mut BatchBuffer<A::Record>,
+    ) -> Result<Vec<(A::Record, usize)>, Error> {
+        let batch = buffer.take_batch();
+        self.write(batch, None).await.map_err(|(error, batch)| {
+            buffer.requeue(batch);
+            error
+        })
+    }
+
+    /// Write `batch`, returning the records that failed, or the whole batch along with the
+    /// error if the request failed, or was still running at `deadline`.
+    async fn write(
+        &self,
+        batch: Vec<(A::Record, usize)>,
+        deadline: Option<(&dyn Clock, Instant)>,
+    ) -> Result<Vec<(A::Record, usize)>, (Error, Vec<(A::Record, usize)>)> {
+        let records = batch.iter().map(|(record, _)| record.clone()).collect();
+        let put = self.api.put(records);
+        let put = match deadline {
+            Some((clock, deadline)) => clock::timeout_at(clock, deadline, put)
+                .await
+                .unwrap_or_else(|| {
+                    Err(DrainTimeout::new("the request timed out at the deadline").into())
+                }),
+            None => put.await,
+        };
+        let errors = match put {
+            Ok(errors) => errors,
+            Err(error) => {
+                *self.last_error.lock().unwrap() = Some(error.to_string());
+                return Err((error, batch));
+            }
+        };
+        let mut first_code = None;
+        let failed: Vec<_> = batch
+            .into_iter()
+            .zip(errors.into_iter().chain(std::iter::repeat(None)))
+            .filter_map(|(record, error)| {
+                let code = error?;
+                first_code.get_or_insert(code);
+                Some(record)
+            })
+            .collect();
+        *self.last_error.lock().unwrap() = first_code
+            .map(|code| format!("{} records were throttled or failed: {code}", failed.len()));
+        Ok(failed)
+    }
+}
+
+/// Put failed records back in the buffer, and describe them as an error.
+fn kept_for_retry<T>(buffer: &mut BatchBuffer<T>, failed: Vec<(T, usize)>) -> Error {
+    let count = failed.len();
+    buffer.requeue(failed);
+    format!("{count} records were throttled or failed, and are kept for retry").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex as StdMutex};
+
+    use super::*;
+    use crate::clock::TokioClock;
+
+    /// Answers each `put` with the next scripted reply, and writes every record once the replies
+    /// run out. Batches with a record in `stalling` are never answered.
+    #[derive(Default)]
+    struct FakeApi {
+        replies: StdMutex<VecDeque<Result<Vec<Option<String>>, Error>>>,
+        stalling: Vec<u32>,
+        batches: StdMutex<Vec<Vec<u32>>>,
+    }
+
+    impl FakeApi {
+        fn replying(replies: impl IntoIterator<Item = Result<Vec<Option<String>>, Error>>) -> Self {
+            Self {
+                replies: StdMutex::new(replies.into_iter().collect()),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl RecordApi for FakeApi {
+        type Record = u32;
+
+        fn put(&self, records: Vec<u32>) -> BoxFuture<'_, Result<Vec<Option<String>>, Error>> {
+            let stalls = records.iter().any(|record| self.stalling.contains(record));
+            self.batches.lock().unwrap().push(records);
+            if stalls {
+                return Box::pin(std::future::pending());
+            }
+            let reply = self
+                .replies
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or(Ok(Vec::new()));
+            Box::pin(async { reply })
+        }
+    }
+
+    fn throttled(pattern: &[bool]) -> Result<Vec<Option<String>>, Error> {
+        Ok(pattern
+            .iter()
+            .map(|&failed| failed.then(|| "ThrottlingException".to_owned()))
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn a_batch_is_written_once_it_is_full() {
+        let writer = RecordWriter::new(FakeApi::default(), 2, 100);
+        writer.push(1, 10).await.unwrap();
+        assert_eq!(writer.pending().await, 1);
+        writer.push(2, 10).await.unwrap();
+        writer.push(3, 95).await.unwrap();
+        assert_eq!(writer.pending().await, 1);
+        assert_eq!(*writer.api().batches.lock().unwrap(), [vec![1, 2]]);
+
+        assert!(writer.push(4, 101).await.is_err());
+        writer.flush().await.unwrap();
+        assert_eq!(*writer.api().batches.lock().unwrap(), [vec![1, 2], vec![3]]);
+    }
+
+    #[tokio::test]
+    async fn failed_records_are_kept_for_the_next_flush() {
+        let writer = RecordWriter::new(FakeApi::replying([throttled(&[false, true])]), 2, 100);
+        writer.push(1, 1).await.unwrap();
+        // Not an error, or the caller would queue the record again
+        writer.push(2, 1).await.unwrap();
+        assert_eq!(writer.pending().await, 1);
+        assert_eq!(
+            writer.last_error().unwrap(),
+            "1 records were throttled or failed: ThrottlingException"
+        );
+
+        writer.flush().await.unwrap();
+        assert_eq!(writer.pending().await, 0);
+        assert_eq!(writer.last_error(), None);
+        assert_eq!(*writer.api().batches.lock().unwrap(), [vec![1, 2], vec![2]]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_request_keeps_the_whole_batch() {
+        let writer = RecordWriter::new(FakeApi::replying([Err("unavailable".into())]), 10, 100);
+        writer.push(1, 1).await.unwrap();
+        writer.push(2, 1).await.unwrap();
+        assert!(writer.flush().await.is_err());
+        assert_eq!(writer.pending().await, 2);
+
+        writer.flush().await.unwrap();
+        assert_eq!(writer.pending().await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_until_retries_throttled_records_until_the_deadline() {
+        let writer = RecordWriter::new(
+            FakeApi::replying([throttled(&[true, false]), throttled(&[true])]),
+            10,
+            100,
+        );
+        writer.push(1, 1).await.unwrap();
+        writer.push(2, 1).await.unwrap();
+        writer
+            .flush_until(&TokioClock, Instant::now() + Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            *writer.api().batches.lock().unwrap(),
+            [vec![1, 2], vec![1], vec![1]]
+        );
+
+        // Not enough time left for a backoff
+        let writer = RecordWriter::new(FakeApi::replying([throttled(&[true])]), 10, 100);
+        writer.push(1, 1).await.unwrap();
+        assert!(writer
+            .flush_until(&TokioClock, Instant::now() + INITIAL_BACKOFF / 2)
+            .await
+            .is_err());
+        assert_eq!(writer.pending().await, 1);
+        assert_eq!(writer.api().batches.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_request_does_not_hold_up_the_batches_after_it() {
+        let writer = RecordWriter::new(FakeApi::replying([Err("unavailable".into())]), 2, 100);
+        for record in 1..=5 {
+            writer.buffer.lock().await.push(record, 1);
+        }
+        assert!(writer.flush().await.is_err());
+        assert_eq!(writer.pending().await, 2);
+        assert_eq!(
+            *writer.api().batches.lock().unwrap(),
+            [vec![1, 2], vec![3, 4], vec![5]]
+        );
+
+        // The failed batch is retried within the deadline
+        let writer = RecordWriter::new(FakeApi::replying([Err("unavailable".into())]), 2, 100);
+        for record in 1..=3 {
+            writer.buffer.lock().await.push(record, 1);
+        }
+        writer
+            .flush_until(&TokioClock, Instant::now() + Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            *writer.api().batches.lock().unwrap(),
+            [vec![1, 2], vec![3], vec![1, 2]]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_request_still_running_at_the_deadline_keeps_its_batch() {
+        let writer = RecordWriter::new(
+            FakeApi {
+                stalling: vec![3],
+                ..FakeApi::default()
+            },
+            2,
+            100,
+        );
+        for record in 1..=3 {
+            writer.buffer.lock().await.push(record, 1);
+        }
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let error = writer.flush_until(&TokioClock, deadline).await.unwrap_err();
+        assert!(error.is::<DrainTimeout>());
+        assert_eq!(error.to_string(), "the request timed out at the deadline");
+        assert_eq!(Instant::now(), deadline);
+        assert_eq!(writer.pending().await, 1);
+        assert_eq!(*writer.api().batches.lock().unwrap(), [vec![1, 2], vec![3]]);
+    }
+}
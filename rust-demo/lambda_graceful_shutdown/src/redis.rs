@@ -0,0 +1,90 @@
+//! Closing Redis connections from the [`redis`] and [`fred`] crates.
+//!
+//! Both clients pipeline commands over a small number of connections. If the environment is
+//! torn down while a pipeline is only partly written, the server sees a truncated command
+//! and the connection is dropped without a QUIT. These hooks wait for commands that were
+//! already sent to be answered, then close the connection cleanly.
+
+#[cfg(feature = "redis")]
+use std::fmt;
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] for a `redis` async connection, like a
+/// [`MultiplexedConnection`](redis::aio::MultiplexedConnection).
+///
+/// Sends a `PING`, which is only answered once every command queued before it has been, then
+/// a `QUIT`. Other clones of the connection stop working once this hook has run.
+#[cfg(feature = "redis")]
+pub struct RedisHook<C> {
+    conn: C,
+}
+
+#[cfg(feature = "redis")]
+impl<C> fmt::Debug for RedisHook<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisHook").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "redis")]
+impl<C> RedisHook<C>
+where
+    C: redis::aio::ConnectionLike + Clone + Send + Sync,
+{
+    /// Create a hook for the given connection. Pass in a clone of yours.
+    pub fn new(conn: C) -> Self {
+        Self { conn }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl<C> ShutdownHook for RedisHook<C>
+where
+    C: redis::aio::ConnectionLike + Clone + Send + Sync,
+{
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            redis::cmd("PING").query_async::<()>(&mut conn).await?;
+            redis::cmd("QUIT").query_async::<()>(&mut conn).await?;
+            Ok(())
+        })
+    }
+}
+
+/// A [`ShutdownHook`] for a `fred` client or pool.
+///
+/// [`quit()`](fred::interfaces::ClientLike::quit) waits for pending commands to finish before
+/// sending `QUIT`, and closes the client's event streams.
+#[cfg(feature = "fred")]
+#[derive(Debug)]
+pub struct FredHook<C> {
+    client: C,
+}
+
+#[cfg(feature = "fred")]
+impl<C: fred::interfaces::ClientLike> FredHook<C> {
+    /// Create a hook for the given client. Clients are cheap to clone, so pass in a clone of yours.
+    pub fn new(client: C) -> Self {
+        Self { client }
+    }
+}
+
+#[cfg(feature = "fred")]
+impl<C: fred::interfaces::ClientLike> ShutdownHook for FredHook<C> {
+    fn name(&self) -> &str {
+        "fred"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.client.quit().await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,258 @@
+use std::{fmt, time::Duration};
+
+use serde_json::{json, Value};
+
+use crate::ShutdownReason;
+
+/// What happened during a call to [`ShutdownCoordinator::shutdown()`](crate::ShutdownCoordinator::shutdown).
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    /// Why the shutdown was triggered.
+    pub reason: ShutdownReason,
+    /// The request id of the invocation in flight or handled last, if the coordinator was
+    /// told about it.
+    pub request_id: Option<String>,
+    /// Total time spent running hooks.
+    pub elapsed: Duration,
+    /// How waiting for the invocations in flight went, if the coordinator was set up to with
+    /// [`with_invocation_drain()`](crate::ShutdownCoordinator::with_invocation_drain).
+    pub invocations: Option<InvocationDrain>,
+    /// How many invocations the sandbox handled, and how often it was frozen, before the
+    /// shutdown.
+    pub sandbox: SandboxStats,
+    /// One entry per registered hook, in the order they were run.
+    pub hooks: Vec<HookReport>,
+}
+
+impl ShutdownReport {
+    /// Returns true if every hook completed successfully.
+    pub fn is_clean(&self) -> bool {
+        self.hooks
+            .iter()
+            .all(|hook| hook.outcome == HookOutcome::Completed)
+    }
+
+    /// How many hooks didn't complete, and why.
+    pub fn counters(&self) -> ShutdownCounters {
+        ShutdownCounters::from_hooks(&self.hooks)
+    }
+
+    /// The exit code for the process: 0 if every hook completed, 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        i32::from(!self.is_clean())
+    }
+
+    /// The report as a single `SHUTDOWN_SUMMARY {json}` line, for log queries and log-based
+    /// alarms, with the JSON from [`to_json()`](Self::to_json).
+    pub fn summary_line(&self) -> String {
+        format!("SHUTDOWN_SUMMARY {}", self.to_json())
+    }
+
+    /// The report as a JSON object.
+    ///
+    /// The object has the `reason`, the `request_id` of the last invocation, `duration_ms`,
+    /// [`exit_code`](Self::exit_code), the [counters](Self::counters), the `sandbox` stats
+    /// with `invocations`, `thaws` and `uptime_ms`, and the `hooks` in the order they ran,
+    /// each with its `name`, `elapsed_ms`, and `outcome`: one of `completed`, `failed`,
+    /// `drain_timed_out`, `timed_out` or `skipped`, with the `error` for the first two.
+    pub fn to_json(&self) -> Value {
+        let counters = self.counters();
+        let hooks: Vec<Value> = self
+            .hooks
+            .iter()
+            .map(|hook| {
+                let (outcome, error) = match &hook.outcome {
+                    HookOutcome::Completed => ("completed", None),
+                    HookOutcome::Failed(error) => ("failed", Some(error)),
+                    HookOutcome::DrainTimedOut(error) => ("drain_timed_out", Some(error)),
+                    HookOutcome::TimedOut => ("timed_out", None),
+                    HookOutcome::Skipped => ("skipped", None),
+                };
+                let mut hook = json!({
+                    "name": hook.name,
+                    "elapsed_ms": hook.elapsed.as_millis() as u64,
+                    "outcome": outcome,
+                });
+                if let Some(error) = error {
+                    hook["error"] = json!(error);
+                }
+                hook
+            })
+            .collect();
+        json!({
+            "reason": self.reason.to_string(),
+            "request_id": self.request_id,
+            "duration_ms": self.elapsed.as_millis() as u64,
+            "exit_code": self.exit_code(),
+            "budget_exceeded": counters.budget_exceeded,
+            "hooks_failed": counters.hooks_failed,
+            "hooks_timed_out": counters.hooks_timed_out,
+            "drains_timed_out": counters.drains_timed_out,
+            "hooks_skipped": counters.hooks_skipped,
+            "sandbox": self.sandbox.to_json(),
+            "hooks": hooks,
+        })
+    }
+}
+
+/// One line summing up the shutdown, e.g. `SIGTERM shutdown finished in 312ms: 3 of 4 hooks
+/// completed, 1 timed out, 0 drains timed out, 0 skipped, over budget`.
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counters = self.counters();
+        let completed = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.outcome == HookOutcome::Completed)
+            .count();
+        write!(
+            f,
+            "{} shutdown finished in {:?}: {completed} of {} hooks completed, {} timed out, \
+             {} drains timed out, {} skipped, {}",
+            self.reason,
+            self.elapsed,
+            self.hooks.len(),
+            counters.hooks_timed_out,
+            counters.drains_timed_out,
+            counters.hooks_skipped,
+            if counters.budget_exceeded {
+                "over budget"
+            } else {
+                "within budget"
+            }
+        )
+    }
+}
+
+/// How waiting for the invocations in flight went, before the hooks ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvocationDrain {
+    /// The number of invocations in flight when the shutdown started.
+    pub in_flight: usize,
+    /// The number of those still running when the drain window closed.
+    pub interrupted: usize,
+    /// How long the wait took.
+    pub elapsed: Duration,
+}
+
+impl InvocationDrain {
+    /// Whether every invocation in flight finished within the drain window.
+    pub fn drained(&self) -> bool {
+        self.interrupted == 0
+    }
+}
+
+/// What the sandbox went through before the shutdown, for tuning how often to flush.
+///
+/// Lambda freezes the sandbox between invocations when no other invocation is waiting, so
+/// buffered telemetry can sit for a long time, or be lost, in a sandbox that is frozen often.
+/// Thaws are inferred from the gaps between the invocations tracked with
+/// [`ShutdownCoordinator::track_invocation()`](crate::ShutdownCoordinator::track_invocation).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SandboxStats {
+    /// The number of invocations tracked.
+    pub invocations: u64,
+    /// The number of tracked invocations that started after a gap long enough that the
+    /// sandbox was most likely frozen in between.
+    pub thaws: u64,
+    /// How long ago the coordinator was created, including the time spent frozen.
+    pub uptime: Duration,
+}
+
+impl SandboxStats {
+    fn to_json(self) -> Value {
+        json!({
+            "invocations": self.invocations,
+            "thaws": self.thaws,
+            "uptime_ms": self.uptime.as_millis() as u64,
+        })
+    }
+}
+
+/// Counts of the hooks that didn't complete during a shutdown.
+///
+/// These are the signals that the shutdown configuration is wrong: a budget that is too short,
+/// or hooks with more to drain than they get time for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownCounters {
+    /// Whether the budget ran out before every hook finished, i.e. a hook timed out or was
+    /// skipped.
+    pub budget_exceeded: bool,
+    /// Hooks that didn't complete, other than the skipped ones.
+    pub hooks_failed: usize,
+    /// Hooks still running when the budget ran out.
+    pub hooks_timed_out: usize,
+    /// Hooks that gave up waiting for something to drain, by returning a
+    /// [`DrainTimeout`](crate::DrainTimeout).
+    pub drains_timed_out: usize,
+    /// Hooks that didn't get to start.
+    pub hooks_skipped: usize,
+}
+
+impl ShutdownCounters {
+    /// Count the outcomes in `hooks`.
+    pub fn from_hooks(hooks: &[HookReport]) -> Self {
+        let mut counters = Self::default();
+        for hook in hooks {
+            match hook.outcome {
+                HookOutcome::Completed => {}
+                HookOutcome::Failed(_) => counters.hooks_failed += 1,
+                HookOutcome::DrainTimedOut(_) => {
+                    counters.hooks_failed += 1;
+                    counters.drains_timed_out += 1;
+                }
+                HookOutcome::TimedOut => {
+                    counters.hooks_failed += 1;
+                    counters.hooks_timed_out += 1;
+                    counters.budget_exceeded = true;
+                }
+                HookOutcome::Skipped => {
+                    counters.hooks_skipped += 1;
+                    counters.budget_exceeded = true;
+                }
+            }
+        }
+        counters
+    }
+}
+
+/// The outcome of a single hook, as recorded in the [`ShutdownReport`].
+#[derive(Debug, Clone)]
+pub struct HookReport {
+    /// The hook's [`name()`](crate::ShutdownHook::name).
+    pub name: String,
+    /// How long the hook ran for.
+    pub elapsed: Duration,
+    /// How the hook finished.
+    pub outcome: HookOutcome,
+    /// Details the hook added with [`ShutdownContext::note()`](crate::ShutdownContext::note).
+    pub notes: Vec<String>,
+}
+
+/// How a hook finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookOutcome {
+    /// The hook returned `Ok(())`.
+    Completed,
+    /// The hook returned an error, rendered with `Display`.
+    Failed(String),
+    /// The hook returned a [`DrainTimeout`](crate::DrainTimeout), because it gave up waiting
+    /// for something to drain.
+    DrainTimedOut(String),
+    /// The budget ran out while the hook was running.
+    TimedOut,
+    /// The budget had already run out before the hook got a chance to start.
+    Skipped,
+}
+
+impl fmt::Display for HookOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookOutcome::Completed => f.write_str("completed"),
+            HookOutcome::Failed(error) => write!(f, "failed: {error}"),
+            HookOutcome::DrainTimedOut(error) => write!(f, "drain timed out: {error}"),
+            HookOutcome::TimedOut => f.write_str("timed out"),
+            HookOutcome::Skipped => f.write_str("skipped"),
+        }
+    }
+}
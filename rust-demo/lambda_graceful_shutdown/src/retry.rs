@@ -0,0 +1,256 @@
+//! Retrying outbound calls, but not into the shutdown.
+//!
+//! A backoff loop around a dependency that has gone away keeps sleeping and calling again
+//! until it runs out of attempts, which can take longer than the whole shutdown window.
+//! [`retry_until_shutdown()`] retries with exponential backoff like any other loop, but stops
+//! as soon as the shutdown starts, with a [`RetryError::ShuttingDown`] the caller can tell
+//! apart from running out of attempts, so the budget goes to the hooks rather than the
+//! retries.
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{
+//!     retry::{retry_until_shutdown, RetryPolicy},
+//!     ShutdownCoordinator,
+//! };
+//!
+//! # async fn send_order() -> Result<(), std::io::Error> { Ok(()) }
+//! # async fn example(shutdown: ShutdownCoordinator) {
+//! let policy = RetryPolicy::new(shutdown.handle()).with_max_attempts(4);
+//! match retry_until_shutdown(&policy, || send_order()).await {
+//!     Ok(()) => {}
+//!     // Keep the order for the next environment, e.g. in a checkpoint
+//!     Err(error) if error.is_shutting_down() => {}
+//!     Err(error) => tracing::error!(%error, "the order could not be sent"),
+//! }
+//! # }
+//! ```
+
+use std::{fmt, future::Future, time::Duration};
+
+use crate::ShutdownHandle;
+
+/// How many times a call is made, unless set with [`RetryPolicy::with_max_attempts()`].
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// The backoff after the first failure, doubled after each one up to the maximum, unless set
+/// with [`RetryPolicy::with_backoff()`].
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How often and how far apart [`retry_until_shutdown()`] calls, and the shutdown it stops
+/// at.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    shutdown: ShutdownHandle,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Make up to 5 attempts, 50ms apart after the first failure and twice as far apart after
+    /// each one after that, up to 2s, until `shutdown` starts.
+    pub fn new(shutdown: ShutdownHandle) -> Self {
+        Self {
+            shutdown,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Make up to `attempts` attempts, the first one included, instead of 5.
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Wait `initial` after the first failure, and twice as long after each one after that,
+    /// up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+}
+
+/// Why [`retry_until_shutdown()`] gave up.
+#[derive(Debug)]
+pub enum RetryError<E> {
+    /// The shutdown started before an attempt succeeded.
+    ShuttingDown {
+        /// The attempts made.
+        attempts: u32,
+        /// The error of the last attempt.
+        last: E,
+    },
+    /// Every attempt failed.
+    Exhausted {
+        /// The attempts made.
+        attempts: u32,
+        /// The error of the last attempt.
+        last: E,
+    },
+}
+
+impl<E> RetryError<E> {
+    /// Returns true if the retries stopped because the shutdown started.
+    pub fn is_shutting_down(&self) -> bool {
+        matches!(self, Self::ShuttingDown { .. })
+    }
+
+    /// The attempts made.
+    pub fn attempts(&self) -> u32 {
+        match self {
+            Self::ShuttingDown { attempts, .. } | Self::Exhausted { attempts, .. } => *attempts,
+        }
+    }
+
+    /// The error of the last attempt.
+    pub fn into_last(self) -> E {
+        match self {
+            Self::ShuttingDown { last, .. } | Self::Exhausted { last, .. } => last,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = if self.attempts() == 1 { "" } else { "s" };
+        match self {
+            Self::ShuttingDown { attempts, last } => write!(
+                f,
+                "stopped retrying at shutdown after {attempts} attempt{plural}: {last}"
+            ),
+            Self::Exhausted { attempts, last } => {
+                write!(f, "failed after {attempts} attempt{plural}: {last}")
+            }
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for RetryError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ShuttingDown { last, .. } | Self::Exhausted { last, .. } => Some(last),
+        }
+    }
+}
+
+/// Call `op` until it succeeds, backing off between attempts as `policy` says, but not once
+/// the shutdown has started.
+///
+/// The first attempt is always made. After that, no attempt is started once the shutdown has
+/// started, and a backoff in progress ends when it does. An attempt already running is left
+/// to finish, since cutting it off could leave it half done; give `op` a timeout of its own
+/// if the dependency can hang. The backoffs run on the clock of the coordinator the policy's
+/// handle came from.
+pub async fn retry_until_shutdown<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    mut op: F,
+) -> Result<T, RetryError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = policy.initial_backoff;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let last = match op().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        if policy.shutdown.is_shutting_down() {
+            return Err(RetryError::ShuttingDown { attempts, last });
+        }
+        if attempts >= policy.max_attempts {
+            return Err(RetryError::Exhausted { attempts, last });
+        }
+        let clock = policy.shutdown.clock();
+        tokio::select! {
+            () = clock.sleep_until(clock.now() + backoff) => {}
+            () = policy.shutdown.shutting_down() => {
+                return Err(RetryError::ShuttingDown { attempts, last });
+            }
+        }
+        backoff = backoff.saturating_mul(2).min(policy.max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::{ShutdownCoordinator, ShutdownReason};
+
+    /// An operation that always fails, and counts how often it was called.
+    fn failing(
+        calls: &Arc<AtomicU32>,
+    ) -> impl FnMut() -> std::future::Ready<Result<(), &'static str>> {
+        let calls = calls.clone();
+        move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            std::future::ready(Err("unavailable"))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_first_attempt_is_made_after_the_shutdown_started() {
+        let shutdown = ShutdownCoordinator::new();
+        shutdown.shutdown(ShutdownReason::Sigterm).await;
+        let calls = Arc::default();
+
+        let policy = RetryPolicy::new(shutdown.handle());
+        let error = retry_until_shutdown(&policy, failing(&calls))
+            .await
+            .unwrap_err();
+        assert!(error.is_shutting_down());
+        assert_eq!(error.attempts(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_shutdown_ends_a_backoff() {
+        let shutdown = ShutdownCoordinator::new();
+        let calls = Arc::default();
+        let policy = RetryPolicy::new(shutdown.handle())
+            .with_backoff(Duration::from_secs(10), Duration::from_secs(10));
+        let started = Instant::now();
+        let retrying = tokio::spawn({
+            let calls = Arc::clone(&calls);
+            async move { retry_until_shutdown(&policy, failing(&calls)).await }
+        });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        shutdown.shutdown(ShutdownReason::Sigterm).await;
+        let error = retrying.await.unwrap().unwrap_err();
+        assert!(error.is_shutting_down());
+        assert_eq!(error.attempts(), 1);
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attempts_run_out_with_a_doubling_backoff() {
+        let shutdown = ShutdownCoordinator::new();
+        let calls = Arc::default();
+        let policy = RetryPolicy::new(shutdown.handle())
+            .with_max_attempts(4)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(30));
+        let started = Instant::now();
+
+        let error = retry_until_shutdown(&policy, failing(&calls))
+            .await
+            .unwrap_err();
+        assert!(!error.is_shutting_down());
+        assert_eq!(error.attempts(), 4);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(started.elapsed(), Duration::from_millis(10 + 20 + 30));
+    }
+}
@@ -0,0 +1,474 @@
+//! Multipart uploads to S3 that don't outlive the execution environment.
+//!
+//! The parts of a multipart upload that is never completed or aborted stay in the bucket,
+//! invisible and billed, until a lifecycle rule cleans them up. [`MultipartUploads`] keeps a
+//! registry of the uploads that are in progress, and its
+//! [`shutdown_hook()`](MultipartUploads::shutdown_hook) settles each of them at shutdown:
+//! uploads whose parts are all uploaded are completed, the rest are aborted. What happened to
+//! each upload is noted in the [`ShutdownReport`](crate::ShutdownReport).
+//!
+//! [`S3CheckpointStore`] is a [`CheckpointStore`] keeping each checkpoint in an object of its
+//! own.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use aws_sdk_s3::{
+    primitives::ByteStream,
+    types::{ChecksumAlgorithm, ChecksumMode, CompletedMultipartUpload, CompletedPart},
+    Client,
+};
+use tokio::task::JoinSet;
+
+use crate::{
+    checkpoint::CheckpointStore, hook::join_within, BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+#[derive(Debug, Clone)]
+struct Upload {
+    bucket: String,
+    key: String,
+    parts: Vec<CompletedPart>,
+    parts_done: bool,
+}
+
+impl Upload {
+    fn location(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+struct Inner {
+    client: Client,
+    /// In-progress uploads, by upload id.
+    uploads: Mutex<HashMap<String, Upload>>,
+}
+
+impl Inner {
+    fn forget(&self, upload_id: &str) {
+        self.uploads.lock().unwrap().remove(upload_id);
+    }
+
+    async fn complete(&self, upload_id: &str, upload: &Upload) -> Result<(), Error> {
+        let mut parts = upload.parts.clone();
+        parts.sort_by_key(|part| part.part_number());
+        self.client
+            .complete_multipart_upload()
+            .bucket(&upload.bucket)
+            .key(&upload.key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+        self.forget(upload_id);
+        Ok(())
+    }
+
+    async fn abort(&self, upload_id: &str, upload: &Upload) -> Result<(), Error> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&upload.bucket)
+            .key(&upload.key)
+            .upload_id(upload_id)
+            .send()
+            .await?;
+        self.forget(upload_id);
+        Ok(())
+    }
+
+    /// Complete an upload if all of its parts are there, or abort it otherwise. Returns a note
+    /// describing what happened, as an error if the upload is still left over.
+    async fn settle(&self, upload_id: &str, upload: &Upload) -> Result<String, String> {
+        let location = upload.location();
+        if upload.parts_done && !upload.parts.is_empty() {
+            let error = match self.complete(upload_id, upload).await {
+                Ok(()) => return Ok(format!("completed {location}")),
+                Err(error) => error,
+            };
+            // Don't leave the parts behind just because they couldn't be assembled.
+            return match self.abort(upload_id, upload).await {
+                Ok(()) => Ok(format!(
+                    "aborted {location} after completing failed: {error}"
+                )),
+                Err(abort_error) => Err(format!(
+                    "failed to complete {location}: {error}, and to abort it: {abort_error}"
+                )),
+            };
+        }
+        match self.abort(upload_id, upload).await {
+            Ok(()) => Ok(format!("aborted {location}")),
+            Err(error) => Err(format!("failed to abort {location}: {error}")),
+        }
+    }
+}
+
+/// A registry of in-progress multipart uploads.
+///
+/// Cloning is cheap, and all clones share the same registry.
+#[derive(Clone)]
+pub struct MultipartUploads {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for MultipartUploads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultipartUploads")
+            .field("in_progress", &self.in_progress())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MultipartUploads {
+    /// Create an empty registry. Uploads are made with `client`.
+    pub fn new(client: Client) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                uploads: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Start a multipart upload to `key` in `bucket`, and track it until it is completed or
+    /// aborted.
+    pub async fn create(
+        &self,
+        bucket: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<MultipartUpload, Error> {
+        let bucket = bucket.into();
+        let key = key.into();
+        let output = self
+            .inner
+            .client
+            .create_multipart_upload()
+            .bucket(&bucket)
+            .key(&key)
+            .send()
+            .await?;
+        let upload_id = output
+            .upload_id()
+            .ok_or("CreateMultipartUpload did not return an upload id")?
+            .to_owned();
+        self.inner.uploads.lock().unwrap().insert(
+            upload_id.clone(),
+            Upload {
+                bucket,
+                key,
+                parts: Vec::new(),
+                parts_done: false,
+            },
+        );
+        Ok(MultipartUpload {
+            inner: self.inner.clone(),
+            upload_id,
+        })
+    }
+
+    /// The number of uploads that have been started and not yet completed or aborted.
+    pub fn in_progress(&self) -> usize {
+        self.inner.uploads.lock().unwrap().len()
+    }
+
+    /// A hook that completes or aborts every upload still in progress when the environment
+    /// shuts down.
+    pub fn shutdown_hook(&self) -> MultipartUploadsHook {
+        MultipartUploadsHook {
+            uploads: self.clone(),
+        }
+    }
+}
+
+/// A multipart upload tracked by [`MultipartUploads`].
+///
+/// Dropping this without calling [`complete()`](Self::complete) or [`abort()`](Self::abort)
+/// leaves the upload in the registry, to be settled by the shutdown hook.
+pub struct MultipartUpload {
+    inner: Arc<Inner>,
+    upload_id: String,
+}
+
+impl fmt::Debug for MultipartUpload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultipartUpload")
+            .field("upload_id", &self.upload_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MultipartUpload {
+    /// The id S3 assigned to this upload.
+    pub fn upload_id(&self) -> &str {
+        &self.upload_id
+    }
+
+    /// Upload one part. Part numbers go from 1 to 10,000; uploading the same number again
+    /// replaces that part.
+    pub async fn upload_part(
+        &self,
+        part_number: i32,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let (bucket, key) = {
+            let uploads = self.inner.uploads.lock().unwrap();
+            let upload = uploads
+                .get(&self.upload_id)
+                .ok_or("the upload has already been completed or aborted")?;
+            (upload.bucket.clone(), upload.key.clone())
+        };
+        let output = self
+            .inner
+            .client
+            .upload_part()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&self.upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(data.into()))
+            .send()
+            .await?;
+
+        let part = CompletedPart::builder()
+            .part_number(part_number)
+            .set_e_tag(output.e_tag().map(str::to_owned))
+            .build();
+        if let Some(upload) = self.inner.uploads.lock().unwrap().get_mut(&self.upload_id) {
+            upload
+                .parts
+                .retain(|existing| existing.part_number() != Some(part_number));
+            upload.parts.push(part);
+        }
+        Ok(())
+    }
+
+    /// Mark every part as uploaded, so that the shutdown hook completes this upload rather than
+    /// aborting it.
+    pub fn parts_done(&self) {
+        if let Some(upload) = self.inner.uploads.lock().unwrap().get_mut(&self.upload_id) {
+            upload.parts_done = true;
+        }
+    }
+
+    /// Complete the upload from the parts uploaded so far.
+    ///
+    /// If this fails, the upload stays in the registry.
+    pub async fn complete(self) -> Result<(), Error> {
+        let upload = self.snapshot()?;
+        self.inner.complete(&self.upload_id, &upload).await
+    }
+
+    /// Abort the upload, deleting the parts uploaded so far.
+    ///
+    /// If this fails, the upload stays in the registry.
+    pub async fn abort(self) -> Result<(), Error> {
+        let upload = self.snapshot()?;
+        self.inner.abort(&self.upload_id, &upload).await
+    }
+
+    fn snapshot(&self) -> Result<Upload, Error> {
+        let uploads = self.inner.uploads.lock().unwrap();
+        Ok(uploads
+            .get(&self.upload_id)
+            .ok_or("the upload has already been completed or aborted")?
+            .clone())
+    }
+}
+
+/// A [`ShutdownHook`] that settles the uploads still in progress in [`MultipartUploads`].
+///
+/// Uploads are settled concurrently. Each one gets a note in the hook's
+/// [`HookReport`](crate::HookReport), and the hook fails if any upload is left over. Uploads
+/// still being settled at the [drain deadline](ShutdownContext::drain_deadline) are noted as
+/// left in progress, and the hook ends with a [`DrainTimeout`](crate::DrainTimeout).
+#[derive(Debug)]
+pub struct MultipartUploadsHook {
+    uploads: MultipartUploads,
+}
+
+impl ShutdownHook for MultipartUploadsHook {
+    fn name(&self) -> &str {
+        "s3-multipart"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let uploads: Vec<_> = self
+                .uploads
+                .inner
+                .uploads
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(upload_id, upload)| (upload_id.clone(), upload.clone()))
+                .collect();
+            let total = uploads.len();
+
+            let mut unsettled = HashMap::new();
+            let mut tasks = JoinSet::new();
+            for (upload_id, upload) in uploads {
+                unsettled.insert(upload_id.clone(), upload.location());
+                let inner = self.uploads.inner.clone();
+                tasks.spawn(async move {
+                    let result = inner.settle(&upload_id, &upload).await;
+                    (upload_id, result)
+                });
+            }
+
+            let mut failed = 0;
+            let joined = join_within(ctx, &mut tasks, |(upload_id, result)| {
+                unsettled.remove(&upload_id);
+                match result {
+                    Ok(note) => ctx.note(note),
+                    Err(note) => {
+                        failed += 1;
+                        ctx.note(note);
+                    }
+                }
+            })
+            .await;
+            failed += joined.panicked;
+            // Uploads whose task panicked or was cut off are neither completed nor aborted
+            for location in unsettled.values() {
+                ctx.note(format!("left {location} in progress"));
+            }
+            if let Some(timed_out) = joined.timed_out("uploads left to complete or abort") {
+                return Err(timed_out);
+            }
+            if failed > 0 {
+                return Err(format!("{failed} of {total} multipart uploads are left over").into());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Where checkpoints are kept in the bucket, unless set with
+/// [`S3CheckpointStore::with_key_scheme()`].
+const DEFAULT_KEY_SCHEME: &str = "checkpoints/{function_name}/{key}";
+
+/// The largest checkpoint saved, unless set with [`S3CheckpointStore::with_max_size()`]. A
+/// single `PutObject` of this size fits comfortably in a 2s shutdown window.
+const DEFAULT_MAX_CHECKPOINT_SIZE: usize = 5 * 1024 * 1024;
+
+/// A [`CheckpointStore`] keeping checkpoints as objects in an S3 bucket.
+///
+/// Each checkpoint is written with a single `PutObject`, never a multipart upload, so a save
+/// cut off by the end of the shutdown window leaves nothing behind. Objects are uploaded with
+/// a SHA-256 checksum, which S3 checks before storing them, and the checksum is checked again
+/// when they are loaded, so a corrupted checkpoint is never resumed from.
+///
+/// Add a lifecycle rule expiring the prefix, so abandoned checkpoints are cleaned up.
+#[derive(Debug, Clone)]
+pub struct S3CheckpointStore {
+    client: Client,
+    bucket: String,
+    key_scheme: String,
+    max_size: usize,
+}
+
+impl S3CheckpointStore {
+    /// Keep checkpoints in `bucket`.
+    pub fn new(client: Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+            key_scheme: DEFAULT_KEY_SCHEME.to_owned(),
+            max_size: DEFAULT_MAX_CHECKPOINT_SIZE,
+        }
+    }
+
+    /// Lay out object keys following `scheme` instead of `checkpoints/{function_name}/{key}`.
+    ///
+    /// `{key}` is replaced by the checkpoint's key, such as the request id, which stays the
+    /// same when Lambda retries an asynchronous invocation. `{function_name}` and
+    /// `{function_version}` are replaced by those of the function, so with
+    /// `checkpoints/{function_version}/{key}` a new version doesn't resume from checkpoints
+    /// saved by an old one.
+    pub fn with_key_scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.key_scheme = scheme.into();
+        self
+    }
+
+    /// Refuse to save checkpoints bigger than `max_size` bytes, instead of 5 MiB.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    fn object_key(&self, key: &str) -> String {
+        let env = |name| std::env::var(name).unwrap_or_default();
+        self.key_scheme
+            .replace("{function_name}", &env("AWS_LAMBDA_FUNCTION_NAME"))
+            .replace("{function_version}", &env("AWS_LAMBDA_FUNCTION_VERSION"))
+            .replace("{key}", key)
+    }
+}
+
+impl CheckpointStore for S3CheckpointStore {
+    fn save<'a>(&'a self, key: &'a str, bytes: Vec<u8>) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            if bytes.len() > self.max_size {
+                return Err(format!(
+                    "the checkpoint for {key} is {} bytes, checkpoints are limited to {}",
+                    bytes.len(),
+                    self.max_size
+                )
+                .into());
+            }
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .checksum_algorithm(ChecksumAlgorithm::Sha256)
+                .body(ByteStream::from(bytes))
+                .send()
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn load<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, Error>> {
+        Box::pin(async move {
+            let result = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .checksum_mode(ChecksumMode::Enabled)
+                .send()
+                .await;
+            let output = match result {
+                Ok(output) => output,
+                Err(error)
+                    if error
+                        .as_service_error()
+                        .is_some_and(|error| error.is_no_such_key()) =>
+                {
+                    return Ok(None)
+                }
+                Err(error) => return Err(error.into()),
+            };
+            let bytes = output.body.collect().await?.into_bytes();
+            Ok(Some(bytes.to_vec()))
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(self.object_key(key))
+                .send()
+                .await?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,349 @@
+//! Compensating the completed steps of sagas that a shutdown interrupts.
+//!
+//! A saga is a sequence of steps across services, each undone by a compensating action if a
+//! later one can't go ahead. When the environment shuts down in the middle of a saga, nothing
+//! runs the compensations, and the steps already done are left dangling.
+//!
+//! A [`Saga`] started from [`Sagas`] records a compensation as each step completes. If shutdown
+//! starts before the saga completes, the [`compensation_hook()`](Sagas::compensation_hook)
+//! runs the compensations of every unfinished saga, last step first, for as long as the budget
+//! allows. With a [`CheckpointStore`], the compensations that didn't get to run are saved
+//! instead, under the saga's id, for a cleanup function to pick up.
+//!
+//! A compensation that was running when the budget ran out is saved too, so compensations
+//! should be idempotent.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::task::JoinSet;
+
+use crate::{
+    checkpoint::CheckpointStore,
+    hook::{join_until, join_within},
+    BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook,
+};
+
+/// Part of the budget kept back for saving the compensations that didn't run, when the hook
+/// has a store.
+const SAVE_RESERVE: Duration = Duration::from_millis(150);
+
+type Compensation = Box<dyn FnOnce() -> BoxFuture<'static, Result<(), Error>> + Send>;
+
+/// A completed step, and how to undo it.
+struct Step {
+    name: String,
+    payload: Value,
+    compensate: Compensation,
+}
+
+/// A registry of the sagas in progress.
+///
+/// Cloning is cheap, and all clones share the same registry.
+#[derive(Clone, Default)]
+pub struct Sagas {
+    running: Arc<Mutex<HashMap<String, Vec<Step>>>>,
+}
+
+impl fmt::Debug for Sagas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sagas")
+            .field("running", &self.running())
+            .finish()
+    }
+}
+
+impl Sagas {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start the saga `id`, such as an order or request id.
+    pub fn begin(&self, id: impl Into<String>) -> Saga {
+        let id = id.into();
+        self.running.lock().unwrap().entry(id.clone()).or_default();
+        Saga {
+            sagas: self.clone(),
+            id,
+        }
+    }
+
+    /// The number of sagas in progress.
+    pub fn running(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+
+    /// A hook that compensates the sagas still in progress when the environment shuts down.
+    pub fn compensation_hook(&self) -> SagaHook {
+        SagaHook {
+            sagas: self.clone(),
+            store: None,
+        }
+    }
+}
+
+/// A saga in progress, tracked by [`Sagas`].
+///
+/// A saga dropped without being completed or compensated stays in progress, and is
+/// compensated by the hook at shutdown.
+pub struct Saga {
+    sagas: Sagas,
+    id: String,
+}
+
+impl fmt::Debug for Saga {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Saga").field("id", &self.id).finish()
+    }
+}
+
+impl Saga {
+    /// The saga's id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Record that the step `name` completed, along with `compensate`, which undoes it.
+    ///
+    /// `payload` describes the step to a cleanup function, in case the compensation is saved
+    /// rather than run.
+    pub fn step_completed<F, Fut>(&self, name: impl Into<String>, payload: Value, compensate: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let step = Step {
+            name: name.into(),
+            payload,
+            compensate: Box::new(move || Box::pin(compensate())),
+        };
+        self.sagas
+            .running
+            .lock()
+            .unwrap()
+            .entry(self.id.clone())
+            .or_default()
+            .push(step);
+    }
+
+    /// Mark the saga as done, dropping its compensations.
+    pub fn complete(self) {
+        self.sagas.running.lock().unwrap().remove(&self.id);
+    }
+
+    /// Undo the saga, running the compensations of its steps, last step first.
+    ///
+    /// If a compensation fails, the ones for earlier steps are not run.
+    pub async fn compensate(self) -> Result<(), Error> {
+        let steps = self
+            .sagas
+            .running
+            .lock()
+            .unwrap()
+            .remove(&self.id)
+            .unwrap_or_default();
+        for step in steps.into_iter().rev() {
+            (step.compensate)()
+                .await
+                .map_err(|error| format!("failed to compensate {}: {error}", step.name))?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`ShutdownHook`] that compensates the sagas tracked by [`Sagas`].
+///
+/// Sagas are compensated concurrently, and the steps of each one in reverse order. The hook
+/// fails if any saga is left with compensations that were neither run nor saved.
+pub struct SagaHook {
+    sagas: Sagas,
+    store: Option<Arc<dyn CheckpointStore>>,
+}
+
+impl fmt::Debug for SagaHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SagaHook")
+            .field("sagas", &self.sagas)
+            .field("store", &self.store.is_some())
+            .finish()
+    }
+}
+
+impl SagaHook {
+    /// Save the compensations that didn't run to `store`, keeping the last 150ms of the budget
+    /// to do so.
+    ///
+    /// Each saga is saved under its id, as JSON: the `saga` id, the shutdown `reason`, and the
+    /// `steps` left to compensate, in the order to run them, each with its `name` and
+    /// `payload`.
+    pub fn with_store(mut self, store: impl CheckpointStore + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+}
+
+impl ShutdownHook for SagaHook {
+    fn name(&self) -> &str {
+        "saga"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let running = std::mem::take(&mut *self.sagas.running.lock().unwrap());
+            let total = running.len();
+
+            // The steps of each saga that are still to be compensated
+            let mut left = HashMap::new();
+            let mut compensations = JoinSet::new();
+            for (id, steps) in running {
+                let descriptions: Vec<(String, Value)> = steps
+                    .iter()
+                    .map(|step| (step.name.clone(), step.payload.clone()))
+                    .collect();
+                let remaining = Arc::new(Mutex::new(descriptions));
+                left.insert(id.clone(), remaining.clone());
+                compensations.spawn(async move {
+                    for step in steps.into_iter().rev() {
+                        if let Err(error) = (step.compensate)().await {
+                            tracing::debug!(
+                                saga = id,
+                                step = step.name,
+                                %error,
+                                "failed to compensate"
+                            );
+                            return;
+                        }
+                        remaining.lock().unwrap().pop();
+                    }
+                });
+            }
+
+            let deadline = match self.store {
+                Some(_) => ctx
+                    .deadline()
+                    .checked_sub(SAVE_RESERVE)
+                    .unwrap_or(ctx.deadline()),
+                None => ctx.drain_deadline(),
+            };
+            let compensating = join_until(ctx, deadline, &mut compensations, |()| {}).await;
+
+            let left: Vec<(String, Vec<(String, Value)>)> = left
+                .into_iter()
+                .filter_map(|(id, remaining)| {
+                    let remaining = std::mem::take(&mut *remaining.lock().unwrap());
+                    (!remaining.is_empty()).then_some((id, remaining))
+                })
+                .collect();
+            let compensated = total - left.len();
+
+            let mut saved = 0;
+            if let (Some(store), false) = (&self.store, left.is_empty()) {
+                let mut saves = JoinSet::new();
+                for (id, remaining) in &left {
+                    let steps: Vec<Value> = remaining
+                        .iter()
+                        .rev()
+                        .map(|(name, payload)| json!({ "name": name, "payload": payload }))
+                        .collect();
+                    let body = json!({
+                        "saga": id,
+                        "reason": ctx.reason().to_string(),
+                        "steps": steps,
+                    });
+                    let store = store.clone();
+                    let id = id.clone();
+                    saves.spawn(async move {
+                        let saved = store.save(&id, body.to_string().into_bytes()).await;
+                        if let Err(error) = &saved {
+                            tracing::debug!(saga = id, %error, "failed to save compensations");
+                        }
+                        saved.is_ok()
+                    });
+                }
+                join_within(ctx, &mut saves, |ok| saved += usize::from(ok)).await;
+            }
+
+            ctx.note(format!(
+                "compensated {compensated} of {total} sagas, saved {saved}"
+            ));
+            let unhandled = left.len() - saved;
+            if unhandled > 0 {
+                let message = format!("{unhandled} sagas were left partly compensated");
+                return Err(match compensating.left {
+                    0 => message.into(),
+                    _ => DrainTimeout::new(message).into(),
+                });
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    #[tokio::test(start_paused = true)]
+    async fn a_panicking_compensation_leaves_the_other_sagas_running() {
+        let sagas = Sagas::new();
+        let undone = Arc::new(Mutex::new(Vec::new()));
+        sagas
+            .begin("panics")
+            .step_completed("reserve", Value::Null, || async {
+                panic!("injected panic")
+            });
+        for id in ["a", "b"] {
+            let undone = undone.clone();
+            sagas
+                .begin(id)
+                .step_completed("reserve", Value::Null, move || async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    undone.lock().unwrap().push(id);
+                    Ok(())
+                });
+        }
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(sagas.compensation_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        let mut undone = undone.lock().unwrap().clone();
+        undone.sort_unstable();
+        assert_eq!(undone, ["a", "b"]);
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::Failed("1 sagas were left partly compensated".to_owned())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_out_of_time_is_a_drain_timeout() {
+        let sagas = Sagas::new();
+        sagas
+            .begin("slow")
+            .step_completed("reserve", Value::Null, || async {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                Ok(())
+            });
+
+        let report = ShutdownCoordinator::new()
+            .with_budget(Duration::from_secs(1))
+            .with_hook(sagas.compensation_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut("1 sagas were left partly compensated".to_owned())
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! How many hooks run at the same time.
+//!
+//! By default hooks run one after the other, so each one gets the whole of what is left of the
+//! budget and they run in a predictable order. When the budget is too tight for that, a
+//! [`Scheduler`] handed to the coordinator with
+//! [`with_scheduler()`](crate::ShutdownCoordinator::with_scheduler) can start several of them
+//! at once instead. Before each step it is asked how many of the hooks that haven't started
+//! yet to start together; the coordinator waits for all of them before asking again.
+//!
+//! [`Adaptive`] runs the hooks one at a time while the time left allows it, based on how long
+//! the hooks before took, and starts the rest all at once when it doesn't. Hooks that share
+//! something, such as a connection, can be kept from running at the same time:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use lambda_graceful_shutdown::{schedule::Adaptive, ShutdownCoordinator};
+//!
+//! let shutdown = ShutdownCoordinator::new().with_scheduler(
+//!     Adaptive::new()
+//!         .with_estimate(Duration::from_millis(100))
+//!         // Both go through the same Redis connection
+//!         .serialize(["flush-cache", "release-locks"]),
+//! );
+//! ```
+//!
+//! [`ShortestFirst`] also changes the order the hooks run in: the ones that matter most go
+//! first, cheapest first, from how long they took when the same work was done during earlier
+//! invocations, so that as many of them as possible finish in time:
+//!
+//! ```
+//! use lambda_graceful_shutdown::{
+//!     schedule::{CostEstimates, ShortestFirst},
+//!     ShutdownCoordinator,
+//! };
+//!
+//! let costs = CostEstimates::new();
+//! let shutdown = ShutdownCoordinator::new()
+//!     .with_scheduler(ShortestFirst::new(&costs).critical(["flush-metrics", "flush-traces"]));
+//!
+//! # async fn flush_metrics() {}
+//! # async fn example(costs: CostEstimates) {
+//! // At the end of every invocation
+//! costs.measure("flush-metrics", flush_metrics()).await;
+//! # }
+//! ```
+//!
+//! [Static hooks](crate::static_hooks) always run one at a time, before the scheduled ones.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{HookOutcome, HookReport, ShutdownHook};
+
+/// Decides how many hooks to start at once.
+pub trait Scheduler: fmt::Debug + Send + Sync {
+    /// How many of [`state.pending`](ScheduleState::pending) to start together, from the
+    /// first one. Anything below 1 counts as 1.
+    fn next_batch(&self, state: &ScheduleState<'_>) -> usize;
+
+    /// Put `hooks` in the order to run them in, once at the start of the shutdown. They are
+    /// left in the order they were registered in, last first, by default.
+    fn order(&self, hooks: &mut [Arc<dyn ShutdownHook>]) {
+        let _ = hooks;
+    }
+}
+
+/// Where the shutdown is at, for a [`Scheduler`] to decide on the next step.
+#[non_exhaustive]
+pub struct ScheduleState<'a> {
+    /// What is left of the budget.
+    pub remaining: Duration,
+    /// The hooks that haven't started yet, in the order they would run in one at a time.
+    pub pending: &'a [Arc<dyn ShutdownHook>],
+    /// The hooks that finished so far.
+    pub finished: &'a [HookReport],
+}
+
+impl fmt::Debug for ScheduleState<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScheduleState")
+            .field("remaining", &self.remaining)
+            .field(
+                "pending",
+                &self
+                    .pending
+                    .iter()
+                    .map(|hook| hook.name())
+                    .collect::<Vec<_>>(),
+            )
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl ScheduleState<'_> {
+    /// How long the hooks that ran so far took on average, or `None` if none has run yet.
+    pub fn average_elapsed(&self) -> Option<Duration> {
+        let ran: Vec<Duration> = self
+            .finished
+            .iter()
+            .filter(|hook| hook.outcome != HookOutcome::Skipped)
+            .map(|hook| hook.elapsed)
+            .collect();
+        let count = u32::try_from(ran.len()).ok().filter(|&count| count > 0)?;
+        Some(ran.iter().sum::<Duration>() / count)
+    }
+}
+
+/// Runs the hooks one after the other. This is what the coordinator does without a scheduler.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl Scheduler for Sequential {
+    fn next_batch(&self, _state: &ScheduleState<'_>) -> usize {
+        1
+    }
+}
+
+/// Runs the hooks one after the other while the time left allows it, and starts the rest at
+/// once when it doesn't.
+///
+/// The time the remaining hooks need is estimated from how long the ones before took, or
+/// from the [estimate](Self::with_estimate) until one has finished. Hooks in the same
+/// [serialized](Self::serialize) group are never started together.
+#[derive(Debug, Clone)]
+pub struct Adaptive {
+    estimate: Duration,
+    groups: Vec<Vec<String>>,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Adaptive {
+    /// Assume hooks take 50ms until one has finished, with no serialized groups.
+    pub fn new() -> Self {
+        Self {
+            estimate: Duration::from_millis(50),
+            groups: Vec::new(),
+        }
+    }
+
+    /// Assume hooks take `estimate` until one has finished, instead of 50ms.
+    pub fn with_estimate(mut self, estimate: Duration) -> Self {
+        self.estimate = estimate;
+        self
+    }
+
+    /// Never start the hooks named `hooks` together, e.g. because they contend on the same
+    /// connection. Can be called once per group.
+    pub fn serialize<I, S>(mut self, hooks: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.groups
+            .push(hooks.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// The serialized group `hook` is in, if any.
+    fn group(&self, hook: &str) -> Option<usize> {
+        self.groups
+            .iter()
+            .position(|group| group.iter().any(|name| name == hook))
+    }
+}
+
+impl Scheduler for Adaptive {
+    fn next_batch(&self, state: &ScheduleState<'_>) -> usize {
+        let estimate = state.average_elapsed().unwrap_or(self.estimate);
+        let needed =
+            estimate.saturating_mul(u32::try_from(state.pending.len()).unwrap_or(u32::MAX));
+        if needed <= state.remaining {
+            return 1;
+        }
+        // Start as many as possible, up to the second hook of a serialized group
+        let mut started_groups = Vec::new();
+        state
+            .pending
+            .iter()
+            .take_while(|hook| match self.group(hook.name()) {
+                Some(group) if started_groups.contains(&group) => false,
+                Some(group) => {
+                    started_groups.push(group);
+                    true
+                }
+                None => true,
+            })
+            .count()
+    }
+}
+
+/// How long each hook's work takes, as a moving average of the times it was measured.
+///
+/// The work a hook does at shutdown, such as flushing a buffer, is often done at the end of
+/// every invocation too. Measuring it there with [`measure()`](Self::measure) or
+/// [`record()`](Self::record) gives [`ShortestFirst`] an idea of what each hook costs before
+/// the shutdown starts. Each new time counts for 30% of the average by default.
+///
+/// Cloning is cheap, and all clones share the same estimates.
+#[derive(Debug, Clone)]
+pub struct CostEstimates {
+    costs: Arc<Mutex<HashMap<String, Duration>>>,
+    smoothing: f64,
+}
+
+impl Default for CostEstimates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CostEstimates {
+    /// Start with no estimates.
+    pub fn new() -> Self {
+        Self {
+            costs: Arc::default(),
+            smoothing: 0.3,
+        }
+    }
+
+    /// Count each new time for `smoothing` of the average, between 0 and 1, instead of 0.3.
+    /// Higher values follow changes faster, lower ones smooth out the odd slow run.
+    pub fn with_smoothing(mut self, smoothing: f64) -> Self {
+        self.smoothing = smoothing.clamp(f64::EPSILON, 1.0);
+        self
+    }
+
+    /// Add a time `elapsed` for the work of the hook named `hook`.
+    pub fn record(&self, hook: &str, elapsed: Duration) {
+        let mut costs = self.costs.lock().unwrap();
+        match costs.get_mut(hook) {
+            Some(cost) => {
+                *cost = cost.mul_f64(1.0 - self.smoothing) + elapsed.mul_f64(self.smoothing);
+            }
+            None => {
+                costs.insert(hook.to_owned(), elapsed);
+            }
+        }
+    }
+
+    /// Run `work`, the same work the hook named `hook` does, and record how long it took.
+    pub async fn measure<F: Future>(&self, hook: &str, work: F) -> F::Output {
+        let started = Instant::now();
+        let output = work.await;
+        self.record(hook, started.elapsed());
+        output
+    }
+
+    /// The estimate for the hook named `hook`, if it was measured.
+    pub fn estimate(&self, hook: &str) -> Option<Duration> {
+        self.costs.lock().unwrap().get(hook).copied()
+    }
+}
+
+/// Runs the critical hooks first, the cheapest of them first, then the others in their usual
+/// order.
+///
+/// When the budget is too short for all of them, this finishes as many of the critical hooks
+/// as the time allows. The cost of each one comes from the [`CostEstimates`]; hooks that
+/// weren't measured are assumed to take the [estimate](Self::with_estimate), 50ms by default.
+/// How many hooks start at once is left to another scheduler set with
+/// [`with_batches()`](Self::with_batches), which is [`Sequential`] by default.
+#[derive(Debug, Clone)]
+pub struct ShortestFirst {
+    costs: CostEstimates,
+    critical: Vec<String>,
+    estimate: Duration,
+    batches: Arc<dyn Scheduler>,
+}
+
+impl ShortestFirst {
+    /// Order the hooks by `costs`, with none of them critical yet.
+    pub fn new(costs: &CostEstimates) -> Self {
+        Self {
+            costs: costs.clone(),
+            critical: Vec::new(),
+            estimate: Duration::from_millis(50),
+            batches: Arc::new(Sequential),
+        }
+    }
+
+    /// Run the hooks named `hooks` before the others. Can be called more than once.
+    pub fn critical<I, S>(mut self, hooks: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.critical.extend(hooks.into_iter().map(Into::into));
+        self
+    }
+
+    /// Assume critical hooks that weren't measured take `estimate`, instead of 50ms.
+    pub fn with_estimate(mut self, estimate: Duration) -> Self {
+        self.estimate = estimate;
+        self
+    }
+
+    /// Let `scheduler` decide how many hooks to start at once, e.g. [`Adaptive`].
+    pub fn with_batches(mut self, scheduler: impl Scheduler + 'static) -> Self {
+        self.batches = Arc::new(scheduler);
+        self
+    }
+}
+
+impl Scheduler for ShortestFirst {
+    fn next_batch(&self, state: &ScheduleState<'_>) -> usize {
+        self.batches.next_batch(state)
+    }
+
+    fn order(&self, hooks: &mut [Arc<dyn ShutdownHook>]) {
+        self.batches.order(hooks);
+        // A stable sort, so that the others and critical hooks of equal cost keep their order
+        hooks.sort_by_cached_key(|hook| {
+            let name = hook.name();
+            if self.critical.iter().any(|critical| critical == name) {
+                (false, self.costs.estimate(name).unwrap_or(self.estimate))
+            } else {
+                (true, Duration::ZERO)
+            }
+        });
+    }
+}
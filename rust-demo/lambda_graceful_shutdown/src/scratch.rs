@@ -0,0 +1,185 @@
+//! Cleaning up scratch space in `/tmp`.
+//!
+//! `/tmp` is the only writable disk in a Lambda execution environment, and it survives across
+//! warm invocations. Files that are never deleted accumulate until the ephemeral storage
+//! limit (512 MB by default, 10 GB at most) is reached, and the function starts failing with
+//! `ENOSPC`.
+//!
+//! [`ScratchSpace`] keeps track of the temporary files and directories a function creates.
+//! Those registered for the whole environment are deleted by its
+//! [`cleanup_hook()`](ScratchSpace::cleanup_hook), and those registered through an
+//! [`InvocationScratch`] are deleted when the invocation ends.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// Makes the names from [`ScratchSpace::create_dir()`] unique within the process.
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// A registry of temporary files and directories to delete.
+///
+/// Cloning is cheap, and all clones share the same registry.
+#[derive(Clone, Default)]
+pub struct ScratchSpace {
+    paths: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl fmt::Debug for ScratchSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScratchSpace")
+            .field("paths", &self.paths.lock().unwrap())
+            .finish()
+    }
+}
+
+impl ScratchSpace {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delete `path` at shutdown. Directories are deleted with everything in them.
+    pub fn register(&self, path: impl Into<PathBuf>) {
+        self.paths.lock().unwrap().push(path.into());
+    }
+
+    /// Create a new, empty directory in the temp directory, and delete it at shutdown.
+    ///
+    /// The directory's name starts with `prefix`.
+    pub fn create_dir(&self, prefix: &str) -> io::Result<PathBuf> {
+        let path = new_dir(prefix)?;
+        self.register(path.clone());
+        Ok(path)
+    }
+
+    /// Start tracking the files for one invocation. They are deleted when the returned guard
+    /// is dropped, or at shutdown if the invocation is still running then.
+    pub fn invocation(&self) -> InvocationScratch {
+        InvocationScratch {
+            space: self.clone(),
+            paths: Vec::new(),
+        }
+    }
+
+    /// Delete every registered path right away.
+    pub fn cleanup(&self) -> io::Result<()> {
+        let paths = std::mem::take(&mut *self.paths.lock().unwrap());
+        remove_all(&paths)
+    }
+
+    /// A hook that deletes every registered path when the environment shuts down.
+    pub fn cleanup_hook(&self) -> ScratchCleanupHook {
+        ScratchCleanupHook {
+            space: self.clone(),
+        }
+    }
+
+    fn unregister(&self, paths: &[PathBuf]) {
+        self.paths
+            .lock()
+            .unwrap()
+            .retain(|path| !paths.contains(path));
+    }
+}
+
+/// The temporary files of a single invocation, deleted when this is dropped.
+///
+/// Create one at the start of the handler with [`ScratchSpace::invocation()`].
+#[derive(Debug)]
+pub struct InvocationScratch {
+    space: ScratchSpace,
+    paths: Vec<PathBuf>,
+}
+
+impl InvocationScratch {
+    /// Delete `path` when the invocation ends.
+    pub fn register(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        self.space.register(path.clone());
+        self.paths.push(path);
+    }
+
+    /// Create a new, empty directory in the temp directory, and delete it when the invocation
+    /// ends.
+    pub fn create_dir(&mut self, prefix: &str) -> io::Result<PathBuf> {
+        let path = new_dir(prefix)?;
+        self.register(path.clone());
+        Ok(path)
+    }
+}
+
+impl Drop for InvocationScratch {
+    fn drop(&mut self) {
+        self.space.unregister(&self.paths);
+        if let Err(error) = remove_all(&self.paths) {
+            tracing::warn!(%error, "failed to delete invocation scratch files");
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that deletes the paths registered with a [`ScratchSpace`].
+#[derive(Debug)]
+pub struct ScratchCleanupHook {
+    space: ScratchSpace,
+}
+
+impl ShutdownHook for ScratchCleanupHook {
+    fn name(&self) -> &str {
+        "scratch"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let space = self.space.clone();
+            let count = tokio::task::spawn_blocking(move || {
+                let count = space.paths.lock().unwrap().len();
+                space.cleanup().map(|()| count)
+            })
+            .await??;
+            ctx.note(format!("deleted {count} paths"));
+            Ok(())
+        })
+    }
+}
+
+fn new_dir(prefix: &str) -> io::Result<PathBuf> {
+    let name = format!(
+        "{prefix}-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = std::env::temp_dir().join(name);
+    fs::create_dir(&path)?;
+    Ok(path)
+}
+
+/// Delete every path, carrying on past failures and returning the first one.
+fn remove_all(paths: &[PathBuf]) -> io::Result<()> {
+    let mut result = Ok(());
+    for path in paths {
+        if let Err(error) = remove(path) {
+            result = result.and(Err(error));
+        }
+    }
+    result
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(error) => Err(error),
+    };
+    match removed {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        removed => removed,
+    }
+}
@@ -0,0 +1,58 @@
+//! Flushing for the [Sentry](https://docs.rs/sentry) client.
+//!
+//! Sentry sends events from a background transport thread. Events captured during the last
+//! invocations before a spindown are still queued there when the environment goes away,
+//! unless the client is flushed first.
+
+use std::sync::Arc;
+
+use sentry_core::Hub;
+
+use crate::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] that flushes the Sentry client bound to a hub, until the
+/// [drain deadline](ShutdownContext::drain_deadline).
+///
+/// Does nothing if Sentry was never initialized.
+#[derive(Debug, Default)]
+pub struct SentryFlushHook {
+    hub: Option<Arc<Hub>>,
+}
+
+impl SentryFlushHook {
+    /// Flush the client of the main hub, which is the one `sentry::init()` binds to.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flush the client of a specific hub instead.
+    pub fn with_hub(mut self, hub: Arc<Hub>) -> Self {
+        self.hub = Some(hub);
+        self
+    }
+}
+
+impl ShutdownHook for SentryFlushHook {
+    fn name(&self) -> &str {
+        "sentry"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let hub = self.hub.clone().unwrap_or_else(Hub::main);
+            let Some(client) = hub.client() else {
+                return Ok(());
+            };
+            let timeout = ctx.drain_remaining_capped(None);
+            // `flush()` blocks the calling thread until the transport queue is empty.
+            let flushed = tokio::task::spawn_blocking(move || client.flush(Some(timeout))).await?;
+            if !flushed {
+                return Err(DrainTimeout::new(format!(
+                    "sentry transport did not flush within {timeout:?}"
+                ))
+                .into());
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,378 @@
+//! Settling Step Functions task tokens when the worker shuts down.
+//!
+//! A state using the `.waitForTaskToken` callback pattern waits until a worker reports back
+//! with the token it was given. If the worker's execution environment goes away first, the
+//! workflow hangs until the state's own timeout, which is often hours.
+//!
+//! [`TaskTokens`] keeps track of the tokens a function is working on, and its
+//! [`shutdown_hook()`](TaskTokens::shutdown_hook) fails each of them with `SendTaskFailure`,
+//! so the workflow can retry or catch the error right away. It can send `SendTaskHeartbeat`
+//! instead, for workflows that rely on a heartbeat timeout to hand work to another worker.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use aws_sdk_sfn::Client;
+use tokio::task::JoinSet;
+
+use crate::{hook::join_within, BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// The error sent with `SendTaskFailure`, unless set with [`TaskTokensHook::with_error()`].
+const DEFAULT_ERROR: &str = "Lambda.Shutdown";
+
+/// A registry of the task tokens a function is working on.
+///
+/// Cloning is cheap, and all clones share the same registry.
+#[derive(Clone, Default)]
+pub struct TaskTokens {
+    tokens: Arc<Mutex<HashSet<String>>>,
+}
+
+impl fmt::Debug for TaskTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskTokens")
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl TaskTokens {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track `token` until the returned guard is dropped.
+    ///
+    /// Drop the guard once the task has been reported with `SendTaskSuccess` or
+    /// `SendTaskFailure`.
+    pub fn register(&self, token: impl Into<String>) -> PendingTask {
+        let token = token.into();
+        self.tokens.lock().unwrap().insert(token.clone());
+        PendingTask {
+            tokens: self.clone(),
+            token,
+        }
+    }
+
+    /// The number of tokens currently tracked.
+    pub fn pending(&self) -> usize {
+        self.tokens.lock().unwrap().len()
+    }
+
+    /// A hook that fails every tracked task when the environment shuts down.
+    pub fn shutdown_hook(&self, client: Client) -> TaskTokensHook {
+        TaskTokensHook {
+            client,
+            tokens: self.clone(),
+            error: DEFAULT_ERROR.to_owned(),
+            heartbeat: false,
+        }
+    }
+}
+
+/// A task token tracked by [`TaskTokens`], until this is dropped.
+pub struct PendingTask {
+    tokens: TaskTokens,
+    token: String,
+}
+
+impl fmt::Debug for PendingTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingTask").finish_non_exhaustive()
+    }
+}
+
+impl PendingTask {
+    /// The task token.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl Drop for PendingTask {
+    fn drop(&mut self) {
+        self.tokens.tokens.lock().unwrap().remove(&self.token);
+    }
+}
+
+/// A [`ShutdownHook`] that reports the tasks tracked by [`TaskTokens`] to Step Functions.
+///
+/// Tasks that have already been settled or have timed out are skipped. The hook fails if any
+/// other call fails, or if the budget runs out before every task has been reported.
+pub struct TaskTokensHook {
+    client: Client,
+    tokens: TaskTokens,
+    error: String,
+    heartbeat: bool,
+}
+
+impl fmt::Debug for TaskTokensHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskTokensHook")
+            .field("tokens", &self.tokens)
+            .field("error", &self.error)
+            .field("heartbeat", &self.heartbeat)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TaskTokensHook {
+    /// Fail tasks with `error` instead of `Lambda.Shutdown`, e.g. to match a state's `Retry`.
+    pub fn with_error(mut self, error: impl Into<String>) -> Self {
+        self.error = error.into();
+        self
+    }
+
+    /// Send a heartbeat for each task instead of failing it.
+    pub fn with_heartbeat(mut self) -> Self {
+        self.heartbeat = true;
+        self
+    }
+}
+
+enum Sent {
+    Reported,
+    AlreadySettled,
+    Failed,
+}
+
+impl ShutdownHook for TaskTokensHook {
+    fn name(&self) -> &str {
+        "sfn"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let tokens: Vec<String> = self.tokens.tokens.lock().unwrap().iter().cloned().collect();
+            let total = tokens.len();
+            let cause = format!("the worker shut down: {}", ctx.reason());
+
+            let mut calls = JoinSet::new();
+            for token in tokens {
+                let client = self.client.clone();
+                if self.heartbeat {
+                    calls.spawn(async move {
+                        match client.send_task_heartbeat().task_token(token).send().await {
+                            Ok(_) => Sent::Reported,
+                            Err(error)
+                                if error.as_service_error().is_some_and(|error| {
+                                    error.is_task_does_not_exist() || error.is_task_timed_out()
+                                }) =>
+                            {
+                                Sent::AlreadySettled
+                            }
+                            Err(error) => {
+                                tracing::debug!(error = %error, "failed to send task heartbeat");
+                                Sent::Failed
+                            }
+                        }
+                    });
+                } else {
+                    let request = client
+                        .send_task_failure()
+                        .task_token(token)
+                        .error(&self.error)
+                        .cause(&cause);
+                    calls.spawn(async move {
+                        match request.send().await {
+                            Ok(_) => Sent::Reported,
+                            Err(error)
+                                if error.as_service_error().is_some_and(|error| {
+                                    error.is_task_does_not_exist() || error.is_task_timed_out()
+                                }) =>
+                            {
+                                Sent::AlreadySettled
+                            }
+                            Err(error) => {
+                                tracing::debug!(error = %error, "failed to send task failure");
+                                Sent::Failed
+                            }
+                        }
+                    });
+                }
+            }
+
+            let (mut reported, mut settled, mut failed) = (0, 0, 0);
+            let joined = join_within(ctx, &mut calls, |sent| match sent {
+                Sent::Reported => reported += 1,
+                Sent::AlreadySettled => settled += 1,
+                Sent::Failed => failed += 1,
+            })
+            .await;
+            failed += joined.panicked;
+
+            let action = if self.heartbeat {
+                "heartbeat"
+            } else {
+                "failure"
+            };
+            ctx.note(format!(
+                "sent {action} for {reported} of {total} tasks, {settled} were already settled"
+            ));
+            if let Some(timed_out) = joined.timed_out("tasks left to report") {
+                return Err(timed_out);
+            }
+            if failed > 0 {
+                return Err(format!("failed to report {failed} tasks").into());
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use aws_sdk_sfn::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+    use aws_smithy_runtime_api::{
+        client::{
+            http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector},
+            orchestrator::{HttpRequest, HttpResponse},
+        },
+        http::StatusCode,
+    };
+    use aws_smithy_types::body::SdkBody;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    /// Answers `SendTaskFailure` and `SendTaskHeartbeat`: tokens starting with `done` no longer
+    /// exist, with `fail` the call fails, and with `slow` it never finishes. Records the
+    /// operation and body of each call.
+    #[derive(Debug, Default)]
+    struct FakeSfn {
+        calls: Mutex<Vec<(String, Value)>>,
+    }
+
+    #[derive(Debug)]
+    struct Connector(Arc<FakeSfn>);
+
+    impl HttpConnector for Connector {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let target = request.headers().get("x-amz-target").unwrap().to_owned();
+            let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+            let token = body["taskToken"].as_str().unwrap().to_owned();
+            self.0.calls.lock().unwrap().push((target, body));
+            let (status, body) = if token.starts_with("done") {
+                (400, r#"{"__type":"TaskDoesNotExist"}"#)
+            } else if token.starts_with("fail") {
+                (500, r#"{"__type":"InternalServerError"}"#)
+            } else if token.starts_with("slow") {
+                return HttpConnectorFuture::new(std::future::pending());
+            } else {
+                (200, "{}")
+            };
+            HttpConnectorFuture::ready(Ok(HttpResponse::new(
+                StatusCode::try_from(status).unwrap(),
+                SdkBody::from(body),
+            )))
+        }
+    }
+
+    fn client(fake: &Arc<FakeSfn>) -> Client {
+        let connector = SharedHttpConnector::new(Connector(fake.clone()));
+        let config = aws_sdk_sfn::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .http_client(http_client_fn(move |_, _| connector.clone()))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        Client::from_conf(config)
+    }
+
+    #[test]
+    fn a_dropped_guard_stops_tracking_its_token() {
+        let tokens = TaskTokens::new();
+        let first = tokens.register("a");
+        let _second = tokens.register("b");
+        assert_eq!(first.token(), "a");
+        assert_eq!(tokens.pending(), 2);
+        drop(first);
+        assert_eq!(tokens.pending(), 1);
+    }
+
+    #[tokio::test]
+    async fn pending_tasks_are_failed_with_the_shutdown_reason() {
+        let fake = Arc::new(FakeSfn::default());
+        let tokens = TaskTokens::new();
+        let _pending = [tokens.register("a"), tokens.register("done-b")];
+        let report = ShutdownCoordinator::new()
+            .with_hook(
+                tokens
+                    .shutdown_hook(client(&fake))
+                    .with_error("Worker.Gone"),
+            )
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent failure for 1 of 2 tasks, 1 were already settled"]
+        );
+
+        let calls = fake.calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        for (target, body) in calls.iter() {
+            assert_eq!(target, "AWSStepFunctions.SendTaskFailure");
+            assert_eq!(body["error"], "Worker.Gone");
+            assert_eq!(
+                body["cause"],
+                format!("the worker shut down: {}", ShutdownReason::Sigterm)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeats_are_sent_instead_when_asked() {
+        let fake = Arc::new(FakeSfn::default());
+        let tokens = TaskTokens::new();
+        let _pending = [tokens.register("a"), tokens.register("fail-b")];
+        let report = ShutdownCoordinator::new()
+            .with_hook(tokens.shutdown_hook(client(&fake)).with_heartbeat())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::Failed("failed to report 1 tasks".to_owned())
+        );
+        assert_eq!(
+            report.hooks[0].notes,
+            ["sent heartbeat for 1 of 2 tasks, 0 were already settled"]
+        );
+        assert!(fake
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(target, _)| target == "AWSStepFunctions.SendTaskHeartbeat"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_out_of_time_is_a_drain_timeout() {
+        let fake = Arc::new(FakeSfn::default());
+        let tokens = TaskTokens::new();
+        let _pending = tokens.register("slow-a");
+        let report = ShutdownCoordinator::new()
+            .with_budget(Duration::from_secs(1))
+            .with_hook(tokens.shutdown_hook(client(&fake)))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut("ran out of time with 1 tasks left to report".to_owned())
+        );
+    }
+}
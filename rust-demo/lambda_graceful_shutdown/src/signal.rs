@@ -0,0 +1,66 @@
+//! Waiting for `SIGTERM` and `SIGINT` alongside the runtime, instead of in a task of its own.
+//!
+//! A signal handler spawned next to `lambda_runtime::run()` that ends with
+//! `std::process::exit()` never lets the runtime finish what it was doing: an invocation still
+//! in flight is cut off wherever it was, and the order in which the hooks, the runtime and
+//! the process exit happen depends on the scheduler.
+//! [`ShutdownCoordinator::run_until_signal()`](crate::ShutdownCoordinator::run_until_signal)
+//! selects on the signals and the runtime together instead. When a signal comes, the runtime
+//! is still polled while the hooks run, so the invocation in flight can respond, and `main`
+//! returns once they are done:
+//!
+//! ```no_run
+//! use std::process::ExitCode;
+//!
+//! use lambda_graceful_shutdown::{signal::RunOutcome, ShutdownCoordinator};
+//! use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+//! use serde_json::Value;
+//!
+//! async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+//!     Ok(event.payload)
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<ExitCode, Error> {
+//!     let shutdown = ShutdownCoordinator::new();
+//!     match shutdown.run_until_signal(run(service_fn(handler))).await? {
+//!         RunOutcome::Finished(result) => result.map(|()| ExitCode::SUCCESS),
+//!         RunOutcome::ShutDown(report) => Ok(ExitCode::from(report.exit_code() as u8)),
+//!     }
+//! }
+//! ```
+
+use std::io;
+
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+pub use crate::trigger::RunOutcome;
+use crate::ShutdownReason;
+
+/// Handlers for the signals that start a shutdown.
+///
+/// The handlers are installed when this is created, so a signal that arrives before
+/// [`recv()`](Self::recv) is first polled isn't lost.
+#[derive(Debug)]
+pub struct ShutdownSignals {
+    sigint: Signal,
+    sigterm: Signal,
+}
+
+impl ShutdownSignals {
+    /// Install the `SIGINT` and `SIGTERM` handlers.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt())?,
+            sigterm: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Wait for the next `SIGINT` or `SIGTERM`.
+    pub async fn recv(&mut self) -> ShutdownReason {
+        tokio::select! {
+            _ = self.sigint.recv() => ShutdownReason::Sigint,
+            _ = self.sigterm.recv() => ShutdownReason::Sigterm,
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Rehearsing a spindown locally, under `cargo lambda watch` or the Runtime Interface
+//! Emulator.
+//!
+//! Neither of them ever shuts the function down the way Lambda does, so the `SIGTERM` handler
+//! and the hooks behind it only run once deployed. [`LocalSpindown`] fills the gap: on
+//! `POST /shutdown/spindown`, or on Enter with
+//! [`with_keystroke()`](LocalSpindown::with_keystroke), it sends the function's own process a
+//! `SIGTERM`, and a `SIGKILL` once the shutdown window is over if the process is still
+//! around, like Lambda would.
+//!
+//! ```text
+//! curl -X POST http://127.0.0.1:9901/shutdown/spindown
+//! ```
+//!
+//! The signals go through the real signal handler, so this checks the whole path: the
+//! handler, the hooks fitting in the window, and the process exiting on its own.
+//! [`spawn_if_local()`](LocalSpindown::spawn_if_local) does nothing on Lambda, so it can be
+//! left in place when deploying.
+
+use std::{
+    io::{self, BufRead},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::environment::{signal, Environment, Signal};
+
+/// Where the trigger listens unless set with [`LocalSpindown::with_address()`]. Next to the
+/// `debug` server's port.
+pub const DEFAULT_ADDRESS: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 9901);
+
+/// The path that triggers the spindown.
+const SPINDOWN_PATH: &str = "/shutdown/spindown";
+
+/// Requests with headers larger than this are rejected.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// The shutdown window with only internal extensions registered, unless set with
+/// [`LocalSpindown::with_window()`].
+const DEFAULT_WINDOW: Duration = Duration::from_millis(500);
+
+/// Sends the function's process a `SIGTERM` on request, then a `SIGKILL` after the shutdown
+/// window, for local development.
+#[derive(Debug, Clone)]
+pub struct LocalSpindown {
+    address: SocketAddr,
+    window: Duration,
+    keystroke: bool,
+}
+
+impl Default for LocalSpindown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalSpindown {
+    /// Listen on [`DEFAULT_ADDRESS`], and kill the process 500ms after the `SIGTERM`.
+    pub fn new() -> Self {
+        Self {
+            address: DEFAULT_ADDRESS,
+            window: DEFAULT_WINDOW,
+            keystroke: false,
+        }
+    }
+
+    /// Listen on `address` instead.
+    pub fn with_address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Kill the process `window` after the `SIGTERM` instead of 500ms, e.g. 2s to emulate
+    /// a function with an external extension.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Also trigger the spindown when Enter is pressed in the terminal the function runs in.
+    pub fn with_keystroke(mut self) -> Self {
+        self.keystroke = true;
+        self
+    }
+
+    /// Wait for triggers until the task is dropped or the listener fails.
+    pub async fn serve(self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.address).await?;
+        tracing::info!(
+            address = %self.address,
+            window_ms = self.window.as_millis() as u64,
+            "POST {SPINDOWN_PATH} to rehearse a spindown"
+        );
+        let spindown = Arc::new(self);
+        if spindown.keystroke {
+            let spindown = spindown.clone();
+            std::thread::spawn(move || {
+                for _line in io::stdin().lock().lines().map_while(Result::ok) {
+                    spindown.trigger();
+                }
+            });
+        }
+        loop {
+            let (stream, _peer) = listener.accept().await?;
+            let spindown = spindown.clone();
+            tokio::spawn(async move {
+                if let Err(error) = spindown.respond(stream).await {
+                    tracing::debug!(%error, "failed to answer a spindown request");
+                }
+            });
+        }
+    }
+
+    /// Start waiting for triggers in the background, unless the function is running on
+    /// Lambda, as found by [`Environment::detect()`].
+    pub fn spawn_if_local(self) -> Option<JoinHandle<io::Result<()>>> {
+        Environment::detect()
+            .is_local_dev()
+            .then(|| tokio::spawn(self.serve()))
+    }
+
+    /// Send the `SIGTERM`, and the `SIGKILL` once the window is over.
+    fn trigger(&self) {
+        let pid = std::process::id();
+        tracing::warn!(
+            pid,
+            window_ms = self.window.as_millis() as u64,
+            "rehearsing a spindown: sending SIGTERM"
+        );
+        if let Err(error) = signal(pid, Signal::Term) {
+            tracing::warn!(%error, "failed to send SIGTERM");
+            return;
+        }
+        // A thread rather than a task, so the kill still comes if the runtime is stuck
+        let window = self.window;
+        std::thread::spawn(move || {
+            std::thread::sleep(window);
+            tracing::warn!(
+                window_ms = window.as_millis() as u64,
+                "the shutdown window is over: sending SIGKILL"
+            );
+            let _ = signal(pid, Signal::Kill);
+        });
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..read]);
+            if request.len() > MAX_REQUEST_BYTES {
+                return write_response(&mut stream, "431 Request Header Fields Too Large").await;
+            }
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+        let (method, path) = (request_line.next(), request_line.next());
+        match (method, path) {
+            (Some("POST"), Some(SPINDOWN_PATH)) => {
+                // Answer first, the process may not be around for long
+                write_response(&mut stream, "202 Accepted").await?;
+                self.trigger();
+                Ok(())
+            }
+            (Some(_), Some(SPINDOWN_PATH)) => {
+                write_response(&mut stream, "405 Method Not Allowed").await
+            }
+            _ => write_response(&mut stream, "404 Not Found").await,
+        }
+    }
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
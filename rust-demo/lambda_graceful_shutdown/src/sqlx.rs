@@ -0,0 +1,60 @@
+//! Closing [`sqlx`] connection pools.
+//!
+//! When the execution environment is torn down, open database connections are cut without a
+//! goodbye. Postgres and MySQL then hold on to the backend until they notice, and RDS Proxy
+//! keeps pinned connections around for a while. Closing the pool terminates each connection
+//! cleanly instead.
+
+use std::time::Duration;
+
+use sqlx::{Database, Pool};
+
+use crate::{clock, BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] that calls [`Pool::close()`] and waits for connections to be closed.
+///
+/// Connections that are checked out are closed as soon as they are returned to the pool, so
+/// this waits as long as the slowest in-flight query, up to the hook's timeout.
+#[derive(Debug)]
+pub struct SqlxPoolHook<DB: Database> {
+    pool: Pool<DB>,
+    timeout: Option<Duration>,
+}
+
+impl<DB: Database> SqlxPoolHook<DB> {
+    /// Create a hook for the given pool. Pools are cheap to clone, so pass in a clone of yours.
+    pub fn new(pool: Pool<DB>) -> Self {
+        Self {
+            pool,
+            timeout: None,
+        }
+    }
+
+    /// Stop waiting for connections after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<DB: Database> ShutdownHook for SqlxPoolHook<DB> {
+    fn name(&self) -> &str {
+        "sqlx"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let timeout = ctx.drain_remaining_capped(self.timeout);
+            let clock = ctx.clock();
+            clock::timeout_at(clock, clock.now() + timeout, self.pool.close())
+                .await
+                .ok_or_else(|| {
+                    DrainTimeout::new(format!(
+                        "{} connections still open after {timeout:?}",
+                        self.pool.size()
+                    ))
+                })?;
+            Ok(())
+        })
+    }
+}
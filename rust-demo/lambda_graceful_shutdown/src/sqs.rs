@@ -0,0 +1,343 @@
+//! Batched sending to SQS.
+//!
+//! `SendMessageBatch` sends up to 10 messages for the price of one request, but batching
+//! means holding on to messages for a while, and a spindown would drop them.
+//! [`BufferedSqsSender`] sends a batch as soon as one is full, and its
+//! [`flush_hook()`](BufferedSqsSender::flush_hook) sends whatever partial batch is left when
+//! the environment shuts down, retrying messages that failed for as long as the budget allows.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use aws_sdk_sqs::{
+    types::{builders::SendMessageBatchRequestEntryBuilder, SendMessageBatchRequestEntry},
+    Client,
+};
+
+use crate::{
+    records::{RecordApi, RecordWriter},
+    BoxFuture, Error, ShutdownContext, ShutdownHook,
+};
+
+/// SQS accepts at most 10 messages per batch.
+const MAX_BATCH_MESSAGES: usize = 10;
+
+/// Default limit on the total size of a batch, which is also the SQS limit for a single
+/// message unless the queue allows larger ones.
+const DEFAULT_MAX_BATCH_BYTES: usize = 256 * 1024;
+
+struct SqsApi {
+    client: Client,
+    queue_url: String,
+    next_id: AtomicU64,
+}
+
+impl RecordApi for SqsApi {
+    type Record = SendMessageBatchRequestEntry;
+
+    fn put(&self, records: Vec<Self::Record>) -> BoxFuture<'_, Result<Vec<Option<String>>, Error>> {
+        Box::pin(async move {
+            let ids: Vec<_> = records.iter().map(|entry| entry.id().to_owned()).collect();
+            let output = self
+                .client
+                .send_message_batch()
+                .queue_url(&self.queue_url)
+                .set_entries(Some(records))
+                .send()
+                .await?;
+            let failed = output.failed();
+            if let Some(failure) = failed.first() {
+                tracing::debug!(
+                    code = failure.code(),
+                    message = failure.message(),
+                    "failed to send messages to SQS"
+                );
+            }
+            Ok(ids
+                .iter()
+                .map(|id| {
+                    failed
+                        .iter()
+                        .find(|failure| failure.id() == id)
+                        .map(|failure| failure.code().to_owned())
+                })
+                .collect())
+        })
+    }
+}
+
+/// Sends messages to a queue in batches of up to 10.
+///
+/// Cloning is cheap, and all clones share the same buffer.
+#[derive(Clone)]
+pub struct BufferedSqsSender {
+    writer: Arc<RecordWriter<SqsApi>>,
+}
+
+impl fmt::Debug for BufferedSqsSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferedSqsSender")
+            .field("queue_url", &self.writer.api().queue_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BufferedSqsSender {
+    /// Create a sender for the queue at `queue_url`.
+    pub fn new(client: Client, queue_url: impl Into<String>) -> Self {
+        Self::with_max_batch_bytes(client, queue_url, DEFAULT_MAX_BATCH_BYTES)
+    }
+
+    /// Create a sender that sends a batch once its message bodies add up to `max_batch_bytes`.
+    pub fn with_max_batch_bytes(
+        client: Client,
+        queue_url: impl Into<String>,
+        max_batch_bytes: usize,
+    ) -> Self {
+        let api = SqsApi {
+            client,
+            queue_url: queue_url.into(),
+            next_id: AtomicU64::new(0),
+        };
+        Self {
+            writer: Arc::new(RecordWriter::new(api, MAX_BATCH_MESSAGES, max_batch_bytes)),
+        }
+    }
+
+    /// Queue a message, sending a batch if this fills one up.
+    ///
+    /// If some messages of that batch are not sent, they are kept for the next send, and
+    /// [`last_error()`](Self::last_error) says why. Only a message that can't be queued is an
+    /// error.
+    pub async fn send(&self, body: impl Into<String>) -> Result<(), Error> {
+        self.send_with(body, |entry| entry).await
+    }
+
+    /// Queue a message with extra attributes, like a message group id for FIFO queues.
+    ///
+    /// The entry id is set by the sender, so it is unique within the batch.
+    pub async fn send_with(
+        &self,
+        body: impl Into<String>,
+        f: impl FnOnce(SendMessageBatchRequestEntryBuilder) -> SendMessageBatchRequestEntryBuilder,
+    ) -> Result<(), Error> {
+        let id = self.writer.api().next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = f(SendMessageBatchRequestEntry::builder().message_body(body))
+            .id(id.to_string())
+            .build()?;
+        let size = entry.message_body().len();
+        self.writer.push(entry, size).await
+    }
+
+    /// The number of messages waiting to be sent.
+    pub async fn pending(&self) -> usize {
+        self.writer.pending().await
+    }
+
+    /// Why the last batch sent failed, in full or in part, unless one has been sent
+    /// in full since.
+    /// Its messages are still queued, and go out with a later batch or flush.
+    pub fn last_error(&self) -> Option<String> {
+        self.writer.last_error()
+    }
+
+    /// Send every queued message, including a final partial batch.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.writer.flush().await
+    }
+
+    /// A hook that sends any queued messages when the environment shuts down.
+    pub fn flush_hook(&self) -> SqsFlushHook {
+        SqsFlushHook {
+            sender: self.clone(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that sends the messages still buffered in a [`BufferedSqsSender`].
+#[derive(Debug)]
+pub struct SqsFlushHook {
+    sender: BufferedSqsSender,
+}
+
+impl ShutdownHook for SqsFlushHook {
+    fn name(&self) -> &str {
+        "sqs"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(
+            self.sender
+                .writer
+                .flush_until(ctx.clock(), ctx.drain_deadline()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex as StdMutex};
+
+    use aws_sdk_sqs::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+    use aws_smithy_runtime_api::{
+        client::{
+            http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector},
+            orchestrator::{HttpRequest, HttpResponse},
+        },
+        http::StatusCode,
+    };
+    use aws_smithy_types::body::SdkBody;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{ShutdownCoordinator, ShutdownReason};
+
+    /// Answers `SendMessageBatch`, failing the messages whose body is in `failing`, and the
+    /// whole request if one of them is in `unavailable`, and records the bodies of each batch.
+    #[derive(Debug, Default)]
+    struct FakeSqs {
+        failing: StdMutex<HashSet<String>>,
+        unavailable: StdMutex<HashSet<String>>,
+        batches: StdMutex<Vec<Vec<String>>>,
+    }
+
+    #[derive(Debug)]
+    struct Connector(Arc<FakeSqs>);
+
+    impl HttpConnector for Connector {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let body: Value = serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+            let failing = self.0.failing.lock().unwrap();
+            let (mut successful, mut failed, mut bodies) = (Vec::new(), Vec::new(), Vec::new());
+            for entry in body["Entries"].as_array().unwrap() {
+                let (id, body) = (&entry["Id"], entry["MessageBody"].as_str().unwrap());
+                bodies.push(body.to_owned());
+                if failing.contains(body) {
+                    failed.push(json!({
+                        "Id": id,
+                        "SenderFault": false,
+                        "Code": "InternalError",
+                        "Message": "try again",
+                    }));
+                } else {
+                    successful.push(json!({ "Id": id, "MessageId": id, "MD5OfMessageBody": "" }));
+                }
+            }
+            let unavailable = self.0.unavailable.lock().unwrap();
+            let status = if bodies.iter().any(|body| unavailable.contains(body)) {
+                successful.clear();
+                failed.clear();
+                500
+            } else {
+                200
+            };
+            self.0.batches.lock().unwrap().push(bodies);
+            if status == 500 {
+                let body = json!({ "__type": "InternalError", "message": "unavailable" });
+                return HttpConnectorFuture::ready(Ok(HttpResponse::new(
+                    StatusCode::try_from(status).unwrap(),
+                    SdkBody::from(body.to_string()),
+                )));
+            }
+            let body = json!({ "Successful": successful, "Failed": failed }).to_string();
+            HttpConnectorFuture::ready(Ok(HttpResponse::new(
+                StatusCode::try_from(200).unwrap(),
+                SdkBody::from(body),
+            )))
+        }
+    }
+
+    fn sender(fake: &Arc<FakeSqs>, max_batch_bytes: usize) -> BufferedSqsSender {
+        let connector = SharedHttpConnector::new(Connector(fake.clone()));
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .http_client(http_client_fn(move |_, _| connector.clone()))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        BufferedSqsSender::with_max_batch_bytes(
+            Client::from_conf(config),
+            "https://sqs.us-east-1.amazonaws.com/123456789012/orders",
+            max_batch_bytes,
+        )
+    }
+
+    #[tokio::test]
+    async fn messages_are_sent_in_batches_of_ten() {
+        let fake = Arc::new(FakeSqs::default());
+        let sender = sender(&fake, 1024);
+        for message in 0..12 {
+            sender.send(message.to_string()).await.unwrap();
+        }
+        assert_eq!(sender.pending().await, 2);
+        assert_eq!(fake.batches.lock().unwrap()[0].len(), 10);
+
+        sender.flush().await.unwrap();
+        assert_eq!(fake.batches.lock().unwrap()[1], ["10", "11"]);
+        assert!(sender.send("x".repeat(1025)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_messages_are_kept_for_the_flush_hook() {
+        let fake = Arc::new(FakeSqs::default());
+        fake.failing.lock().unwrap().insert("b".to_owned());
+        let sender = sender(&fake, 2);
+        sender.send("a").await.unwrap();
+        sender.send("b").await.unwrap();
+        assert_eq!(sender.pending().await, 1);
+        assert_eq!(
+            sender.last_error().unwrap(),
+            "1 records were throttled or failed: InternalError"
+        );
+
+        fake.failing.lock().unwrap().clear();
+        let report = ShutdownCoordinator::new()
+            .with_hook(sender.flush_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(sender.pending().await, 0);
+        assert_eq!(sender.last_error(), None);
+        assert_eq!(*fake.batches.lock().unwrap(), [vec!["a", "b"], vec!["b"]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_batch_does_not_hold_up_the_ones_after_it() {
+        let fake = Arc::new(FakeSqs::default());
+        fake.unavailable.lock().unwrap().insert("0".to_owned());
+        let sender = sender(&fake, 1024);
+        for message in 0..15 {
+            let _ = sender.send(message.to_string()).await;
+        }
+        assert_eq!(sender.pending().await, 15);
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(sender.flush_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(!report.is_clean());
+        assert_eq!(sender.pending().await, 10);
+        let batches = fake.batches.lock().unwrap();
+        assert_eq!(
+            batches
+                .iter()
+                .filter(|batch| batch[0] == "10")
+                .collect::<Vec<_>>(),
+            [&["10", "11", "12", "13", "14"]]
+        );
+        // The failed batch was retried while the budget lasted
+        assert!(batches.iter().filter(|batch| batch[0] == "0").count() > 7);
+    }
+}
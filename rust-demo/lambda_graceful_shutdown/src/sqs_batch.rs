@@ -0,0 +1,82 @@
+//! Handing unprocessed SQS messages back to the queue when shutdown interrupts a batch.
+//!
+//! An SQS-triggered function gets up to 10,000 messages per invocation. If the environment
+//! starts shutting down halfway through, the whole batch is eventually retried, including the
+//! messages that were already processed, or worse, some messages are left half-processed.
+//!
+//! [`process_sqs_batch()`] works through a batch one message at a time, and stops starting
+//! new ones once [`ShutdownCoordinator::shutdown()`] has been called. The messages it didn't
+//! get to are returned as `batchItemFailures`, so SQS redelivers exactly those. This needs
+//! `ReportBatchItemFailures` enabled on the event source mapping.
+
+use std::{fmt, future::Future};
+
+use aws_lambda_events::event::sqs::{BatchItemFailure, SqsBatchResponse, SqsEvent, SqsMessage};
+
+use crate::ShutdownCoordinator;
+
+/// Process the messages of `event` in order with `handle`, until they are all done or the
+/// environment starts shutting down.
+///
+/// Messages that `handle` fails on, and those not processed because of the shutdown, are
+/// returned as failures for SQS to redeliver. The message being processed when the shutdown
+/// starts is allowed to finish. On FIFO queues, every message after a failed one is returned
+/// as well, so messages in a group are never processed out of order.
+///
+/// The point at which the batch was cut off is logged, with the number of messages processed
+/// and the id of the first one left over.
+pub async fn process_sqs_batch<F, Fut, E>(
+    coordinator: &ShutdownCoordinator,
+    event: SqsEvent,
+    mut handle: F,
+) -> SqsBatchResponse
+where
+    F: FnMut(SqsMessage) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: fmt::Display,
+{
+    let total = event.records.len();
+    let mut failures = Vec::new();
+    let mut messages = event.records.into_iter();
+    let mut processed = 0;
+
+    while let Some(message) = messages.next() {
+        if coordinator.is_shutting_down() {
+            tracing::warn!(
+                processed,
+                unprocessed = total - processed,
+                first_unprocessed = message.message_id.as_deref(),
+                "shutdown interrupted the SQS batch, returning the rest to the queue"
+            );
+            failures.extend(std::iter::once(message).chain(messages).filter_map(failure));
+            break;
+        }
+
+        let fifo = message.attributes.contains_key("MessageGroupId");
+        let message_id = message.message_id.clone();
+        processed += 1;
+        if let Err(error) = handle(message).await {
+            tracing::debug!(message_id, %error, "failed to process SQS message");
+            failures.extend(message_id.map(|item_identifier| BatchItemFailure { item_identifier }));
+            if fifo {
+                failures.extend(messages.filter_map(failure));
+                break;
+            }
+        }
+    }
+
+    SqsBatchResponse {
+        batch_item_failures: failures,
+    }
+}
+
+/// The failure returning `message` to the queue.
+fn failure(message: SqsMessage) -> Option<BatchItemFailure> {
+    match message.message_id {
+        Some(item_identifier) => Some(BatchItemFailure { item_identifier }),
+        None => {
+            tracing::warn!("an SQS message without a message id can't be returned to the queue");
+            None
+        }
+    }
+}
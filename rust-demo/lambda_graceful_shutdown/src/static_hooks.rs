@@ -0,0 +1,135 @@
+//! Hooks known at compile time, dispatched without boxing.
+//!
+//! The hooks registered with a [`ShutdownCoordinator`] are trait objects, so each call returns
+//! a boxed future. When a function has a small set of hooks that never changes, they can be
+//! kept in a tuple instead: [`StaticHooks`] runs them with their concrete types, so each call
+//! is monomorphized and nothing is boxed.
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{
+//!     static_hooks::{StaticHook, StaticHooks},
+//!     Error, ShutdownContext, ShutdownCoordinator, ShutdownReason,
+//! };
+//!
+//! struct FlushMetrics;
+//!
+//! impl StaticHook for FlushMetrics {
+//!     fn name(&self) -> &str {
+//!         "flush-metrics"
+//!     }
+//!
+//!     async fn shutdown(&self, _ctx: &ShutdownContext) -> Result<(), Error> {
+//!         // flush whatever is still buffered
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # struct ClosePool;
+//! # impl StaticHook for ClosePool {
+//! #     fn name(&self) -> &str { "close-pool" }
+//! #     async fn shutdown(&self, _ctx: &ShutdownContext) -> Result<(), Error> { Ok(()) }
+//! # }
+//! # async fn example() {
+//! let hooks = StaticHooks::new((FlushMetrics, ClosePool));
+//! let shutdown = ShutdownCoordinator::new();
+//! // ...from the SIGTERM handler:
+//! let report = shutdown.shutdown_static(ShutdownReason::Sigterm, &hooks).await;
+//! # }
+//! ```
+//!
+//! Tuples of up to 8 hooks are supported; nest them for more. A static hook is usually a
+//! struct owning the resource it tears down, with an `async fn shutdown()`. Closures made into
+//! hooks with [`hook_fn()`](crate::hook_fn) are registered with the coordinator instead, since
+//! their futures can't be proven `Send` without boxing.
+//!
+//! [`ShutdownCoordinator`]: crate::ShutdownCoordinator
+
+use std::future::Future;
+
+pub use crate::coordinator::HookRunner;
+use crate::{Error, ShutdownContext};
+
+/// A [`ShutdownHook`](crate::ShutdownHook) whose future isn't boxed.
+///
+/// Implement it with an `async fn shutdown()`, which is `Send` as long as the state it holds
+/// across `.await`s is.
+pub trait StaticHook: Send + Sync {
+    /// Name used in logs and in the [`ShutdownReport`](crate::ShutdownReport).
+    fn name(&self) -> &str;
+
+    /// Flush, close or otherwise tear down whatever this hook is responsible for.
+    fn shutdown<'a>(
+        &'a self,
+        ctx: &'a ShutdownContext,
+    ) -> impl Future<Output = Result<(), Error>> + Send + 'a;
+}
+
+/// A fixed set of hooks, such as a tuple of [`StaticHook`]s.
+pub trait HookSet: Send + Sync {
+    /// Run each hook with `runner`, in order.
+    fn run_each<'r>(
+        &'r self,
+        runner: &'r mut HookRunner<'_>,
+    ) -> impl Future<Output = ()> + Send + 'r;
+}
+
+impl HookSet for () {
+    async fn run_each<'r>(&'r self, _runner: &'r mut HookRunner<'_>) {}
+}
+
+macro_rules! impl_hook_set {
+    ($($hook:ident),+) => {
+        impl<$($hook: HookSet),+> HookSet for ($($hook,)+) {
+            fn run_each<'r>(
+                &'r self,
+                runner: &'r mut HookRunner<'_>,
+            ) -> impl Future<Output = ()> + Send + 'r {
+                #[allow(non_snake_case)]
+                let ($($hook,)+) = self;
+                async move {
+                    $($hook.run_each(runner).await;)+
+                }
+            }
+        }
+    };
+}
+
+impl_hook_set!(A);
+impl_hook_set!(A, B);
+impl_hook_set!(A, B, C);
+impl_hook_set!(A, B, C, D);
+impl_hook_set!(A, B, C, D, E);
+impl_hook_set!(A, B, C, D, E, F);
+impl_hook_set!(A, B, C, D, E, F, G);
+impl_hook_set!(A, B, C, D, E, F, G, H);
+
+/// Every [`StaticHook`] is a set of one.
+impl<H: StaticHook> HookSet for H {
+    fn run_each<'r>(
+        &'r self,
+        runner: &'r mut HookRunner<'_>,
+    ) -> impl Future<Output = ()> + Send + 'r {
+        runner.run(self)
+    }
+}
+
+/// A set of hooks run by [`ShutdownCoordinator::shutdown_static()`], in the order they appear
+/// in, before the hooks registered with the coordinator.
+///
+/// [`ShutdownCoordinator::shutdown_static()`]: crate::ShutdownCoordinator::shutdown_static
+#[derive(Debug, Clone, Default)]
+pub struct StaticHooks<T> {
+    hooks: T,
+}
+
+impl<T: HookSet> StaticHooks<T> {
+    /// Run `hooks`, usually a tuple of [`StaticHook`]s.
+    pub fn new(hooks: T) -> Self {
+        Self { hooks }
+    }
+
+    /// The hooks.
+    pub fn hooks(&self) -> &T {
+        &self.hooks
+    }
+}
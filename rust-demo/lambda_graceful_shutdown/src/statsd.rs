@@ -0,0 +1,48 @@
+//! Flushing for [`cadence`] StatsD clients, e.g. pointed at the Datadog Lambda extension.
+//!
+//! Buffered sinks like [`BufferedUdpMetricSink`](cadence::BufferedUdpMetricSink) and
+//! [`BufferedUnixMetricSink`](cadence::BufferedUnixMetricSink) only send a packet once their
+//! buffer fills up, so the last few metrics of a function usually sit in memory until
+//! something flushes them.
+
+use std::sync::{Arc, Mutex};
+
+use cadence::StatsdClient;
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] that flushes a [`StatsdClient`], then lets go of it.
+///
+/// Once every other clone of the client is gone too, the socket is closed. A
+/// [`QueuingMetricSink`](cadence::QueuingMetricSink) hands metrics to a background thread
+/// that this hook can't wait for, so prefer a plain buffered sink on Lambda.
+#[derive(Debug)]
+pub struct StatsdFlushHook {
+    client: Mutex<Option<Arc<StatsdClient>>>,
+}
+
+impl StatsdFlushHook {
+    /// Create a hook for the given client.
+    pub fn new(client: Arc<StatsdClient>) -> Self {
+        Self {
+            client: Mutex::new(Some(client)),
+        }
+    }
+}
+
+impl ShutdownHook for StatsdFlushHook {
+    fn name(&self) -> &str {
+        "statsd"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let client = self.client.lock().unwrap().take();
+            if let Some(client) = client {
+                // Sending on a unix socket can block if the agent is slow to read.
+                tokio::task::spawn_blocking(move || client.flush()).await??;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,111 @@
+//! Writes to stdout held back while a shutdown runs, and written together when it ends.
+//!
+//! The lifecycle records, the `SHUTDOWN_SUMMARY` line and hooks flushing EMF metrics all write
+//! to stdout in the last milliseconds of the environment, usually one short line at a time.
+//! While [`ShutdownCoordinator::shutdown()`](crate::ShutdownCoordinator::shutdown) runs,
+//! whatever goes through [`BatchedStdout`] is kept in memory instead, and written with as few
+//! vectored writes as possible once the shutdown is over. Outside of a shutdown, it writes
+//! straight through.
+//!
+//! [`EmfMetrics`](crate::emf::EmfMetrics) writes through it by default. Anything written to
+//! stdout some other way, such as with `println!` or a `tracing` subscriber, isn't held back,
+//! so it can show up before lines written through [`BatchedStdout`] earlier.
+
+use std::{
+    io::{self, IoSlice, Write},
+    sync::Mutex,
+};
+
+/// The writes held back while at least one shutdown runs.
+static BATCH: Mutex<Batch> = Mutex::new(Batch {
+    shutdowns: 0,
+    pending: Vec::new(),
+});
+
+#[derive(Debug)]
+struct Batch {
+    /// How many shutdowns are running, usually at most one.
+    shutdowns: usize,
+    pending: Vec<Vec<u8>>,
+}
+
+/// Stdout, with writes held back while a shutdown runs.
+///
+/// Every write is kept whole, so lines written at once stay together.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchedStdout;
+
+impl Write for BatchedStdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut batch = BATCH.lock().unwrap();
+        if batch.shutdowns > 0 {
+            batch.pending.push(buf.to_vec());
+            return Ok(());
+        }
+        drop(batch);
+        io::stdout().lock().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Held back writes go out when the shutdown ends
+        io::stdout().lock().flush()
+    }
+}
+
+/// Hold back the writes until the [`Held`] is dropped, at the end of a shutdown.
+pub(crate) fn hold() -> Held {
+    BATCH.lock().unwrap().shutdowns += 1;
+    Held
+}
+
+/// Writes held back by [`hold()`], written when the last one is dropped, even if a hook
+/// panicked.
+#[derive(Debug)]
+pub(crate) struct Held;
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        let pending = {
+            // Written even if a panic poisoned the lock, since this may run while unwinding
+            let mut batch = BATCH
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            batch.shutdowns -= 1;
+            if batch.shutdowns > 0 {
+                return;
+            }
+            std::mem::take(&mut batch.pending)
+        };
+        // Nothing to do if stdout is gone
+        let _ = write_all_vectored(&mut io::stdout().lock(), &pending);
+    }
+}
+
+/// Write `line` and a newline through [`BatchedStdout`], ignoring errors.
+pub(crate) fn write_line(mut line: String) {
+    line.push('\n');
+    // Nothing to do if stdout is gone
+    let _ = BatchedStdout.write_all(line.as_bytes());
+}
+
+/// Write all of `buffers` to `out`, in as few calls as `out` allows.
+fn write_all_vectored(out: &mut impl Write, buffers: &[Vec<u8>]) -> io::Result<()> {
+    let mut slices: Vec<IoSlice<'_>> = buffers.iter().map(|buffer| IoSlice::new(buffer)).collect();
+    let mut slices = &mut slices[..];
+    // Skips the empty buffers at the start
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match out.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    out.flush()
+}
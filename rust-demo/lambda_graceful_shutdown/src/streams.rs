@@ -0,0 +1,184 @@
+//! Checkpointing the position in a Kinesis or DynamoDB stream when a batch is interrupted.
+//!
+//! When a stream-triggered invocation fails or is cut off, Lambda retries the batch from the
+//! record reported in `batchItemFailures`, or from the start. Records processed before the
+//! interruption are then processed again.
+//!
+//! [`StreamCheckpoint`] keeps the sequence number of the last record fully processed in
+//! [`Checkpoints`], so the [`checkpoint_hook()`](Checkpoints::checkpoint_hook) saves it when
+//! the environment shuts down. [`process_stream_batch()`] skips the records up to that
+//! position when the batch comes back, and stops at the next record once shutdown starts,
+//! which makes processing close to exactly-once. This needs `ReportBatchItemFailures`
+//! enabled on the event source mapping.
+
+use std::{cmp::Ordering, fmt, future::Future};
+
+use aws_lambda_events::event::{
+    dynamodb::EventRecord,
+    kinesis::KinesisEventRecord,
+    streams::{
+        DynamoDbBatchItemFailure, DynamoDbEventResponse, KinesisBatchItemFailure,
+        KinesisEventResponse,
+    },
+};
+
+use crate::{
+    checkpoint::{CheckpointStore, Checkpoints},
+    Error, ShutdownCoordinator,
+};
+
+/// A record from a stream, ordered by its sequence number.
+pub trait StreamRecord {
+    /// The record's sequence number within its shard.
+    fn sequence_number(&self) -> Option<&str>;
+}
+
+impl StreamRecord for KinesisEventRecord {
+    fn sequence_number(&self) -> Option<&str> {
+        Some(&self.kinesis.sequence_number)
+    }
+}
+
+impl StreamRecord for EventRecord {
+    fn sequence_number(&self) -> Option<&str> {
+        self.change.sequence_number.as_deref()
+    }
+}
+
+/// The position up to which the records of one shard have been processed.
+pub struct StreamCheckpoint<S> {
+    checkpoints: Checkpoints<S>,
+    key: String,
+}
+
+impl<S> fmt::Debug for StreamCheckpoint<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamCheckpoint")
+            .field("key", &self.key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: CheckpointStore> StreamCheckpoint<S> {
+    /// Track the position of the shard identified by `key`, in `checkpoints`.
+    ///
+    /// Every record of a batch comes from the same shard, so the stream's ARN and the shard id
+    /// at the start of the first record's `eventID` make a good key.
+    pub fn new(checkpoints: Checkpoints<S>, key: impl Into<String>) -> Self {
+        Self {
+            checkpoints,
+            key: key.into(),
+        }
+    }
+
+    /// The sequence number of the last record processed in an earlier invocation, if it was
+    /// interrupted.
+    pub async fn last_processed(&self) -> Result<Option<String>, Error> {
+        match self.checkpoints.load(&self.key).await? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record that every record up to `sequence_number` has been processed.
+    pub fn processed(&self, sequence_number: &str) {
+        self.checkpoints
+            .update(&self.key, sequence_number.as_bytes().to_vec());
+    }
+
+    /// Save the position right away, rather than waiting for shutdown.
+    pub async fn save(&self) -> Result<(), Error> {
+        self.checkpoints.save(&self.key).await
+    }
+
+    /// Forget the position, once the whole batch has been processed and Lambda moves on.
+    pub async fn complete(&self) -> Result<(), Error> {
+        self.checkpoints.complete(&self.key).await
+    }
+}
+
+/// Process `records` in order with `handle`, resuming after the last record processed by an
+/// interrupted invocation, and stopping once the environment starts shutting down.
+///
+/// Returns the sequence number of the first record that wasn't processed, to report as a
+/// batch item failure with [`kinesis_response()`] or [`dynamodb_response()`], or `None` if
+/// the whole batch was processed. If `handle` fails, the position is saved right away, since
+/// Lambda retries the batch without necessarily shutting the environment down.
+pub async fn process_stream_batch<R, S, F, Fut, E>(
+    coordinator: &ShutdownCoordinator,
+    checkpoint: &StreamCheckpoint<S>,
+    records: Vec<R>,
+    mut handle: F,
+) -> Result<Option<String>, Error>
+where
+    R: StreamRecord,
+    S: CheckpointStore,
+    F: FnMut(R) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: fmt::Display,
+{
+    let last_processed = checkpoint.last_processed().await?;
+    let mut skipped = 0;
+    for record in records {
+        let Some(sequence_number) = record.sequence_number().map(str::to_owned) else {
+            return Err("a stream record has no sequence number".into());
+        };
+        if last_processed
+            .as_deref()
+            .is_some_and(|last| compare(&sequence_number, last) != Ordering::Greater)
+        {
+            skipped += 1;
+            continue;
+        }
+        if coordinator.is_shutting_down() {
+            tracing::warn!(
+                first_unprocessed = sequence_number,
+                "shutdown interrupted the stream batch"
+            );
+            return Ok(Some(sequence_number));
+        }
+        if let Err(error) = handle(record).await {
+            tracing::debug!(sequence_number, %error, "failed to process stream record");
+            checkpoint.save().await?;
+            return Ok(Some(sequence_number));
+        }
+        checkpoint.processed(&sequence_number);
+    }
+    if skipped > 0 {
+        tracing::info!(
+            skipped,
+            "skipped stream records processed before an interruption"
+        );
+    }
+    checkpoint.complete().await?;
+    Ok(None)
+}
+
+/// The response reporting `first_unprocessed` as a batch item failure to Kinesis.
+pub fn kinesis_response(first_unprocessed: Option<String>) -> KinesisEventResponse {
+    KinesisEventResponse {
+        batch_item_failures: first_unprocessed
+            .into_iter()
+            .map(|item_identifier| KinesisBatchItemFailure {
+                item_identifier: Some(item_identifier),
+            })
+            .collect(),
+    }
+}
+
+/// The response reporting `first_unprocessed` as a batch item failure to DynamoDB Streams.
+pub fn dynamodb_response(first_unprocessed: Option<String>) -> DynamoDbEventResponse {
+    DynamoDbEventResponse {
+        batch_item_failures: first_unprocessed
+            .into_iter()
+            .map(|item_identifier| DynamoDbBatchItemFailure {
+                item_identifier: Some(item_identifier),
+            })
+            .collect(),
+    }
+}
+
+/// Sequence numbers are decimal strings of varying length, so compare them as numbers.
+fn compare(a: &str, b: &str) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
@@ -0,0 +1,475 @@
+//! Handlers that are another program, run as a child process and told about the shutdown.
+//!
+//! A function can keep its business logic in an existing binary, written in anything, and
+//! leave the Lambda side to a small Rust wrapper. [`Subprocess`] starts the binary during
+//! Init, and [`ChildHandler::invoke()`] passes it each event and reads back its reply. When
+//! the environment shuts down, [`shutdown_hook()`](ChildHandler::shutdown_hook) relays it:
+//! the child gets a control message, then `SIGTERM` if it hasn't exited shortly after, and
+//! the rest of the budget to flush and exit.
+//!
+//! The child talks newline-delimited JSON, over its stdin and stdout by default, or over a
+//! Unix socket whose path it finds in [`SOCKET_ENV`] with
+//! [`over_socket()`](Subprocess::over_socket). Each invocation is one line each way:
+//!
+//! ```text
+//! > {"type":"invoke","request_id":"8476a536","deadline_ms":1700000000000,"payload":{...}}
+//! < {"type":"response","payload":{...}}
+//! < {"type":"error","error":"what went wrong"}
+//! ```
+//!
+//! and the shutdown is one more line, after which its input is closed:
+//!
+//! ```text
+//! > {"type":"shutdown","reason":"SIGTERM","remaining_ms":480}
+//! ```
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{
+//!     handler::IntoRuntimeShutdownHook, subprocess::Subprocess, ShutdownCoordinator,
+//! };
+//! use lambda_runtime::{run, service_fn, spawn_graceful_shutdown_handler, Error, LambdaEvent};
+//! use serde_json::Value;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let child = Subprocess::new("/opt/bin/handler.py").spawn().await?;
+//!     let shutdown = ShutdownCoordinator::new().with_hook(child.shutdown_hook());
+//!     spawn_graceful_shutdown_handler(shutdown.into_runtime_hook()).await;
+//!
+//!     run(service_fn(|event: LambdaEvent<Value>| {
+//!         let child = child.clone();
+//!         async move {
+//!             let (payload, context) = event.into_parts();
+//!             child.invoke(&context.request_id, context.deadline, payload).await
+//!         }
+//!     }))
+//!     .await
+//! }
+//! ```
+
+use std::{ffi::OsString, fmt, io, path::PathBuf, process::Stdio, sync::Arc, time::Duration};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UnixListener,
+    process::{Child, Command},
+    sync::Mutex,
+};
+
+use crate::{
+    clock,
+    environment::{signal, Signal},
+    BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook,
+};
+
+/// The environment variable holding the socket path, for a child started with
+/// [`Subprocess::over_socket()`].
+pub const SOCKET_ENV: &str = "LAMBDA_GRACEFUL_SHUTDOWN_SOCKET";
+
+/// How long the child has to exit after the shutdown message before it is sent `SIGTERM`,
+/// unless set with [`Subprocess::with_term_delay()`].
+pub const DEFAULT_TERM_DELAY: Duration = Duration::from_millis(100);
+
+type Reader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// A program to run as the handler: what to start, and how to talk to it.
+#[derive(Debug, Clone)]
+pub struct Subprocess {
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    socket: Option<PathBuf>,
+    term_delay: Duration,
+}
+
+impl Subprocess {
+    /// Run `program`, talking to it over its stdin and stdout.
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            socket: None,
+            term_delay: DEFAULT_TERM_DELAY,
+        }
+    }
+
+    /// Pass `arg` to the program.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Pass `args` to the program.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the environment variable `key` for the program, on top of the function's own.
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Talk to the program over a Unix socket at `path` instead, leaving its stdout to its
+    /// logs. The program connects to the path it finds in [`SOCKET_ENV`]. `/tmp` is the only
+    /// place on Lambda it can be.
+    pub fn over_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.socket = Some(path.into());
+        self
+    }
+
+    /// Wait `delay` after the shutdown message before sending the program `SIGTERM`, instead
+    /// of [`DEFAULT_TERM_DELAY`]. A program that exits on the message is never signalled, and
+    /// one that handles only the signal gets it right after the delay. Without the delay, a
+    /// program could be stopped by the signal before it read the message.
+    pub fn with_term_delay(mut self, delay: Duration) -> Self {
+        self.term_delay = delay;
+        self
+    }
+
+    /// Start the program, and with a socket, wait for it to connect.
+    ///
+    /// Fails if it can't be started, or exits before connecting.
+    pub async fn spawn(self) -> io::Result<ChildHandler> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .envs(self.envs.iter().map(|(key, value)| (key, value)))
+            // If the function goes away without relaying the shutdown, so does the child
+            .kill_on_drop(true);
+        let (child, reader, writer): (_, Box<dyn AsyncRead + Send + Unpin>, Writer) =
+            match &self.socket {
+                None => {
+                    let mut child = command
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()?;
+                    let stdin = child.stdin.take().expect("stdin is piped");
+                    let stdout = child.stdout.take().expect("stdout is piped");
+                    (child, Box::new(stdout), Box::new(stdin))
+                }
+                Some(path) => {
+                    // A socket left behind by an earlier process would fail the bind
+                    let _ = std::fs::remove_file(path);
+                    let listener = UnixListener::bind(path)?;
+                    let mut child = command.stdin(Stdio::null()).env(SOCKET_ENV, path).spawn()?;
+                    let stream = tokio::select! {
+                        accepted = listener.accept() => accepted?.0,
+                        status = child.wait() => {
+                            return Err(io::Error::other(format!(
+                                "the handler exited with {} before connecting",
+                                status?
+                            )));
+                        }
+                    };
+                    let (reader, writer) = stream.into_split();
+                    (child, Box::new(reader), Box::new(writer))
+                }
+            };
+        let pid = child
+            .id()
+            .ok_or_else(|| io::Error::other("the handler exited right away"))?;
+        Ok(ChildHandler {
+            inner: Arc::new(Inner {
+                pid,
+                term_delay: self.term_delay,
+                reader: Mutex::new(BufReader::new(reader)),
+                writer: Mutex::new(Some(writer)),
+                child: Mutex::new(child),
+            }),
+        })
+    }
+}
+
+/// A handler running as a child process, started by [`Subprocess::spawn()`].
+///
+/// Cloning is cheap, and all clones talk to the same process.
+#[derive(Clone)]
+pub struct ChildHandler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pid: u32,
+    term_delay: Duration,
+    reader: Mutex<Reader>,
+    /// `None` once the shutdown was relayed and the child's input closed.
+    writer: Mutex<Option<Writer>>,
+    child: Mutex<Child>,
+}
+
+impl fmt::Debug for ChildHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChildHandler")
+            .field("pid", &self.inner.pid)
+            .finish()
+    }
+}
+
+impl ChildHandler {
+    /// The child's process id.
+    pub fn pid(&self) -> u32 {
+        self.inner.pid
+    }
+
+    /// Pass an event to the child, and wait for its reply.
+    ///
+    /// `deadline_ms` is when the invocation times out, in milliseconds since the Unix epoch,
+    /// as in `lambda_runtime::Context::deadline`. Invocations are passed one at a time. Fails
+    /// if the child replies with an error, or closes its end before replying.
+    pub async fn invoke(
+        &self,
+        request_id: &str,
+        deadline_ms: u64,
+        payload: Value,
+    ) -> Result<Value, Error> {
+        // Holding the reader for the whole round trip keeps the replies in order
+        let mut reader = self.inner.reader.lock().await;
+        self.send(&json!({
+            "type": "invoke",
+            "request_id": request_id,
+            "deadline_ms": deadline_ms,
+            "payload": payload,
+        }))
+        .await?;
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err("the handler closed its end before replying".into());
+        }
+        let mut reply: Value = serde_json::from_str(&line)?;
+        match reply["type"].as_str() {
+            Some("response") => Ok(reply["payload"].take()),
+            Some("error") => Err(reply["error"]
+                .as_str()
+                .unwrap_or("the handler failed")
+                .into()),
+            _ => Err(format!("unexpected message from the handler: {}", line.trim_end()).into()),
+        }
+    }
+
+    /// A hook that relays the shutdown to the child, and waits for it to exit.
+    pub fn shutdown_hook(&self) -> RelayHook {
+        RelayHook {
+            handler: self.clone(),
+        }
+    }
+
+    async fn send(&self, message: &Value) -> io::Result<()> {
+        let mut writer = self.inner.writer.lock().await;
+        let writer = writer.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the shutdown was relayed to the handler",
+            )
+        })?;
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        writer.flush().await
+    }
+
+    /// Send the shutdown message, and close the child's input.
+    async fn relay(&self, ctx: &ShutdownContext) -> io::Result<()> {
+        self.send(&json!({
+            "type": "shutdown",
+            "reason": ctx.reason().to_string(),
+            "remaining_ms": u64::try_from(ctx.remaining().as_millis()).unwrap_or(u64::MAX),
+        }))
+        .await?;
+        // A child that reads until the end of its input sees it too
+        match self.inner.writer.lock().await.take() {
+            Some(mut writer) => writer.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// The hook returned by [`ChildHandler::shutdown_hook()`].
+///
+/// It sends the child the shutdown message, then `SIGTERM` if it is still running after the
+/// [term delay](Subprocess::with_term_delay), and waits for it to exit until the budget runs
+/// out, when it is killed. Register it first, so it runs last, after the
+/// hooks that may still need the child.
+#[derive(Debug, Clone)]
+pub struct RelayHook {
+    handler: ChildHandler,
+}
+
+impl ShutdownHook for RelayHook {
+    fn name(&self) -> &str {
+        "subprocess"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let clock = ctx.clock();
+            match clock::timeout_at(clock, ctx.drain_deadline(), self.handler.relay(ctx)).await {
+                Some(Ok(())) => {}
+                Some(Err(error)) => {
+                    ctx.note(format!("failed to send the shutdown message: {error}"))
+                }
+                None => ctx.note("timed out sending the shutdown message"),
+            }
+            let mut child = self.handler.inner.child.lock().await;
+            let term_at = ctx
+                .drain_deadline()
+                .min(clock.now() + self.handler.inner.term_delay);
+            let mut exited = clock::timeout_at(clock, term_at, child.wait()).await;
+            if exited.is_none() {
+                if let Err(error) = signal(self.handler.pid(), Signal::Term) {
+                    ctx.note(format!("failed to signal the handler: {error}"));
+                }
+                exited = clock::timeout_at(clock, ctx.drain_deadline(), child.wait()).await;
+            }
+            match exited {
+                Some(Ok(status)) => {
+                    if !status.success() {
+                        ctx.note(format!("the handler exited with {status}"));
+                    }
+                    Ok(())
+                }
+                Some(Err(error)) => Err(error.into()),
+                None => {
+                    let _ = child.start_kill();
+                    Err(DrainTimeout::new("killed the handler, which didn't exit in time").into())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    fn shell(script: &str) -> Subprocess {
+        Subprocess::new("/bin/sh").args(["-c", script])
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn invocations_are_one_line_each_way() {
+        // Replies with the message it was sent
+        let child = shell(
+            r#"while read -r line; do printf '{"type":"response","payload":%s}\n' "$line"; done"#,
+        )
+        .spawn()
+        .await
+        .unwrap();
+        for request_id in ["first", "second"] {
+            let message = child
+                .invoke(request_id, 1_700_000_000_000, json!({ "order": 7 }))
+                .await
+                .unwrap();
+            assert_eq!(
+                message,
+                json!({
+                    "type": "invoke",
+                    "request_id": request_id,
+                    "deadline_ms": 1_700_000_000_000u64,
+                    "payload": { "order": 7 },
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn error_replies_and_a_closed_end_fail_the_invocation() {
+        let child = shell(r#"read -r line; echo '{"type":"error","error":"boom"}'"#)
+            .spawn()
+            .await
+            .unwrap();
+        let error = child.invoke("first", 0, Value::Null).await.unwrap_err();
+        assert_eq!(error.to_string(), "boom");
+        assert!(child.invoke("second", 0, Value::Null).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn the_shutdown_is_relayed_as_a_last_line() {
+        let out = temp_path("subprocess-relayed");
+        let child =
+            shell(r#"while read -r line; do last="$line"; done; printf '%s' "$last" > "$OUT""#)
+                .env("OUT", &out)
+                .spawn()
+                .await
+                .unwrap();
+        let report = ShutdownCoordinator::new()
+            .with_hook(child.shutdown_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert!(report.hooks[0].notes.is_empty());
+
+        let message: Value = serde_json::from_slice(&std::fs::read(&out).unwrap()).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert_eq!(message["type"], "shutdown");
+        assert_eq!(message["reason"], "SIGTERM");
+        assert!(message["remaining_ms"].is_u64());
+        assert!(child.invoke("late", 0, Value::Null).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_child_that_ignores_the_message_gets_sigterm() {
+        let child = shell("trap 'exit 7' TERM; while :; do sleep 0.01; done")
+            .with_term_delay(Duration::from_millis(20))
+            .spawn()
+            .await
+            .unwrap();
+        let report = ShutdownCoordinator::new()
+            .with_hook(child.shutdown_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["the handler exited with exit status: 7"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_child_that_ignores_sigterm_is_killed() {
+        let child = shell("trap '' TERM; while :; do sleep 0.01; done")
+            .with_term_delay(Duration::from_millis(20))
+            .spawn()
+            .await
+            .unwrap();
+        // Let the shell install its trap first
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let report = ShutdownCoordinator::new()
+            .with_budget(Duration::from_millis(300))
+            .with_hook(child.shutdown_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut("killed the handler, which didn't exit in time".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_child_that_exits_before_connecting_fails_the_spawn() {
+        let socket = temp_path("subprocess.sock");
+        let error = shell("exit 2")
+            .over_socket(&socket)
+            .spawn()
+            .await
+            .unwrap_err();
+        let _ = std::fs::remove_file(&socket);
+        assert_eq!(
+            error.to_string(),
+            "the handler exited with exit status: 2 before connecting"
+        );
+    }
+}
@@ -0,0 +1,471 @@
+//! Auxiliary processes started during Init, watched while the function runs, and stopped in
+//! order at shutdown.
+//!
+//! A function packaged as a container image can run more than the runtime: a local proxy, an
+//! agent, a log forwarder. Lambda only signals the runtime and the extensions, so those would
+//! be frozen mid-write. A [`Supervisor`] starts them, restarts the ones that should keep
+//! running if they exit, and [`shutdown_hook()`](RunningSidecars::shutdown_hook) stops them
+//! within the budget: each gets a `SIGTERM`, and a `SIGKILL` if it hasn't exited by its stop
+//! timeout.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use lambda_graceful_shutdown::{
+//!     supervisor::{Sidecar, Supervisor},
+//!     ShutdownCoordinator,
+//! };
+//!
+//! # fn example() -> std::io::Result<()> {
+//! // Started in this order, and stopped in the reverse one: the agent ships through the proxy
+//! let sidecars = Supervisor::new()
+//!     .with(Sidecar::new("proxy", "/opt/bin/proxy").arg("--listen=127.0.0.1:8125"))
+//!     .with(
+//!         Sidecar::new("agent", "/opt/bin/agent")
+//!             .restart_on_exit()
+//!             .with_stop_timeout(Duration::from_millis(300)),
+//!     )
+//!     .start()?;
+//! let shutdown = ShutdownCoordinator::new().with_hook(sidecars.shutdown_hook());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    ffi::OsString,
+    fmt, io,
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    process::{Child, Command},
+    sync::watch,
+};
+
+use crate::{
+    clock,
+    environment::{signal, Signal},
+    BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook,
+};
+
+/// How long a sidecar gets to exit after its `SIGTERM`, unless set with
+/// [`Sidecar::with_stop_timeout()`].
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long to wait before restarting a sidecar that exited.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// A process for a [`Supervisor`] to run: a program, its arguments and environment, and how
+/// to stop it.
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    name: String,
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    stop_timeout: Duration,
+    restart: bool,
+}
+
+impl Sidecar {
+    /// Run `program`, under `name` in logs and reports.
+    pub fn new(name: impl Into<String>, program: impl Into<OsString>) -> Self {
+        Self {
+            name: name.into(),
+            program: program.into(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            restart: false,
+        }
+    }
+
+    /// Pass `arg` to the program.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Pass `args` to the program.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set the environment variable `key` for the program, on top of the function's own.
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Give the process `timeout` to exit after its `SIGTERM` before killing it, instead of
+    /// [`DEFAULT_STOP_TIMEOUT`]. It never gets more than what is left of the budget.
+    pub fn with_stop_timeout(mut self, timeout: Duration) -> Self {
+        self.stop_timeout = timeout;
+        self
+    }
+
+    /// Start the process again whenever it exits before the shutdown.
+    pub fn restart_on_exit(mut self) -> Self {
+        self.restart = true;
+        self
+    }
+
+    fn spawn(&self) -> io::Result<Child> {
+        Command::new(&self.program)
+            .args(&self.args)
+            .envs(self.envs.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            // If the function goes away without stopping them, so do they
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+/// The [`Sidecar`]s to start, in order.
+#[derive(Debug, Clone, Default)]
+pub struct Supervisor {
+    sidecars: Vec<Sidecar>,
+}
+
+impl Supervisor {
+    /// A supervisor with no sidecars.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `sidecar`. They are started in the order they were added, and stopped in the
+    /// reverse order, so one that the others depend on can be added first.
+    pub fn with(mut self, sidecar: Sidecar) -> Self {
+        self.sidecars.push(sidecar);
+        self
+    }
+
+    /// Start every sidecar, and a task watching each of them.
+    ///
+    /// Fails if one of them can't be started. The ones started before it are then killed.
+    pub fn start(self) -> io::Result<RunningSidecars> {
+        let mut running: Vec<Supervised> = Vec::with_capacity(self.sidecars.len());
+        for sidecar in self.sidecars {
+            let child = match sidecar.spawn() {
+                Ok(child) => child,
+                Err(error) => {
+                    for started in &running {
+                        started.kill();
+                    }
+                    return Err(io::Error::new(
+                        error.kind(),
+                        format!("failed to start sidecar {}: {error}", sidecar.name),
+                    ));
+                }
+            };
+            running.push(Supervised::watch(sidecar, child));
+        }
+        Ok(RunningSidecars {
+            sidecars: Arc::new(running),
+        })
+    }
+}
+
+/// The sidecars a [`Supervisor`] started. Cloning is cheap, and all clones share them.
+#[derive(Debug, Clone)]
+pub struct RunningSidecars {
+    sidecars: Arc<Vec<Supervised>>,
+}
+
+impl RunningSidecars {
+    /// The process id of the sidecar `name`, if it is running.
+    pub fn pid(&self, name: &str) -> Option<u32> {
+        let sidecar = self.sidecars.iter().find(|sidecar| sidecar.name == name)?;
+        match *sidecar.state.borrow() {
+            State::Running(pid) => Some(pid),
+            State::Exited(_) => None,
+        }
+    }
+
+    /// How the sidecar `name` last exited, if it isn't running. `None` while it runs, or if
+    /// its status couldn't be read.
+    pub fn exit_status(&self, name: &str) -> Option<ExitStatus> {
+        let sidecar = self.sidecars.iter().find(|sidecar| sidecar.name == name)?;
+        match *sidecar.state.borrow() {
+            State::Running(_) => None,
+            State::Exited(status) => status,
+        }
+    }
+
+    /// How many times the sidecar `name` was restarted.
+    pub fn restarts(&self, name: &str) -> u32 {
+        self.sidecars
+            .iter()
+            .find(|sidecar| sidecar.name == name)
+            .map_or(0, |sidecar| sidecar.restarts.load(Ordering::Relaxed))
+    }
+
+    /// A hook that stops the sidecars, in the reverse of the order they were added.
+    pub fn shutdown_hook(&self) -> SupervisorHook {
+        SupervisorHook {
+            sidecars: self.clone(),
+        }
+    }
+}
+
+/// A sidecar that was started, and the task watching it.
+struct Supervised {
+    name: String,
+    stop_timeout: Duration,
+    state: watch::Receiver<State>,
+    stopping: Arc<AtomicBool>,
+    restarts: Arc<AtomicU32>,
+}
+
+impl fmt::Debug for Supervised {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Supervised")
+            .field("name", &self.name)
+            .field("state", &*self.state.borrow())
+            .field("restarts", &self.restarts.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Running(u32),
+    Exited(Option<ExitStatus>),
+}
+
+impl Supervised {
+    /// Watch `child` in a task of its own, restarting it if `sidecar` says to.
+    fn watch(sidecar: Sidecar, child: Child) -> Self {
+        let initial = child.id().map_or(State::Exited(None), State::Running);
+        let (state_tx, state) = watch::channel(initial);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let restarts = Arc::new(AtomicU32::new(0));
+        let supervised = Self {
+            name: sidecar.name.clone(),
+            stop_timeout: sidecar.stop_timeout,
+            state,
+            stopping: stopping.clone(),
+            restarts: restarts.clone(),
+        };
+        tokio::spawn(async move {
+            let mut child = child;
+            loop {
+                let status = child.wait().await.ok();
+                state_tx.send_replace(State::Exited(status));
+                if stopping.load(Ordering::SeqCst) || !sidecar.restart {
+                    if !stopping.load(Ordering::SeqCst) {
+                        tracing::warn!(sidecar = sidecar.name, ?status, "sidecar exited");
+                    }
+                    return;
+                }
+                tracing::warn!(
+                    sidecar = sidecar.name,
+                    ?status,
+                    "sidecar exited, restarting it"
+                );
+                tokio::time::sleep(RESTART_DELAY).await;
+                if stopping.load(Ordering::SeqCst) {
+                    return;
+                }
+                match sidecar.spawn() {
+                    Ok(restarted) => {
+                        restarts.fetch_add(1, Ordering::Relaxed);
+                        if let Some(pid) = restarted.id() {
+                            state_tx.send_replace(State::Running(pid));
+                        }
+                        child = restarted;
+                    }
+                    Err(error) => {
+                        tracing::warn!(sidecar = sidecar.name, %error, "failed to restart sidecar");
+                        return;
+                    }
+                }
+            }
+        });
+        supervised
+    }
+
+    /// Send the process `SIGKILL`, and don't restart it.
+    fn kill(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let State::Running(pid) = *self.state.borrow() {
+            let _ = signal(pid, Signal::Kill);
+        }
+    }
+
+    /// Send the process `SIGTERM`, and `SIGKILL` if it doesn't exit within its stop timeout or
+    /// what is left of the budget. Returns whether it exited on its own.
+    async fn stop(&self, ctx: &ShutdownContext) -> io::Result<bool> {
+        self.stopping.store(true, Ordering::SeqCst);
+        let State::Running(pid) = *self.state.borrow() else {
+            return Ok(true);
+        };
+        signal(pid, Signal::Term)?;
+        let mut state = self.state.clone();
+        let timeout = ctx.remaining().min(self.stop_timeout);
+        let clock = ctx.clock();
+        let exited = clock::timeout_at(clock, clock.now() + timeout, async {
+            let _ = state
+                .wait_for(|state| matches!(state, State::Exited(_)))
+                .await;
+        })
+        .await;
+        if exited.is_some() {
+            return Ok(true);
+        }
+        signal(pid, Signal::Kill)?;
+        Ok(false)
+    }
+}
+
+/// The hook returned by [`RunningSidecars::shutdown_hook()`].
+#[derive(Debug, Clone)]
+pub struct SupervisorHook {
+    sidecars: RunningSidecars,
+}
+
+impl ShutdownHook for SupervisorHook {
+    fn name(&self) -> &str {
+        "supervisor"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut killed = Vec::new();
+            for sidecar in self.sidecars.sidecars.iter().rev() {
+                match sidecar.stop(ctx).await {
+                    Ok(true) => {}
+                    Ok(false) => killed.push(sidecar.name.as_str()),
+                    Err(error) => ctx.note(format!("failed to stop {}: {error}", sidecar.name)),
+                }
+            }
+            if killed.is_empty() {
+                Ok(())
+            } else {
+                Err(DrainTimeout::new(format!(
+                    "killed sidecars that didn't exit in time: {}",
+                    killed.join(", ")
+                ))
+                .into())
+            }
+        })
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    fn shell(name: &str, script: &str) -> Sidecar {
+        Sidecar::new(name, "/bin/sh").args(["-c", script])
+    }
+
+    /// Wait for the watching task to see `name` exit.
+    async fn exited(sidecars: &RunningSidecars, name: &str) -> Option<ExitStatus> {
+        let sidecar = sidecars.sidecars.iter().find(|s| s.name == name).unwrap();
+        let mut state = sidecar.state.clone();
+        let _ = state
+            .wait_for(|state| matches!(state, State::Exited(_)))
+            .await;
+        sidecars.exit_status(name)
+    }
+
+    #[tokio::test]
+    async fn sidecars_are_stopped_with_sigterm() {
+        let sidecars = Supervisor::new()
+            .with(Sidecar::new("proxy", "sleep").arg("30"))
+            .with(shell("agent", "exec sleep \"$DURATION\"").env("DURATION", "30"))
+            .start()
+            .unwrap();
+        assert!(sidecars.pid("proxy").is_some());
+        assert!(sidecars.pid("agent").is_some());
+        assert_eq!(sidecars.pid("missing"), None);
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(sidecars.shutdown_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        for name in ["proxy", "agent"] {
+            let status = exited(&sidecars, name).await.unwrap();
+            assert_eq!(status.signal(), Some(libc::SIGTERM));
+            assert_eq!(sidecars.pid(name), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_sidecar_ignoring_sigterm_is_killed() {
+        let sidecars = Supervisor::new()
+            .with(
+                shell("stubborn", "trap '' TERM; while :; do :; done")
+                    .with_stop_timeout(Duration::from_millis(50)),
+            )
+            .start()
+            .unwrap();
+        // Let the shell install its trap first
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(sidecars.shutdown_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut(
+                "killed sidecars that didn't exit in time: stubborn".to_owned()
+            )
+        );
+        let status = exited(&sidecars, "stubborn").await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    }
+
+    #[tokio::test]
+    async fn only_sidecars_asked_to_are_restarted() {
+        let sidecars = Supervisor::new()
+            .with(shell("once", "exit 3"))
+            .with(shell("again", "sleep 0.05").restart_on_exit())
+            .start()
+            .unwrap();
+        let status = exited(&sidecars, "once").await.unwrap();
+        assert_eq!(status.code(), Some(3));
+        while sidecars.restarts("again") < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(sidecars.restarts("once"), 0);
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(sidecars.shutdown_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        let restarts = sidecars.restarts("again");
+        tokio::time::sleep(RESTART_DELAY * 3).await;
+        assert_eq!(sidecars.restarts("again"), restarts);
+    }
+
+    #[tokio::test]
+    async fn failing_to_start_a_sidecar_names_it() {
+        let error = Supervisor::new()
+            .with(Sidecar::new("proxy", "sleep").arg("30"))
+            .with(Sidecar::new("agent", "/no/such/agent"))
+            .start()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error
+            .to_string()
+            .starts_with("failed to start sidecar agent: "));
+    }
+}
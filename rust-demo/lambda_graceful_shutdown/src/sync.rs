@@ -0,0 +1,149 @@
+//! The state the coordinator shares between the shutdown and the rest of the function.
+//!
+//! A shutdown can start while an invocation begins or ends, or while a hook is registered,
+//! and each of those races is a chance to lose data: a hook that never runs, or an invocation
+//! that isn't waited for. The primitives here keep that state, and nothing else, so that they
+//! can be model-checked with [loom](https://docs.rs/loom). Build with
+//! `RUSTFLAGS="--cfg lambda_graceful_shutdown_loom"` to swap in loom's atomics and mutexes,
+//! and run the checks in `tests/loom.rs`:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg lambda_graceful_shutdown_loom" cargo test --release --test loom
+//! ```
+//!
+//! Waking up async waiters goes through tokio's `Notify`, which loom doesn't model; only the
+//! state transitions are checked.
+
+use std::sync::Arc;
+
+#[cfg(lambda_graceful_shutdown_loom)]
+use loom::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Mutex,
+};
+#[cfg(not(lambda_graceful_shutdown_loom))]
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Mutex,
+};
+
+use smallvec::SmallVec;
+use tokio::sync::Notify;
+
+/// How many items a [`Snapshot`] holds before it moves to the heap. Most functions register
+/// fewer hooks than this, so taking a snapshot when the shutdown starts doesn't allocate.
+pub const INLINE_ITEMS: usize = 16;
+
+/// The items of a [`Registry`], from [`Registry::snapshot_rev()`].
+pub type Snapshot<T> = SmallVec<[Arc<T>; INLINE_ITEMS]>;
+
+/// Set once, when the shutdown starts, and never cleared.
+#[derive(Debug, Default)]
+pub struct Latch {
+    set: AtomicBool,
+    notify: Notify,
+}
+
+impl Latch {
+    /// Set the latch. Returns true for the call that set it, false if it was already set.
+    pub fn trigger(&self) -> bool {
+        let first = !self.set.swap(true, Ordering::AcqRel);
+        if first {
+            self.notify.notify_waiters();
+        }
+        first
+    }
+
+    /// Whether the latch is set.
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Wait until the latch is set.
+    pub async fn wait(&self) {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            // Register before checking, so a trigger in between isn't missed
+            notified.as_mut().enable();
+            if self.is_set() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// The number of invocations in flight.
+#[derive(Debug, Default)]
+pub struct InFlightCounter {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlightCounter {
+    /// Count an invocation that started.
+    pub fn enter(&self) {
+        self.count.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Count an invocation that finished. Returns true if it was the last one in flight.
+    pub fn exit(&self) -> bool {
+        let last = self.count.fetch_sub(1, Ordering::AcqRel) == 1;
+        if last {
+            self.idle.notify_waiters();
+        }
+        last
+    }
+
+    /// The number of invocations in flight.
+    pub fn get(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Wait until no invocation is in flight.
+    pub async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.get() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// The registered hooks, or anything else registered from several places at once.
+pub struct Registry<T: ?Sized> {
+    items: Mutex<Vec<Arc<T>>>,
+}
+
+impl<T: ?Sized> Default for Registry<T> {
+    fn default() -> Self {
+        Self {
+            items: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<T: ?Sized> Registry<T> {
+    /// Make room for `additional` more items, so registering them doesn't reallocate.
+    pub fn reserve(&self, additional: usize) {
+        self.items.lock().unwrap().reserve(additional);
+    }
+
+    /// Add `item` after the ones already registered.
+    pub fn push(&self, item: Arc<T>) {
+        self.items.lock().unwrap().push(item);
+    }
+
+    /// The registered items, last registered first.
+    ///
+    /// This is a copy, so the lock isn't held while the items are used, and items registered
+    /// in the meantime are left for the next snapshot.
+    pub fn snapshot_rev(&self) -> Snapshot<T> {
+        self.items.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
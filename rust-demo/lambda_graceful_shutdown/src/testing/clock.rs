@@ -0,0 +1,77 @@
+//! A clock that only moves when told to.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, time::Instant};
+
+use crate::{clock::Clock, BoxFuture};
+
+/// A [`Clock`] that stands still until [`advance()`](Self::advance) is called, for tests that
+/// step a shutdown through its deadlines one at a time.
+///
+/// Hand it to the coordinator with
+/// [`with_clock()`](crate::ShutdownCoordinator::with_clock), and keep a clone to move it
+/// forward. Cloning is cheap, and all clones share the same time.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use lambda_graceful_shutdown::{testing::ManualClock, ShutdownCoordinator, ShutdownReason};
+///
+/// # async fn example() {
+/// let clock = ManualClock::new();
+/// let shutdown = ShutdownCoordinator::new().with_clock(clock.clone());
+/// let running = tokio::spawn(async move { shutdown.shutdown(ShutdownReason::Sigterm).await });
+/// clock.advance(Duration::from_millis(450));
+/// let report = running.await.unwrap();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    offset: Arc<watch::Sender<Duration>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    /// A clock standing at the current time.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: Arc::new(watch::Sender::new(Duration::ZERO)),
+        }
+    }
+
+    /// Move the clock forward by `duration`, waking up everything waiting for a deadline that
+    /// has now passed.
+    pub fn advance(&self, duration: Duration) {
+        self.offset.send_modify(|offset| *offset += duration);
+    }
+
+    /// How far the clock has moved since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.offset.borrow()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let wait = deadline.saturating_duration_since(self.start);
+        let mut offset = self.offset.subscribe();
+        Box::pin(async move {
+            // Once every clone of the clock is dropped, the deadline never comes
+            if offset.wait_for(|offset| *offset >= wait).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
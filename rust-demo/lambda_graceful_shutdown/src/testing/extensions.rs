@@ -0,0 +1,351 @@
+//! An emulated Lambda Extensions API.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use super::server::{self, Request, Response, ServerTask};
+
+/// The header carrying the identifier handed out at registration.
+const IDENTIFIER_HEADER: &str = "Lambda-Extension-Identifier";
+
+/// How long after an event is sent its `deadlineMs` is, like a function with a 3s timeout.
+const EVENT_DEADLINE: Duration = Duration::from_secs(3);
+
+/// The ARN of the emulated function.
+pub(super) const FUNCTION_ARN: &str = "arn:aws:lambda:us-east-1:123456789012:function:mock";
+
+/// An extension that registered with a [`MockExtensionsApi`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    /// The name sent in the `Lambda-Extension-Name` header.
+    pub name: String,
+    /// The identifier handed out to the extension.
+    pub id: String,
+    /// The events the extension subscribed to, `INVOKE` and `SHUTDOWN`. Empty for an
+    /// extension registered only so that the runtime receives `SIGTERM`.
+    pub events: Vec<String>,
+}
+
+/// When the error reported by an extension happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPhase {
+    /// Reported to `/extension/init/error`.
+    Init,
+    /// Reported to `/extension/exit/error`.
+    Exit,
+}
+
+/// An error reported by an extension to a [`MockExtensionsApi`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionError {
+    /// Which endpoint the error was reported to.
+    pub phase: ErrorPhase,
+    /// The identifier of the extension that reported it.
+    pub extension_id: String,
+    /// The `Lambda-Extension-Function-Error-Type` header, if any.
+    pub error_type: Option<String>,
+    /// The body of the request.
+    pub body: String,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    registrations: Vec<Registration>,
+    /// The events not yet picked up by each extension, by identifier.
+    queues: HashMap<String, VecDeque<Value>>,
+    errors: Vec<ExtensionError>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever an extension registers or an event is sent.
+    changed: Notify,
+}
+
+/// A local server emulating the Lambda Extensions API, for testing extensions and shutdown
+/// handling without deploying them.
+///
+/// Point the code under test at it by setting `AWS_LAMBDA_RUNTIME_API` to its
+/// [`endpoint()`](Self::endpoint). The server answers:
+///
+/// - `POST /2020-01-01/extension/register`, handing out an identifier
+/// - `GET /2020-01-01/extension/event/next`, which waits until an event is sent to the
+///   extension with [`send_invoke()`](Self::send_invoke) or
+///   [`send_shutdown()`](Self::send_shutdown), like the real API
+/// - `POST /2020-01-01/extension/init/error` and `/exit/error`, recording the error
+///
+/// Clones share the same server, which stops when the last of them is dropped.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::testing::MockExtensionsApi;
+///
+/// # async fn example() -> std::io::Result<()> {
+/// let api = MockExtensionsApi::start().await?;
+/// std::env::set_var("AWS_LAMBDA_RUNTIME_API", api.endpoint());
+/// // ...start the extension under test, then:
+/// api.wait_for_registrations(1).await;
+/// api.send_shutdown("spindown");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockExtensionsApi {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+    _server: Arc<ServerTask>,
+}
+
+impl fmt::Debug for MockExtensionsApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockExtensionsApi")
+            .field("address", &self.address)
+            .field("state", &self.shared.state.lock().unwrap())
+            .finish()
+    }
+}
+
+impl MockExtensionsApi {
+    /// Start the server on a free port on the loopback interface.
+    pub async fn start() -> io::Result<Self> {
+        let shared = Arc::new(Shared::default());
+        let handler = shared.clone();
+        let (address, server) = server::spawn(move |request| {
+            let shared = handler.clone();
+            async move { shared.handle(request).await }
+        })
+        .await?;
+        Ok(Self::served_by(address, shared, server))
+    }
+
+    /// The API handled by `shared`, answered by `server` on `address`.
+    pub(super) fn served_by(
+        address: SocketAddr,
+        shared: Arc<Shared>,
+        server: Arc<ServerTask>,
+    ) -> Self {
+        Self {
+            address,
+            shared,
+            _server: server,
+        }
+    }
+
+    /// The `host:port` to set `AWS_LAMBDA_RUNTIME_API` to.
+    pub fn endpoint(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// The extensions registered so far, in the order they registered.
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.shared.state.lock().unwrap().registrations.clone()
+    }
+
+    /// The errors reported so far.
+    pub fn errors(&self) -> Vec<ExtensionError> {
+        self.shared.state.lock().unwrap().errors.clone()
+    }
+
+    /// Wait until at least `count` extensions have registered.
+    pub async fn wait_for_registrations(&self, count: usize) {
+        loop {
+            let changed = self.shared.changed.notified();
+            if self.shared.state.lock().unwrap().registrations.len() >= count {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Send an `INVOKE` event for `request_id` to the registered extensions subscribed to it.
+    ///
+    /// A [`MockRuntimeApi`](super::MockRuntimeApi) sends these on its own, whenever the
+    /// runtime picks up an invocation.
+    pub fn send_invoke(&self, request_id: &str) {
+        self.shared.send_invoke(
+            request_id,
+            deadline_ms(),
+            "Root=1-00000000-000000000000000000000000;Sampled=0",
+        );
+    }
+
+    /// Send a `SHUTDOWN` event to the registered extensions subscribed to it, with a
+    /// `shutdownReason` such as `spindown`, `timeout` or `failure`.
+    pub fn send_shutdown(&self, reason: &str) {
+        self.shared.send(
+            "SHUTDOWN",
+            json!({
+                "eventType": "SHUTDOWN",
+                "deadlineMs": deadline_ms(),
+                "shutdownReason": reason,
+            }),
+        );
+    }
+}
+
+impl Shared {
+    pub(super) fn send_invoke(&self, request_id: &str, deadline_ms: u64, trace_id: &str) {
+        self.send(
+            "INVOKE",
+            json!({
+                "eventType": "INVOKE",
+                "deadlineMs": deadline_ms,
+                "requestId": request_id,
+                "invokedFunctionArn": FUNCTION_ARN,
+                "tracing": {
+                    "type": "X-Amzn-Trace-Id",
+                    "value": trace_id,
+                },
+            }),
+        );
+    }
+
+    fn send(&self, event_type: &str, event: Value) {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            registrations,
+            queues,
+            ..
+        } = &mut *state;
+        for registration in registrations
+            .iter()
+            .filter(|registration| registration.events.iter().any(|e| e == event_type))
+        {
+            queues
+                .entry(registration.id.clone())
+                .or_default()
+                .push_back(event.clone());
+        }
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    pub(super) async fn handle(&self, request: Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/2020-01-01/extension/register") => self.register(&request),
+            ("GET", "/2020-01-01/extension/event/next") => match self.identify(&request) {
+                Ok(id) => self.next_event(&id).await,
+                Err(response) => response,
+            },
+            ("POST", "/2020-01-01/extension/init/error") => {
+                self.record_error(ErrorPhase::Init, &request)
+            }
+            ("POST", "/2020-01-01/extension/exit/error") => {
+                self.record_error(ErrorPhase::Exit, &request)
+            }
+            _ => Response::new("404 Not Found"),
+        }
+    }
+
+    fn register(&self, request: &Request) -> Response {
+        let Some(name) = request.header("Lambda-Extension-Name") else {
+            return error("400 Bad Request", "missing Lambda-Extension-Name header");
+        };
+        let events = match serde_json::from_slice::<Value>(&request.body) {
+            Ok(Value::Object(body)) => body
+                .get("events")
+                .and_then(Value::as_array)
+                .map(|events| {
+                    events
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => return error("400 Bad Request", "expected a JSON object with `events`"),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let id = format!("mock-extension-{}", state.registrations.len() + 1);
+        state.registrations.push(Registration {
+            name: name.to_owned(),
+            id: id.clone(),
+            events,
+        });
+        drop(state);
+        self.changed.notify_waiters();
+
+        Response::json(
+            "200 OK",
+            json!({
+                "functionName": "mock",
+                "functionVersion": "$LATEST",
+                "handler": "bootstrap",
+                "accountId": "123456789012",
+            }),
+        )
+        .with_header(IDENTIFIER_HEADER, id)
+    }
+
+    /// The identifier of the extension making `request`, or the response rejecting it.
+    fn identify(&self, request: &Request) -> Result<String, Response> {
+        let id = request.header(IDENTIFIER_HEADER).unwrap_or_default();
+        let state = self.state.lock().unwrap();
+        if state
+            .registrations
+            .iter()
+            .any(|registration| registration.id == id)
+        {
+            Ok(id.to_owned())
+        } else {
+            Err(error("403 Forbidden", "unknown extension identifier"))
+        }
+    }
+
+    async fn next_event(&self, id: &str) -> Response {
+        loop {
+            let changed = self.changed.notified();
+            let event = self
+                .state
+                .lock()
+                .unwrap()
+                .queues
+                .get_mut(id)
+                .and_then(VecDeque::pop_front);
+            if let Some(event) = event {
+                return Response::json("200 OK", event);
+            }
+            changed.await;
+        }
+    }
+
+    fn record_error(&self, phase: ErrorPhase, request: &Request) -> Response {
+        let extension_id = match self.identify(request) {
+            Ok(id) => id,
+            Err(response) => return response,
+        };
+        self.state.lock().unwrap().errors.push(ExtensionError {
+            phase,
+            extension_id,
+            error_type: request
+                .header("Lambda-Extension-Function-Error-Type")
+                .map(str::to_owned),
+            body: request.body_text(),
+        });
+        Response::json("202 Accepted", json!({ "status": "OK" }))
+    }
+}
+
+/// An error response shaped like the ones of the real API.
+fn error(status: &'static str, message: &str) -> Response {
+    Response::json(
+        status,
+        json!({ "errorMessage": message, "errorType": "Extension.InvalidRequest" }),
+    )
+}
+
+fn deadline_ms() -> u64 {
+    (SystemTime::now() + EVENT_DEADLINE)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
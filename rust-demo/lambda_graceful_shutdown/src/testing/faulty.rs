@@ -0,0 +1,124 @@
+//! Hooks that misbehave on purpose.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// A [`ShutdownHook`] wrapped to be slow, fail, or panic on demand, to check how the rest of
+/// the shutdown copes.
+///
+/// The wrapped hook runs after the [`delay()`](Self::delay), unless the call fails or panics
+/// instead. Failures and panics are counted per call to
+/// [`shutdown()`](ShutdownHook::shutdown), so a hook that is retried can fail the first few
+/// times and then succeed.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use lambda_graceful_shutdown::{hook_fn, testing::{FaultyHook, ShutdownSim}};
+///
+/// # async fn example() {
+/// let flush = FaultyHook::wrapping(hook_fn("flush", |_ctx| async { Ok(()) }))
+///     .fail_times(1)
+///     .delay(Duration::from_millis(800));
+/// ShutdownSim::new().hook(flush).trigger().await;
+/// # }
+/// ```
+///
+/// The coordinator doesn't catch panics: a hook set up with [`panics()`](Self::panics) takes
+/// the shutdown down with it, the way a real one would.
+pub struct FaultyHook<H> {
+    hook: H,
+    delay: Option<Duration>,
+    failures: usize,
+    panics: bool,
+    error: String,
+    calls: AtomicUsize,
+}
+
+impl<H: ShutdownHook> fmt::Debug for FaultyHook<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultyHook")
+            .field("name", &self.hook.name())
+            .field("delay", &self.delay)
+            .field("failures", &self.failures)
+            .field("panics", &self.panics)
+            .field("calls", &self.calls())
+            .finish()
+    }
+}
+
+impl<H: ShutdownHook> FaultyHook<H> {
+    /// Wrap `hook`, which runs normally until a fault is added.
+    pub fn wrapping(hook: H) -> Self {
+        Self {
+            hook,
+            delay: None,
+            failures: 0,
+            panics: false,
+            error: "injected failure".to_owned(),
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fail the first `times` calls without running the wrapped hook.
+    pub fn fail_times(mut self, times: usize) -> Self {
+        self.failures = times;
+        self
+    }
+
+    /// Fail every call without running the wrapped hook.
+    pub fn fail_always(self) -> Self {
+        self.fail_times(usize::MAX)
+    }
+
+    /// Fail with `message` instead of `injected failure`.
+    pub fn with_error(mut self, message: impl Into<String>) -> Self {
+        self.error = message.into();
+        self
+    }
+
+    /// Wait `delay` at the start of every call, on the coordinator's
+    /// [clock](crate::ShutdownCoordinator::with_clock).
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Panic, after the delay, instead of running the wrapped hook.
+    pub fn panics(mut self) -> Self {
+        self.panics = true;
+        self
+    }
+
+    /// How many times the hook was called.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+}
+
+impl<H: ShutdownHook> ShutdownHook for FaultyHook<H> {
+    fn name(&self) -> &str {
+        self.hook.name()
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let call = self.calls.fetch_add(1, Ordering::Relaxed);
+            if let Some(delay) = self.delay {
+                ctx.clock().sleep_until(ctx.clock().now() + delay).await;
+            }
+            if self.panics {
+                panic!("injected panic in hook {}", self.hook.name());
+            }
+            if call < self.failures {
+                return Err(self.error.clone().into());
+            }
+            self.hook.shutdown(ctx).await
+        })
+    }
+}
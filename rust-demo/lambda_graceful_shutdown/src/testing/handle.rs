@@ -0,0 +1,38 @@
+//! The other end of a test-controlled [`ShutdownHandle`](crate::ShutdownHandle).
+
+use std::sync::Arc;
+
+use crate::sync::Latch;
+
+/// Starts the "shutdown" of a handle from
+/// [`ShutdownHandle::test_controlled()`](crate::ShutdownHandle::test_controlled), at the
+/// point of a test that should see it.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::ShutdownHandle;
+///
+/// fn process(records: &[u32], shutdown: &ShutdownHandle) -> usize {
+///     records.iter().take_while(|_| !shutdown.is_shutting_down()).count()
+/// }
+///
+/// let (handle, controller) = ShutdownHandle::test_controlled();
+/// assert_eq!(process(&[1, 2, 3], &handle), 3);
+/// controller.trip();
+/// assert_eq!(process(&[1, 2, 3], &handle), 0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ShutdownController {
+    pub(crate) started: Arc<Latch>,
+}
+
+impl ShutdownController {
+    /// Start the shutdown: the handle reports it, and wakes up whatever waits for it.
+    pub fn trip(&self) {
+        self.started.trigger();
+    }
+
+    /// Whether [`trip()`](Self::trip) was called.
+    pub fn is_tripped(&self) -> bool {
+        self.started.is_set()
+    }
+}
@@ -0,0 +1,168 @@
+//! Everything an end-to-end shutdown test needs, set up in one go.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use lambda_runtime::{layers::TracingLayer, service_fn, Diagnostic, LambdaEvent, Runtime};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use super::{InvocationResult, MockRuntimeApi, SimulatedShutdown};
+use crate::{ShutdownCoordinator, ShutdownReason};
+
+/// Held while pointing `AWS_LAMBDA_RUNTIME_API` at a test's server and starting the runtime,
+/// which reads it, so tests running in parallel each get their own server.
+static RUNTIME_ENV: Mutex<()> = Mutex::new(());
+
+/// A [`MockRuntimeApi`], a [`ShutdownCoordinator`], and the function under test running
+/// against them.
+///
+/// Used by the [`lambda_shutdown_test`](crate::lambda_shutdown_test) attribute, or directly
+/// through [`run()`](Self::run):
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{hook_fn, testing::ShutdownTestHarness, ShutdownCoordinator};
+/// use lambda_runtime::{Error, LambdaEvent};
+/// use serde_json::{json, Value};
+///
+/// async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+///     Ok(event.payload)
+/// }
+///
+/// #[test]
+/// fn flushes_after_an_invocation() {
+///     ShutdownTestHarness::run(|harness| async move {
+///         let harness = harness.with_coordinator(
+///             ShutdownCoordinator::new().with_hook(hook_fn("flush", |_ctx| async { Ok(()) })),
+///         );
+///         harness.start_function(handler);
+///         harness.invoke(json!({ "hello": "world" })).await;
+///         harness.sigterm().await.assert_clean();
+///     });
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ShutdownTestHarness {
+    api: MockRuntimeApi,
+    coordinator: ShutdownCoordinator,
+}
+
+impl ShutdownTestHarness {
+    /// Run `test` with a new harness, on a single-threaded tokio runtime with the clock
+    /// paused, so that sleeps and timeouts take no time and the timings are the same on every
+    /// run. Returns what `test` returns, e.g. a `Result` for the test function to return.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the runtime or the server can't be started, and if `test` panics.
+    pub fn run<F, Fut, T>(test: F) -> T
+    where
+        F: FnOnce(Self) -> Fut,
+        Fut: Future<Output = T>,
+    {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .expect("failed to start the tokio runtime")
+            .block_on(async move { test(Self::start().await).await })
+    }
+
+    /// Start a [`MockRuntimeApi`], with a coordinator that has no hooks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server can't be started.
+    pub async fn start() -> Self {
+        Self {
+            api: MockRuntimeApi::start()
+                .await
+                .expect("failed to start the mock Runtime API"),
+            coordinator: ShutdownCoordinator::new(),
+        }
+    }
+
+    /// Use `coordinator`, with its hooks and settings, instead of one with no hooks.
+    pub fn with_coordinator(mut self, coordinator: ShutdownCoordinator) -> Self {
+        self.coordinator = coordinator;
+        self
+    }
+
+    /// The emulated Runtime API.
+    pub fn api(&self) -> &MockRuntimeApi {
+        &self.api
+    }
+
+    /// The coordinator the shutdown runs on.
+    pub fn coordinator(&self) -> &ShutdownCoordinator {
+        &self.coordinator
+    }
+
+    /// Run `handler` as the function, in the background, with every invocation tracked by
+    /// the coordinator like
+    /// [`track_invocation()`](ShutdownCoordinator::track_invocation) does.
+    pub fn start_function<A, R, F, Fut>(&self, handler: F)
+    where
+        F: Fn(LambdaEvent<A>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, lambda_runtime::Error>> + Send + 'static,
+        A: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+    {
+        let coordinator = self.coordinator.clone();
+        let handler = Arc::new(handler);
+        let service = service_fn(move |event: LambdaEvent<A>| {
+            let coordinator = coordinator.clone();
+            let handler = handler.clone();
+            async move {
+                let _invocation = coordinator.track_invocation(
+                    event.context.request_id.clone(),
+                    event.context.xray_trace_id.clone(),
+                );
+                handler(event).await.map_err(Diagnostic::from)
+            }
+        });
+
+        let runtime = {
+            let _env = RUNTIME_ENV
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            self.api.set_env();
+            Runtime::new(service).layer(TracingLayer::new())
+        };
+        tokio::spawn(async move {
+            if let Err(error) = runtime.run().await {
+                tracing::debug!(%error, "the function under test stopped");
+            }
+        });
+    }
+
+    /// Invoke the function with `payload`, and wait for it to respond.
+    pub async fn invoke(&self, payload: Value) -> InvocationResult {
+        let request_id = self.api.invoke(payload);
+        self.api.wait_for_result(&request_id).await
+    }
+
+    /// Invoke the function with `payload`, and return its request id once the handler has
+    /// started, while the invocation is still in flight.
+    pub async fn invoke_in_background(&self, payload: Value) -> String {
+        let tracked = self.coordinator.sandbox_stats().invocations;
+        let request_id = self.api.invoke(payload);
+        self.api.wait_for_pickup(&request_id).await;
+        // The runtime picks the invocation up a few polls before the handler runs
+        while self.coordinator.sandbox_stats().invocations == tracked {
+            tokio::task::yield_now().await;
+        }
+        request_id
+    }
+
+    /// Shut down like a `SIGTERM` would, and return the outcome to assert on.
+    ///
+    /// This calls [`shutdown()`](ShutdownCoordinator::shutdown) rather than sending a signal,
+    /// which would reach every test in the process.
+    pub async fn sigterm(&self) -> SimulatedShutdown {
+        let report = self.coordinator.shutdown(ShutdownReason::Sigterm).await;
+        SimulatedShutdown::new(self.coordinator.budget(), report)
+    }
+}
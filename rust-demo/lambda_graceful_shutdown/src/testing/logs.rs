@@ -0,0 +1,187 @@
+//! Captured lifecycle records, to assert on or compare with a golden file.
+
+use std::{
+    env, fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+
+use crate::ShutdownReason;
+
+/// Set to `1` to overwrite the golden files compared in
+/// [`LogCapture::assert_snapshot()`] with the records of the current run.
+const UPDATE_ENV: &str = "UPDATE_SHUTDOWN_SNAPSHOTS";
+
+/// The lifecycle records of a coordinator, kept in memory rather than written to stdout.
+///
+/// Hand it to the coordinator with
+/// [`with_lifecycle_capture()`](crate::ShutdownCoordinator::with_lifecycle_capture). Each
+/// record is the JSON object
+/// [`with_lifecycle_logs()`](crate::ShutdownCoordinator::with_lifecycle_logs) writes with
+/// `AWS_LAMBDA_LOG_FORMAT=JSON`: a `shutdown.started` record, one `shutdown.hook_finished`
+/// record per hook, and a `shutdown.completed` record.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{
+///     hook_fn, testing::LogCapture, ShutdownCoordinator, ShutdownReason,
+/// };
+///
+/// # async fn example() {
+/// let logs = LogCapture::new();
+/// let shutdown = ShutdownCoordinator::new()
+///     .with_lifecycle_capture(&logs)
+///     .with_hook(hook_fn("flush", |_ctx| async { Ok(()) }));
+/// shutdown.shutdown(ShutdownReason::Sigterm).await;
+///
+/// logs.assert_reason(ShutdownReason::Sigterm)
+///     .assert_hook_outcomes(&[("flush", "completed")])
+///     .assert_snapshot("tests/snapshots/flush.jsonl");
+/// # }
+/// ```
+///
+/// Cloning is cheap, and all clones share the same records.
+#[derive(Debug, Clone, Default)]
+pub struct LogCapture {
+    pub(crate) records: Arc<Mutex<Vec<Value>>>,
+}
+
+impl LogCapture {
+    /// Start with no records.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The records so far, oldest first.
+    pub fn records(&self) -> Vec<Value> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// The `event_type` of each record so far, oldest first.
+    pub fn event_types(&self) -> Vec<String> {
+        self.records()
+            .iter()
+            .map(|record| field(record, "event_type"))
+            .collect()
+    }
+
+    /// Forget the records so far, e.g. between two shutdowns of the same coordinator.
+    pub fn clear(&self) {
+        self.records.lock().unwrap().clear();
+    }
+
+    /// The records with the fields that change from run to run left out: the timestamp, the
+    /// message, which repeats the other fields, and the timings, which end in `_ms`.
+    ///
+    /// One JSON object per line, with its fields sorted, so it changes only when the shutdown
+    /// behaves differently.
+    pub fn snapshot(&self) -> String {
+        self.records()
+            .into_iter()
+            .map(|mut record| {
+                if let Some(record) = record.as_object_mut() {
+                    record.retain(|name, _| {
+                        !matches!(name.as_str(), "timestamp" | "message") && !name.ends_with("_ms")
+                    });
+                }
+                record.to_string() + "\n"
+            })
+            .collect()
+    }
+
+    /// Assert that the records have exactly these `event_type`s, in order.
+    #[track_caller]
+    pub fn assert_sequence(&self, expected: &[&str]) -> &Self {
+        let event_types = self.event_types();
+        assert!(
+            event_types == expected,
+            "expected the records {expected:?}, got {event_types:?}"
+        );
+        self
+    }
+
+    /// Assert that the shutdown started for `reason`.
+    #[track_caller]
+    pub fn assert_reason(&self, reason: ShutdownReason) -> &Self {
+        self.assert_field("shutdown.started", "reason", reason.to_string())
+    }
+
+    /// Assert that the hooks finished with these outcomes, such as `("flush", "completed")`,
+    /// in order.
+    #[track_caller]
+    pub fn assert_hook_outcomes(&self, expected: &[(&str, &str)]) -> &Self {
+        let outcomes: Vec<(String, String)> = self
+            .records()
+            .iter()
+            .filter(|record| field(record, "event_type") == "shutdown.hook_finished")
+            .map(|record| (field(record, "hook"), field(record, "outcome")))
+            .collect();
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(hook, outcome)| (hook.to_string(), outcome.to_string()))
+            .collect();
+        assert!(
+            outcomes == expected,
+            "expected the hook outcomes {expected:?}, got {outcomes:?}"
+        );
+        self
+    }
+
+    /// Assert that the first record of `event_type` has `value` in its field `name`.
+    #[track_caller]
+    pub fn assert_field(&self, event_type: &str, name: &str, value: impl Into<Value>) -> &Self {
+        let value = value.into();
+        let records = self.records();
+        let Some(record) = records
+            .iter()
+            .find(|record| field(record, "event_type") == event_type)
+        else {
+            panic!("no {event_type} record in {:?}", self.event_types());
+        };
+        assert!(
+            record.get(name) == Some(&value),
+            "expected {name} = {value} in the {event_type} record, got {record}"
+        );
+        self
+    }
+
+    /// Assert that the [`snapshot()`](Self::snapshot) matches the golden file at `path`.
+    ///
+    /// The file is written, with its parent directories, if it doesn't exist yet or
+    /// `UPDATE_SHUTDOWN_SNAPSHOTS=1` is set, so a new or intended change can be reviewed in
+    /// the diff.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot differs, or if the file can't be read or written.
+    #[track_caller]
+    pub fn assert_snapshot(&self, path: impl AsRef<Path>) -> &Self {
+        let path = path.as_ref();
+        let snapshot = self.snapshot();
+        let update = env::var(UPDATE_ENV).is_ok_and(|update| update == "1");
+        match fs::read_to_string(path) {
+            Ok(golden) if !update => assert!(
+                golden == snapshot,
+                "the shutdown records differ from {}, run with {UPDATE_ENV}=1 to update it\n\
+                 expected:\n{golden}\ngot:\n{snapshot}",
+                path.display()
+            ),
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                panic!("failed to read {}: {error}", path.display())
+            }
+            _ => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).expect("failed to create the snapshot directory");
+                }
+                fs::write(path, snapshot).expect("failed to write the snapshot");
+            }
+        }
+        self
+    }
+}
+
+/// The string field `name` of `record`, or an empty string.
+fn field(record: &Value, name: &str) -> String {
+    record[name].as_str().unwrap_or_default().to_owned()
+}
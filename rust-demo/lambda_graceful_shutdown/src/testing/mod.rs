@@ -0,0 +1,59 @@
+//! Emulated Lambda APIs, for testing shutdown handling with `cargo test`.
+//!
+//! Shutdown code is hard to exercise: the signals and events that trigger it only come from
+//! Lambda. The servers in this module stand in for the APIs Lambda exposes to the execution
+//! environment, on a free port on the loopback interface, so that code registering an
+//! extension or reacting to a `SHUTDOWN` event can run in a test:
+//!
+//! - [`MockExtensionsApi`] emulates the
+//!   [Extensions API](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-extensions-api.html)
+//! - [`MockRuntimeApi`] emulates the
+//!   [Runtime API](https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html), with the
+//!   Extensions API next to it like on Lambda, to run a function through invocations and a
+//!   shutdown
+//!
+//! To check that a set of hooks fits in the shutdown window without a Lambda API at all,
+//! run a [`ShutdownSim`]. A [`ShutdownTestHarness`] sets up a `MockRuntimeApi`, a coordinator
+//! and the function under test on a paused clock, and is what the `#[lambda_shutdown_test]`
+//! attribute (feature `macros`) hands to each test. A [`ShutdownScenario`] adds an extension
+//! registered for `SHUTDOWN` events, to drive a function through the whole shutdown wiring.
+//! To step a shutdown through its deadlines by hand, give the coordinator a [`ManualClock`].
+//!
+//! A [`LogCapture`] keeps the coordinator's lifecycle records, to assert on the order of the
+//! shutdown phases and the hook outcomes, or to compare them with a golden file.
+//! [`FaultyHook`] wraps a hook to make it slow, fail or panic, and a [`HookTrace`] records
+//! when each hook started and finished, to check properties of randomly generated shutdowns.
+//! Handler code that checks [`ShutdownHandle::is_shutting_down()`](crate::ShutdownHandle)
+//! can be tested without a coordinator, with a [`ShutdownController`] to trip it.
+//!
+//! To go one step further, a [`RieImage`] runs a function image under the Runtime Interface
+//! Emulator in Docker, and stops it to check what its shutdown logged.
+//!
+//! Both the extension and runtime clients find the APIs through the `AWS_LAMBDA_RUNTIME_API`
+//! environment variable, which is shared by the whole test binary. Keep tests that set it in
+//! their own file, or run them one at a time.
+
+mod clock;
+mod extensions;
+mod faulty;
+mod handle;
+mod harness;
+mod logs;
+mod rie;
+mod runtime;
+mod scenario;
+mod server;
+mod sim;
+mod trace;
+
+pub use clock::ManualClock;
+pub use extensions::{ErrorPhase, ExtensionError, MockExtensionsApi, Registration};
+pub use faulty::FaultyHook;
+pub use handle::ShutdownController;
+pub use harness::ShutdownTestHarness;
+pub use logs::LogCapture;
+pub use rie::{RieContainer, RieImage, RieLogs};
+pub use runtime::{InvocationResult, MockRuntimeApi};
+pub use scenario::{ShutdownScenario, EXTENSION_NAME};
+pub use sim::{ShutdownSim, SimulatedShutdown};
+pub use trace::{HookTrace, TraceEvent, TraceEventKind};
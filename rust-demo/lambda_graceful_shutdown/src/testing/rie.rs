@@ -0,0 +1,234 @@
+//! A function image running under the Runtime Interface Emulator, in Docker.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Where the emulator listens in the container.
+const RIE_PORT: &str = "8080/tcp";
+
+/// The path the emulator takes invocations on.
+const INVOCATIONS_PATH: &str = "/2015-03-31/functions/function/invocations";
+
+/// How long to wait for the emulator to answer the first invocation.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `docker stop` waits for the container after the `SIGTERM`, before killing it.
+const STOP_TIMEOUT_SECS: &str = "10";
+
+/// A function image built on one of the `public.ecr.aws/lambda/provided` base images, which
+/// run the [Runtime Interface Emulator](https://github.com/aws/aws-lambda-runtime-interface-emulator).
+///
+/// The emulator is the closest to Lambda a test can get without deploying: the function runs
+/// with the real runtime client and extensions, and `docker stop` sends it a `SIGTERM`, so the
+/// shutdown goes through the real signal handler. This needs the `docker` CLI and a daemon,
+/// so tests using it are usually `#[ignore]`d and run on their own:
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::testing::RieImage;
+/// use serde_json::json;
+///
+/// #[tokio::test]
+/// #[ignore = "needs docker"]
+/// async fn writes_the_shutdown_summary() {
+///     let image = RieImage::build(
+///         "graceful-shutdown-rie",
+///         "..",
+///         "../rust_app_internal_extension_from_helper/Dockerfile",
+///     )
+///     .unwrap();
+///     let container = image.run().unwrap();
+///     container.invoke(&json!({})).await.unwrap();
+///
+///     let logs = container.stop().unwrap();
+///     assert!(logs.assert_shutdown_summary()["hooks"].is_array());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RieImage {
+    tag: String,
+}
+
+impl RieImage {
+    /// Build the image in `dockerfile` from the `context` directory, and tag it as `tag`.
+    ///
+    /// Docker caches the layers, so building again for each test only takes long the first
+    /// time, or after the function changed.
+    pub fn build(
+        tag: impl Into<String>,
+        context: impl AsRef<Path>,
+        dockerfile: impl AsRef<Path>,
+    ) -> io::Result<Self> {
+        let tag = tag.into();
+        let context = context.as_ref().to_string_lossy();
+        let dockerfile = dockerfile.as_ref().to_string_lossy();
+        docker(&["build", "--quiet", "-t", &tag, "-f", &dockerfile, &context])?;
+        Ok(Self { tag })
+    }
+
+    /// An image that was already built and tagged as `tag`.
+    pub fn existing(tag: impl Into<String>) -> Self {
+        Self { tag: tag.into() }
+    }
+
+    /// The image's tag.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Start a container from the image, with the emulator on a free port of the loopback
+    /// interface.
+    pub fn run(&self) -> io::Result<RieContainer> {
+        let id = docker(&["run", "--detach", "--publish", "127.0.0.1::8080", &self.tag])?;
+        let mut container = RieContainer {
+            id,
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+            removed: false,
+        };
+        let port = docker(&["port", &container.id, RIE_PORT])?;
+        // Removed when dropped, if the port can't be found
+        container.address = port
+            .lines()
+            .find_map(|line| line.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("unexpected `docker port` output: {port}")))?;
+        Ok(container)
+    }
+}
+
+/// A container started with [`RieImage::run()`]. It is removed when dropped, if it wasn't
+/// [stopped](Self::stop) before.
+#[derive(Debug)]
+pub struct RieContainer {
+    id: String,
+    address: SocketAddr,
+    removed: bool,
+}
+
+impl RieContainer {
+    /// The container's id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Where the emulator listens.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Invoke the function with `payload`, and return what it responded.
+    ///
+    /// The first invocation is retried until the emulator answers, for up to 30s, since it
+    /// takes a moment to start.
+    pub async fn invoke(&self, payload: &Value) -> io::Result<Value> {
+        let started = Instant::now();
+        let body = loop {
+            match post(self.address, INVOCATIONS_PATH, &payload.to_string()).await {
+                Ok(body) => break body,
+                Err(error) if started.elapsed() < STARTUP_TIMEOUT => {
+                    tracing::debug!(%error, "the emulator isn't answering yet");
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                Err(error) => return Err(error),
+            }
+        };
+        serde_json::from_str(&body).map_err(io::Error::other)
+    }
+
+    /// Stop the container with a `SIGTERM`, like Lambda shutting the environment down, wait
+    /// for it to exit, and return what it logged.
+    pub fn stop(mut self) -> io::Result<RieLogs> {
+        docker(&["stop", "--time", STOP_TIMEOUT_SECS, &self.id])?;
+        let output = Command::new("docker").args(["logs", &self.id]).output()?;
+        let logs = RieLogs {
+            output: String::from_utf8_lossy(&output.stdout).into_owned()
+                + &String::from_utf8_lossy(&output.stderr),
+        };
+        docker(&["rm", &self.id])?;
+        self.removed = true;
+        Ok(logs)
+    }
+}
+
+impl Drop for RieContainer {
+    fn drop(&mut self) {
+        if !self.removed {
+            let _ = docker(&["rm", "--force", &self.id]);
+        }
+    }
+}
+
+/// What a container logged, from [`RieContainer::stop()`].
+#[derive(Debug, Clone)]
+pub struct RieLogs {
+    output: String,
+}
+
+impl RieLogs {
+    /// Everything the container wrote to stdout, then to stderr.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// The report from the last `SHUTDOWN_SUMMARY` line, if there is one.
+    /// See [`ShutdownReport::summary_line()`](crate::ShutdownReport::summary_line).
+    pub fn shutdown_summary(&self) -> Option<Value> {
+        self.output
+            .lines()
+            .rev()
+            .find_map(|line| line.split_once("SHUTDOWN_SUMMARY "))
+            .and_then(|(_, summary)| serde_json::from_str(summary).ok())
+    }
+
+    /// Assert that the function wrote a `SHUTDOWN_SUMMARY` line, and return its report.
+    #[track_caller]
+    pub fn assert_shutdown_summary(&self) -> Value {
+        match self.shutdown_summary() {
+            Some(summary) => summary,
+            None => panic!("no SHUTDOWN_SUMMARY in the logs:\n{}", self.output),
+        }
+    }
+}
+
+/// Run `docker` with `args`, and return what it wrote to stdout.
+fn docker(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("docker").args(args).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    } else {
+        Err(io::Error::other(format!(
+            "docker {} exited with {}: {}",
+            args.first().unwrap_or(&""),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Post `body` to `path` on the emulator, and return the body of the response.
+async fn post(address: SocketAddr, path: &str, body: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(address).await?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nhost: {address}\r\ncontent-type: application/json\r\n\
+         content-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    match response.split_once("\r\n\r\n") {
+        Some((head, body)) if head.starts_with("HTTP/1.1 200") => Ok(body.to_owned()),
+        // Docker accepts the connection before the emulator is up, and then closes it
+        Some((head, _)) => Err(io::Error::other(format!("the emulator answered {head}"))),
+        None => Err(io::Error::other("incomplete response")),
+    }
+}
@@ -0,0 +1,338 @@
+//! An emulated Lambda Runtime API.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+
+use super::{
+    extensions::{self, MockExtensionsApi},
+    server::{self, Request, Response},
+};
+
+/// How long the function gets for each invocation, unless set with
+/// [`MockRuntimeApi::with_invocation_timeout()`].
+const DEFAULT_INVOCATION_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The prefix of every Runtime API path about one invocation.
+const INVOCATION_PATH: &str = "/2018-06-01/runtime/invocation/";
+
+/// How the function finished an invocation sent by a [`MockRuntimeApi`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvocationResult {
+    /// The function posted a response, with this body.
+    Response(String),
+    /// The function reported an error, with the `Lambda-Runtime-Function-Error-Type` header
+    /// and the body, usually an `errorType` and `errorMessage`.
+    Error {
+        /// The `Lambda-Runtime-Function-Error-Type` header, if any.
+        error_type: Option<String>,
+        /// The body of the request.
+        body: String,
+    },
+}
+
+impl InvocationResult {
+    /// The response body as JSON, if the function posted a JSON response.
+    pub fn json(&self) -> Option<Value> {
+        match self {
+            InvocationResult::Response(body) => serde_json::from_str(body).ok(),
+            InvocationResult::Error { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Invocation {
+    request_id: String,
+    trace_id: String,
+    payload: Value,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The invocations not yet picked up by the runtime.
+    pending: VecDeque<Invocation>,
+    /// The request ids picked up by the runtime, and not finished yet.
+    in_flight: Vec<String>,
+    results: HashMap<String, InvocationResult>,
+    init_errors: Vec<String>,
+    next_request: u64,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever an invocation is queued, picked up or finished.
+    changed: Notify,
+    timeout: Mutex<Option<Duration>>,
+    extensions: Arc<extensions::Shared>,
+}
+
+/// A local server emulating the Lambda Runtime API, and the Extensions API next to it, for
+/// running a function end to end in a test.
+///
+/// Call [`set_env()`](Self::set_env) before starting the runtime, which points it at the
+/// server and sets the other environment variables `lambda_runtime` needs. The server
+/// answers:
+///
+/// - `GET /2018-06-01/runtime/invocation/next`, which waits until an invocation is queued
+///   with [`invoke()`](Self::invoke), like the real API
+/// - `POST /2018-06-01/runtime/invocation/{request_id}/response` and `.../error`, recording
+///   the [`InvocationResult`]
+/// - `POST /2018-06-01/runtime/init/error`, recording the error
+/// - the Extensions API endpoints, handled by [`extensions()`](Self::extensions), so that
+///   `spawn_graceful_shutdown_handler()` can register its extension
+///
+/// Only buffered responses are supported, not streaming ones. Clones share the same server,
+/// which stops when the last of them is dropped.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::testing::MockRuntimeApi;
+/// use serde_json::json;
+///
+/// # async fn example() -> std::io::Result<()> {
+/// let api = MockRuntimeApi::start().await?;
+/// api.set_env();
+/// // ...spawn `lambda_runtime::run()` with the function under test, then:
+/// let request_id = api.invoke(json!({ "name": "test" }));
+/// let result = api.wait_for_result(&request_id).await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockRuntimeApi {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+    extensions: MockExtensionsApi,
+}
+
+impl fmt::Debug for MockRuntimeApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockRuntimeApi")
+            .field("address", &self.address)
+            .field("state", &self.shared.state.lock().unwrap())
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+impl MockRuntimeApi {
+    /// Start the server on a free port on the loopback interface.
+    pub async fn start() -> io::Result<Self> {
+        let shared = Arc::new(Shared::default());
+        let handler = shared.clone();
+        let (address, server) = server::spawn(move |request| {
+            let shared = handler.clone();
+            async move { shared.handle(request).await }
+        })
+        .await?;
+        let extensions =
+            MockExtensionsApi::served_by(address, shared.extensions.clone(), server.clone());
+        Ok(Self {
+            address,
+            shared,
+            extensions,
+        })
+    }
+
+    /// Give the function `timeout` for each invocation, reflected in its deadline, instead
+    /// of 3s.
+    pub fn with_invocation_timeout(self, timeout: Duration) -> Self {
+        *self.shared.timeout.lock().unwrap() = Some(timeout);
+        self
+    }
+
+    /// The `host:port` to set `AWS_LAMBDA_RUNTIME_API` to.
+    pub fn endpoint(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Set `AWS_LAMBDA_RUNTIME_API` to the server, and the function name, version and memory
+    /// size that `lambda_runtime` reads at startup, unless they are already set.
+    pub fn set_env(&self) {
+        std::env::set_var("AWS_LAMBDA_RUNTIME_API", self.endpoint());
+        for (name, value) in [
+            ("AWS_LAMBDA_FUNCTION_NAME", "mock"),
+            ("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST"),
+            ("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "128"),
+        ] {
+            if std::env::var_os(name).is_none() {
+                std::env::set_var(name, value);
+            }
+        }
+    }
+
+    /// The Extensions API served next to the Runtime API.
+    pub fn extensions(&self) -> &MockExtensionsApi {
+        &self.extensions
+    }
+
+    /// Queue an invocation with `payload`, and return its request id.
+    ///
+    /// Extensions subscribed to `INVOKE` events receive one when the runtime picks the
+    /// invocation up.
+    pub fn invoke(&self, payload: Value) -> String {
+        let mut state = self.shared.state.lock().unwrap();
+        state.next_request += 1;
+        let request_id = format!("mock-request-{}", state.next_request);
+        let trace_id = format!("Root=1-00000000-{:024x};Sampled=0", state.next_request);
+        state.pending.push_back(Invocation {
+            request_id: request_id.clone(),
+            trace_id,
+            payload,
+        });
+        drop(state);
+        self.shared.changed.notify_waiters();
+        request_id
+    }
+
+    /// The request ids of the invocations the runtime picked up, but hasn't finished.
+    pub fn in_flight(&self) -> Vec<String> {
+        self.shared.state.lock().unwrap().in_flight.clone()
+    }
+
+    /// How the invocation `request_id` finished, if it has.
+    pub fn result(&self, request_id: &str) -> Option<InvocationResult> {
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .results
+            .get(request_id)
+            .cloned()
+    }
+
+    /// Wait until the invocation `request_id` has finished.
+    pub async fn wait_for_result(&self, request_id: &str) -> InvocationResult {
+        loop {
+            let changed = self.shared.changed.notified();
+            if let Some(result) = self.result(request_id) {
+                return result;
+            }
+            changed.await;
+        }
+    }
+
+    /// Wait until the runtime has picked up the invocation `request_id`.
+    pub async fn wait_for_pickup(&self, request_id: &str) {
+        loop {
+            let changed = self.shared.changed.notified();
+            {
+                let state = self.shared.state.lock().unwrap();
+                if !state
+                    .pending
+                    .iter()
+                    .any(|invocation| invocation.request_id == request_id)
+                {
+                    return;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// The bodies of the initialization errors reported so far.
+    pub fn init_errors(&self) -> Vec<String> {
+        self.shared.state.lock().unwrap().init_errors.clone()
+    }
+}
+
+impl Shared {
+    async fn handle(&self, request: Request) -> Response {
+        if request.path.starts_with("/2020-01-01/extension/") {
+            return self.extensions.handle(request).await;
+        }
+        if request.method == "POST" && request.path == "/2018-06-01/runtime/init/error" {
+            self.state
+                .lock()
+                .unwrap()
+                .init_errors
+                .push(request.body_text());
+            return accepted();
+        }
+        let Some(rest) = request.path.strip_prefix(INVOCATION_PATH) else {
+            return Response::new("404 Not Found");
+        };
+        match (request.method.as_str(), rest.split_once('/')) {
+            ("GET", None) if rest == "next" => self.next_invocation().await,
+            ("POST", Some((request_id, "response"))) => {
+                self.finish(request_id, InvocationResult::Response(request.body_text()))
+            }
+            ("POST", Some((request_id, "error"))) => self.finish(
+                request_id,
+                InvocationResult::Error {
+                    error_type: request
+                        .header("Lambda-Runtime-Function-Error-Type")
+                        .map(str::to_owned),
+                    body: request.body_text(),
+                },
+            ),
+            _ => Response::new("404 Not Found"),
+        }
+    }
+
+    async fn next_invocation(&self) -> Response {
+        let invocation = loop {
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(invocation) = state.pending.pop_front() {
+                    state.in_flight.push(invocation.request_id.clone());
+                    break invocation;
+                }
+            }
+            changed.await;
+        };
+        self.changed.notify_waiters();
+
+        let timeout = self
+            .timeout
+            .lock()
+            .unwrap()
+            .unwrap_or(DEFAULT_INVOCATION_TIMEOUT);
+        let deadline_ms = (SystemTime::now() + timeout)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.extensions
+            .send_invoke(&invocation.request_id, deadline_ms, &invocation.trace_id);
+
+        Response::json("200 OK", &invocation.payload)
+            .with_header("Lambda-Runtime-Aws-Request-Id", invocation.request_id)
+            .with_header("Lambda-Runtime-Deadline-Ms", deadline_ms.to_string())
+            .with_header(
+                "Lambda-Runtime-Invoked-Function-Arn",
+                extensions::FUNCTION_ARN,
+            )
+            .with_header("Lambda-Runtime-Trace-Id", invocation.trace_id)
+    }
+
+    fn finish(&self, request_id: &str, result: InvocationResult) -> Response {
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.in_flight.iter().position(|id| id == request_id) else {
+            return Response::json(
+                "400 Bad Request",
+                json!({
+                    "errorMessage": "unknown or finished request id",
+                    "errorType": "InvalidRequestID",
+                }),
+            );
+        };
+        state.in_flight.remove(index);
+        state.results.insert(request_id.to_owned(), result);
+        drop(state);
+        self.changed.notify_waiters();
+        accepted()
+    }
+}
+
+fn accepted() -> Response {
+    Response::json("202 Accepted", json!({ "status": "OK" }))
+}
@@ -0,0 +1,225 @@
+//! A function, its extension and the graceful shutdown wiring, started in one call.
+
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+
+use lambda_runtime::LambdaEvent;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::watch,
+};
+
+use super::{InvocationResult, MockRuntimeApi, ShutdownTestHarness, SimulatedShutdown};
+use crate::{ShutdownCoordinator, ShutdownReason, ShutdownReport};
+
+/// The name the scenario's extension registers with.
+pub const EXTENSION_NAME: &str = "lambda-graceful-shutdown";
+
+/// A function running against a [`MockRuntimeApi`], with an extension registered for
+/// `SHUTDOWN` events that shuts its coordinator down, the way a function with an external
+/// extension is wired on Lambda.
+///
+/// [`start()`](Self::start) sets everything up and returns once the extension has registered.
+/// The scenario can then be driven like Lambda would: invocations, a `SIGTERM` with
+/// [`sigterm()`](Self::sigterm), a `SHUTDOWN` event with [`spindown()`](Self::spindown), and
+/// time moving on with [`advance()`](Self::advance).
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use lambda_graceful_shutdown::{hook_fn, testing::ShutdownScenario, ShutdownCoordinator};
+/// use lambda_runtime::{Error, LambdaEvent};
+/// use serde_json::{json, Value};
+///
+/// async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+///     tokio::time::sleep(Duration::from_millis(200)).await;
+///     Ok(event.payload)
+/// }
+///
+/// #[tokio::test(start_paused = true)]
+/// async fn drains_the_invocation_on_spindown() {
+///     let coordinator = ShutdownCoordinator::new()
+///         .with_invocation_drain(Duration::from_millis(300))
+///         .with_hook(hook_fn("flush", |_ctx| async { Ok(()) }));
+///     let scenario = ShutdownScenario::start(coordinator, handler).await;
+///
+///     scenario.invoke_in_background(json!({})).await;
+///     scenario.spindown("spindown").await.assert_drained().assert_clean();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ShutdownScenario {
+    harness: ShutdownTestHarness,
+    reports: watch::Receiver<Option<ShutdownReport>>,
+}
+
+impl ShutdownScenario {
+    /// Start a [`MockRuntimeApi`], register the extension with it, and run `handler` as the
+    /// function, with its invocations tracked by `coordinator`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server can't be started, or the extension can't register.
+    pub async fn start<A, R, F, Fut>(coordinator: ShutdownCoordinator, handler: F) -> Self
+    where
+        F: Fn(LambdaEvent<A>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, lambda_runtime::Error>> + Send + 'static,
+        A: DeserializeOwned + Send + 'static,
+        R: Serialize + Send + 'static,
+    {
+        let harness = ShutdownTestHarness::start()
+            .await
+            .with_coordinator(coordinator);
+        let address = harness
+            .api()
+            .extensions()
+            .endpoint()
+            .parse()
+            .expect("the mock API listens on a socket address");
+        let extension_id = register(address)
+            .await
+            .expect("failed to register the extension");
+
+        let (report_tx, reports) = watch::channel(None);
+        let coordinator = harness.coordinator().clone();
+        tokio::spawn(async move {
+            match next_shutdown(address, &extension_id).await {
+                Ok(reason) => {
+                    report_tx.send_replace(Some(coordinator.shutdown(reason).await));
+                }
+                Err(error) => tracing::debug!(%error, "the scenario's extension stopped"),
+            }
+        });
+
+        harness.start_function(handler);
+        Self { harness, reports }
+    }
+
+    /// The harness underneath, with the API and the coordinator.
+    pub fn harness(&self) -> &ShutdownTestHarness {
+        &self.harness
+    }
+
+    /// The emulated Runtime API, and the Extensions API next to it.
+    pub fn api(&self) -> &MockRuntimeApi {
+        self.harness.api()
+    }
+
+    /// The coordinator the shutdown runs on.
+    pub fn coordinator(&self) -> &ShutdownCoordinator {
+        self.harness.coordinator()
+    }
+
+    /// Invoke the function with `payload`, and wait for it to respond.
+    pub async fn invoke(&self, payload: Value) -> InvocationResult {
+        self.harness.invoke(payload).await
+    }
+
+    /// Invoke the function with `payload`, and return its request id once the handler has
+    /// started. See [`ShutdownTestHarness::invoke_in_background()`].
+    pub async fn invoke_in_background(&self, payload: Value) -> String {
+        self.harness.invoke_in_background(payload).await
+    }
+
+    /// Shut down like the runtime's `SIGTERM` handler would. See
+    /// [`ShutdownTestHarness::sigterm()`].
+    pub async fn sigterm(&self) -> SimulatedShutdown {
+        self.harness.sigterm().await
+    }
+
+    /// Send the extension a `SHUTDOWN` event with `reason`, such as `spindown`, `timeout` or
+    /// `failure`, and wait for the shutdown it starts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the extension stopped before getting the event, e.g. after an earlier
+    /// `spindown()`.
+    pub async fn spindown(&self, reason: &str) -> SimulatedShutdown {
+        self.api().extensions().send_shutdown(reason);
+        let mut reports = self.reports.clone();
+        let report = reports
+            .wait_for(Option::is_some)
+            .await
+            .expect("the scenario's extension stopped")
+            .clone()
+            .expect("waited for a report");
+        SimulatedShutdown::new(self.coordinator().budget(), report)
+    }
+
+    /// Move tokio's paused clock forward by `duration`, running whatever was waiting for it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the clock isn't paused, as it is in [`ShutdownTestHarness::run()`] and under
+    /// `#[tokio::test(start_paused = true)]`.
+    pub async fn advance(&self, duration: Duration) {
+        tokio::time::advance(duration).await;
+    }
+}
+
+/// Register an extension for `SHUTDOWN` events with the Extensions API at `address`, and
+/// return its identifier.
+async fn register(address: SocketAddr) -> io::Result<String> {
+    let (head, _body) = request(
+        address,
+        "POST",
+        "/2020-01-01/extension/register",
+        &format!("Lambda-Extension-Name: {EXTENSION_NAME}\r\n"),
+        r#"{"events":["SHUTDOWN"]}"#,
+    )
+    .await?;
+    head.lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("lambda-extension-identifier")
+                .then(|| value.trim().to_owned())
+        })
+        .ok_or_else(|| io::Error::other(format!("registration failed: {head}")))
+}
+
+/// Wait for the next `SHUTDOWN` event, and return its reason.
+async fn next_shutdown(address: SocketAddr, extension_id: &str) -> io::Result<ShutdownReason> {
+    loop {
+        let (_head, body) = request(
+            address,
+            "GET",
+            "/2020-01-01/extension/event/next",
+            &format!("Lambda-Extension-Identifier: {extension_id}\r\n"),
+            "",
+        )
+        .await?;
+        let event: Value = serde_json::from_str(&body)?;
+        if event["eventType"] == "SHUTDOWN" {
+            let reason = event["shutdownReason"].as_str().unwrap_or_default();
+            return Ok(
+                ShutdownReason::from_extension_reason(reason).unwrap_or(ShutdownReason::Spindown)
+            );
+        }
+    }
+}
+
+/// Make one request to the API at `address`, and return the head and body of the response.
+async fn request(
+    address: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &str,
+    body: &str,
+) -> io::Result<(String, String)> {
+    let mut stream = TcpStream::connect(address).await?;
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nhost: {address}\r\n{headers}content-length: {}\r\n\
+         connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // The server answers one request per connection, and then closes it
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    match response.split_once("\r\n\r\n") {
+        Some((head, body)) => Ok((head.to_owned(), body.to_owned())),
+        None => Err(io::Error::other("incomplete response")),
+    }
+}
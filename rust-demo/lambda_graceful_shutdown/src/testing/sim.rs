@@ -0,0 +1,214 @@
+//! Simulated shutdowns, without a signal or a Lambda API.
+
+use std::time::Duration;
+
+use crate::{HookOutcome, ShutdownCoordinator, ShutdownHook, ShutdownReason, ShutdownReport};
+
+/// A shutdown, set up step by step and then triggered, to check that a set of hooks fits in
+/// the shutdown window.
+///
+/// The simulation runs the hooks of a [`ShutdownCoordinator`] like a `SIGTERM` would, with
+/// the given number of invocations in flight, and returns a [`SimulatedShutdown`] to assert
+/// on. Under `#[tokio::test(start_paused = true)]`, it runs on tokio's paused clock: hooks
+/// that sleep or time out don't slow the test down, and the timings are the same on every
+/// run.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use lambda_graceful_shutdown::{hook_fn, testing::ShutdownSim};
+///
+/// # async fn example() {
+/// let shutdown = ShutdownSim::new()
+///     .hook(hook_fn("flush", |_ctx| async {
+///         tokio::time::sleep(Duration::from_millis(100)).await;
+///         Ok(())
+///     }))
+///     .budget(Duration::from_millis(500))
+///     .in_flight(1)
+///     .trigger()
+///     .await;
+/// shutdown.assert_clean().assert_within(Duration::from_millis(500));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ShutdownSim {
+    coordinator: ShutdownCoordinator,
+    reason: ShutdownReason,
+    in_flight: usize,
+    invocations_finish_after: Option<Duration>,
+}
+
+impl Default for ShutdownSim {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownSim {
+    /// Simulate a `SIGTERM` for a coordinator with no hooks and the
+    /// [`DEFAULT_BUDGET`](crate::DEFAULT_BUDGET).
+    pub fn new() -> Self {
+        Self::for_coordinator(ShutdownCoordinator::new())
+    }
+
+    /// Simulate a `SIGTERM` for `coordinator`, with the hooks and settings it already has.
+    pub fn for_coordinator(coordinator: ShutdownCoordinator) -> Self {
+        Self {
+            coordinator,
+            reason: ShutdownReason::Sigterm,
+            in_flight: 0,
+            invocations_finish_after: None,
+        }
+    }
+
+    /// Register `hook` with the coordinator.
+    pub fn hook(self, hook: impl ShutdownHook + 'static) -> Self {
+        self.coordinator.register(hook);
+        self
+    }
+
+    /// Give the hooks `budget` in total, instead of the
+    /// [`DEFAULT_BUDGET`](crate::DEFAULT_BUDGET) of 450ms.
+    pub fn budget(mut self, budget: Duration) -> Self {
+        self.coordinator = self.coordinator.with_budget(budget);
+        self
+    }
+
+    /// Trigger the shutdown for `reason` instead of `SIGTERM`.
+    pub fn reason(mut self, reason: ShutdownReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Have `count` invocations in flight when the shutdown starts.
+    ///
+    /// They are tracked with [`track_invocation()`](ShutdownCoordinator::track_invocation),
+    /// and keep running until the shutdown is over, unless
+    /// [`invocations_finish_after()`](Self::invocations_finish_after) is set.
+    pub fn in_flight(mut self, count: usize) -> Self {
+        self.in_flight = count;
+        self
+    }
+
+    /// Wait up to `window` for the invocations in flight before running the hooks. See
+    /// [`with_invocation_drain()`](ShutdownCoordinator::with_invocation_drain).
+    pub fn drain_window(mut self, window: Duration) -> Self {
+        self.coordinator = self.coordinator.with_invocation_drain(window);
+        self
+    }
+
+    /// Have the invocations in flight finish `delay` after the shutdown starts.
+    pub fn invocations_finish_after(mut self, delay: Duration) -> Self {
+        self.invocations_finish_after = Some(delay);
+        self
+    }
+
+    /// Run the shutdown.
+    pub async fn trigger(self) -> SimulatedShutdown {
+        let mut guards: Vec<_> = (1..=self.in_flight)
+            .map(|n| {
+                self.coordinator
+                    .track_invocation(format!("simulated-request-{n}"), None)
+            })
+            .collect();
+        if let Some(delay) = self.invocations_finish_after {
+            let finishing = std::mem::take(&mut guards);
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                drop(finishing);
+            });
+        }
+
+        let report = self.coordinator.shutdown(self.reason).await;
+        drop(guards);
+        SimulatedShutdown::new(self.coordinator.budget(), report)
+    }
+}
+
+/// The outcome of a [`ShutdownSim`] or a
+/// [`ShutdownTestHarness`](super::ShutdownTestHarness), with assertions on its report.
+///
+/// The assertions panic with the report's summary, so a failing test shows what went wrong.
+#[derive(Debug, Clone)]
+pub struct SimulatedShutdown {
+    budget: Duration,
+    report: ShutdownReport,
+}
+
+impl SimulatedShutdown {
+    pub(super) fn new(budget: Duration, report: ShutdownReport) -> Self {
+        Self { budget, report }
+    }
+
+    /// The report of the shutdown.
+    pub fn report(&self) -> &ShutdownReport {
+        &self.report
+    }
+
+    /// The report of the shutdown.
+    pub fn into_report(self) -> ShutdownReport {
+        self.report
+    }
+
+    /// Assert that every hook completed.
+    #[track_caller]
+    pub fn assert_clean(&self) -> &Self {
+        assert!(
+            self.report.is_clean(),
+            "not every hook completed: {}",
+            self.report
+        );
+        self
+    }
+
+    /// Assert that the hooks finished within the budget.
+    #[track_caller]
+    pub fn assert_within_budget(&self) -> &Self {
+        self.assert_within(self.budget)
+    }
+
+    /// Assert that the whole shutdown, including the invocation drain, took no more than
+    /// `window`, such as Lambda's 500ms with only internal extensions.
+    #[track_caller]
+    pub fn assert_within(&self, window: Duration) -> &Self {
+        assert!(
+            self.report.elapsed <= window,
+            "the shutdown took {:?}, more than {window:?}: {}",
+            self.report.elapsed,
+            self.report
+        );
+        self
+    }
+
+    /// Assert that the hook `name` ran and completed.
+    #[track_caller]
+    pub fn assert_completed(&self, name: &str) -> &Self {
+        match self.report.hooks.iter().find(|hook| hook.name == name) {
+            Some(hook) => assert!(
+                hook.outcome == HookOutcome::Completed,
+                "hook {name} {}: {}",
+                hook.outcome,
+                self.report
+            ),
+            None => panic!("no hook named {name} ran: {}", self.report),
+        }
+        self
+    }
+
+    /// Assert that every invocation in flight finished before the hooks ran.
+    #[track_caller]
+    pub fn assert_drained(&self) -> &Self {
+        match self.report.invocations {
+            Some(drain) => assert!(
+                drain.drained(),
+                "{} of {} invocations were still running after {:?}",
+                drain.interrupted,
+                drain.in_flight,
+                drain.elapsed
+            ),
+            None => panic!("the coordinator has no invocation drain set up"),
+        }
+        self
+    }
+}
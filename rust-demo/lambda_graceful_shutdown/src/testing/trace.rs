@@ -0,0 +1,122 @@
+//! The hooks of a shutdown, as they start and finish.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::HookOutcome;
+
+/// When each hook of a coordinator started and finished, for checking properties of a
+/// shutdown, such as its hooks running one at a time or all of them fitting in the budget.
+///
+/// Hand it to the coordinator with
+/// [`with_hook_trace()`](crate::ShutdownCoordinator::with_hook_trace). The times are measured
+/// on the coordinator's [clock](crate::ShutdownCoordinator::with_clock), from the start of the
+/// shutdown.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{hook_fn, testing::HookTrace, ShutdownCoordinator, ShutdownReason};
+///
+/// # async fn example() {
+/// let trace = HookTrace::new();
+/// let shutdown = ShutdownCoordinator::new()
+///     .with_hook_trace(&trace)
+///     .with_hook(hook_fn("flush", |_ctx| async { Ok(()) }));
+/// shutdown.shutdown(ShutdownReason::Sigterm).await;
+///
+/// assert_eq!(trace.attempted(), ["flush"]);
+/// assert!(trace.finished_by() <= shutdown.budget());
+/// # }
+/// ```
+///
+/// Cloning is cheap, and all clones share the same events.
+#[derive(Debug, Clone, Default)]
+pub struct HookTrace {
+    pub(crate) events: Arc<Mutex<Vec<TraceEvent>>>,
+}
+
+/// A hook starting or finishing, recorded in a [`HookTrace`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// The hook's [`name()`](crate::ShutdownHook::name).
+    pub hook: String,
+    /// How long after the start of the shutdown it happened.
+    pub at: Duration,
+    /// What happened.
+    pub kind: TraceEventKind,
+}
+
+/// What happened to a hook in a [`TraceEvent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEventKind {
+    /// The hook was called. Hooks [skipped](HookOutcome::Skipped) because the budget had run
+    /// out are never called, so they only finish.
+    Started,
+    /// The hook returned, was abandoned or was skipped.
+    Finished(HookOutcome),
+}
+
+impl HookTrace {
+    /// Start with no events.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The events so far, oldest first.
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The hooks that were called, in the order they started.
+    pub fn attempted(&self) -> Vec<String> {
+        self.events()
+            .into_iter()
+            .filter(|event| event.kind == TraceEventKind::Started)
+            .map(|event| event.hook)
+            .collect()
+    }
+
+    /// The outcome of each hook, in the order they finished.
+    pub fn outcomes(&self) -> Vec<(String, HookOutcome)> {
+        self.events()
+            .into_iter()
+            .filter_map(|event| match event.kind {
+                TraceEventKind::Finished(outcome) => Some((event.hook, outcome)),
+                TraceEventKind::Started => None,
+            })
+            .collect()
+    }
+
+    /// When the last hook finished, from the start of the shutdown, or zero without hooks.
+    pub fn finished_by(&self) -> Duration {
+        self.events()
+            .iter()
+            .map(|event| event.at)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Whether each hook finished before the next one started.
+    pub fn is_sequential(&self) -> bool {
+        let mut running: Option<String> = None;
+        self.events().into_iter().all(|event| match event.kind {
+            TraceEventKind::Started => running.replace(event.hook).is_none(),
+            TraceEventKind::Finished(HookOutcome::Skipped) => running.is_none(),
+            TraceEventKind::Finished(_) => running.take() == Some(event.hook),
+        })
+    }
+
+    /// Forget the events so far, e.g. between two shutdowns of the same coordinator.
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    pub(crate) fn record(&self, hook: &str, at: Duration, kind: TraceEventKind) {
+        self.events.lock().unwrap().push(TraceEvent {
+            hook: hook.to_owned(),
+            at,
+            kind,
+        });
+    }
+}
@@ -0,0 +1,250 @@
+//! Draining [`tonic`] gRPC channels and servers.
+//!
+//! A [`Channel`] multiplexes every RPC over one HTTP/2 connection. Cutting that connection at
+//! spindown resets the streams that are still open, so the server sees calls fail halfway.
+//! [`TrackedChannel`] lends the channel out per call, and its
+//! [`shutdown_hook()`](TrackedChannel::shutdown_hook) stops new calls, waits for the
+//! outstanding ones, and then drops the channel, which lets the connection end with a
+//! `GOAWAY` rather than mid-stream.
+//!
+//! On the serving side, behind the Lambda Web Adapter or VPC Lattice, [`GracefulServer`] hands
+//! `Server::serve_with_shutdown()` a signal that fires when its
+//! [`shutdown_hook()`](GracefulServer::shutdown_hook) runs. The server then stops accepting
+//! connections and lets the open streams finish, and the hook waits for it before the
+//! coordinator moves on to the hooks that flush what those streams produced:
+//!
+//! ```no_run
+//! use lambda_graceful_shutdown::{hook_fn, tonic::GracefulServer, ShutdownCoordinator};
+//! use tonic::{service::Routes, transport::Server};
+//!
+//! # async fn example(routes: Routes) -> Result<(), tonic::transport::Error> {
+//! let server = GracefulServer::new();
+//! let shutdown = ShutdownCoordinator::new()
+//!     .with_hook(hook_fn("metrics", |_ctx| async { Ok(()) }))
+//!     // Registered last, so it runs first
+//!     .with_hook(server.shutdown_hook());
+//!
+//! let addr = "127.0.0.1:50051".parse().unwrap();
+//! server
+//!     .serve(|signal| {
+//!         Server::builder()
+//!             .add_routes(routes)
+//!             .serve_with_shutdown(addr, signal)
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+use tonic::transport::Channel;
+
+use crate::{
+    clock,
+    http::{HttpClientHook, InFlight, TrackedClient},
+    BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook,
+};
+
+/// A tonic [`Channel`] that can be drained at shutdown.
+///
+/// Cloning is cheap, and all clones share the same channel. Create your generated clients
+/// from [`acquire()`](Self::acquire) per call, rather than keeping one around.
+#[derive(Clone)]
+pub struct TrackedChannel {
+    channel: TrackedClient<Channel>,
+}
+
+impl fmt::Debug for TrackedChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrackedChannel")
+            .field("closed", &self.channel.is_closed())
+            .field("in_flight", &self.channel.in_flight())
+            .finish()
+    }
+}
+
+impl TrackedChannel {
+    /// Wrap `channel`.
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            channel: TrackedClient::new(channel),
+        }
+    }
+
+    /// Borrow the channel for one call, or `None` once the shutdown hook has run.
+    ///
+    /// The call counts as outstanding until the returned [`InFlight`] is dropped; for
+    /// streaming calls, hold on to it until the stream has ended.
+    pub fn acquire(&self) -> Option<InFlight<Channel>> {
+        self.channel.acquire()
+    }
+
+    /// The number of calls currently outstanding.
+    pub fn in_flight(&self) -> usize {
+        self.channel.in_flight()
+    }
+
+    /// A hook that drops the channel once the outstanding calls are done.
+    pub fn shutdown_hook(&self) -> ChannelDrainHook {
+        ChannelDrainHook {
+            hook: self.channel.shutdown_hook(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that drains the channel in a [`TrackedChannel`].
+#[derive(Debug)]
+pub struct ChannelDrainHook {
+    hook: HttpClientHook<Channel>,
+}
+
+impl ChannelDrainHook {
+    /// Stop waiting for outstanding calls after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.hook = self.hook.with_timeout(timeout);
+        self
+    }
+}
+
+impl ShutdownHook for ChannelDrainHook {
+    fn name(&self) -> &str {
+        "tonic"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        self.hook.shutdown(ctx)
+    }
+}
+
+/// Connects a tonic server's `serve_with_shutdown()` to the coordinator.
+///
+/// Cloning is cheap, and all clones share the same server.
+#[derive(Clone)]
+pub struct GracefulServer {
+    stop: Arc<watch::Sender<bool>>,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Serving,
+    Stopped,
+}
+
+impl fmt::Debug for GracefulServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GracefulServer")
+            .field("phase", &*self.phase.borrow())
+            .field("stopping", &*self.stop.borrow())
+            .finish()
+    }
+}
+
+impl Default for GracefulServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GracefulServer {
+    /// A server that isn't serving yet.
+    pub fn new() -> Self {
+        Self {
+            stop: Arc::new(watch::channel(false).0),
+            phase: Arc::new(watch::channel(Phase::Idle).0),
+        }
+    }
+
+    /// Run the server `serve` returns, passing it the signal for its `serve_with_shutdown()`,
+    /// and return what it returns once it has stopped.
+    ///
+    /// The signal fires when the [shutdown hook](Self::shutdown_hook) runs, or right away if
+    /// it already has.
+    pub async fn serve<F, Fut, E>(&self, serve: F) -> Result<(), E>
+    where
+        F: FnOnce(BoxFuture<'static, ()>) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+    {
+        self.phase.send_replace(Phase::Serving);
+        // Also marks the server stopped if this future is dropped before it finishes
+        let _stopped = StoppedOnDrop(&self.phase);
+        serve(self.signal()).await
+    }
+
+    /// Returns true while [`serve()`](Self::serve) is running.
+    pub fn is_serving(&self) -> bool {
+        *self.phase.borrow() == Phase::Serving
+    }
+
+    /// A hook that stops the server and waits for its open streams to finish.
+    pub fn shutdown_hook(&self) -> ServerDrainHook {
+        ServerDrainHook {
+            server: self.clone(),
+            timeout: None,
+        }
+    }
+
+    fn signal(&self) -> BoxFuture<'static, ()> {
+        let mut stop = self.stop.subscribe();
+        Box::pin(async move {
+            // The sender lives as long as the server, so this only fails once nothing can
+            // stop it anymore
+            let _ = stop.wait_for(|&stop| stop).await;
+        })
+    }
+}
+
+struct StoppedOnDrop<'a>(&'a watch::Sender<Phase>);
+
+impl Drop for StoppedOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.send_replace(Phase::Stopped);
+    }
+}
+
+/// A [`ShutdownHook`] that stops a [`GracefulServer`].
+///
+/// The server gets what is left of the budget, or the timeout if one is set, to finish its
+/// open streams; if it hasn't by then, the hook gives up on it and the next hooks run.
+#[derive(Debug)]
+pub struct ServerDrainHook {
+    server: GracefulServer,
+    timeout: Option<Duration>,
+}
+
+impl ServerDrainHook {
+    /// Stop waiting for open streams after `timeout`, even if there is budget left.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl ShutdownHook for ServerDrainHook {
+    fn name(&self) -> &str {
+        "tonic-server"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.server.stop.send_replace(true);
+            let mut phase = self.server.phase.subscribe();
+            if *phase.borrow() == Phase::Idle {
+                return Ok(());
+            }
+            let clock = ctx.clock();
+            let deadline = clock.now() + ctx.remaining_capped(self.timeout);
+            let stopped = clock::timeout_at(clock, deadline, async {
+                let _ = phase.wait_for(|&phase| phase == Phase::Stopped).await;
+            })
+            .await;
+            match stopped {
+                Some(()) => Ok(()),
+                None => Err(DrainTimeout::new("the gRPC server still had streams open").into()),
+            }
+        })
+    }
+}
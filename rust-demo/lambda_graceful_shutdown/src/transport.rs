@@ -0,0 +1,161 @@
+//! The HTTP stack the integrations that send requests go through.
+//!
+//! The `webhook`, `http-batch`, `loki` and `opensearch` integrations only need to `POST` a body
+//! and read the response, so they take any [`HttpClient`] rather than a client of their own.
+//! A function that already talks HTTP can hand them the client it has, instead of building a
+//! second connection pool and TLS stack into its binary:
+//!
+//! - `reqwest::Client`, with the `reqwest` feature
+//! - hyper-util's legacy `Client`, over any connector such as `hyper-rustls`, with the `hyper`
+//!   feature
+//!
+//! The integrations only turn on the `transport` feature, so neither is built unless it's asked
+//! for. Anything else can implement [`HttpClient`] itself. Set up authentication, or headers
+//! every request needs, on the client.
+//!
+//! The Runtime and Extensions API clients of `lambda_runtime` and `lambda-extension` don't go
+//! through [`HttpClient`]; both crates are built on hyper 1.x already.
+//!
+//! ```no_run
+//! # #[cfg(feature = "hyper")]
+//! # fn example() {
+//! use http_body_util::Full;
+//! use hyper_util::{
+//!     client::legacy::{connect::HttpConnector, Client},
+//!     rt::TokioExecutor,
+//! };
+//! use lambda_graceful_shutdown::webhook::WebhookHook;
+//!
+//! let client: Client<_, Full<bytes::Bytes>> =
+//!     Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+//! let webhook = WebhookHook::new(client, "http://127.0.0.1:9000/shutdowns");
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use tokio::time::Instant;
+
+use crate::{
+    clock::{self, Clock},
+    BoxFuture, Error,
+};
+
+/// A `POST` one of the integrations sends.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct HttpRequest {
+    /// Where it goes, an `http://` or `https://` URL.
+    pub url: String,
+    /// The `Content-Type` of the body.
+    pub content_type: String,
+    /// The body.
+    pub body: Vec<u8>,
+}
+
+/// The response to an [`HttpRequest`].
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    /// The status code.
+    pub status: u16,
+    /// The body, read to the end.
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Returns true for a `2xx` status.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Returns true if sending the same request again won't help: a `4xx` status, other than
+    /// `429 Too Many Requests`.
+    pub fn is_rejected(&self) -> bool {
+        (400..500).contains(&self.status) && self.status != 429
+    }
+}
+
+/// Sends the requests of the integrations.
+///
+/// Requests are given a timeout by the integrations, which drop the future returned by
+/// [`send()`](Self::send) when it runs out, so implementations don't need one of their own.
+pub trait HttpClient: Send + Sync + 'static {
+    /// Send `request`, and read the response body. Responses with an error status are not an
+    /// error here.
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>>;
+}
+
+impl<C: HttpClient + ?Sized> HttpClient for Arc<C> {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        (**self).send(request)
+    }
+}
+
+impl<C: HttpClient + ?Sized> HttpClient for Box<C> {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        (**self).send(request)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpClient for reqwest::Client {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        Box::pin(async move {
+            let response = self
+                .post(request.url)
+                .header(reqwest::header::CONTENT_TYPE, request.content_type)
+                .body(request.body)
+                .send()
+                .await?;
+            let status = response.status().as_u16();
+            let body = response.bytes().await?.to_vec();
+            Ok(HttpResponse { status, body })
+        })
+    }
+}
+
+#[cfg(feature = "hyper")]
+impl<C> HttpClient for hyper_util::client::legacy::Client<C, http_body_util::Full<bytes::Bytes>>
+where
+    C: hyper_util::client::legacy::connect::Connect + Clone + Send + Sync + 'static,
+{
+    fn send(&self, request: HttpRequest) -> BoxFuture<'_, Result<HttpResponse, Error>> {
+        use http_body_util::BodyExt;
+
+        Box::pin(async move {
+            let request = hyper::Request::post(request.url)
+                .header(hyper::header::CONTENT_TYPE, request.content_type)
+                .body(http_body_util::Full::new(bytes::Bytes::from(request.body)))?;
+            let response = self.request(request).await?;
+            let status = response.status().as_u16();
+            let body = response.into_body().collect().await?.to_bytes().to_vec();
+            Ok(HttpResponse { status, body })
+        })
+    }
+}
+
+/// A POST of `body` to `url`, which gives up at `deadline`, on the clock given with it.
+pub(crate) async fn post(
+    client: &dyn HttpClient,
+    url: &str,
+    content_type: &str,
+    body: impl Into<Vec<u8>>,
+    deadline: Option<(&dyn Clock, Instant)>,
+) -> Result<HttpResponse, Error> {
+    let request = HttpRequest {
+        url: url.to_owned(),
+        content_type: content_type.to_owned(),
+        body: body.into(),
+    };
+    match deadline {
+        Some((clock, deadline)) => {
+            let timeout = deadline.saturating_duration_since(clock.now());
+            clock::timeout_at(clock, deadline, client.send(request))
+                .await
+                .unwrap_or_else(|| {
+                    Err(format!("the request to {url} timed out after {timeout:?}").into())
+                })
+        }
+        None => client.send(request).await,
+    }
+}
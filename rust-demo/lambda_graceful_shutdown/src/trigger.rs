@@ -0,0 +1,124 @@
+//! Shutdowns started by the host, for runtimes that don't get Unix signals.
+//!
+//! A custom runtime compiled to `wasm32-wasi` can't install a `SIGTERM` handler: the host
+//! running the module learns about the shutdown, and has to pass it on, usually by calling a
+//! function the module exports. [`ShutdownTrigger`] is what that function fires. It is cheap
+//! to clone, and can live in a static, since an exported function has nowhere else to find
+//! it. [`ShutdownCoordinator::run_until()`](crate::ShutdownCoordinator::run_until) then runs
+//! the runtime until it fires, the way
+//! [`run_until_signal()`](crate::ShutdownCoordinator::run_until_signal) does with signals:
+//!
+//! ```no_run
+//! use std::sync::LazyLock;
+//!
+//! use lambda_graceful_shutdown::{
+//!     trigger::{RunOutcome, ShutdownTrigger},
+//!     ShutdownCoordinator, ShutdownReason,
+//! };
+//!
+//! static TRIGGER: LazyLock<ShutdownTrigger> = LazyLock::new(ShutdownTrigger::new);
+//!
+//! /// Called by the host when the environment shuts down.
+//! #[no_mangle]
+//! pub extern "C" fn on_shutdown() {
+//!     TRIGGER.fire(ShutdownReason::Sigterm);
+//! }
+//!
+//! # async fn example(runtime: impl std::future::Future<Output = ()>) -> i32 {
+//! let shutdown = ShutdownCoordinator::new();
+//! match shutdown.run_until(TRIGGER.fired(), runtime).await {
+//!     RunOutcome::Finished(()) => 0,
+//!     RunOutcome::ShutDown(report) => report.exit_code(),
+//! }
+//! # }
+//! ```
+//!
+//! A host that can't call into the module, only answer when asked, can be asked regularly
+//! with [`poll_host()`] instead.
+
+#[cfg(feature = "tokio-runtime")]
+use std::time::Duration;
+use std::{future::Future, sync::Arc};
+
+use tokio::sync::watch;
+
+use crate::{ShutdownReason, ShutdownReport};
+
+/// Starts a shutdown from outside of the async code, such as a callback from the host.
+///
+/// Cloning is cheap, and all clones fire together.
+#[derive(Debug, Clone)]
+pub struct ShutdownTrigger {
+    reason: Arc<watch::Sender<Option<ShutdownReason>>>,
+}
+
+impl Default for ShutdownTrigger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownTrigger {
+    /// A trigger that hasn't fired yet.
+    pub fn new() -> Self {
+        Self {
+            reason: Arc::new(watch::Sender::new(None)),
+        }
+    }
+
+    /// Start the shutdown for `reason`. Doesn't block, so it can be called from any thread or
+    /// callback. Only the first call counts.
+    pub fn fire(&self, reason: ShutdownReason) {
+        self.reason.send_if_modified(|current| {
+            let first = current.is_none();
+            current.get_or_insert(reason);
+            first
+        });
+    }
+
+    /// Whether the trigger has fired.
+    pub fn is_fired(&self) -> bool {
+        self.reason.borrow().is_some()
+    }
+
+    /// Wait for the trigger to fire, and return the reason it fired for.
+    pub fn fired(&self) -> impl Future<Output = ShutdownReason> + Send + 'static {
+        let mut reason = self.reason.subscribe();
+        async move {
+            let fired = reason
+                .wait_for(Option::is_some)
+                .await
+                .map(|reason| reason.expect("waited for a reason"));
+            match fired {
+                Ok(reason) => reason,
+                // Only once every trigger is gone, which then can't fire anymore
+                Err(_) => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Call `check` every `interval` until it returns a reason to shut down, e.g. a function the
+/// host gives the module to ask whether the environment is shutting down.
+#[cfg(feature = "tokio-runtime")]
+pub async fn poll_host<F>(mut check: F, interval: Duration) -> ShutdownReason
+where
+    F: FnMut() -> Option<ShutdownReason>,
+{
+    loop {
+        if let Some(reason) = check() {
+            return reason;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// How [`run_until()`](crate::ShutdownCoordinator::run_until) or
+/// [`run_until_signal()`](crate::ShutdownCoordinator::run_until_signal) ended.
+#[derive(Debug)]
+pub enum RunOutcome<T> {
+    /// The runtime returned before the shutdown started, usually with an error.
+    Finished(T),
+    /// The shutdown started, and ran.
+    ShutDown(ShutdownReport),
+}
@@ -0,0 +1,91 @@
+//! Reporting how the shutdown went to a webhook.
+//!
+//! Teams that collect lifecycle telemetry outside CloudWatch can have each environment report
+//! its own shutdown. [`WebhookHook`] POSTs the [`ShutdownReport`] of the hooks that ran before
+//! it, as JSON, to an endpoint of your choosing. It is best-effort: the request gets a short
+//! timeout, so a slow endpoint can't eat into the budget of the hooks that run after it.
+//!
+//! Requests go through an [`HttpClient`], so set up authentication on the client, for example
+//! with default headers.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use crate::{
+    transport::{self, HttpClient},
+    BoxFuture, Error, ShutdownContext, ShutdownHook, ShutdownReport,
+};
+
+/// How long the request may take, unless set with [`WebhookHook::with_timeout()`].
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A [`ShutdownHook`] that POSTs the report of the hooks that ran before it to a webhook.
+///
+/// The body is the report's [`to_json()`](ShutdownReport::to_json), with the `duration_ms`
+/// so far. Register this hook early, right after the log flush hook, so it runs late and
+/// reports on almost every other hook.
+pub struct WebhookHook {
+    client: Arc<dyn HttpClient>,
+    url: String,
+    timeout: Duration,
+}
+
+impl fmt::Debug for WebhookHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebhookHook")
+            .field("url", &self.url)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebhookHook {
+    /// Create a hook that POSTs to `url`, which should be an `https://` URL.
+    pub fn new(client: impl HttpClient, url: impl Into<String>) -> Self {
+        Self {
+            client: Arc::new(client),
+            url: url.into(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Give up on the request after `timeout`, instead of 200ms. The request never runs past
+    /// the end of the budget.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl ShutdownHook for WebhookHook {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let report = ShutdownReport {
+                reason: ctx.reason(),
+                request_id: ctx.request_id().map(str::to_owned),
+                elapsed: ctx.elapsed(),
+                invocations: ctx.invocation_drain(),
+                sandbox: ctx.sandbox_stats(),
+                hooks: ctx.hook_reports(),
+            };
+            let response = transport::post(
+                &*self.client,
+                &self.url,
+                "application/json",
+                report.to_json().to_string(),
+                Some((
+                    ctx.clock(),
+                    ctx.clock().now() + ctx.remaining_capped(Some(self.timeout)),
+                )),
+            )
+            .await?;
+            if !response.is_success() {
+                return Err(format!("the webhook responded with {}", response.status).into());
+            }
+            Ok(())
+        })
+    }
+}
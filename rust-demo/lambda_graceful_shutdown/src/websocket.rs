@@ -0,0 +1,286 @@
+//! Telling API Gateway WebSocket clients that the server is going away.
+//!
+//! A client connected through an API Gateway WebSocket API only finds out that the function
+//! serving it went away when its next message times out. [`GoingAwayHook`] posts a message to
+//! each connection at shutdown, through the API Gateway Management API, so clients can
+//! reconnect right away.
+
+use std::{fmt, future::Future, sync::Arc};
+
+use aws_sdk_apigatewaymanagement::{primitives::Blob, Client};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{hook::join_within, BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// The message posted to each connection, unless set with
+/// [`GoingAwayHook::with_message()`].
+const DEFAULT_MESSAGE: &str = r#"{"type":"going_away"}"#;
+
+/// How many messages are posted at once, by default.
+const DEFAULT_CONCURRENCY: usize = 16;
+
+/// Knows which WebSocket connections to notify, e.g. by looking them up in a DynamoDB table.
+///
+/// Return only the connections this execution environment is responsible for. Any function
+/// can post to any connection, so returning all of them would have every environment that
+/// spins down disconnect every client.
+///
+/// Implemented for closures returning a future, like [`hook_fn()`](crate::hook_fn).
+pub trait ConnectionStore: Send + Sync {
+    /// The ids of the connections to notify.
+    fn connection_ids(&self) -> BoxFuture<'_, Result<Vec<String>, Error>>;
+}
+
+impl<F, Fut> ConnectionStore for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Vec<String>, Error>> + Send + 'static,
+{
+    fn connection_ids(&self) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        Box::pin(self())
+    }
+}
+
+/// A [`ShutdownHook`] that posts a "going away" message to WebSocket connections.
+///
+/// Connections that have already closed are skipped. The hook fails if any other post fails,
+/// or if the budget runs out before every connection has been notified.
+pub struct GoingAwayHook<S> {
+    client: Client,
+    store: S,
+    message: Arc<[u8]>,
+    concurrency: usize,
+}
+
+impl<S> fmt::Debug for GoingAwayHook<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GoingAwayHook")
+            .field("concurrency", &self.concurrency)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: ConnectionStore> GoingAwayHook<S> {
+    /// Create a hook that looks up connections in `store`, and posts to them with `client`.
+    ///
+    /// The client's endpoint must be set to the API's connection URL, like
+    /// `https://{api-id}.execute-api.{region}.amazonaws.com/{stage}`.
+    pub fn new(client: Client, store: S) -> Self {
+        Self {
+            client,
+            store,
+            message: DEFAULT_MESSAGE.as_bytes().into(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    /// Post `message` instead of `{"type":"going_away"}`.
+    pub fn with_message(mut self, message: impl Into<Vec<u8>>) -> Self {
+        self.message = message.into().into();
+        self
+    }
+
+    /// Post up to `concurrency` messages at once.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+}
+
+impl<S: ConnectionStore> ShutdownHook for GoingAwayHook<S> {
+    fn name(&self) -> &str {
+        "websocket"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let connection_ids = self.store.connection_ids().await?;
+            let total = connection_ids.len();
+            let (mut notified, mut gone, mut failed) = (0, 0, 0);
+
+            let permits = Arc::new(Semaphore::new(self.concurrency));
+            let mut posts = JoinSet::new();
+            for connection_id in connection_ids {
+                let request = self
+                    .client
+                    .post_to_connection()
+                    .connection_id(connection_id)
+                    .data(Blob::new(self.message.to_vec()));
+                let permits = permits.clone();
+                posts.spawn(async move {
+                    let _permit = permits.acquire_owned().await;
+                    request.send().await
+                });
+            }
+            let joined = join_within(ctx, &mut posts, |result| match result {
+                Ok(_) => notified += 1,
+                Err(error)
+                    if error
+                        .as_service_error()
+                        .is_some_and(|error| error.is_gone_exception()) =>
+                {
+                    gone += 1
+                }
+                Err(error) => {
+                    failed += 1;
+                    tracing::debug!(error = %error, "failed to notify WebSocket client");
+                }
+            })
+            .await;
+            failed += joined.panicked;
+
+            ctx.note(format!(
+                "notified {notified} of {total} connections, {gone} had already closed"
+            ));
+            if let Some(timed_out) = joined.timed_out("connections left to notify") {
+                return Err(timed_out);
+            }
+            if failed > 0 {
+                return Err(format!("failed to notify {failed} connections").into());
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use aws_sdk_apigatewaymanagement::config::{
+        retry::RetryConfig, timeout::TimeoutConfig, BehaviorVersion, Credentials, IdentityCache,
+        Region, StalledStreamProtectionConfig,
+    };
+    use aws_smithy_runtime_api::{
+        client::{
+            http::{http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpConnector},
+            orchestrator::{HttpRequest, HttpResponse},
+        },
+        http::StatusCode,
+    };
+    use aws_smithy_types::body::SdkBody;
+
+    use super::*;
+    use crate::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    /// Answers `PostToConnection`: connections whose id starts with `gone` have closed, with
+    /// `fail` the post fails, and with `slow` it never finishes. Records each post.
+    #[derive(Debug, Default)]
+    struct FakeApi {
+        posts: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    #[derive(Debug)]
+    struct Connector(Arc<FakeApi>);
+
+    impl HttpConnector for Connector {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let connection_id = request.uri().rsplit('/').next().unwrap().to_owned();
+            let body = request.body().bytes().unwrap().to_vec();
+            self.0
+                .posts
+                .lock()
+                .unwrap()
+                .push((connection_id.clone(), body));
+            let (status, error) = if connection_id.starts_with("gone") {
+                (410, Some("GoneException"))
+            } else if connection_id.starts_with("fail") {
+                (500, Some("InternalServerError"))
+            } else if connection_id.starts_with("slow") {
+                return HttpConnectorFuture::new(std::future::pending());
+            } else {
+                (200, None)
+            };
+            let mut response =
+                HttpResponse::new(StatusCode::try_from(status).unwrap(), SdkBody::from("{}"));
+            if let Some(error) = error {
+                response.headers_mut().insert("x-amzn-errortype", error);
+            }
+            HttpConnectorFuture::ready(Ok(response))
+        }
+    }
+
+    fn client(fake: &Arc<FakeApi>) -> Client {
+        let connector = SharedHttpConnector::new(Connector(fake.clone()));
+        let config = aws_sdk_apigatewaymanagement::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url("https://abc123.execute-api.us-east-1.amazonaws.com/prod")
+            .credentials_provider(Credentials::new("key", "secret", None, None, "test"))
+            .http_client(http_client_fn(move |_, _| connector.clone()))
+            .retry_config(RetryConfig::disabled())
+            .timeout_config(TimeoutConfig::disabled())
+            .stalled_stream_protection(StalledStreamProtectionConfig::disabled())
+            .identity_cache(IdentityCache::no_cache())
+            .build();
+        Client::from_conf(config)
+    }
+
+    fn connections(ids: &'static [&'static str]) -> impl ConnectionStore {
+        move || async move { Ok(ids.iter().map(|id| (*id).to_owned()).collect()) }
+    }
+
+    #[tokio::test]
+    async fn every_connection_is_told_the_server_is_going_away() {
+        let fake = Arc::new(FakeApi::default());
+        let hook = GoingAwayHook::new(client(&fake), connections(&["a", "b", "gone-c"]))
+            .with_concurrency(2);
+        let report = ShutdownCoordinator::new()
+            .with_hook(hook)
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["notified 2 of 3 connections, 1 had already closed"]
+        );
+        let mut posts = fake.posts.lock().unwrap().clone();
+        posts.sort();
+        assert_eq!(
+            posts,
+            ["a", "b", "gone-c"].map(|id| (id.to_owned(), DEFAULT_MESSAGE.as_bytes().to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_post_fails_the_hook() {
+        let fake = Arc::new(FakeApi::default());
+        let hook =
+            GoingAwayHook::new(client(&fake), connections(&["a", "fail-b"])).with_message("bye");
+        let report = ShutdownCoordinator::new()
+            .with_hook(hook)
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::Failed("failed to notify 1 connections".to_owned())
+        );
+        assert!(fake
+            .posts
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(_, body)| body == b"bye"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_out_of_time_is_a_drain_timeout() {
+        let fake = Arc::new(FakeApi::default());
+        let hook = GoingAwayHook::new(client(&fake), connections(&["a", "slow-b", "slow-c"]));
+        let report = ShutdownCoordinator::new()
+            .with_budget(Duration::from_secs(1))
+            .with_hook(hook)
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut(
+                "ran out of time with 2 connections left to notify".to_owned()
+            )
+        );
+        assert_eq!(
+            report.hooks[0].notes,
+            ["notified 1 of 3 connections, 0 had already closed"]
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! Micro-batching events across invocations, with an early close at shutdown.
+//!
+//! A handler can save a lot of downstream calls by collecting events from several invocations
+//! and writing them together once enough have arrived, or once the oldest has waited long
+//! enough. The catch is that the open batch lives in memory, and is lost if the environment
+//! shuts down before the window closes.
+//!
+//! [`BatchWindow`] collects events and hands each batch to a flush function when it is full or
+//! old enough. Its [`early_close_hook()`](BatchWindow::early_close_hook) closes the open batch
+//! early when shutdown starts, flushes it, and resets the window. How often batches are closed
+//! early shows up in [`BatchWindow::stats()`], and in EMF metrics when set up with
+//! [`with_metrics()`](BatchWindow::with_metrics). A high rate means the window is too long for
+//! how long environments live.
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{emf::EmfMetrics, BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+type Flush<T> = Box<dyn Fn(Vec<T>) -> BoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+/// Why a batch was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CloseReason {
+    /// The batch reached its maximum size.
+    Full,
+    /// The oldest event in the batch had waited for the whole window.
+    Expired,
+    /// The batch was closed before it was full or expired, usually because of a shutdown.
+    Early,
+}
+
+/// How many batches a [`BatchWindow`] has closed, and why.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WindowStats {
+    /// Batches closed because they were full or expired.
+    pub closed: u64,
+    /// Batches closed early.
+    pub closed_early: u64,
+    /// Events flushed, across all batches.
+    pub flushed: u64,
+    /// Events in batches whose flush failed.
+    pub dropped: u64,
+}
+
+struct Batch<T> {
+    events: Vec<T>,
+    opened: Option<Instant>,
+}
+
+struct Inner<T> {
+    max_events: usize,
+    window: Duration,
+    flush: Flush<T>,
+    batch: Mutex<Batch<T>>,
+    closed: AtomicU64,
+    closed_early: AtomicU64,
+    flushed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Events collected across invocations, flushed in batches.
+///
+/// Cloning is cheap, and all clones share the same batch.
+pub struct BatchWindow<T> {
+    inner: Arc<Inner<T>>,
+    metrics: Option<EmfMetrics>,
+}
+
+impl<T> Clone for BatchWindow<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for BatchWindow<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchWindow")
+            .field("max_events", &self.inner.max_events)
+            .field("window", &self.inner.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Send + 'static> BatchWindow<T> {
+    /// Create a window that passes batches of up to `max_events` to `flush`, at most `window`
+    /// after the first event of the batch arrived.
+    ///
+    /// A batch that `flush` fails on is dropped, and counted in [`stats()`](Self::stats), so
+    /// retry inside `flush` when that matters.
+    pub fn new<F, Fut>(max_events: usize, window: Duration, flush: F) -> Self
+    where
+        F: Fn(Vec<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        Self {
+            inner: Arc::new(Inner {
+                max_events: max_events.max(1),
+                window,
+                flush: Box::new(move |events| Box::pin(flush(events))),
+                batch: Mutex::new(Batch {
+                    events: Vec::new(),
+                    opened: None,
+                }),
+                closed: AtomicU64::new(0),
+                closed_early: AtomicU64::new(0),
+                flushed: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+            }),
+            metrics: None,
+        }
+    }
+
+    /// Count closed batches in `metrics`, as `BatchWindowClosed` and `BatchWindowClosedEarly`,
+    /// and record the size of each as `BatchWindowSize`.
+    ///
+    /// Register the metrics' flush hook before the window's early-close hook, so hooks running
+    /// in reverse order flush the metrics last.
+    pub fn with_metrics(mut self, metrics: EmfMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Add `event` to the open batch, flushing the batch if this fills it up, or if it has
+    /// been open for longer than the window.
+    pub async fn push(&self, event: T) -> Result<(), Error> {
+        let mut batch = self.inner.batch.lock().await;
+        batch.opened.get_or_insert_with(Instant::now);
+        batch.events.push(event);
+        if batch.events.len() >= self.inner.max_events {
+            self.inner
+                .close(&mut batch, CloseReason::Full, self.metrics.as_ref())
+                .await
+        } else {
+            self.inner
+                .close_if_expired(&mut batch, self.metrics.as_ref())
+                .await
+        }
+    }
+
+    /// Flush the open batch if it has been open for longer than the window, e.g. at the start
+    /// of an invocation that may not push anything.
+    pub async fn flush_expired(&self) -> Result<(), Error> {
+        let mut batch = self.inner.batch.lock().await;
+        self.inner
+            .close_if_expired(&mut batch, self.metrics.as_ref())
+            .await
+    }
+
+    /// Close the open batch right away, flush it, and start a new one.
+    pub async fn close_early(&self) -> Result<(), Error> {
+        let mut batch = self.inner.batch.lock().await;
+        self.inner
+            .close(&mut batch, CloseReason::Early, self.metrics.as_ref())
+            .await
+    }
+
+    /// The number of events in the open batch.
+    pub async fn pending(&self) -> usize {
+        self.inner.batch.lock().await.events.len()
+    }
+
+    /// How many batches have been closed so far, and why.
+    pub fn stats(&self) -> WindowStats {
+        WindowStats {
+            closed: self.inner.closed.load(Ordering::Relaxed),
+            closed_early: self.inner.closed_early.load(Ordering::Relaxed),
+            flushed: self.inner.flushed.load(Ordering::Relaxed),
+            dropped: self.inner.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// A hook that closes the open batch early and flushes it when the environment shuts down.
+    pub fn early_close_hook(&self) -> EarlyCloseHook<T> {
+        EarlyCloseHook {
+            window: self.clone(),
+        }
+    }
+}
+
+impl<T> Inner<T> {
+    async fn close_if_expired(
+        &self,
+        batch: &mut Batch<T>,
+        metrics: Option<&EmfMetrics>,
+    ) -> Result<(), Error> {
+        match batch.opened {
+            Some(opened) if opened.elapsed() >= self.window => {
+                self.close(batch, CloseReason::Expired, metrics).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush the events of `batch` and reset it.
+    async fn close(
+        &self,
+        batch: &mut Batch<T>,
+        reason: CloseReason,
+        metrics: Option<&EmfMetrics>,
+    ) -> Result<(), Error> {
+        batch.opened = None;
+        if batch.events.is_empty() {
+            return Ok(());
+        }
+        let events = std::mem::take(&mut batch.events);
+        let count = events.len();
+        if let Err(error) = (self.flush)(events).await {
+            self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+            return Err(error);
+        }
+
+        self.flushed.fetch_add(count as u64, Ordering::Relaxed);
+        let counter = match reason {
+            CloseReason::Early => &self.closed_early,
+            CloseReason::Full | CloseReason::Expired => &self.closed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(?reason, events = count, "closed batch window");
+        if let Some(metrics) = metrics {
+            let name = match reason {
+                CloseReason::Early => "BatchWindowClosedEarly",
+                CloseReason::Full | CloseReason::Expired => "BatchWindowClosed",
+            };
+            metrics.count(name, 1);
+            metrics.count("BatchWindowSize", count as u64);
+        }
+        Ok(())
+    }
+}
+
+/// A [`ShutdownHook`] that closes the open batch of a [`BatchWindow`] early and flushes it.
+pub struct EarlyCloseHook<T> {
+    window: BatchWindow<T>,
+}
+
+impl<T> fmt::Debug for EarlyCloseHook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EarlyCloseHook")
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+impl<T: Send + 'static> ShutdownHook for EarlyCloseHook<T> {
+    fn name(&self) -> &str {
+        "batch-window"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let pending = self.window.pending().await;
+            if pending == 0 {
+                return Ok(());
+            }
+            self.window.close_early().await?;
+            let stats = self.window.stats();
+            ctx.note(format!(
+                "closed the batch early with {pending} events, {} of {} batches closed early",
+                stats.closed_early,
+                stats.closed + stats.closed_early
+            ));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use super::*;
+    use crate::{ShutdownCoordinator, ShutdownReason};
+
+    type Flushed = Arc<StdMutex<Vec<Vec<u32>>>>;
+
+    /// A window of up to 3 events and 1s, and the batches it flushed.
+    fn window() -> (BatchWindow<u32>, Flushed) {
+        let flushed = Arc::new(StdMutex::new(Vec::new()));
+        let window = BatchWindow::new(3, Duration::from_secs(1), {
+            let flushed = flushed.clone();
+            move |events| {
+                flushed.lock().unwrap().push(events);
+                async { Ok(()) }
+            }
+        });
+        (window, flushed)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_close_when_full_or_expired() {
+        let (window, flushed) = window();
+        for event in 1..=4 {
+            window.push(event).await.unwrap();
+        }
+        assert_eq!(*flushed.lock().unwrap(), [vec![1, 2, 3]]);
+
+        tokio::time::advance(Duration::from_millis(999)).await;
+        window.flush_expired().await.unwrap();
+        assert_eq!(window.pending().await, 1);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        window.flush_expired().await.unwrap();
+        assert_eq!(*flushed.lock().unwrap(), [vec![1, 2, 3], vec![4]]);
+
+        // The next batch's window starts with its first event
+        window.push(5).await.unwrap();
+        tokio::time::advance(Duration::from_millis(500)).await;
+        window.push(6).await.unwrap();
+        assert_eq!(window.pending().await, 2);
+        assert_eq!(
+            window.stats(),
+            WindowStats {
+                closed: 2,
+                closed_early: 0,
+                flushed: 4,
+                dropped: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn a_batch_whose_flush_fails_is_dropped() {
+        let window = BatchWindow::new(2, Duration::from_secs(1), |_: Vec<u32>| async {
+            Err::<(), Error>("unavailable".into())
+        });
+        window.push(1).await.unwrap();
+        assert!(window.push(2).await.is_err());
+        assert_eq!(window.pending().await, 0);
+        assert_eq!(window.stats().dropped, 2);
+        assert_eq!(window.stats().closed, 0);
+    }
+
+    #[tokio::test]
+    async fn the_hook_closes_the_open_batch_early() {
+        let (window, flushed) = window();
+        for event in 1..=4 {
+            window.push(event).await.unwrap();
+        }
+
+        let report = ShutdownCoordinator::new()
+            .with_hook(window.early_close_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.is_clean());
+        assert_eq!(
+            report.hooks[0].notes,
+            ["closed the batch early with 1 events, 1 of 2 batches closed early"]
+        );
+        assert_eq!(*flushed.lock().unwrap(), [vec![1, 2, 3], vec![4]]);
+        assert_eq!(window.stats().closed_early, 1);
+
+        // Nothing left to close
+        let report = ShutdownCoordinator::new()
+            .with_hook(window.early_close_hook())
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        assert!(report.hooks[0].notes.is_empty());
+        assert_eq!(window.stats().closed_early, 1);
+    }
+}
@@ -0,0 +1,199 @@
+//! Buffered X-Ray segment emission over UDP.
+//!
+//! The X-Ray daemon (or the Lambda X-Ray integration) listens on the UDP address in
+//! `AWS_XRAY_DAEMON_ADDRESS`. [`XRayEmitter`] queues serialized segment documents in memory
+//! and sends them when flushed, so sending doesn't add latency in the middle of an
+//! invocation. Its [`flush_hook()`](XRayEmitter::flush_hook) sends whatever is still queued
+//! before the environment goes away.
+//!
+//! See the [segment document reference](https://docs.aws.amazon.com/xray/latest/devguide/xray-api-segmentdocuments.html)
+//! for what a segment looks like.
+
+use std::{
+    collections::VecDeque,
+    env, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use tokio::net::UdpSocket;
+
+use crate::{BoxFuture, Error, ShutdownContext, ShutdownHook};
+
+/// Where the daemon listens if `AWS_XRAY_DAEMON_ADDRESS` isn't set.
+const DEFAULT_DAEMON_ADDRESS: &str = "127.0.0.1:2000";
+
+/// Every datagram starts with this header, followed by a newline and the segment document.
+const HEADER: &[u8] = b"{\"format\": \"json\", \"version\": 1}\n";
+
+/// The daemon drops datagrams bigger than this.
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Queues X-Ray segment documents and sends them to the daemon over UDP.
+///
+/// Cloning is cheap, and all clones share the same queue and socket.
+#[derive(Debug, Clone)]
+pub struct XRayEmitter {
+    daemon_address: SocketAddr,
+    pending: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    /// Held for the whole of a flush, so concurrent flushes don't send the same segment twice.
+    socket: Arc<tokio::sync::Mutex<Option<UdpSocket>>>,
+}
+
+impl XRayEmitter {
+    /// Create an emitter that sends to the UDP address in `AWS_XRAY_DAEMON_ADDRESS`.
+    ///
+    /// The variable is either a plain `host:port`, or separate addresses in the
+    /// `tcp:host:port udp:host:port` form, in which case the UDP one is used.
+    pub fn from_env() -> io::Result<Self> {
+        let address = env::var("AWS_XRAY_DAEMON_ADDRESS")
+            .unwrap_or_else(|_| DEFAULT_DAEMON_ADDRESS.to_owned());
+        let udp_address = address
+            .split_whitespace()
+            .find_map(|part| part.strip_prefix("udp:"))
+            .unwrap_or(&address);
+        let daemon_address = udp_address.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid AWS_XRAY_DAEMON_ADDRESS: {address}"),
+            )
+        })?;
+        Ok(Self::new(daemon_address))
+    }
+
+    /// Create an emitter that sends to the given daemon address.
+    pub fn new(daemon_address: SocketAddr) -> Self {
+        Self {
+            daemon_address,
+            pending: Arc::default(),
+            socket: Arc::default(),
+        }
+    }
+
+    /// Serialize a segment document and queue it until the next [`flush()`](Self::flush).
+    pub fn emit(&self, segment: &impl Serialize) -> Result<(), Error> {
+        let mut datagram = HEADER.to_vec();
+        serde_json::to_writer(&mut datagram, segment)?;
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            return Err(format!(
+                "segment document is {} bytes, the X-Ray daemon only accepts {MAX_DATAGRAM_SIZE}",
+                datagram.len()
+            )
+            .into());
+        }
+        self.pending.lock().unwrap().push_back(datagram);
+        Ok(())
+    }
+
+    /// The number of segments waiting to be sent.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Send every queued segment, returning how many were sent.
+    ///
+    /// If a send fails, or the flush is cancelled, that segment and the ones after it stay
+    /// queued.
+    pub async fn flush(&self) -> io::Result<usize> {
+        let mut socket = self.socket.lock().await;
+        let socket = match &mut *socket {
+            Some(socket) => socket,
+            None => {
+                let local_address = if self.daemon_address.is_ipv6() {
+                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+                } else {
+                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+                };
+                let new_socket = UdpSocket::bind(local_address).await?;
+                new_socket.connect(self.daemon_address).await?;
+                socket.insert(new_socket)
+            }
+        };
+
+        let mut sent = 0;
+        loop {
+            // Only taken off the queue once it's sent.
+            let Some(datagram) = self.pending.lock().unwrap().front().cloned() else {
+                return Ok(sent);
+            };
+            socket.send(&datagram).await?;
+            self.pending.lock().unwrap().pop_front();
+            sent += 1;
+        }
+    }
+
+    /// A hook that sends any queued segments when the environment shuts down.
+    pub fn flush_hook(&self) -> XRayFlushHook {
+        XRayFlushHook {
+            emitter: self.clone(),
+        }
+    }
+}
+
+/// A [`ShutdownHook`] that sends queued X-Ray segments, see [`XRayEmitter::flush_hook()`].
+#[derive(Debug)]
+pub struct XRayFlushHook {
+    emitter: XRayEmitter,
+}
+
+impl ShutdownHook for XRayFlushHook {
+    fn name(&self) -> &str {
+        "xray"
+    }
+
+    fn shutdown<'a>(&'a self, _ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.emitter.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    async fn receive(daemon: &UdpSocket) -> serde_json::Value {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        let len = daemon.recv(&mut buffer).await.unwrap();
+        let document = buffer[..len].strip_prefix(HEADER).unwrap();
+        serde_json::from_slice(document).unwrap()
+    }
+
+    #[tokio::test]
+    async fn flush_sends_queued_segments_in_order() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let emitter = XRayEmitter::new(daemon.local_addr().unwrap());
+        emitter.emit(&json!({"name": "first"})).unwrap();
+        emitter.emit(&json!({"name": "second"})).unwrap();
+
+        assert_eq!(emitter.flush().await.unwrap(), 2);
+        assert_eq!(emitter.pending(), 0);
+        assert_eq!(receive(&daemon).await, json!({"name": "first"}));
+        assert_eq!(receive(&daemon).await, json!({"name": "second"}));
+    }
+
+    #[tokio::test]
+    async fn flush_sends_to_an_ipv6_daemon() {
+        let Ok(daemon) = UdpSocket::bind("[::1]:0").await else {
+            // No IPv6 loopback here.
+            return;
+        };
+        let emitter = XRayEmitter::new(daemon.local_addr().unwrap());
+        emitter.emit(&json!({"name": "segment"})).unwrap();
+
+        assert_eq!(emitter.flush().await.unwrap(), 1);
+        assert_eq!(receive(&daemon).await, json!({"name": "segment"}));
+    }
+
+    #[test]
+    fn oversized_segments_are_refused() {
+        let emitter = XRayEmitter::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 2000)));
+        let segment = json!({"annotations": "x".repeat(MAX_DATAGRAM_SIZE)});
+        assert!(emitter.emit(&segment).is_err());
+        assert_eq!(emitter.pending(), 0);
+    }
+}
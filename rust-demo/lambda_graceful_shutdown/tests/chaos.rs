@@ -0,0 +1,83 @@
+//! Shutdowns injected into tracked invocations.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use lambda_graceful_shutdown::{chaos::Chaos, hook_fn, ShutdownCoordinator, ShutdownReason};
+
+#[tokio::test(start_paused = true)]
+async fn injects_a_shutdown_into_the_invocation() {
+    let shutdown = ShutdownCoordinator::new()
+        .with_invocation_drain(Duration::from_millis(100))
+        .with_chaos(
+            Chaos::new(1.0)
+                .with_max_delay(Duration::from_millis(50))
+                .without_exit(),
+        );
+
+    let _invocation = shutdown.track_invocation("request-1", None);
+    tokio::time::timeout(Duration::from_millis(60), shutdown.shutting_down())
+        .await
+        .expect("no shutdown was injected");
+    // Let the injected shutdown finish, with the invocation still running
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let report = shutdown.last_report().unwrap();
+    assert_eq!(report.reason, ShutdownReason::Sigterm);
+    assert_eq!(report.request_id.as_deref(), Some("request-1"));
+    assert_eq!(report.invocations.unwrap().interrupted, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn disabled_chaos_never_injects() {
+    let shutdown = ShutdownCoordinator::new().with_chaos(Chaos::new(0.0).without_exit());
+
+    for n in 0..100 {
+        let _invocation = shutdown.track_invocation(format!("request-{n}"), None);
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(!shutdown.is_shutting_down());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_signal_during_an_injected_shutdown_runs_the_hooks_once() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let shutdown = ShutdownCoordinator::new()
+        .with_chaos(
+            Chaos::new(1.0)
+                .with_max_delay(Duration::from_millis(50))
+                .without_exit(),
+        )
+        .with_hook(hook_fn("slow", {
+            let runs = runs.clone();
+            move |_ctx| {
+                runs.fetch_add(1, Ordering::SeqCst);
+                async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok(())
+                }
+            }
+        }));
+
+    let _invocation = shutdown.track_invocation("request-1", None);
+    shutdown.shutting_down().await;
+    // SIGTERM arrives while the injected shutdown's hook is running
+    let report = shutdown.shutdown(ShutdownReason::Sigterm).await;
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!(report.hooks.len(), 1);
+    assert!(report.is_clean());
+
+    // A shutdown after that one runs the hooks again, once for both of these
+    let (first, second) = tokio::join!(
+        shutdown.shutdown(ShutdownReason::Sigterm),
+        shutdown.shutdown(ShutdownReason::Sigint)
+    );
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    assert_eq!(first.reason, ShutdownReason::Sigterm);
+    assert_eq!(second.reason, ShutdownReason::Sigterm);
+}
@@ -0,0 +1,71 @@
+//! Shutdown deadlines on a clock moved by hand.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{
+    hook_fn, testing::ManualClock, HookOutcome, ShutdownCoordinator, ShutdownReason,
+};
+
+#[tokio::test]
+async fn hook_times_out_when_the_clock_reaches_the_deadline() {
+    let clock = ManualClock::new();
+    let shutdown = ShutdownCoordinator::new()
+        .with_clock(clock.clone())
+        .with_hook(hook_fn("stuck", |_ctx| std::future::pending()));
+
+    let running = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.shutdown(ShutdownReason::Sigterm).await }
+    });
+    tokio::task::yield_now().await;
+    assert!(!running.is_finished());
+
+    clock.advance(Duration::from_millis(449));
+    tokio::task::yield_now().await;
+    assert!(!running.is_finished());
+
+    clock.advance(Duration::from_millis(1));
+    let report = running.await.unwrap();
+    assert_eq!(report.hooks[0].outcome, HookOutcome::TimedOut);
+    assert_eq!(report.elapsed, Duration::from_millis(450));
+}
+
+#[tokio::test]
+async fn drain_window_runs_on_the_clock() {
+    let clock = ManualClock::new();
+    let shutdown = ShutdownCoordinator::new()
+        .with_clock(clock.clone())
+        .with_invocation_drain(Duration::from_millis(100));
+    let _invocation = shutdown.track_invocation("request-1", None);
+
+    let running = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.shutdown(ShutdownReason::Sigterm).await }
+    });
+    // Let the shutdown start before moving the clock
+    tokio::task::yield_now().await;
+    clock.advance(Duration::from_millis(100));
+    let drain = running.await.unwrap().invocations.unwrap();
+    assert_eq!(drain.interrupted, 1);
+    assert_eq!(drain.elapsed, Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn invocations_started_during_the_drain_are_not_counted() {
+    let clock = ManualClock::new();
+    let shutdown = ShutdownCoordinator::new()
+        .with_clock(clock.clone())
+        .with_invocation_drain(Duration::from_millis(100));
+    let _invocation = shutdown.track_invocation("request-1", None);
+
+    let running = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.shutdown(ShutdownReason::Sigterm).await }
+    });
+    tokio::task::yield_now().await;
+    let _late = shutdown.track_invocation("request-2", None);
+    clock.advance(Duration::from_millis(100));
+    let drain = running.await.unwrap().invocations.unwrap();
+    assert_eq!(drain.in_flight, 1);
+    assert_eq!(drain.interrupted, 1);
+}
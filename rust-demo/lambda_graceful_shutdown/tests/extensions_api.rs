@@ -0,0 +1,117 @@
+//! Extensions registering with, and being shut down by, the emulated Extensions API.
+
+use std::time::Duration;
+
+use lambda_extension::{service_fn, Error, Extension, LambdaEvent, NextEvent};
+use lambda_graceful_shutdown::{
+    hook_fn,
+    testing::{ErrorPhase, MockExtensionsApi},
+    ShutdownCoordinator, ShutdownReason, ShutdownReport,
+};
+use tokio::sync::{mpsc, Mutex};
+
+/// `AWS_LAMBDA_RUNTIME_API` is shared by every test in this file.
+static RUNTIME_API: Mutex<()> = Mutex::const_new(());
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn start_api() -> MockExtensionsApi {
+    let api = MockExtensionsApi::start().await.unwrap();
+    std::env::set_var("AWS_LAMBDA_RUNTIME_API", api.endpoint());
+    api
+}
+
+#[tokio::test]
+async fn shutdown_event_runs_the_hooks() {
+    let _env = RUNTIME_API.lock().await;
+    let api = start_api().await;
+    let shutdown = ShutdownCoordinator::new().with_hook(hook_fn("flush", |_ctx| async { Ok(()) }));
+    let (reports, mut received) = mpsc::unbounded_channel::<ShutdownReport>();
+
+    let extension = Extension::new()
+        .with_extension_name("shutdown-test")
+        .with_events(&["SHUTDOWN"])
+        .with_events_processor(service_fn(move |event: LambdaEvent| {
+            let shutdown = shutdown.clone();
+            let reports = reports.clone();
+            async move {
+                if let NextEvent::Shutdown(event) = event.next {
+                    let reason = ShutdownReason::from_extension_reason(&event.shutdown_reason)
+                        .ok_or("unknown shutdown reason")?;
+                    reports.send(shutdown.shutdown(reason).await)?;
+                }
+                Ok::<(), Error>(())
+            }
+        }));
+    tokio::spawn(extension.run());
+
+    tokio::time::timeout(TIMEOUT, api.wait_for_registrations(1))
+        .await
+        .unwrap();
+    let registration = &api.registrations()[0];
+    assert_eq!(registration.name, "shutdown-test");
+    assert_eq!(registration.events, ["SHUTDOWN"]);
+
+    api.send_shutdown("spindown");
+    let report = tokio::time::timeout(TIMEOUT, received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.reason, ShutdownReason::Spindown);
+    assert!(report.is_clean());
+    assert_eq!(report.hooks.len(), 1);
+    assert!(api.errors().is_empty());
+}
+
+#[tokio::test]
+async fn failed_shutdown_is_reported_as_exit_error() {
+    let _env = RUNTIME_API.lock().await;
+    let api = start_api().await;
+
+    let extension = Extension::new()
+        .with_extension_name("failing-test")
+        .with_events(&["SHUTDOWN"])
+        .with_events_processor(service_fn(|_event: LambdaEvent| async {
+            Err::<(), Error>("could not flush".into())
+        }));
+    let run = tokio::spawn(extension.run());
+
+    tokio::time::timeout(TIMEOUT, api.wait_for_registrations(1))
+        .await
+        .unwrap();
+    api.send_shutdown("timeout");
+    let result = tokio::time::timeout(TIMEOUT, run).await.unwrap().unwrap();
+    assert!(result.is_err());
+
+    let errors = api.errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].phase, ErrorPhase::Exit);
+    assert_eq!(errors[0].extension_id, api.registrations()[0].id);
+    // The extension client sends the error message as the error type
+    assert_eq!(errors[0].error_type.as_deref(), Some("could not flush"));
+}
+
+#[tokio::test]
+async fn no_op_registration_receives_no_events() {
+    let _env = RUNTIME_API.lock().await;
+    let api = start_api().await;
+
+    // This is how `spawn_graceful_shutdown_handler()` gets the runtime a SIGTERM
+    let extension = Extension::new()
+        .with_extension_name("no-op-test")
+        .with_events(&[])
+        .register()
+        .await
+        .unwrap();
+    let run = tokio::spawn(extension.run());
+
+    let registration = &api.registrations()[0];
+    assert_eq!(registration.name, "no-op-test");
+    assert!(registration.events.is_empty());
+
+    api.send_invoke("request-1");
+    api.send_shutdown("spindown");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!run.is_finished());
+    run.abort();
+}
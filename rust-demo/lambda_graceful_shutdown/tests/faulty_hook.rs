@@ -0,0 +1,69 @@
+//! Shutdowns with hooks that are slow, fail, or panic.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{
+    hook_fn,
+    testing::{FaultyHook, ShutdownSim},
+    HookOutcome, ShutdownCoordinator, ShutdownReason,
+};
+
+fn ok_hook(name: &'static str) -> impl lambda_graceful_shutdown::ShutdownHook {
+    hook_fn(name, |_ctx| async { Ok(()) })
+}
+
+#[tokio::test(start_paused = true)]
+async fn recovers_after_the_injected_failures() {
+    let shutdown =
+        ShutdownCoordinator::new().with_hook(FaultyHook::wrapping(ok_hook("flush")).fail_times(1));
+
+    let first = shutdown.shutdown(ShutdownReason::Sigterm).await;
+    assert_eq!(
+        first.hooks[0].outcome,
+        HookOutcome::Failed("injected failure".to_owned())
+    );
+    let second = shutdown.shutdown(ShutdownReason::Sigterm).await;
+    assert_eq!(second.hooks[0].outcome, HookOutcome::Completed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn other_hooks_run_past_a_failing_one() {
+    let shutdown = ShutdownSim::new()
+        // Hooks run last registered first
+        .hook(FaultyHook::wrapping(ok_hook("close-pool")).delay(Duration::from_millis(800)))
+        .hook(ok_hook("flush-logs"))
+        .hook(
+            FaultyHook::wrapping(ok_hook("flush-metrics"))
+                .fail_always()
+                .with_error("endpoint unreachable"),
+        )
+        .trigger()
+        .await;
+
+    shutdown.assert_completed("flush-logs");
+    let outcomes: Vec<_> = shutdown
+        .report()
+        .hooks
+        .iter()
+        .map(|hook| hook.outcome.clone())
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            HookOutcome::Failed("endpoint unreachable".to_owned()),
+            HookOutcome::Completed,
+            HookOutcome::TimedOut,
+        ]
+    );
+    assert_eq!(shutdown.report().elapsed, Duration::from_millis(450));
+}
+
+#[tokio::test(start_paused = true)]
+async fn panicking_hook_takes_the_shutdown_down() {
+    let shutdown =
+        ShutdownCoordinator::new().with_hook(FaultyHook::wrapping(ok_hook("flush")).panics());
+
+    let result =
+        tokio::spawn(async move { shutdown.shutdown(ShutdownReason::Sigterm).await }).await;
+    assert!(result.unwrap_err().is_panic());
+}
@@ -0,0 +1,108 @@
+//! Properties of shutdowns with randomly generated hooks and budgets.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{
+    hook_fn,
+    testing::{FaultyHook, HookTrace, TraceEventKind},
+    HookOutcome, ShutdownCoordinator, ShutdownReason, ShutdownReport,
+};
+use proptest::prelude::*;
+
+/// How much past the budget a shutdown may finish, for the time it takes to notice.
+const EPSILON: Duration = Duration::from_millis(1);
+
+/// How long a hook takes, and whether it fails.
+#[derive(Debug, Clone)]
+struct HookSpec {
+    delay: Duration,
+    fails: bool,
+}
+
+fn hook_spec() -> impl Strategy<Value = HookSpec> {
+    (0..600u64, any::<bool>()).prop_map(|(delay_ms, fails)| HookSpec {
+        delay: Duration::from_millis(delay_ms),
+        fails,
+    })
+}
+
+fn budget() -> impl Strategy<Value = Duration> {
+    (1..2500u64).prop_map(Duration::from_millis)
+}
+
+/// Shut down with one hook per spec, registered in order as `hook-0`, `hook-1`, ...
+fn shut_down(budget: Duration, specs: &[HookSpec]) -> (ShutdownReport, HookTrace) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    let trace = HookTrace::new();
+    let shutdown = ShutdownCoordinator::new()
+        .with_budget(budget)
+        .with_hook_trace(&trace);
+    for (index, spec) in specs.iter().enumerate() {
+        let hook = FaultyHook::wrapping(hook_fn(format!("hook-{index}"), |_ctx| async { Ok(()) }))
+            .delay(spec.delay)
+            .fail_times(usize::from(spec.fails));
+        shutdown.register(hook);
+    }
+    let report = runtime.block_on(shutdown.shutdown(ShutdownReason::Sigterm));
+    (report, trace)
+}
+
+proptest! {
+    #[test]
+    fn hooks_run_one_at_a_time_last_registered_first(
+        budget in budget(),
+        specs in prop::collection::vec(hook_spec(), 0..8),
+    ) {
+        let (report, trace) = shut_down(budget, &specs);
+
+        let expected: Vec<String> = (0..specs.len()).rev().map(|i| format!("hook-{i}")).collect();
+        let finished: Vec<String> = trace.outcomes().into_iter().map(|(hook, _)| hook).collect();
+        prop_assert_eq!(&finished, &expected);
+        prop_assert!(trace.is_sequential(), "hooks overlapped: {:?}", trace.events());
+        let reported: Vec<String> = report.hooks.iter().map(|hook| hook.name.clone()).collect();
+        prop_assert_eq!(reported, expected);
+    }
+
+    #[test]
+    fn shutdown_fits_in_the_budget(
+        budget in budget(),
+        specs in prop::collection::vec(hook_spec(), 0..8),
+    ) {
+        let (report, trace) = shut_down(budget, &specs);
+
+        prop_assert!(trace.finished_by() <= budget + EPSILON, "{:?}", trace.events());
+        prop_assert!(report.elapsed <= budget + EPSILON, "{:?}", report);
+    }
+
+    #[test]
+    fn every_hook_is_attempted_while_budget_remains(
+        budget in budget(),
+        specs in prop::collection::vec(hook_spec(), 0..8),
+    ) {
+        let (report, trace) = shut_down(budget, &specs);
+
+        for event in trace.events() {
+            if event.kind == TraceEventKind::Finished(HookOutcome::Skipped) {
+                prop_assert!(event.at >= budget, "skipped with budget left: {:?}", event);
+            }
+        }
+        let attempted = trace.attempted();
+        let not_skipped: Vec<String> = report
+            .hooks
+            .iter()
+            .filter(|hook| hook.outcome != HookOutcome::Skipped)
+            .map(|hook| hook.name.clone())
+            .collect();
+        prop_assert_eq!(attempted, not_skipped);
+        // Failing hooks don't use up the budget, so with room for every delay nothing is skipped
+        let total: Duration = specs.iter().map(|spec| spec.delay).sum();
+        if total < budget {
+            prop_assert_eq!(report.counters().hooks_skipped, 0);
+            prop_assert_eq!(report.counters().hooks_timed_out, 0);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Model checks of the coordinator's synchronization, over every interleaving loom finds.
+//!
+//! These only build with loom swapped in:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg lambda_graceful_shutdown_loom" cargo test --release --test loom
+//! ```
+#![cfg(lambda_graceful_shutdown_loom)]
+
+use std::sync::Arc as StdArc;
+
+use lambda_graceful_shutdown::sync::{InFlightCounter, Latch, Registry};
+use loom::{sync::Arc, thread};
+
+#[test]
+fn latch_is_triggered_once() {
+    loom::model(|| {
+        let latch = Arc::new(Latch::default());
+        let other = {
+            let latch = latch.clone();
+            thread::spawn(move || latch.trigger())
+        };
+        let here = latch.trigger();
+        let there = other.join().unwrap();
+
+        assert!(here ^ there, "exactly one trigger wins");
+        assert!(latch.is_set());
+    });
+}
+
+#[test]
+fn last_invocation_out_is_seen_once() {
+    loom::model(|| {
+        let counter = Arc::new(InFlightCounter::default());
+        counter.enter();
+        counter.enter();
+        let other = {
+            let counter = counter.clone();
+            thread::spawn(move || counter.exit())
+        };
+        let here = counter.exit();
+        let there = other.join().unwrap();
+
+        assert!(here ^ there, "exactly one exit sees the counter reach zero");
+        assert_eq!(counter.get(), 0);
+    });
+}
+
+#[test]
+fn overlapping_invocations_always_end_idle() {
+    loom::model(|| {
+        let counter = Arc::new(InFlightCounter::default());
+        let other = {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                counter.enter();
+                counter.exit()
+            })
+        };
+        counter.enter();
+        let here = counter.exit();
+        let there = other.join().unwrap();
+
+        // A drain waiting for the last invocation out is always woken up
+        assert!(here || there);
+        assert_eq!(counter.get(), 0);
+    });
+}
+
+#[test]
+fn hook_registered_during_a_snapshot_is_kept() {
+    loom::model(|| {
+        let registry = Arc::new(Registry::<&'static str>::default());
+        registry.push(StdArc::new("flush-logs"));
+        let registering = {
+            let registry = registry.clone();
+            thread::spawn(move || registry.push(StdArc::new("flush-metrics")))
+        };
+        let snapshot = registry.snapshot_rev();
+        registering.join().unwrap();
+
+        match snapshot.len() {
+            1 => assert_eq!(*snapshot[0], "flush-logs"),
+            2 => assert_eq!(*snapshot[1], "flush-logs"),
+            len => panic!("snapshot of {len} hooks"),
+        }
+        let names: Vec<_> = registry.snapshot_rev().iter().map(|name| **name).collect();
+        assert_eq!(names, ["flush-metrics", "flush-logs"]);
+    });
+}
@@ -0,0 +1,37 @@
+//! The internal extension example, under the Runtime Interface Emulator in Docker.
+//!
+//! Needs the `docker` CLI and a daemon, and building the image takes a few minutes the first
+//! time, so it only runs when asked for:
+//!
+//! ```text
+//! cargo test --test rie -- --ignored
+//! ```
+
+use std::path::Path;
+
+use lambda_graceful_shutdown::testing::RieImage;
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn stopping_the_container_writes_the_shutdown_summary() {
+    let demo = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let image = RieImage::build(
+        "lambda-graceful-shutdown-rie",
+        &demo,
+        demo.join("rust_app_internal_extension_from_helper/Dockerfile"),
+    )
+    .expect("failed to build the image");
+    let event = std::fs::read_to_string(demo.join("events/event.json")).unwrap();
+
+    let container = image.run().expect("failed to start the container");
+    let response = container
+        .invoke(&serde_json::from_str(&event).unwrap())
+        .await
+        .expect("failed to invoke the function");
+    assert_eq!(response["statusCode"], 200);
+
+    let logs = container.stop().expect("failed to stop the container");
+    let summary = logs.assert_shutdown_summary();
+    assert_eq!(summary["reason"], "SIGTERM");
+    assert_eq!(summary["exit_code"], 0, "{}", logs.output());
+}
@@ -0,0 +1,118 @@
+//! A function run end to end against the emulated Runtime API: invocations, then a SIGTERM
+//! while one is in flight, which is drained before the hooks run.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use lambda_graceful_shutdown::{
+    hook_fn,
+    testing::{InvocationResult, MockRuntimeApi},
+    ShutdownCoordinator, ShutdownReason,
+};
+use lambda_runtime::{service_fn, Error, LambdaEvent};
+use serde_json::{json, Value};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::oneshot,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let sleep_ms = event.payload["sleep_ms"].as_u64().unwrap_or(0);
+    tokio::time::sleep(Duration::from_millis(sleep_ms)).await;
+    match event.payload["fail"].as_bool() {
+        Some(true) => Err("asked to fail".into()),
+        _ => Ok(json!({ "request_id": event.context.request_id })),
+    }
+}
+
+#[tokio::test]
+async fn invoke_respond_sigterm_drain_hooks() {
+    let api = MockRuntimeApi::start().await.unwrap();
+    api.set_env();
+
+    let flushed = Arc::new(AtomicBool::new(false));
+    let flush = flushed.clone();
+    let shutdown = ShutdownCoordinator::new()
+        .with_budget(Duration::from_secs(2))
+        .with_invocation_drain(Duration::from_secs(1))
+        .with_hook(hook_fn("flush", move |_ctx| {
+            let flush = flush.clone();
+            async move {
+                flush.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        }));
+
+    // Installed before anything sends the signal, so SIGTERM doesn't kill the test
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let (report_tx, report_rx) = oneshot::channel();
+    let coordinator = shutdown.clone();
+    tokio::spawn(async move {
+        sigterm.recv().await;
+        let _ = report_tx.send(coordinator.shutdown(ShutdownReason::Sigterm).await);
+    });
+
+    tokio::spawn(lambda_runtime::run(service_fn(
+        move |event: LambdaEvent<Value>| {
+            let shutdown = shutdown.clone();
+            async move {
+                let _invocation = shutdown.track_invocation(
+                    event.context.request_id.clone(),
+                    event.context.xray_trace_id.clone(),
+                );
+                handler(event).await
+            }
+        },
+    )));
+
+    // A plain invocation
+    let first = api.invoke(json!({}));
+    let result = tokio::time::timeout(TIMEOUT, api.wait_for_result(&first))
+        .await
+        .unwrap();
+    assert_eq!(result.json().unwrap()["request_id"], first);
+
+    // A failing one
+    let failing = api.invoke(json!({ "fail": true }));
+    let result = tokio::time::timeout(TIMEOUT, api.wait_for_result(&failing))
+        .await
+        .unwrap();
+    assert!(matches!(result, InvocationResult::Error { .. }));
+
+    // One still running when SIGTERM arrives
+    let slow = api.invoke(json!({ "sleep_ms": 300 }));
+    tokio::time::timeout(TIMEOUT, api.wait_for_pickup(&slow))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // SAFETY: sending a signal to our own process, which has a handler for it
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGTERM);
+    }
+
+    let report = tokio::time::timeout(TIMEOUT, report_rx)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.reason, ShutdownReason::Sigterm);
+    assert_eq!(report.request_id.as_deref(), Some(slow.as_str()));
+    let drain = report.invocations.unwrap();
+    assert_eq!(drain.in_flight, 1);
+    assert!(drain.drained());
+    assert!(report.is_clean());
+    assert!(flushed.load(Ordering::SeqCst));
+    assert_eq!(report.sandbox.invocations, 3);
+
+    // The drained invocation gets to respond, right after its guard was dropped
+    let result = tokio::time::timeout(TIMEOUT, api.wait_for_result(&slow))
+        .await
+        .unwrap();
+    assert_eq!(result.json().unwrap()["request_id"], slow);
+}
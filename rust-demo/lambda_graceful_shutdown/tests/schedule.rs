@@ -0,0 +1,49 @@
+//! Hooks a scheduler starts together.
+
+use lambda_graceful_shutdown::{
+    hook_fn,
+    schedule::{ScheduleState, Scheduler},
+    HookOutcome, ShutdownCoordinator, ShutdownReason,
+};
+
+/// Starts every pending hook at once.
+#[derive(Debug)]
+struct AllAtOnce;
+
+impl Scheduler for AllAtOnce {
+    fn next_batch(&self, state: &ScheduleState<'_>) -> usize {
+        state.pending.len()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn hooks_started_together_keep_their_own_notes() {
+    let shutdown = ShutdownCoordinator::new()
+        .with_scheduler(AllAtOnce)
+        .with_hook(hook_fn("first", |ctx| async move {
+            ctx.note("from the first");
+            Ok(())
+        }))
+        .with_hook(hook_fn("second", |ctx| async move {
+            tokio::task::yield_now().await;
+            ctx.note("from the second");
+            Ok(())
+        }));
+
+    let report = shutdown.shutdown(ShutdownReason::Sigterm).await;
+    let notes: Vec<_> = report
+        .hooks
+        .iter()
+        .map(|hook| {
+            assert_eq!(hook.outcome, HookOutcome::Completed);
+            (hook.name.as_str(), hook.notes.clone())
+        })
+        .collect();
+    assert_eq!(
+        notes,
+        [
+            ("second", vec!["from the second".to_owned()]),
+            ("first", vec!["from the first".to_owned()]),
+        ]
+    );
+}
@@ -0,0 +1,55 @@
+//! Handler code driven by a test-controlled shutdown handle.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{ShutdownCoordinator, ShutdownHandle, ShutdownReason};
+
+/// Process records until the environment is about to go away, and return how many were done.
+async fn process(records: usize, shutdown: &ShutdownHandle) -> usize {
+    for done in 0..records {
+        if shutdown.is_shutting_down() {
+            return done;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    records
+}
+
+#[tokio::test(start_paused = true)]
+async fn stops_where_the_shutdown_is_tripped() {
+    let (handle, controller) = ShutdownHandle::test_controlled();
+
+    let processing = tokio::spawn({
+        let handle = handle.clone();
+        async move { process(10, &handle).await }
+    });
+    tokio::time::sleep(Duration::from_millis(35)).await;
+    controller.trip();
+
+    assert_eq!(processing.await.unwrap(), 4);
+    assert!(controller.is_tripped());
+}
+
+#[tokio::test(start_paused = true)]
+async fn wakes_up_waiters_when_tripped() {
+    let (handle, controller) = ShutdownHandle::test_controlled();
+    let waiting = tokio::spawn(async move { handle.shutting_down().await });
+
+    tokio::task::yield_now().await;
+    assert!(!waiting.is_finished());
+    controller.trip();
+    tokio::time::timeout(Duration::from_secs(1), waiting)
+        .await
+        .expect("the waiter wasn't woken up")
+        .unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn coordinator_handle_follows_the_shutdown() {
+    let shutdown = ShutdownCoordinator::new();
+    let handle = shutdown.handle();
+
+    assert!(!handle.is_shutting_down());
+    shutdown.shutdown(ShutdownReason::Sigterm).await;
+    assert!(handle.is_shutting_down());
+}
@@ -0,0 +1,50 @@
+//! Assertions on the lifecycle records of a shutdown.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{hook_fn, testing::LogCapture, ShutdownCoordinator, ShutdownReason};
+
+fn coordinator(logs: &LogCapture) -> ShutdownCoordinator {
+    ShutdownCoordinator::new()
+        .with_lifecycle_capture(logs)
+        .with_hook(hook_fn("close-pool", |_ctx| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        }))
+        .with_hook(hook_fn("flush-logs", |_ctx| async { Ok(()) }))
+        .with_hook(hook_fn("flush-metrics", |_ctx| async {
+            Err("metrics endpoint unreachable".into())
+        }))
+}
+
+#[tokio::test(start_paused = true)]
+async fn records_every_phase_in_order() {
+    let logs = LogCapture::new();
+    let shutdown = coordinator(&logs);
+    shutdown.set_request_id("request-1");
+    shutdown.shutdown(ShutdownReason::Spindown).await;
+
+    logs.assert_sequence(&[
+        "shutdown.started",
+        "shutdown.hook_finished",
+        "shutdown.hook_finished",
+        "shutdown.hook_finished",
+        "shutdown.completed",
+    ])
+    .assert_reason(ShutdownReason::Spindown)
+    .assert_hook_outcomes(&[
+        ("flush-metrics", "failed: metrics endpoint unreachable"),
+        ("flush-logs", "completed"),
+        ("close-pool", "timed out"),
+    ])
+    .assert_field("shutdown.completed", "budget_exceeded", true)
+    .assert_field("shutdown.completed", "requestId", "request-1");
+}
+
+#[tokio::test(start_paused = true)]
+async fn matches_the_golden_file() {
+    let logs = LogCapture::new();
+    coordinator(&logs).shutdown(ShutdownReason::Sigterm).await;
+
+    logs.assert_snapshot("tests/snapshots/shutdown_logs.jsonl");
+}
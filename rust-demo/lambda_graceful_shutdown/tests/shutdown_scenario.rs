@@ -0,0 +1,74 @@
+//! Functions driven through invocations and shutdowns, with their extension registered.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{
+    hook_fn,
+    testing::{InvocationResult, ShutdownScenario, EXTENSION_NAME},
+    ShutdownCoordinator, ShutdownReason,
+};
+use lambda_runtime::{Error, LambdaEvent};
+use serde_json::{json, Value};
+
+async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let sleep_ms = event.payload["sleep_ms"].as_u64().unwrap_or(0);
+    tokio::time::sleep(Duration::from_millis(sleep_ms)).await;
+    Ok(json!({ "request_id": event.context.request_id }))
+}
+
+fn coordinator() -> ShutdownCoordinator {
+    ShutdownCoordinator::new()
+        .with_budget(Duration::from_millis(1800))
+        .with_invocation_drain(Duration::from_millis(500))
+        .with_hook(hook_fn("flush", |_ctx| async { Ok(()) }))
+}
+
+#[tokio::test(start_paused = true)]
+async fn spindown_drains_the_invocation_in_flight() {
+    let scenario = ShutdownScenario::start(coordinator(), handler).await;
+    let registrations = scenario.api().extensions().registrations();
+    assert_eq!(registrations[0].name, EXTENSION_NAME);
+    assert_eq!(registrations[0].events, ["SHUTDOWN"]);
+
+    let done = scenario.invoke(json!({})).await;
+    assert!(matches!(done, InvocationResult::Response(_)));
+    let slow = scenario
+        .invoke_in_background(json!({ "sleep_ms": 200 }))
+        .await;
+
+    let shutdown = scenario.spindown("spindown").await;
+    shutdown
+        .assert_drained()
+        .assert_clean()
+        .assert_completed("flush");
+    let report = shutdown.report();
+    assert_eq!(report.reason, ShutdownReason::Spindown);
+    assert_eq!(report.request_id.as_deref(), Some(slow.as_str()));
+    assert_eq!(report.sandbox.invocations, 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn timeout_reason_reaches_the_hooks() {
+    let scenario = ShutdownScenario::start(coordinator(), handler).await;
+
+    scenario
+        .invoke_in_background(json!({ "sleep_ms": 5000 }))
+        .await;
+    let shutdown = scenario.spindown("timeout").await;
+    let report = shutdown.report();
+    assert_eq!(report.reason, ShutdownReason::Timeout);
+    assert_eq!(report.invocations.unwrap().interrupted, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn advancing_time_finishes_the_invocation() {
+    let scenario = ShutdownScenario::start(coordinator(), handler).await;
+
+    let request_id = scenario
+        .invoke_in_background(json!({ "sleep_ms": 1000 }))
+        .await;
+    scenario.advance(Duration::from_millis(1000)).await;
+    let result = scenario.api().wait_for_result(&request_id).await;
+    assert_eq!(result.json().unwrap()["request_id"], request_id);
+    scenario.sigterm().await.assert_clean();
+}
@@ -0,0 +1,61 @@
+//! Simulated shutdowns on tokio's paused clock.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{hook_fn, testing::ShutdownSim, HookOutcome};
+
+fn sleeping_hook(name: &'static str, ms: u64) -> impl lambda_graceful_shutdown::ShutdownHook {
+    hook_fn(name, move |_ctx| async move {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        Ok(())
+    })
+}
+
+#[tokio::test(start_paused = true)]
+async fn hooks_fit_in_the_internal_extension_window() {
+    let shutdown = ShutdownSim::new()
+        .hook(sleeping_hook("flush-logs", 50))
+        .hook(sleeping_hook("flush-metrics", 150))
+        .budget(Duration::from_millis(450))
+        .in_flight(1)
+        .drain_window(Duration::from_millis(200))
+        .invocations_finish_after(Duration::from_millis(100))
+        .trigger()
+        .await;
+
+    shutdown
+        .assert_drained()
+        .assert_clean()
+        .assert_completed("flush-logs")
+        .assert_within_budget()
+        .assert_within(Duration::from_millis(500));
+    // Paused time only moves when every task is waiting, so the timings are exact
+    assert_eq!(shutdown.report().elapsed, Duration::from_millis(300));
+}
+
+#[tokio::test(start_paused = true)]
+async fn interrupted_invocation_eats_into_the_budget() {
+    let shutdown = ShutdownSim::new()
+        .hook(sleeping_hook("flush", 200))
+        .budget(Duration::from_millis(450))
+        .in_flight(2)
+        .drain_window(Duration::from_millis(300))
+        .trigger()
+        .await;
+
+    let report = shutdown.report();
+    let drain = report.invocations.unwrap();
+    assert_eq!((drain.in_flight, drain.interrupted), (2, 2));
+    assert_eq!(report.hooks[0].outcome, HookOutcome::TimedOut);
+    assert!(report.counters().budget_exceeded);
+}
+
+#[tokio::test(start_paused = true)]
+#[should_panic(expected = "not every hook completed")]
+async fn slow_hook_fails_the_assertion() {
+    ShutdownSim::new()
+        .hook(sleeping_hook("too-slow", 1000))
+        .trigger()
+        .await
+        .assert_clean();
+}
@@ -0,0 +1,81 @@
+//! End-to-end shutdowns with the `#[lambda_shutdown_test]` harness.
+
+use std::time::Duration;
+
+use lambda_graceful_shutdown::{
+    hook_fn, lambda_shutdown_test, testing::ShutdownTestHarness, ShutdownCoordinator,
+};
+use lambda_runtime::{Error, LambdaEvent};
+use serde_json::{json, Value};
+
+async fn echo(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    Ok(event.payload)
+}
+
+async fn slow(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    Ok(event.payload)
+}
+
+#[lambda_shutdown_test]
+async fn flushes_after_an_invocation(harness: ShutdownTestHarness) {
+    let harness = harness.with_coordinator(
+        ShutdownCoordinator::new().with_hook(hook_fn("flush", |_ctx| async { Ok(()) })),
+    );
+    harness.start_function(echo);
+
+    let result = harness.invoke(json!({ "hello": "world" })).await;
+    assert_eq!(result.json(), Some(json!({ "hello": "world" })));
+    harness
+        .sigterm()
+        .await
+        .assert_clean()
+        .assert_completed("flush");
+}
+
+#[lambda_shutdown_test]
+async fn drains_the_invocation_in_flight(harness: ShutdownTestHarness) {
+    let harness = harness.with_coordinator(
+        ShutdownCoordinator::new().with_invocation_drain(Duration::from_millis(200)),
+    );
+    harness.start_function(slow);
+
+    let request_id = harness.invoke_in_background(json!({})).await;
+    harness.sigterm().await.assert_drained();
+    // The response is posted once the handler returns, after its invocation is tracked as done
+    let result = harness.api().wait_for_result(&request_id).await;
+    assert_eq!(result.json(), Some(json!({})));
+}
+
+#[lambda_shutdown_test]
+#[should_panic(expected = "invocations were still running")]
+async fn interrupted_invocation_fails_the_assertion(harness: ShutdownTestHarness) {
+    let harness = harness.with_coordinator(
+        ShutdownCoordinator::new().with_invocation_drain(Duration::from_millis(50)),
+    );
+    harness.start_function(slow);
+
+    harness.invoke_in_background(json!({})).await;
+    harness.sigterm().await.assert_drained();
+}
+
+#[lambda_shutdown_test]
+async fn can_return_a_result(harness: ShutdownTestHarness) -> Result<(), Error> {
+    harness.start_function(echo);
+    harness.invoke(json!({})).await;
+    harness.sigterm().await.assert_clean();
+    Ok(())
+}
+
+// Run by `an_error_fails_the_test`, since it fails
+#[lambda_shutdown_test]
+#[ignore]
+async fn returns_an_error() -> Result<(), Error> {
+    Err("failed on purpose".into())
+}
+
+#[test]
+fn an_error_fails_the_test() {
+    let error = returns_an_error().unwrap_err();
+    assert_eq!(error.to_string(), "failed on purpose");
+}
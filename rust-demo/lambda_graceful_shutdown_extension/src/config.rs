@@ -0,0 +1,286 @@
+//! The hooks to run and the budget they share, read from a JSON file.
+
+use std::{collections::BTreeMap, env, fs, io, path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+/// The variable holding the path of the configuration file.
+pub const CONFIG_ENV: &str = "LAMBDA_GRACEFUL_SHUTDOWN_CONFIG";
+
+/// Where the configuration is read from unless set with `LAMBDA_GRACEFUL_SHUTDOWN_CONFIG`: the
+/// function's own code, so it is deployed along with it.
+const DEFAULT_PATH: &str = "/var/task/graceful-shutdown.json";
+
+/// The configuration file.
+///
+/// ```json
+/// {
+///   "budget_ms": 1800,
+///   "hooks": [
+///     { "name": "flush-app", "http": { "url": "http://127.0.0.1:8080/shutdown" } },
+///     { "name": "upload-logs", "command": ["/opt/bin/upload-logs", "--final"] },
+///     { "name": "marker", "file": { "path": "/tmp/shutting-down", "wait_for": "/tmp/done" } }
+///   ],
+///   "control": { "port": 9009 }
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// How long all hooks are allowed to take. Lambda gives an external extension 2s.
+    #[serde(default = "default_budget_ms")]
+    pub budget_ms: u64,
+    /// The hooks, run in the order they are listed in.
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    /// The control API for processes that hold the shutdown until they are done, off unless
+    /// set.
+    #[serde(default)]
+    pub control: Option<ControlConfig>,
+}
+
+impl Config {
+    /// Read the file named by `LAMBDA_GRACEFUL_SHUTDOWN_CONFIG`, or the default one.
+    pub fn from_env() -> io::Result<Self> {
+        let path =
+            env::var_os(CONFIG_ENV).map_or_else(|| PathBuf::from(DEFAULT_PATH), PathBuf::from);
+        let config = fs::read_to_string(&path).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("failed to read {}: {error}", path.display()),
+            )
+        })?;
+        Self::parse(&config).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid configuration in {}: {error}", path.display()),
+            )
+        })
+    }
+
+    /// Parse the contents of a configuration file, and check the HTTP hooks.
+    fn parse(config: &str) -> Result<Self, String> {
+        let config: Self = serde_json::from_str(config).map_err(|error| error.to_string())?;
+        for hook in &config.hooks {
+            if let Action::Http(http) = &hook.action {
+                http.check()
+                    .map_err(|error| format!("hook {}: {error}", hook.name))?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// The budget as a duration.
+    pub fn budget(&self) -> Duration {
+        Duration::from_millis(self.budget_ms)
+    }
+}
+
+fn default_budget_ms() -> u64 {
+    1800
+}
+
+/// One hook, with a name for the logs and the report, and what it does.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// What a hook does.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Run a program, given as its path and arguments, and wait for it to exit successfully.
+    /// It gets `LAMBDA_SHUTDOWN_REASON` and `LAMBDA_SHUTDOWN_REMAINING_MS` in its environment,
+    /// and is killed if it runs out of time.
+    Command(Vec<String>),
+    /// Send a request to an HTTP endpoint, usually one the function listens on, and wait for
+    /// a 2xx response.
+    Http(HttpAction),
+    /// Write a file the function watches for, and optionally wait for another one to show up.
+    File(FileAction),
+}
+
+/// A request made by an [`Action::Http`] hook.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpAction {
+    /// A plain `http://` URL, such as `http://127.0.0.1:8080/shutdown`.
+    pub url: String,
+    /// `POST` unless set.
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Sent along with the request.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl HttpAction {
+    /// Check that the method and headers can't end the line they go on, and split the request
+    /// into another one.
+    fn check(&self) -> Result<(), String> {
+        if !is_token(&self.method) {
+            return Err(format!("invalid method {:?}", self.method));
+        }
+        for (name, value) in &self.headers {
+            if !is_token(name) {
+                return Err(format!("invalid header name {name:?}"));
+            }
+            if value.contains(['\r', '\n', '\0']) {
+                return Err(format!("invalid value for header {name}: {value:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` is a token, as HTTP methods and header names are.
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+fn default_method() -> String {
+    "POST".to_owned()
+}
+
+/// Where the [control API](crate::control) listens.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The port on `127.0.0.1`, 9009 unless set.
+    #[serde(default = "default_control_port")]
+    pub port: u16,
+}
+
+fn default_control_port() -> u16 {
+    9009
+}
+
+/// The files an [`Action::File`] hook writes and waits for.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileAction {
+    /// Written with the same JSON body an HTTP hook sends.
+    pub path: PathBuf,
+    /// The file the function creates once it has done its part.
+    #[serde(default)]
+    pub wait_for: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_documented_example_parses() {
+        let config = Config::parse(
+            r#"{
+                "budget_ms": 1500,
+                "hooks": [
+                    { "name": "flush-app", "http": { "url": "http://127.0.0.1:8080/shutdown" } },
+                    { "name": "upload-logs", "command": ["/opt/bin/upload-logs", "--final"] },
+                    { "name": "marker", "file": { "path": "/tmp/shutting-down", "wait_for": "/tmp/done" } }
+                ],
+                "control": { "port": 9010 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.budget(), Duration::from_millis(1500));
+        assert_eq!(config.control.unwrap().port, 9010);
+
+        let names: Vec<_> = config.hooks.iter().map(|hook| hook.name.as_str()).collect();
+        assert_eq!(names, ["flush-app", "upload-logs", "marker"]);
+        let Action::Http(http) = &config.hooks[0].action else {
+            panic!("not an HTTP hook: {:?}", config.hooks[0]);
+        };
+        assert_eq!(http.url, "http://127.0.0.1:8080/shutdown");
+        assert_eq!(http.method, "POST");
+        assert!(http.headers.is_empty());
+        let Action::Command(command) = &config.hooks[1].action else {
+            panic!("not a command hook: {:?}", config.hooks[1]);
+        };
+        assert_eq!(command, &["/opt/bin/upload-logs", "--final"]);
+        let Action::File(file) = &config.hooks[2].action else {
+            panic!("not a file hook: {:?}", config.hooks[2]);
+        };
+        assert_eq!(file.path, PathBuf::from("/tmp/shutting-down"));
+        assert_eq!(file.wait_for, Some(PathBuf::from("/tmp/done")));
+    }
+
+    #[test]
+    fn everything_has_a_default() {
+        let config = Config::parse("{}").unwrap();
+        assert_eq!(config.budget(), Duration::from_millis(1800));
+        assert!(config.hooks.is_empty());
+        assert!(config.control.is_none());
+
+        let config = Config::parse(r#"{ "control": {} }"#).unwrap();
+        assert_eq!(config.control.unwrap().port, 9009);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for config in [
+            r#"{ "budget": 1000 }"#,
+            r#"{ "control": { "host": "0.0.0.0" } }"#,
+            r#"{ "hooks": [{ "name": "a", "command": ["true"], "timeout_ms": 10 }] }"#,
+            r#"{ "hooks": [{ "name": "a", "http": { "url": "http://a", "body": "" } }] }"#,
+            r#"{ "hooks": [{ "name": "a", "file": { "path": "/tmp/a", "wait": "/tmp/b" } }] }"#,
+        ] {
+            assert!(Config::parse(config).is_err(), "{config} parsed");
+        }
+    }
+
+    #[test]
+    fn a_hook_has_exactly_one_action() {
+        for config in [
+            r#"{ "hooks": [{ "name": "a" }] }"#,
+            r#"{ "hooks": [{ "name": "a", "command": ["true"], "file": { "path": "/tmp/a" } }] }"#,
+            r#"{ "hooks": [{ "name": "a", "signal": "TERM" }] }"#,
+        ] {
+            assert!(Config::parse(config).is_err(), "{config} parsed");
+        }
+    }
+
+    #[test]
+    fn headers_that_would_split_the_request_are_rejected() {
+        let hook =
+            |http: &str| format!(r#"{{ "hooks": [{{ "name": "flush", "http": {http} }}] }}"#);
+        let config = Config::parse(&hook(
+            r#"{ "url": "http://a", "method": "PUT", "headers": { "x-token": "secret" } }"#,
+        ))
+        .unwrap();
+        let Action::Http(http) = &config.hooks[0].action else {
+            panic!("not an HTTP hook: {:?}", config.hooks[0]);
+        };
+        assert_eq!(http.method, "PUT");
+        assert_eq!(http.headers["x-token"], "secret");
+
+        for (http, error) in [
+            (
+                r#"{ "url": "http://a", "headers": { "x-token": "a\r\nx-admin: 1" } }"#,
+                r#"hook flush: invalid value for header x-token: "a\r\nx-admin: 1""#,
+            ),
+            (
+                r#"{ "url": "http://a", "headers": { "x-token\n": "a" } }"#,
+                r#"hook flush: invalid header name "x-token\n""#,
+            ),
+            (
+                r#"{ "url": "http://a", "headers": { "": "a" } }"#,
+                r#"hook flush: invalid header name """#,
+            ),
+            (
+                r#"{ "url": "http://a", "method": "GET / HTTP/1.1\r\n" }"#,
+                r#"hook flush: invalid method "GET / HTTP/1.1\r\n""#,
+            ),
+        ] {
+            assert_eq!(Config::parse(&hook(http)).unwrap_err(), error);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! A control API on localhost, for processes in the sandbox that take part in the shutdown.
+//!
+//! Hooks from the configuration only tell the function about the shutdown. A process that
+//! has to flush something of its own, in whatever language, can instead hold the shutdown
+//! until it is done, over plain HTTP/1.1 on `127.0.0.1`:
+//!
+//! - `POST /v1/subscribe/{name}` is answered when the shutdown starts, with the same JSON
+//!   body the HTTP hooks send. `name` is the one used in the logs and the report.
+//! - `POST /v1/done/{name}` tells the extension that `name` has finished flushing.
+//!
+//! The [`ControlHook`] runs before the configured hooks, and waits until every process that
+//! subscribed before the shutdown started has reported that it is done. A process that
+//! subscribes later is answered at once, but not waited for.
+
+use std::{collections::BTreeMap, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use lambda_graceful_shutdown::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+use serde_json::json;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use crate::hooks::shutdown_body;
+
+/// The largest request head read before giving up on the request.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+/// How long to wait before accepting again after a failure, which is usually the process
+/// running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What the subscribed processes are waiting for, and which of them are done.
+#[derive(Debug)]
+struct State {
+    /// The body sent to subscribers, once the shutdown has started.
+    shutdown: watch::Sender<Option<String>>,
+    /// Whether each process that subscribed before the shutdown has reported it is done.
+    done: watch::Sender<BTreeMap<String, bool>>,
+}
+
+/// The control API, listening on `127.0.0.1`.
+#[derive(Debug, Clone)]
+pub struct ControlServer {
+    state: Arc<State>,
+}
+
+impl ControlServer {
+    /// Listen on `port`, and serve requests in a task of their own.
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        Ok(Self::serve(listener))
+    }
+
+    /// Serve requests on `listener`, in a task of their own.
+    fn serve(listener: TcpListener) -> Self {
+        let server = Self {
+            state: Arc::new(State {
+                shutdown: watch::channel(None).0,
+                done: watch::channel(BTreeMap::new()).0,
+            }),
+        };
+        let state = server.state.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(error) => {
+                        tracing::warn!(%error, "failed to accept a control connection");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                // A client that goes away mid-request only loses its own answer
+                tokio::spawn(async move {
+                    let _ = serve(stream, &state).await;
+                });
+            }
+        });
+        server
+    }
+
+    /// The hook that starts the shutdown for the subscribed processes.
+    pub fn shutdown_hook(&self) -> ControlHook {
+        ControlHook {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// Answer the one request on `stream`.
+async fn serve(mut stream: TcpStream, state: &State) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || head.len() + read > MAX_HEAD_BYTES {
+            return respond(&mut stream, "400 Bad Request", r#"{"error":"bad request"}"#).await;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next().unwrap_or_default());
+    let path = path.split_once('?').map_or(path, |(path, _query)| path);
+
+    match (
+        method,
+        path.strip_prefix("/v1/")
+            .and_then(|path| path.split_once('/')),
+    ) {
+        (Some("POST"), Some(("subscribe", name))) if !name.is_empty() => {
+            let mut shutdown = state.shutdown.subscribe();
+            if shutdown.borrow().is_none() {
+                state.done.send_modify(|done| {
+                    done.entry(name.to_owned()).or_insert(false);
+                });
+            }
+            let body = match shutdown.wait_for(Option::is_some).await {
+                Ok(body) => body.clone().unwrap_or_default(),
+                Err(_) => return Ok(()),
+            };
+            respond(&mut stream, "200 OK", &body).await
+        }
+        (Some("POST"), Some(("done", name))) if !name.is_empty() => {
+            let known = state
+                .done
+                .send_if_modified(|done| match done.get_mut(name) {
+                    Some(finished) => {
+                        *finished = true;
+                        true
+                    }
+                    None => false,
+                });
+            if known {
+                respond(&mut stream, "200 OK", "{}").await
+            } else {
+                let body = json!({ "error": format!("{name} never subscribed") }).to_string();
+                respond(&mut stream, "404 Not Found", &body).await
+            }
+        }
+        _ => respond(&mut stream, "404 Not Found", r#"{"error":"not found"}"#).await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\
+         connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answers the subscribed processes, and waits for them to report that they are done.
+#[derive(Debug)]
+pub struct ControlHook {
+    state: Arc<State>,
+}
+
+impl ShutdownHook for ControlHook {
+    fn name(&self) -> &str {
+        "control"
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.state.shutdown.send_replace(Some(shutdown_body(ctx)));
+            let mut done = self.state.done.subscribe();
+            let clock = ctx.clock();
+            tokio::select! {
+                _ = done.wait_for(|done| done.values().all(|&done| done)) => Ok(()),
+                () = clock.sleep_until(ctx.drain_deadline()) => {
+                    let pending: Vec<_> = self
+                        .state
+                        .done
+                        .borrow()
+                        .iter()
+                        .filter(|(_, &done)| !done)
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    Err(DrainTimeout::new(format!(
+                        "never reported done: {}",
+                        pending.join(", ")
+                    ))
+                    .into())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use lambda_graceful_shutdown::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+
+    use super::*;
+
+    async fn start() -> (ControlServer, SocketAddr) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (ControlServer::serve(listener), addr)
+    }
+
+    /// Send a `POST` to `path`, and read the whole response.
+    async fn post(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST {path} HTTP/1.1\r\nhost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// Subscribe as `name` from a task of its own, and wait until the server knows about it.
+    async fn subscribe(
+        server: &ControlServer,
+        addr: SocketAddr,
+        name: &str,
+    ) -> tokio::task::JoinHandle<String> {
+        let path = format!("/v1/subscribe/{name}");
+        let subscribed = tokio::spawn(async move { post(addr, &path).await });
+        let name = name.split_once('?').map_or(name, |(name, _)| name);
+        let mut done = server.state.done.subscribe();
+        done.wait_for(|done| done.contains_key(name)).await.unwrap();
+        subscribed
+    }
+
+    fn coordinator(server: &ControlServer) -> ShutdownCoordinator {
+        ShutdownCoordinator::new()
+            .with_budget(Duration::from_millis(200))
+            .with_hook(server.shutdown_hook())
+    }
+
+    #[tokio::test]
+    async fn the_shutdown_waits_for_subscribers_to_report_done() {
+        let (server, addr) = start().await;
+        let subscribed = subscribe(&server, addr, "flusher").await;
+        let shutdown = coordinator(&server);
+        let shutdown =
+            tokio::spawn(async move { shutdown.shutdown(ShutdownReason::Sigterm).await });
+
+        let response = subscribed.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("SIGTERM"), "{response}");
+        assert!(!shutdown.is_finished());
+
+        let response = post(addr, "/v1/done/flusher").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let report = shutdown.await.unwrap();
+        assert_eq!(report.hooks[0].outcome, HookOutcome::Completed);
+    }
+
+    #[tokio::test]
+    async fn done_for_a_name_that_never_subscribed_is_not_found() {
+        let (_server, addr) = start().await;
+        let response = post(addr, "/v1/done/nobody").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("nobody never subscribed"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn a_subscriber_that_never_reports_done_is_a_drain_timeout() {
+        let (server, addr) = start().await;
+        let _subscribed = subscribe(&server, addr, "flusher").await;
+        let report = coordinator(&server).shutdown(ShutdownReason::Sigterm).await;
+        assert_eq!(
+            report.hooks[0].outcome,
+            HookOutcome::DrainTimedOut("never reported done: flusher".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn the_query_string_is_not_part_of_the_name() {
+        let (server, addr) = start().await;
+        let subscribed = subscribe(&server, addr, "flusher?pid=42").await;
+        let shutdown = coordinator(&server);
+        let shutdown =
+            tokio::spawn(async move { shutdown.shutdown(ShutdownReason::Sigterm).await });
+        subscribed.await.unwrap();
+
+        let response = post(addr, "/v1/done/flusher?pid=42").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let report = shutdown.await.unwrap();
+        assert_eq!(report.hooks[0].outcome, HookOutcome::Completed);
+    }
+}
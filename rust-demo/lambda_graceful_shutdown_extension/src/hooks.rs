@@ -0,0 +1,314 @@
+//! The shutdown hooks built from the configuration.
+
+use std::{net::ToSocketAddrs, time::Duration};
+
+use lambda_graceful_shutdown::{BoxFuture, DrainTimeout, Error, ShutdownContext, ShutdownHook};
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    process::Command,
+};
+
+use crate::config::{Action, FileAction, HookConfig, HttpAction};
+
+/// How often a file hook checks for the file it waits for.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A hook from the configuration.
+#[derive(Debug)]
+pub struct ConfiguredHook {
+    config: HookConfig,
+}
+
+impl ConfiguredHook {
+    pub fn new(config: HookConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl ShutdownHook for ConfiguredHook {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            match &self.config.action {
+                Action::Command(command) => run_command(command, ctx).await,
+                Action::Http(http) => call_endpoint(http, ctx).await,
+                Action::File(file) => signal_file(file, ctx).await,
+            }
+        })
+    }
+}
+
+/// What hooks tell the function about the shutdown, as JSON.
+pub fn shutdown_body(ctx: &ShutdownContext) -> String {
+    json!({
+        "reason": ctx.reason().to_string(),
+        "remaining_ms": ctx.remaining().as_millis() as u64,
+    })
+    .to_string()
+}
+
+async fn run_command(command: &[String], ctx: &ShutdownContext) -> Result<(), Error> {
+    let [program, args @ ..] = command else {
+        return Err("the command is empty".into());
+    };
+    let status = Command::new(program)
+        .args(args)
+        .env("LAMBDA_SHUTDOWN_REASON", ctx.reason().to_string())
+        .env(
+            "LAMBDA_SHUTDOWN_REMAINING_MS",
+            ctx.remaining().as_millis().to_string(),
+        )
+        // Dropped by the coordinator when it runs out of time
+        .kill_on_drop(true)
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(format!("{program} exited with {status}").into());
+    }
+    Ok(())
+}
+
+async fn call_endpoint(http: &HttpAction, ctx: &ShutdownContext) -> Result<(), Error> {
+    let rest = http
+        .url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("only http:// URLs are supported, not {}", http.url))?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let path = if path.is_empty() { "/" } else { path };
+    let address = with_port(authority)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{authority} doesn't resolve"))?;
+
+    let body = shutdown_body(ctx);
+    let mut request = format!(
+        "{} {path} HTTP/1.1\r\nhost: {authority}\r\ncontent-type: application/json\r\n\
+         content-length: {}\r\nconnection: close\r\n",
+        http.method,
+        body.len()
+    );
+    for (name, value) in &http.headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(&body);
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(request.as_bytes()).await?;
+    // Only the status line matters, and the endpoint may keep the connection open after it
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).await?;
+    let status = status_line
+        .strip_prefix("HTTP/")
+        .and_then(|rest| rest.split(' ').nth(1))
+        .ok_or("the endpoint sent an invalid response")?;
+    if !status.starts_with('2') {
+        return Err(format!("the endpoint responded with {status}").into());
+    }
+    Ok(())
+}
+
+/// `authority`, with port 80 unless it has a port, as in `localhost:8080` or `[::1]:8080`.
+fn with_port(authority: &str) -> String {
+    let has_port = match authority.rsplit_once(']') {
+        Some((_, after)) => after.starts_with(':'),
+        None => authority.contains(':'),
+    };
+    if has_port {
+        authority.to_owned()
+    } else {
+        format!("{authority}:80")
+    }
+}
+
+async fn signal_file(file: &FileAction, ctx: &ShutdownContext) -> Result<(), Error> {
+    tokio::fs::write(&file.path, shutdown_body(ctx)).await?;
+    let Some(wait_for) = &file.wait_for else {
+        return Ok(());
+    };
+    let clock = ctx.clock();
+    while !tokio::fs::try_exists(wait_for).await? {
+        if clock.now() >= ctx.drain_deadline() {
+            return Err(
+                DrainTimeout::new(format!("{} never showed up", wait_for.display())).into(),
+            );
+        }
+        // The last check is at the deadline, rather than up to an interval before it
+        clock
+            .sleep_until((clock.now() + POLL_INTERVAL).min(ctx.drain_deadline()))
+            .await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, path::PathBuf};
+
+    use lambda_graceful_shutdown::{HookOutcome, ShutdownCoordinator, ShutdownReason};
+    use serde_json::Value;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+
+    /// Run the hook configured as `config`, with `budget`.
+    async fn run(config: Value, budget: Duration) -> HookOutcome {
+        let config: HookConfig = serde_json::from_value(config).unwrap();
+        let report = ShutdownCoordinator::new()
+            .with_budget(budget)
+            .with_hook(ConfiguredHook::new(config))
+            .shutdown(ShutdownReason::Sigterm)
+            .await;
+        report.hooks[0].outcome.clone()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("graceful-shutdown-{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn a_command_gets_the_shutdown_in_its_environment() {
+        let check = r#"test "$LAMBDA_SHUTDOWN_REASON" = SIGTERM && test "$LAMBDA_SHUTDOWN_REMAINING_MS" -gt 0"#;
+        let outcome = run(
+            json!({ "name": "ok", "command": ["/bin/sh", "-c", check] }),
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(outcome, HookOutcome::Completed);
+
+        let outcome = run(
+            json!({ "name": "fails", "command": ["/bin/sh", "-c", "exit 3"] }),
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(
+            outcome,
+            HookOutcome::Failed("/bin/sh exited with exit status: 3".to_owned())
+        );
+
+        let outcome = run(
+            json!({ "name": "empty", "command": [] }),
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(
+            outcome,
+            HookOutcome::Failed("the command is empty".to_owned())
+        );
+    }
+
+    /// Accept one request, send `response`, and hand back the request while the connection
+    /// stays open.
+    async fn respond_once(listener: TcpListener, response: &'static str) -> (String, TcpStream) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+        stream.write_all(response.as_bytes()).await.unwrap();
+        (String::from_utf8(request).unwrap(), stream)
+    }
+
+    #[tokio::test]
+    async fn an_http_hook_posts_the_shutdown_and_reads_only_the_status() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/shutdown", listener.local_addr().unwrap());
+        let server = tokio::spawn(respond_once(listener, "HTTP/1.1 204 No Content\r\n\r\n"));
+        let config = json!({
+            "name": "flush",
+            "http": { "url": url, "headers": { "x-token": "secret" } },
+        });
+        // The server never closes the connection, so this only finishes if the status is enough
+        let outcome = run(config, Duration::from_secs(5)).await;
+        assert_eq!(outcome, HookOutcome::Completed);
+
+        let (request, _open) = server.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("POST /shutdown HTTP/1.1\r\n"), "{head}");
+        assert!(head.contains("\r\nx-token: secret"), "{head}");
+        assert!(
+            head.contains("\r\ncontent-type: application/json"),
+            "{head}"
+        );
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["reason"], "SIGTERM");
+        assert!(body["remaining_ms"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn an_error_status_fails_the_http_hook() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(respond_once(
+            listener,
+            "HTTP/1.1 503 Service Unavailable\r\n\r\n",
+        ));
+        let config = json!({ "name": "flush", "http": { "url": url, "method": "PUT" } });
+        let outcome = run(config, Duration::from_secs(5)).await;
+        assert_eq!(
+            outcome,
+            HookOutcome::Failed("the endpoint responded with 503".to_owned())
+        );
+        let (request, _open) = server.await.unwrap();
+        assert!(request.starts_with("PUT / HTTP/1.1\r\n"), "{request}");
+    }
+
+    #[test]
+    fn port_80_is_the_default_for_names_and_addresses() {
+        assert_eq!(with_port("localhost"), "localhost:80");
+        assert_eq!(with_port("localhost:8080"), "localhost:8080");
+        assert_eq!(with_port("127.0.0.1"), "127.0.0.1:80");
+        assert_eq!(with_port("[::1]"), "[::1]:80");
+        assert_eq!(with_port("[::1]:8080"), "[::1]:8080");
+        assert!(with_port("[::1]").to_socket_addrs().is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_file_hook_writes_the_file_and_waits_for_the_other() {
+        let (path, wait_for) = (temp_path("shutting-down"), temp_path("done"));
+        let _ = std::fs::remove_file(&wait_for);
+        let done = wait_for.clone();
+        let function = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tokio::fs::write(done, "").await.unwrap();
+        });
+        let config = json!({ "name": "marker", "file": { "path": path, "wait_for": wait_for } });
+        let outcome = run(config, Duration::from_secs(5)).await;
+        assert_eq!(outcome, HookOutcome::Completed);
+        function.await.unwrap();
+
+        let body: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(body["reason"], "SIGTERM");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&wait_for).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_file_that_never_shows_up_is_a_drain_timeout() {
+        let (path, wait_for) = (temp_path("marker"), temp_path("never"));
+        let config = json!({ "name": "marker", "file": { "path": path, "wait_for": wait_for } });
+        let outcome = run(config, Duration::from_millis(100)).await;
+        assert_eq!(
+            outcome,
+            HookOutcome::DrainTimedOut(format!("{} never showed up", wait_for.display()))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+//! An external extension that runs the shutdown hooks listed in a configuration file, for
+//! functions written in any language.
+//!
+//! Packaged as a layer, it registers for `SHUTDOWN` events, which gives the environment the
+//! 2s shutdown window of external extensions. When the event arrives, it runs the hooks in the
+//! [configuration](config::Config): programs to run, HTTP endpoints of the function to call,
+//! and files to write and wait for. With a [control API](control), processes in other
+//! languages can also hold the shutdown until they report they are done. How each one went is
+//! logged, along with the final `SHUTDOWN_SUMMARY` line, as a Rust function using
+//! `lambda-graceful-shutdown` would.
+
+mod config;
+mod control;
+mod hooks;
+
+use lambda_extension::{service_fn, Error, Extension, LambdaEvent, NextEvent};
+use lambda_graceful_shutdown::{logging::Logging, ShutdownCoordinator, ShutdownReason};
+
+use crate::{config::Config, control::ControlServer, hooks::ConfiguredHook};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // Follows the function's log level and format, like the function's own logs
+    Logging::from_env().init(std::io::stdout);
+    let config = Config::from_env()?;
+    let shutdown = ShutdownCoordinator::new()
+        .with_budget(config.budget())
+        .with_lifecycle_logs();
+    // Registered last first, so that they run in the order they are listed in
+    for hook in config.hooks.into_iter().rev() {
+        shutdown.register(ConfiguredHook::new(hook));
+    }
+    if let Some(control) = &config.control {
+        // Registered last, so the processes that subscribed flush before the configured hooks
+        // run, which may depend on them
+        let server = ControlServer::bind(control.port).await?;
+        shutdown.register(server.shutdown_hook());
+    }
+    shutdown.log_inventory();
+
+    Extension::new()
+        .with_events(&["SHUTDOWN"])
+        .with_events_processor(service_fn(move |event: LambdaEvent| {
+            let shutdown = shutdown.clone();
+            async move {
+                if let NextEvent::Shutdown(event) = event.next {
+                    let reason = ShutdownReason::from_extension_reason(&event.shutdown_reason)
+                        .unwrap_or(ShutdownReason::Spindown);
+                    shutdown.shutdown(reason).await;
+                }
+                Ok::<(), Error>(())
+            }
+        }))
+        .run()
+        .await
+}
@@ -0,0 +1,45 @@
+/*
+ * C bindings for lambda-graceful-shutdown, built as liblambda_graceful_shutdown_ffi.so by
+ * the crate in this directory. See src/lib.rs for the details.
+ */
+#ifndef LAMBDA_GRACEFUL_SHUTDOWN_H
+#define LAMBDA_GRACEFUL_SHUTDOWN_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Called at shutdown with the user_data it was registered with and the milliseconds left
+ * of the budget. Returns 0 on success and anything else on failure.
+ */
+typedef int (*graceful_shutdown_hook)(void *user_data, uint32_t remaining_ms);
+
+/*
+ * Give the hooks budget_ms milliseconds in total, or the default 450ms if 0. Returns 0, or
+ * -1 if a hook was already registered.
+ */
+int graceful_shutdown_init(uint32_t budget_ms);
+
+/*
+ * Register callback to run at shutdown under name, which is copied. user_data is passed to
+ * callback on another thread, and must still be valid then. Returns 0, or -1 if name or
+ * callback is null or name isn't UTF-8.
+ */
+int graceful_shutdown_register_hook(const char *name, graceful_shutdown_hook callback,
+                                    void *user_data);
+
+/*
+ * Block until SIGTERM or SIGINT arrives, run the hooks, and return the exit code for the
+ * process: 0 if every hook completed, 1 otherwise, or -1 if the signal handlers can't be
+ * installed. Call it on a thread of its own, early on.
+ */
+int graceful_shutdown_wait(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
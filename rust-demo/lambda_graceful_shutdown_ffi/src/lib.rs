@@ -0,0 +1,155 @@
+//! C bindings for the shutdown coordinator of `lambda-graceful-shutdown`.
+//!
+//! A custom runtime written in C, C++ or Zig, packaged as a `provided.al2023` bootstrap, can
+//! link against the `cdylib` this crate builds and use the same hooks and budget as a Rust
+//! function. The declarations are in `include/lambda_graceful_shutdown.h`:
+//!
+//! - [`graceful_shutdown_init()`] sets the budget, before anything else if at all
+//! - [`graceful_shutdown_register_hook()`] registers a callback to run at shutdown
+//! - [`graceful_shutdown_wait()`] blocks until `SIGTERM` or `SIGINT` arrives, runs the hooks,
+//!   and returns the exit code for the process
+//!
+//! Hooks run in reverse registration order, like in Rust. Each callback runs on a thread of
+//! its own, so one that is still running when the budget runs out is abandoned rather than
+//! holding up the rest.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use lambda_graceful_shutdown::{
+    signal::ShutdownSignals, BoxFuture, Error, ShutdownContext, ShutdownCoordinator, ShutdownHook,
+};
+
+/// The coordinator behind the C functions, created by the first of them to be called.
+static COORDINATOR: OnceLock<ShutdownCoordinator> = OnceLock::new();
+
+/// A hook callback: called with the `user_data` it was registered with and the milliseconds
+/// left of the budget, it returns 0 on success and anything else on failure.
+pub type HookCallback = unsafe extern "C" fn(user_data: *mut c_void, remaining_ms: u32) -> c_int;
+
+fn coordinator() -> &'static ShutdownCoordinator {
+    COORDINATOR.get_or_init(|| ShutdownCoordinator::new().with_lifecycle_logs())
+}
+
+/// Give the hooks `budget_ms` milliseconds in total, or the default 450ms if 0.
+///
+/// Returns 0, or -1 if a hook was already registered or the shutdown already waited for,
+/// since the budget can only be set before.
+#[no_mangle]
+pub extern "C" fn graceful_shutdown_init(budget_ms: u32) -> c_int {
+    let mut shutdown = ShutdownCoordinator::new().with_lifecycle_logs();
+    if budget_ms > 0 {
+        shutdown = shutdown.with_budget(Duration::from_millis(budget_ms.into()));
+    }
+    match COORDINATOR.set(shutdown) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Register `callback` to run at shutdown under `name`, with `user_data`.
+///
+/// Returns 0, or -1 if `name` is null or not UTF-8, or `callback` is null.
+///
+/// # Safety
+///
+/// `name` must be null or point to a NUL-terminated string, which is copied. `user_data` is
+/// passed to `callback` on another thread, and must still be valid when the shutdown runs.
+#[no_mangle]
+pub unsafe extern "C" fn graceful_shutdown_register_hook(
+    name: *const c_char,
+    callback: Option<HookCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(callback) = callback else {
+        return -1;
+    };
+    if name.is_null() {
+        return -1;
+    }
+    // SAFETY: the caller passes a NUL-terminated string
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return -1;
+    };
+    coordinator().register(CallbackHook {
+        name: name.to_owned(),
+        callback,
+        user_data: UserData(user_data),
+    });
+    0
+}
+
+/// Block until `SIGTERM` or `SIGINT` arrives, then run the hooks, and return the exit code
+/// for the process: 0 if every hook completed, 1 otherwise.
+///
+/// Call this on a thread of its own early in the bootstrap, since the signal handlers are
+/// installed when it starts. Returns -1 if they can't be.
+#[no_mangle]
+pub extern "C" fn graceful_shutdown_wait() -> c_int {
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    else {
+        return -1;
+    };
+    let code = runtime.block_on(async {
+        let Ok(mut signals) = ShutdownSignals::new() else {
+            return -1;
+        };
+        let reason = signals.recv().await;
+        coordinator().shutdown(reason).await.exit_code()
+    });
+    // Don't wait for the callbacks that ran out of time
+    runtime.shutdown_background();
+    code
+}
+
+/// The `user_data` of a hook.
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+// SAFETY: whoever registers the hook promises that `user_data` can be used from the thread
+// the callback runs on
+unsafe impl Send for UserData {}
+// SAFETY: as above, the pointer is only ever passed on to the callback
+unsafe impl Sync for UserData {}
+
+impl UserData {
+    /// The pointer, taken through a method so that closures capture the whole `UserData`.
+    fn get(self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// A hook registered with [`graceful_shutdown_register_hook()`].
+struct CallbackHook {
+    name: String,
+    callback: HookCallback,
+    user_data: UserData,
+}
+
+impl ShutdownHook for CallbackHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn shutdown<'a>(&'a self, ctx: &'a ShutdownContext) -> BoxFuture<'a, Result<(), Error>> {
+        let (callback, user_data) = (self.callback, self.user_data);
+        let remaining_ms = u32::try_from(ctx.remaining().as_millis()).unwrap_or(u32::MAX);
+        Box::pin(async move {
+            // The callback blocks, so it gets a thread of its own
+            let status = tokio::task::spawn_blocking(move || {
+                // SAFETY: the callback was registered with this `user_data`
+                unsafe { callback(user_data.get(), remaining_ms) }
+            })
+            .await?;
+            match status {
+                0 => Ok(()),
+                status => Err(format!("the hook returned {status}").into()),
+            }
+        })
+    }
+}
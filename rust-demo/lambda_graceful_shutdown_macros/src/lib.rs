@@ -0,0 +1,243 @@
+//! Attribute macros for `lambda-graceful-shutdown`. Use them through the `macros` feature of
+//! that crate rather than depending on this one.
+
+use proc_macro::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{meta, parse_macro_input, FnArg, ItemFn, LitStr, ReturnType, Type};
+
+/// The path to the runtime crate, from the generated code.
+fn krate() -> proc_macro2::TokenStream {
+    quote!(::lambda_graceful_shutdown)
+}
+
+/// Turn an async function into a constructor for a
+/// [`ShutdownHook`](https://docs.rs/lambda-graceful-shutdown/latest/lambda_graceful_shutdown/trait.ShutdownHook.html).
+///
+/// The function can take a `&ShutdownContext` (or a `ShutdownContext`), or nothing at all, and
+/// return `()` or a `Result<(), E>` for any error that converts into
+/// `lambda_graceful_shutdown::Error`. It is replaced by a function of the same name that takes
+/// no arguments and returns the hook. The generated hook logs when it starts, and when it
+/// finishes with how long it took and the error if it failed, at `debug`.
+///
+/// The hook is named after the function, with underscores turned into dashes, unless a name
+/// is given with `#[shutdown_hook(name = "...")]`.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{shutdown_hook, ShutdownContext, ShutdownCoordinator};
+///
+/// #[shutdown_hook]
+/// async fn flush_metrics(ctx: &ShutdownContext) -> Result<(), std::io::Error> {
+///     println!("flushing with {:?} left", ctx.remaining());
+///     Ok(())
+/// }
+///
+/// #[shutdown_hook(name = "goodbye")]
+/// async fn say_goodbye() {
+///     println!("goodbye");
+/// }
+///
+/// let shutdown = ShutdownCoordinator::new()
+///     .with_hook(flush_metrics())
+///     .with_hook(say_goodbye());
+/// ```
+#[proc_macro_attribute]
+pub fn shutdown_hook(args: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name = None;
+    let parser = meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported shutdown_hook argument, expected `name`"))
+        }
+    });
+    parse_macro_input!(args with parser);
+    let function = parse_macro_input!(item as ItemFn);
+
+    match expand(name, function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(name: Option<LitStr>, function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            sig.fn_token,
+            "shutdown hooks must be async functions",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "shutdown hooks can't be generic",
+        ));
+    }
+
+    // How to pass the context to the function, if it takes it
+    let call = match sig.inputs.len() {
+        0 => quote!(__hook()),
+        1 => match &sig.inputs[0] {
+            FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new_spanned(
+                    receiver,
+                    "shutdown hooks can't take `self`",
+                ))
+            }
+            FnArg::Typed(arg) => match &*arg.ty {
+                Type::Reference(_) => quote!(__hook(&ctx)),
+                _ => quote!(__hook(ctx.clone())),
+            },
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &sig.inputs,
+                "shutdown hooks take at most one argument, the `ShutdownContext`",
+            ))
+        }
+    };
+
+    let ident = &sig.ident;
+    let name = name.map_or_else(|| ident.to_string().replace('_', "-"), |name| name.value());
+    let inputs = &sig.inputs;
+    let output = match &sig.output {
+        ReturnType::Default => quote!(),
+        output => output.to_token_stream(),
+    };
+    let krate = krate();
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #ident() -> impl #krate::ShutdownHook {
+            async fn __hook(#inputs) #output #block
+
+            #krate::hook_fn(#name, |ctx: #krate::ShutdownContext| async move {
+                let started = ::std::time::Instant::now();
+                #krate::__private::tracing::debug!(hook = #name, "running shutdown hook");
+                let result = #krate::__private::IntoHookResult::into_hook_result(#call.await);
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+                match &result {
+                    ::std::result::Result::Ok(()) => #krate::__private::tracing::debug!(
+                        hook = #name,
+                        elapsed_ms,
+                        "shutdown hook finished"
+                    ),
+                    ::std::result::Result::Err(error) => #krate::__private::tracing::debug!(
+                        hook = #name,
+                        elapsed_ms,
+                        %error,
+                        "shutdown hook failed"
+                    ),
+                }
+                result
+            })
+        }
+    })
+}
+
+/// Run an async test with a `lambda_graceful_shutdown::testing::ShutdownTestHarness`: a mock
+/// Runtime and Extensions API, a coordinator, and tokio's clock paused.
+///
+/// The function can take the harness as its only argument, or nothing at all, and becomes a
+/// `#[test]`. Like a `#[test]`, it can return a `Result`, and fails if it returns an `Err`.
+/// Other attributes, such as `#[should_panic]`, are kept. Needs the `macros` and `testing`
+/// features of `lambda-graceful-shutdown`.
+///
+/// ```no_run
+/// use lambda_graceful_shutdown::{lambda_shutdown_test, testing::ShutdownTestHarness};
+/// use lambda_runtime::{Error, LambdaEvent};
+/// use serde_json::{json, Value};
+///
+/// async fn handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
+///     Ok(event.payload)
+/// }
+///
+/// #[lambda_shutdown_test]
+/// async fn shuts_down_cleanly(harness: ShutdownTestHarness) {
+///     harness.start_function(handler);
+///     harness.invoke(json!({})).await;
+///     harness.sigterm().await.assert_clean();
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn lambda_shutdown_test(args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let args = proc_macro2::TokenStream::from(args);
+        return syn::Error::new_spanned(args, "lambda_shutdown_test takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let function = parse_macro_input!(item as ItemFn);
+
+    match expand_test(function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand_test(function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            sig.fn_token,
+            "shutdown tests must be async functions",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "shutdown tests can't be generic",
+        ));
+    }
+    let call = match sig.inputs.len() {
+        0 => quote!(__test()),
+        1 => match &sig.inputs[0] {
+            FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new_spanned(
+                    receiver,
+                    "shutdown tests can't take `self`",
+                ))
+            }
+            FnArg::Typed(_) => quote!(__test(harness)),
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &sig.inputs,
+                "shutdown tests take at most one argument, the `ShutdownTestHarness`",
+            ))
+        }
+    };
+
+    let ident = &sig.ident;
+    let inputs = &sig.inputs;
+    let output = match &sig.output {
+        ReturnType::Default => quote!(),
+        output => output.to_token_stream(),
+    };
+    let krate = krate();
+
+    Ok(quote! {
+        #[test]
+        #(#attrs)*
+        #vis fn #ident() #output {
+            async fn __test(#inputs) #output #block
+
+            #krate::testing::ShutdownTestHarness::run(|harness| async move {
+                #[allow(unused_variables)]
+                let harness = harness;
+                #call.await
+            })
+        }
+    })
+}
@@ -0,0 +1,73 @@
+//! Hooks generated by `#[shutdown_hook]`.
+
+use lambda_graceful_shutdown::{
+    shutdown_hook, DrainTimeout, HookOutcome, ShutdownContext, ShutdownCoordinator, ShutdownHook,
+    ShutdownReason,
+};
+
+#[shutdown_hook]
+async fn flush_metrics(ctx: &ShutdownContext) -> Result<(), std::io::Error> {
+    ctx.note(format!("flushed at {}", ctx.reason()));
+    Ok(())
+}
+
+#[shutdown_hook(name = "goodbye")]
+async fn say_goodbye() {}
+
+#[shutdown_hook]
+async fn owns_the_context(ctx: ShutdownContext) -> Result<(), lambda_graceful_shutdown::Error> {
+    ctx.note("took the context by value");
+    Err("failed on purpose".into())
+}
+
+#[shutdown_hook]
+async fn gives_up_draining() -> Result<(), DrainTimeout> {
+    Err(DrainTimeout::new("2 items left"))
+}
+
+#[test]
+fn hooks_are_named_after_the_function_unless_named() {
+    assert_eq!(flush_metrics().name(), "flush-metrics");
+    assert_eq!(say_goodbye().name(), "goodbye");
+    assert_eq!(owns_the_context().name(), "owns-the-context");
+}
+
+#[tokio::test]
+async fn hooks_get_the_context_and_report_their_result() {
+    let report = ShutdownCoordinator::new()
+        .with_hook(flush_metrics())
+        .with_hook(say_goodbye())
+        .with_hook(owns_the_context())
+        .with_hook(gives_up_draining())
+        .shutdown(ShutdownReason::Sigterm)
+        .await;
+
+    let hook = |name: &str| {
+        report
+            .hooks
+            .iter()
+            .find(|hook| hook.name == name)
+            .unwrap_or_else(|| panic!("no report for {name}"))
+    };
+    assert_eq!(hook("flush-metrics").outcome, HookOutcome::Completed);
+    assert_eq!(hook("flush-metrics").notes, ["flushed at SIGTERM"]);
+    assert_eq!(hook("goodbye").outcome, HookOutcome::Completed);
+    assert_eq!(
+        hook("owns-the-context").outcome,
+        HookOutcome::Failed("failed on purpose".to_owned())
+    );
+    assert_eq!(
+        hook("owns-the-context").notes,
+        ["took the context by value"]
+    );
+    assert_eq!(
+        hook("gives-up-draining").outcome,
+        HookOutcome::DrainTimedOut("2 items left".to_owned())
+    );
+}
+
+#[test]
+fn unsupported_functions_are_rejected() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,8 @@
+use lambda_graceful_shutdown::shutdown_hook;
+
+#[shutdown_hook]
+async fn flush<T: Default>() {
+    let _ = T::default();
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use lambda_graceful_shutdown::shutdown_hook;
+
+struct Metrics;
+
+impl Metrics {
+    #[shutdown_hook]
+    async fn flush(&self) {}
+}
+
+fn main() {}
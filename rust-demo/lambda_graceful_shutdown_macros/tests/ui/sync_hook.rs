@@ -0,0 +1,6 @@
+use lambda_graceful_shutdown::shutdown_hook;
+
+#[shutdown_hook]
+fn flush() {}
+
+fn main() {}
@@ -1,15 +1,19 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, process::ExitCode, time::Duration};
 
 use aws_lambda_events::apigw::ApiGatewayProxyRequest;
+use lambda_graceful_shutdown::{
+    appender, chaos::Chaos, logging::Logging, signal::RunOutcome, spindown::LocalSpindown,
+    ShutdownCoordinator,
+};
 use lambda_runtime::{run, service_fn, tracing, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::signal::unix::{signal, SignalKind};
 
 /// This is a made-up example. Requests come into the runtime as unicode
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 #[derive(Deserialize)]
+#[allow(dead_code)]
 struct Request {}
 
 /// This is a made-up example of what a response structure may look like.
@@ -55,32 +59,57 @@ async fn function_handler(event: LambdaEvent<ApiGatewayProxyRequest>) -> Result<
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
-    tracing::init_default_subscriber();
+async fn main() -> Result<ExitCode, Error> {
+    // Log through a non-blocking writer, and keep its guard in a shutdown hook so that
+    // buffered lines get flushed before we exit
+    let (writer, log_flush_hook) = appender::non_blocking(std::io::stdout());
+    // Follow the function's log level and format, set LAMBDA_GRACEFUL_SHUTDOWN_LOG_LEVEL=debug
+    // to see what the shutdown hooks do
+    Logging::from_env().init(writer);
 
-    // Handle SIGTERM signal:
-    // https://tokio.rs/tokio/topics/shutdown
-    // https://rust-cli.github.io/book/in-depth/signals.html
-    tokio::spawn(async move {
-        let mut sigint = signal(SignalKind::interrupt()).unwrap();
-        let mut sigterm = signal(SignalKind::terminate()).unwrap();
-        tokio::select! {
-            _sigint = sigint.recv() => {
-                println!("[runtime] SIGINT received");
-                println!("[runtime] Graceful shutdown in progress ...");
-                println!("executing graceful shutdown handler logic");
-                println!("[runtime] Graceful shutdown completed");
-                std::process::exit(0);
-            },
-            _sigterm = sigterm.recv()=> {
-                println!("[runtime] SIGTERM received");
-                println!("[runtime] Graceful shutdown in progress ...");
-                println!("executing graceful shutdown handler logic");
-                println!("[runtime] Graceful shutdown completed");
-                std::process::exit(0);
-            },
+    // With an external extension registered, Lambda gives us 2s to shut down
+    let shutdown = ShutdownCoordinator::new()
+        .with_budget(Duration::from_millis(1800))
+        // Give an invocation still running at SIGTERM a chance to finish before the hooks run
+        .with_invocation_drain(Duration::from_millis(500))
+        // Warn when a hook uses more than half of the time it had, before it starts timing out
+        .with_slow_hook_warning(0.5)
+        // Log how many invocations this sandbox handled and how often it was frozen
+        .with_sandbox_stats_log(100)
+        // Set LAMBDA_GRACEFUL_SHUTDOWN_CHAOS=0.05 in staging to interrupt some invocations
+        .with_chaos(Chaos::from_env())
+        .with_hook(log_flush_hook)
+        // Shutdown progress is written as JSON when the function logs in JSON
+        .with_lifecycle_logs();
+    // Record which hooks will run at shutdown in the first lines of the log stream
+    shutdown.log_inventory();
+    // Under `cargo lambda watch`, `curl -X POST localhost:9901/shutdown/spindown` sends us a
+    // SIGTERM, and a SIGKILL once the 2s window is over
+    LocalSpindown::new()
+        .with_window(Duration::from_secs(2))
+        .spawn_if_local();
+
+    let runtime = run(service_fn(|event: LambdaEvent<ApiGatewayProxyRequest>| {
+        let shutdown = shutdown.clone();
+        async move {
+            // Correlate the shutdown records and span with the last invocation
+            let _invocation = shutdown.track_invocation(
+                event.context.request_id.clone(),
+                event.context.xray_trace_id.clone(),
+            );
+            function_handler(event).await
         }
-    });
+    }));
 
-    run(service_fn(function_handler)).await
+    // Handle SIGTERM and SIGINT in the same select as the runtime, which keeps running while
+    // the hooks do, so the invocation in flight can still respond:
+    // https://tokio.rs/tokio/topics/shutdown
+    // https://rust-cli.github.io/book/in-depth/signals.html
+    // The hooks run in `shutdown` and `shutdown_hook` spans, and the lifecycle logs report how
+    // long it took, even after the log flush hook has run. The process exits after a final
+    // SHUTDOWN_SUMMARY line, with 1 if a hook didn't complete
+    match shutdown.run_until_signal(runtime).await? {
+        RunOutcome::Finished(result) => result.map(|()| ExitCode::SUCCESS),
+        RunOutcome::ShutDown(report) => Ok(ExitCode::from(report.exit_code() as u8)),
+    }
 }
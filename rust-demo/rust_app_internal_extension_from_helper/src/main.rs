@@ -1,6 +1,13 @@
 use std::collections::HashMap;
 
 use aws_lambda_events::apigw::ApiGatewayProxyRequest;
+use lambda_graceful_shutdown::{
+    appender,
+    history::ReportHistory,
+    init::{self, InitTimings},
+    logging::Logging,
+    ShutdownCoordinator, ShutdownReason,
+};
 use lambda_runtime::{
     run, service_fn, spawn_graceful_shutdown_handler, tracing, Error, LambdaEvent,
 };
@@ -11,6 +18,7 @@ use serde_json::json;
 /// strings in json format, which can map to any structure that implements `serde::Deserialize`
 /// The runtime pays no attention to the contents of the request payload.
 #[derive(Deserialize)]
+#[allow(dead_code)]
 struct Request {}
 
 /// This is a made-up example of what a response structure may look like.
@@ -57,12 +65,45 @@ async fn function_handler(event: LambdaEvent<ApiGatewayProxyRequest>) -> Result<
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing::init_default_subscriber();
+    // Logged as one record when the runtime starts, to see what the shutdown handling adds
+    // to the cold start
+    let timings = InitTimings::start();
+    let shutdown = ShutdownCoordinator::new()
+        .with_init_timings(&timings)
+        .with_lifecycle_logs()
+        // Keep the last reports in /tmp, to look at earlier shutdowns when testing with the
+        // Runtime Interface Emulator
+        .with_report_history(ReportHistory::default());
+
+    // Register the helper's extension in the background while the rest of the Init phase
+    // runs, rather than waiting for the round trip before anything else
+    let coordinator = shutdown.clone();
+    let registration = init::start_early(spawn_graceful_shutdown_handler(|| async move {
+        tracing::info!("graceful shutdown in progress");
+        // The helper doesn't tell us which signal fired, but on Lambda it is always SIGTERM
+        // The lifecycle logs report how long the hooks took, even after the log flush hook
+        coordinator.shutdown(ShutdownReason::Sigterm).await;
+    }))
+    .timed(&timings);
 
-    spawn_graceful_shutdown_handler(|| async move {
-        eprintln!("my custom shutdown logic");
-    })
-    .await;
+    // Log through a non-blocking writer, and keep its guard in a shutdown hook so that
+    // buffered lines get flushed before the helper exits the process
+    let (writer, log_flush_hook) = appender::non_blocking(std::io::stdout());
+    // Follow the function's log level and format, set LAMBDA_GRACEFUL_SHUTDOWN_LOG_LEVEL=debug
+    // to see what the shutdown hooks do
+    Logging::from_env().init(writer);
+    shutdown.register(log_flush_hook);
+    // Record which hooks will run at shutdown in the first lines of the log stream
+    shutdown.log_inventory();
 
-    run(service_fn(function_handler)).await
+    // The extension has to be registered before the runtime asks for the first invocation
+    registration
+        .before(run(service_fn(
+            |event: LambdaEvent<ApiGatewayProxyRequest>| {
+                // Correlate the shutdown logs with the invocation that preceded them
+                shutdown.set_request_id(event.context.request_id.clone());
+                function_handler(event)
+            },
+        )))
+        .await
 }